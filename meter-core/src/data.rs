@@ -1,8 +1,14 @@
-use std::fmt::{Display, Write as FmtWrite};
-use std::io::{Read, Write as StdIoWrite};
+use crate::p1_meter::CompleteP1Measurement;
+use serde::Serialize;
+use std::error::Error;
+use std::fmt::{self, Display, Write as FmtWrite};
+use std::io::Write as StdIoWrite;
 use std::process::{Command, Stdio};
 use std::str::FromStr;
+use std::thread;
 use std::time::Instant;
+#[cfg(any(test, feature = "fake-sqlite"))]
+use std::{collections::VecDeque, sync::Mutex, time::Duration};
 
 /*
 CREATE TABLE data_202208 (
@@ -39,7 +45,7 @@ pub struct Data202208 {
     pub water_m3: Option<f64>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize)]
 #[allow(non_snake_case)]
 pub struct Data202303 {
     pub timestamp: i64,
@@ -53,6 +59,138 @@ pub struct Data202303 {
     pub water_m3: Option<f64>,
 }
 
+impl Data202303 {
+    /// Record without any value.
+    pub fn empty(timestamp: i64) -> Self {
+        Data202303 {
+            timestamp,
+            pv2012_kWh: None,
+            pv2022_kWh: None,
+            peak_conso_kWh: None,
+            off_conso_kWh: None,
+            peak_inj_kWh: None,
+            off_inj_kWh: None,
+            gas_m3: None,
+            water_m3: None,
+        }
+    }
+
+    /// Record holding the 4 energy registers of `p1`.
+    pub fn from_p1(timestamp: i64, p1: &CompleteP1Measurement) -> Self {
+        Data202303 {
+            peak_conso_kWh: Some(p1.peak_hour_consumption),
+            off_conso_kWh: Some(p1.off_hour_consumption),
+            peak_inj_kWh: Some(p1.peak_hour_injection),
+            off_inj_kWh: Some(p1.off_hour_injection),
+            gas_m3: p1.gas_m3,
+            water_m3: p1.water_m3,
+            ..Data202303::empty(timestamp)
+        }
+    }
+
+    #[allow(non_snake_case)]
+    pub fn with_pv2012(self, pv2012_kWh: Option<f64>) -> Self {
+        Data202303 { pv2012_kWh, ..self }
+    }
+
+    #[allow(non_snake_case)]
+    pub fn with_pv2022(self, pv2022_kWh: Option<f64>) -> Self {
+        Data202303 { pv2022_kWh, ..self }
+    }
+
+    pub fn with_gas(self, gas_m3: Option<f64>) -> Self {
+        Data202303 { gas_m3, ..self }
+    }
+
+    pub fn with_water(self, water_m3: Option<f64>) -> Self {
+        Data202303 { water_m3, ..self }
+    }
+
+    /// True when at least one column besides `timestamp` holds a value (even 0.0).
+    pub fn has_values(&self) -> bool {
+        self.pv2012_kWh.is_some()
+            || self.pv2022_kWh.is_some()
+            || self.peak_conso_kWh.is_some()
+            || self.off_conso_kWh.is_some()
+            || self.peak_inj_kWh.is_some()
+            || self.off_inj_kWh.is_some()
+            || self.gas_m3.is_some()
+            || self.water_m3.is_some()
+    }
+
+    /// Fill every column that is `None` in `self` with the value from `other`,
+    /// leaving `timestamp` untouched.
+    pub fn merge_from(&mut self, other: &Data202303) {
+        self.pv2012_kWh = self.pv2012_kWh.or(other.pv2012_kWh);
+        self.pv2022_kWh = self.pv2022_kWh.or(other.pv2022_kWh);
+        self.peak_conso_kWh = self.peak_conso_kWh.or(other.peak_conso_kWh);
+        self.off_conso_kWh = self.off_conso_kWh.or(other.off_conso_kWh);
+        self.peak_inj_kWh = self.peak_inj_kWh.or(other.peak_inj_kWh);
+        self.off_inj_kWh = self.off_inj_kWh.or(other.off_inj_kWh);
+        self.gas_m3 = self.gas_m3.or(other.gas_m3);
+        self.water_m3 = self.water_m3.or(other.water_m3);
+    }
+
+    /// Change of every column from `earlier` to `later` (`None` unless both
+    /// are known), with `timestamp` holding the time elapsed in seconds.
+    pub fn diff(later: &Data202303, earlier: &Data202303) -> Data202303 {
+        let delta = |get: fn(&Data202303) -> Option<f64>| Some(get(later)? - get(earlier)?);
+        Data202303 {
+            timestamp: later.timestamp.saturating_sub(earlier.timestamp),
+            pv2012_kWh: delta(|r| r.pv2012_kWh),
+            pv2022_kWh: delta(|r| r.pv2022_kWh),
+            peak_conso_kWh: delta(|r| r.peak_conso_kWh),
+            off_conso_kWh: delta(|r| r.off_conso_kWh),
+            peak_inj_kWh: delta(|r| r.peak_inj_kWh),
+            off_inj_kWh: delta(|r| r.off_inj_kWh),
+            gas_m3: delta(|r| r.gas_m3),
+            water_m3: delta(|r| r.water_m3),
+        }
+    }
+
+    /// Names of the columns besides `timestamp`, all known to `field_accessor`.
+    pub const VALUE_COLUMNS: [&'static str; 8] = [
+        "pv2012_kWh",
+        "pv2022_kWh",
+        "peak_conso_kWh",
+        "off_conso_kWh",
+        "peak_inj_kWh",
+        "off_inj_kWh",
+        "gas_m3",
+        "water_m3",
+    ];
+
+    /// Getter for the column called `name`, `None` for unknown columns.
+    pub fn field_accessor(name: &str) -> Option<fn(&Data202303) -> Option<f64>> {
+        match name {
+            "pv2012_kWh" => Some(|r| r.pv2012_kWh),
+            "pv2022_kWh" => Some(|r| r.pv2022_kWh),
+            "peak_conso_kWh" => Some(|r| r.peak_conso_kWh),
+            "off_conso_kWh" => Some(|r| r.off_conso_kWh),
+            "peak_inj_kWh" => Some(|r| r.peak_inj_kWh),
+            "off_inj_kWh" => Some(|r| r.off_inj_kWh),
+            "gas_m3" => Some(|r| r.gas_m3),
+            "water_m3" => Some(|r| r.water_m3),
+            _ => None,
+        }
+    }
+
+    /// The column called `name`, to change it, `None` for unknown columns.
+    pub fn field_mut(&mut self, name: &str) -> Option<&mut Option<f64>> {
+        match name {
+            "pv2012_kWh" => Some(&mut self.pv2012_kWh),
+            "pv2022_kWh" => Some(&mut self.pv2022_kWh),
+            "peak_conso_kWh" => Some(&mut self.peak_conso_kWh),
+            "off_conso_kWh" => Some(&mut self.off_conso_kWh),
+            "peak_inj_kWh" => Some(&mut self.peak_inj_kWh),
+            "off_inj_kWh" => Some(&mut self.off_inj_kWh),
+            "gas_m3" => Some(&mut self.gas_m3),
+            "water_m3" => Some(&mut self.water_m3),
+            _ => None,
+        }
+    }
+}
+
 pub fn clone_data202303(x: &Data202303) -> Data202303 {
     Data202303 {
         timestamp: x.timestamp,
@@ -67,6 +205,128 @@ pub fn clone_data202303(x: &Data202303) -> Data202303 {
     }
 }
 
+/// Most records `fill_gaps` inserts into a single gap, a day of minutes.
+pub const MAX_FILLER_ROWS: usize = 1440;
+
+/// Copy `rows`, inserting records without any value every `step_secs` seconds
+/// where consecutive rows are more than `step_secs` apart, so that gaps in the
+/// series can be rendered explicitly.  At most `MAX_FILLER_ROWS` records go
+/// into one gap: the rest of a longer one stays unfilled.
+pub fn fill_gaps(rows: &[Data202303], step_secs: i64) -> Vec<Data202303> {
+    let mut result = Vec::with_capacity(rows.len());
+    for (idx, row) in rows.iter().enumerate() {
+        if step_secs > 0 && idx > 0 {
+            let previous = rows[idx - 1].timestamp;
+            result.extend(
+                (1..=MAX_FILLER_ROWS as i64)
+                    .map_while(|k| {
+                        step_secs
+                            .checked_mul(k)
+                            .and_then(|d| previous.checked_add(d))
+                    })
+                    .take_while(|timestamp| *timestamp < row.timestamp)
+                    .map(Data202303::empty),
+            );
+        }
+        result.push(clone_data202303(row));
+    }
+    result
+}
+
+/// Return `(start_ts, end_ts)` for every pair of consecutive rows that are more
+/// than `expected_step_secs + tolerance` seconds apart, e.g. because polling was
+/// down.
+pub fn find_gaps(rows: &[Data202303], expected_step_secs: i64, tolerance: i64) -> Vec<(i64, i64)> {
+    let max_step = expected_step_secs.saturating_add(tolerance);
+    rows.windows(2)
+        .filter(|pair| pair[1].timestamp.saturating_sub(pair[0].timestamp) > max_step)
+        .map(|pair| (pair[0].timestamp, pair[1].timestamp))
+        .collect()
+}
+
+/// Average power drawn from the grid (kW) between two readings of the
+/// consumption registers, `None` if one is missing or `later` is not later.
+pub fn average_power_kw(earlier: &Data202303, later: &Data202303) -> Option<f64> {
+    let diff = Data202303::diff(later, earlier);
+    if diff.timestamp <= 0 {
+        return None;
+    }
+    let hours = diff.timestamp as f64 / 3600.0;
+    Some((diff.peak_conso_kWh? + diff.off_conso_kWh?) / hours)
+}
+
+/// Fraction of the energy produced by both PV installations between `first`
+/// and `last` that was not injected into the grid, clamped to [0, 1].  `None`
+/// if a register is missing or nothing was produced.
+pub fn self_consumption_ratio(first: &Data202303, last: &Data202303) -> Option<f64> {
+    let diff = Data202303::diff(last, first);
+    let production = diff.pv2022_kWh? + diff.pv2012_kWh?;
+    let injection = diff.peak_inj_kWh? + diff.off_inj_kWh?;
+    if production == 0.0 {
+        return None;
+    }
+    Some(((production - injection) / production).clamp(0.0, 1.0))
+}
+
+/// Why reading from or writing to the database failed.
+#[derive(Debug, PartialEq)]
+pub enum DataError {
+    /// The database could not run the script.
+    Sqlite(String),
+    /// A line of output could not be parsed.
+    Parse { line: String, reason: String },
+    /// The output does not have the expected shape.
+    UnexpectedOutput(String),
+    /// The caller asked for something that cannot be done.
+    InvalidArgument(String),
+}
+
+impl Display for DataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DataError::Sqlite(e) => write!(f, "SQLite failed: {}", e),
+            DataError::Parse { line, reason } => write!(f, "{} in '{}'", reason, line),
+            DataError::UnexpectedOutput(output) => {
+                write!(f, "Unexpected output from SQLite: '{}'", output)
+            }
+            DataError::InvalidArgument(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for DataError {}
+
+/// Lets callers that still report errors as `String` use `?`.
+impl From<DataError> for String {
+    fn from(e: DataError) -> String {
+        e.to_string()
+    }
+}
+
+/// Parse `line` with `parse`, reporting failures as `DataError::Parse`.
+fn parse_line<T>(line: &str, parse: fn(&str) -> Result<T, String>) -> Result<T, DataError> {
+    parse(line).map_err(|reason| DataError::Parse {
+        line: line.to_string(),
+        reason,
+    })
+}
+
+/// Parse the row count printed before the rows of `table`.
+fn parse_row_count(line: Option<&str>, table: &str, output: &str) -> Result<usize, DataError> {
+    match line.map(|line| (line, usize::from_str(line))) {
+        Some((_, Ok(count))) => Ok(count),
+        None => Err(DataError::UnexpectedOutput(output.to_string())),
+        Some((line, Err(_))) => Err(DataError::Parse {
+            line: line.to_string(),
+            reason: format!("Malformed row count for {}", table),
+        }),
+    }
+}
+
+/// Columns filled by the INSERT statements, named so that they do not depend on
+/// the column order (or count) of the table.
+const DATA_202303_COLUMNS: &str = "timestamp, pv2012_kWh, pv2022_kWh, peak_conso_kWh, off_conso_kWh, peak_inj_kWh, off_inj_kWh, gas_m3, water_m3";
+
 fn some_val_to_sql<A>(v: Option<A>) -> String
 where
     A: Display,
@@ -77,11 +337,11 @@ where
     }
 }
 
-pub fn insert_data_202303(cmd: &str, meas: &Data202303) -> Result<usize, String> {
-    let sql_output = call_sqlite3(
-        cmd,
+pub fn insert_data_202303(db: &dyn Sqlite, meas: &Data202303) -> Result<usize, DataError> {
+    let sql_output = db.run(
         format!(
-            ".mode list\nINSERT INTO data_202303 VALUES ({}, {}, {}, {}, {}, {}, {}, {}, {});\nSELECT COUNT(*) FROM data_202303;",
+            ".mode list\nINSERT INTO data_202303 ({}) VALUES ({}, {}, {}, {}, {}, {}, {}, {}, {});\nSELECT COUNT(*) FROM data_202303;",
+            DATA_202303_COLUMNS,
             meas.timestamp,
             &some_val_to_sql(meas.pv2012_kWh),
             &some_val_to_sql(meas.pv2022_kWh),
@@ -90,24 +350,45 @@ pub fn insert_data_202303(cmd: &str, meas: &Data202303) -> Result<usize, String>
             &some_val_to_sql(meas.peak_inj_kWh),
             &some_val_to_sql(meas.off_inj_kWh),
             &some_val_to_sql(meas.gas_m3),
-            &some_val_to_sql(meas.water_m3)).as_str());
-    usize::from_str(&sql_output.trim()).map_err(|e| format!("{}", e))
+            &some_val_to_sql(meas.water_m3)).as_str())?;
+    parse_line(sql_output.trim(), |line| {
+        usize::from_str(line).map_err(|e| format!("{}", e))
+    })
 }
 
-pub fn insert_many_data_202303<'a, I>(cmd: &str, data_iter: I) -> Result<usize, String>
+/// Build the SQL script inserting all rows in one transaction, framed by a row
+/// count before and after so that the caller can tell how many were inserted.
+/// Returns `None` when there is nothing to insert.
+pub fn build_insert_many_sql<'a, I>(data_iter: I) -> Option<String>
 where
     I: IntoIterator<Item = &'a Data202303>,
 {
-    let start = Instant::now();
+    build_insert_many_sql_with_pragmas("", data_iter)
+}
 
-    let mut sql =
-        String::from(".mode list\nSELECT COUNT(*) FROM data_202303;\nBEGIN TRANSACTION;\n");
+/// Like `build_insert_many_sql` but running `pragmas` first, with their output
+/// discarded so that it does not get mixed up with the row counts.
+pub fn build_insert_many_sql_with_pragmas<'a, I>(pragmas: &str, data_iter: I) -> Option<String>
+where
+    I: IntoIterator<Item = &'a Data202303>,
+{
+    let mut sql = String::from(".mode list\n");
+    if !pragmas.trim().is_empty() {
+        writeln!(
+            &mut sql,
+            ".output /dev/null\n{}\n.output stdout",
+            pragmas.trim()
+        )
+        .unwrap();
+    }
+    sql.push_str("SELECT COUNT(*) FROM data_202303;\nBEGIN TRANSACTION;\n");
     let mut inserted_any = false;
 
     for meas in data_iter {
-        write!(
+        writeln!(
             &mut sql,
-            "INSERT INTO data_202303 VALUES ({}, {}, {}, {}, {}, {}, {}, {}, {});\n",
+            "INSERT INTO data_202303 ({}) VALUES ({}, {}, {}, {}, {}, {}, {}, {}, {});",
+            DATA_202303_COLUMNS,
             meas.timestamp,
             some_val_to_sql(meas.pv2012_kWh),
             some_val_to_sql(meas.pv2022_kWh),
@@ -123,38 +404,61 @@ where
     }
 
     if !inserted_any {
-        println!(
+        return None;
+    }
+
+    sql.push_str("COMMIT;\nSELECT COUNT(*) FROM data_202303;");
+    Some(sql)
+}
+
+pub fn insert_many_data_202303<'a, I>(db: &dyn Sqlite, data_iter: I) -> Result<usize, DataError>
+where
+    I: IntoIterator<Item = &'a Data202303>,
+{
+    insert_many_data_202303_with_pragmas(db, "", data_iter)
+}
+
+/// Like `insert_many_data_202303` but running `pragmas` before inserting.
+pub fn insert_many_data_202303_with_pragmas<'a, I>(
+    db: &dyn Sqlite,
+    pragmas: &str,
+    data_iter: I,
+) -> Result<usize, DataError>
+where
+    I: IntoIterator<Item = &'a Data202303>,
+{
+    let start = Instant::now();
+    let Some(sql) = build_insert_many_sql_with_pragmas(pragmas, data_iter) else {
+        tracing::info!(
             "insert_many_data_202303 executed in {:.3}s, early return",
             start.elapsed().as_secs_f64()
         );
         return Ok(0);
-    }
-
-    sql.push_str("COMMIT;\nSELECT COUNT(*) FROM data_202303;");
+    };
 
-    let sql_output = call_sqlite3(cmd, &sql);
+    let sql_output = db.run(&sql)?;
 
     // Expect two lines: one for initial count, one for final count
     let lines: Vec<&str> = sql_output.lines().collect();
     if lines.len() < 2 {
-        println!(
+        tracing::error!(
             "insert_many_data_202303 failed after {:.3}s",
             start.elapsed().as_secs_f64()
         );
-        return Err(format!("Unexpected output from SQLite: '{}'", sql_output));
+        return Err(DataError::UnexpectedOutput(sql_output));
     }
 
-    let first_line = lines[0].trim();
-    let before = first_line
-        .parse::<usize>()
-        .map_err(|e| format!("Failed to parse initial count in '{}': {}", first_line, e))?;
-    let last_line = lines.last().unwrap().trim();
-    let after = last_line
-        .parse::<usize>()
-        .map_err(|e| format!("Failed to parse final count in '{}': {}", last_line, e))?;
+    let before = parse_line(lines[0].trim(), |line| {
+        line.parse::<usize>()
+            .map_err(|e| format!("Failed to parse initial count ({})", e))
+    })?;
+    let after = parse_line(lines.last().unwrap().trim(), |line| {
+        line.parse::<usize>()
+            .map_err(|e| format!("Failed to parse final count ({})", e))
+    })?;
 
     let inserted = after - before;
-    println!(
+    tracing::info!(
         "insert_many_data_202303 inserted {} rows in {:.3}s",
         inserted,
         start.elapsed().as_secs_f64()
@@ -163,6 +467,37 @@ where
     Ok(inserted)
 }
 
+/// Insert the rows in chunks of at most `chunk_size` rows, each chunk in its own
+/// transaction.  On failure, the error comes with the number of rows inserted
+/// by the previous chunks.
+pub fn insert_many_data_202303_chunked<'a, I>(
+    db: &dyn Sqlite,
+    data_iter: I,
+    chunk_size: usize,
+) -> Result<usize, (usize, DataError)>
+where
+    I: IntoIterator<Item = &'a Data202303>,
+{
+    if chunk_size == 0 {
+        return Err((
+            0,
+            DataError::InvalidArgument("chunk_size must be strictly positive".to_string()),
+        ));
+    }
+    let mut inserted = 0;
+    let mut chunk = Vec::with_capacity(chunk_size);
+    let mut data_iter = data_iter.into_iter().peekable();
+    while data_iter.peek().is_some() {
+        chunk.clear();
+        chunk.extend(data_iter.by_ref().take(chunk_size));
+        match insert_many_data_202303(db, chunk.iter().copied()) {
+            Ok(n) => inserted += n,
+            Err(e) => return Err((inserted, e)),
+        }
+    }
+    Ok(inserted)
+}
+
 fn some_str_to_result<B, C, F>(a: Option<&str>, f: F) -> Result<Option<B>, String>
 where
     F: FnOnce(&str) -> Result<B, C>,
@@ -171,7 +506,7 @@ where
     match a {
         None => Ok(None),
         Some(s) => {
-            if s.trim().len() == 0 {
+            if s.trim().is_empty() {
                 Ok(None)
             } else {
                 f(s).map(Some).map_err(|e| format!("{}", e))
@@ -180,118 +515,262 @@ where
     }
 }
 
-pub fn select_data_202208(cmd: &str) -> Result<Vec<Data202208>, String> {
-    let sql_output = call_sqlite3(
-        cmd,
+pub fn select_data_202208(db: &dyn Sqlite) -> Result<Vec<Data202208>, DataError> {
+    let sql_output = db.run(
         ".mode list\nSELECT COUNT(*) FROM data_202208;\nSELECT timestamp, pv2012_kWh, pv2022_kWh, peak_conso_kWh, off_conso_kWh, gas_m3, water_m3 FROM data_202208;",
-    );
+    )?;
     let mut info = sql_output.lines();
-    let count = match info.next().map(usize::from_str) {
-        Some(Ok(count)) => count,
-        None => {
-            return Err("No row count for data_202208".to_string());
-        }
-        Some(Err(_)) => {
-            return Err("Malformed row count for data_202208".to_string());
-        }
-    };
+    let count = parse_row_count(info.next(), "data_202208", &sql_output)?;
     let mut result = Vec::<Data202208>::with_capacity(count);
     for line in info {
-        let mut cols = line.split("|");
-        let timestamp = match cols.next().map(i64::from_str) {
-            Some(Ok(ts)) => ts,
-            None => {
-                return Err("No timestamp".to_string());
-            }
-            Some(Err(_)) => return Err("Unable to parse timestamp".to_string()),
-        };
-        result.push(Data202208 {
-            timestamp,
-            pv2012_kWh: some_str_to_result(cols.next(), f64::from_str)?,
-            pv2022_kWh: some_str_to_result(cols.next(), f64::from_str)?,
-            peak_conso_kWh: some_str_to_result(cols.next(), f64::from_str)?,
-            off_conso_kWh: some_str_to_result(cols.next(), f64::from_str)?,
-            gas_m3: some_str_to_result(cols.next(), f64::from_str)?,
-            water_m3: some_str_to_result(cols.next(), f64::from_str)?,
-        })
+        result.push(parse_line(line, parse_data_202208_line)?);
     }
-    return Ok(result);
+    Ok(result)
 }
 
-pub fn select_data_202303(cmd: &str) -> Result<Vec<Data202303>, String> {
-    let sql_output = call_sqlite3(
-        cmd,
-        ".mode list\nSELECT COUNT(*) FROM data_202303;\nSELECT timestamp, pv2012_kWh, pv2022_kWh, peak_conso_kWh, off_conso_kWh, peak_inj_kWh, off_inj_kWh, gas_m3, water_m3 FROM data_202303;",
-    );
-    let mut info = sql_output.lines();
-    let count = match info.next().map(usize::from_str) {
-        Some(Ok(count)) => count,
+fn parse_data_202208_line(line: &str) -> Result<Data202208, String> {
+    let mut cols = line.split("|");
+    let timestamp = match cols.next().map(i64::from_str) {
+        Some(Ok(ts)) => ts,
         None => {
-            return Err("No row count for data_202208".to_string());
+            return Err("No timestamp".to_string());
         }
-        Some(Err(_)) => {
-            return Err("Malformed row count for data_202208".to_string());
+        Some(Err(_)) => return Err("Unable to parse timestamp".to_string()),
+    };
+    Ok(Data202208 {
+        timestamp,
+        pv2012_kWh: some_str_to_result(cols.next(), f64::from_str)?,
+        pv2022_kWh: some_str_to_result(cols.next(), f64::from_str)?,
+        peak_conso_kWh: some_str_to_result(cols.next(), f64::from_str)?,
+        off_conso_kWh: some_str_to_result(cols.next(), f64::from_str)?,
+        gas_m3: some_str_to_result(cols.next(), f64::from_str)?,
+        water_m3: some_str_to_result(cols.next(), f64::from_str)?,
+    })
+}
+
+fn parse_data_202303_line(line: &str) -> Result<Data202303, String> {
+    let mut cols = line.split("|");
+    let timestamp = match cols.next().map(i64::from_str) {
+        Some(Ok(ts)) => ts,
+        None => {
+            return Err("No timestamp".to_string());
         }
+        Some(Err(_)) => return Err("Unable to parse timestamp".to_string()),
     };
-    let mut result = Vec::<Data202303>::with_capacity(count);
+    Ok(Data202303 {
+        timestamp,
+        pv2012_kWh: some_str_to_result(cols.next(), f64::from_str)?,
+        pv2022_kWh: some_str_to_result(cols.next(), f64::from_str)?,
+        peak_conso_kWh: some_str_to_result(cols.next(), f64::from_str)?,
+        off_conso_kWh: some_str_to_result(cols.next(), f64::from_str)?,
+        peak_inj_kWh: some_str_to_result(cols.next(), f64::from_str)?,
+        off_inj_kWh: some_str_to_result(cols.next(), f64::from_str)?,
+        gas_m3: some_str_to_result(cols.next(), f64::from_str)?,
+        water_m3: some_str_to_result(cols.next(), f64::from_str)?,
+    })
+}
+
+/// Call `f` for each row of data_202303 as it is parsed, stopping at the first
+/// error.  Returns the number of rows passed to `f`.
+pub fn for_each_data_202303<F, E>(db: &dyn Sqlite, mut f: F) -> Result<usize, E>
+where
+    F: FnMut(Data202303) -> Result<(), E>,
+    E: From<DataError>,
+{
+    let sql_output = db.run(
+        ".mode list\nSELECT COUNT(*) FROM data_202303;\nSELECT timestamp, pv2012_kWh, pv2022_kWh, peak_conso_kWh, off_conso_kWh, peak_inj_kWh, off_inj_kWh, gas_m3, water_m3 FROM data_202303;",
+    )?;
+    let mut info = sql_output.lines();
+    parse_row_count(info.next(), "data_202303", &sql_output)?;
+    let mut dispatched = 0;
     for line in info {
-        let mut cols = line.split("|");
-        let timestamp = match cols.next().map(i64::from_str) {
-            Some(Ok(ts)) => ts,
-            None => {
-                return Err("No timestamp".to_string());
-            }
-            Some(Err(_)) => return Err("Unable to parse timestamp".to_string()),
-        };
-        result.push(Data202303 {
-            timestamp,
-            pv2012_kWh: some_str_to_result(cols.next(), f64::from_str)?,
-            pv2022_kWh: some_str_to_result(cols.next(), f64::from_str)?,
-            peak_conso_kWh: some_str_to_result(cols.next(), f64::from_str)?,
-            off_conso_kWh: some_str_to_result(cols.next(), f64::from_str)?,
-            peak_inj_kWh: some_str_to_result(cols.next(), f64::from_str)?,
-            off_inj_kWh: some_str_to_result(cols.next(), f64::from_str)?,
-            gas_m3: some_str_to_result(cols.next(), f64::from_str)?,
-            water_m3: some_str_to_result(cols.next(), f64::from_str)?,
-        })
+        f(parse_line(line, parse_data_202303_line)?)?;
+        dispatched += 1;
+    }
+    Ok(dispatched)
+}
+
+pub fn select_data_202303(db: &dyn Sqlite) -> Result<Vec<Data202303>, DataError> {
+    let mut result = Vec::<Data202303>::new();
+    for_each_data_202303::<_, DataError>(db, |row| {
+        result.push(row);
+        Ok(())
+    })?;
+    Ok(result)
+}
+
+/// The row with the highest timestamp, `Ok(None)` if the table is empty.
+pub fn select_latest_data_202303(db: &dyn Sqlite) -> Result<Option<Data202303>, DataError> {
+    let sql_output = db.run(
+        ".mode list\nSELECT timestamp, pv2012_kWh, pv2022_kWh, peak_conso_kWh, off_conso_kWh, peak_inj_kWh, off_inj_kWh, gas_m3, water_m3 FROM data_202303 ORDER BY timestamp DESC LIMIT 1;",
+    )?;
+    match sql_output.lines().find(|line| !line.trim().is_empty()) {
+        Some(line) => parse_line(line, parse_data_202303_line).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// The `n` rows with the highest timestamps, oldest first.
+pub fn select_last_data_202303(db: &dyn Sqlite, n: usize) -> Result<Vec<Data202303>, DataError> {
+    let sql_output = db.run(&format!(
+        ".mode list\nSELECT timestamp, pv2012_kWh, pv2022_kWh, peak_conso_kWh, off_conso_kWh, peak_inj_kWh, off_inj_kWh, gas_m3, water_m3 FROM data_202303 ORDER BY timestamp DESC LIMIT {n};",
+    ))?;
+    let mut result = Vec::new();
+    for line in sql_output.lines().filter(|line| !line.trim().is_empty()) {
+        result.push(parse_line(line, parse_data_202303_line)?);
+    }
+    result.reverse();
+    Ok(result)
+}
+
+/// The rows with `from_ts <= timestamp <= to_ts`, oldest first.
+pub fn select_data_202303_range(
+    db: &dyn Sqlite,
+    from_ts: i64,
+    to_ts: i64,
+) -> Result<Vec<Data202303>, DataError> {
+    let sql_output = db.run(&format!(
+        ".mode list\nSELECT timestamp, pv2012_kWh, pv2022_kWh, peak_conso_kWh, off_conso_kWh, peak_inj_kWh, off_inj_kWh, gas_m3, water_m3 FROM data_202303 WHERE timestamp >= {from_ts} AND timestamp <= {to_ts} ORDER BY timestamp;",
+    ))?;
+    let mut result = Vec::new();
+    for line in sql_output.lines().filter(|line| !line.trim().is_empty()) {
+        result.push(parse_line(line, parse_data_202303_line)?);
+    }
+    Ok(result)
+}
+
+/// Consumption during one calendar day, as the difference between the
+/// largest and smallest meter reading of that day.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct DailyDelta {
+    pub day: String,
+    pub peak: Option<f64>,
+    pub off: Option<f64>,
+}
+
+/// Peak and off-peak consumption per day for the rows with
+/// `from_ts <= timestamp < to_ts`, days starting at midnight `tz_offset`
+/// seconds east of UTC.
+pub fn select_daily_deltas_202303(
+    db: &dyn Sqlite,
+    from_ts: i64,
+    to_ts: i64,
+    tz_offset: i64,
+) -> Result<Vec<DailyDelta>, DataError> {
+    let sql_output = db.run(&format!(
+        ".mode list\nSELECT date(timestamp + {tz_offset}, 'unixepoch') AS day, MAX(peak_conso_kWh) - MIN(peak_conso_kWh), MAX(off_conso_kWh) - MIN(off_conso_kWh) FROM data_202303 WHERE timestamp >= {from_ts} AND timestamp < {to_ts} GROUP BY day ORDER BY day;",
+    ))?;
+    let mut result = Vec::new();
+    for line in sql_output.lines().filter(|line| !line.trim().is_empty()) {
+        result.push(parse_line(line, parse_daily_delta_line)?);
+    }
+    Ok(result)
+}
+
+fn parse_daily_delta_line(line: &str) -> Result<DailyDelta, String> {
+    let mut cols = line.split("|");
+    let day = match cols.next() {
+        Some(day) if day.len() == 10 => day.to_string(),
+        _ => return Err("Unable to parse day".to_string()),
+    };
+    Ok(DailyDelta {
+        day,
+        peak: some_str_to_result(cols.next(), f64::from_str)?,
+        off: some_str_to_result(cols.next(), f64::from_str)?,
+    })
+}
+
+/// Runs SQL scripts against the database and returns what they print.
+pub trait Sqlite {
+    fn run(&self, sql: &str) -> Result<String, DataError>;
+}
+
+/// Pipes the scripts into a shell command, typically `sqlite3 <database>`.
+pub struct SqliteCmd<'a>(pub &'a str);
+
+impl Sqlite for SqliteCmd<'_> {
+    fn run(&self, sql: &str) -> Result<String, DataError> {
+        call_sqlite3(self.0, sql)
     }
-    return Ok(result);
 }
 
-pub fn call_sqlite3(cmd: &str, input: &str) -> String {
+/// Answers each script with the next canned output (empty once they run out)
+/// and records the scripts it was given, to test without a shell.
+#[cfg(any(test, feature = "fake-sqlite"))]
+pub struct FakeSqlite {
+    outputs: Mutex<VecDeque<String>>,
+    scripts: Mutex<Vec<String>>,
+    delay: Duration,
+}
+
+#[cfg(any(test, feature = "fake-sqlite"))]
+impl FakeSqlite {
+    pub fn new(outputs: &[&str]) -> Self {
+        FakeSqlite {
+            outputs: Mutex::new(outputs.iter().map(|o| o.to_string()).collect()),
+            scripts: Mutex::new(Vec::new()),
+            delay: Duration::ZERO,
+        }
+    }
+
+    /// Take `delay` to answer each script, like a busy database.
+    pub fn with_delay(self, delay: Duration) -> Self {
+        FakeSqlite { delay, ..self }
+    }
+
+    pub fn scripts(&self) -> Vec<String> {
+        self.scripts.lock().unwrap().clone()
+    }
+}
+
+#[cfg(any(test, feature = "fake-sqlite"))]
+impl Sqlite for FakeSqlite {
+    fn run(&self, sql: &str) -> Result<String, DataError> {
+        self.scripts.lock().unwrap().push(sql.to_string());
+        thread::sleep(self.delay);
+        Ok(self.outputs.lock().unwrap().pop_front().unwrap_or_default())
+    }
+}
+
+/// Pipe `input` into `cmd` and return what it prints.  Failing to run it, to
+/// talk to it or a non-zero exit status (e.g. sqlite3 reporting an error on
+/// stderr) is a `DataError::Sqlite`.
+pub fn call_sqlite3(cmd: &str, input: &str) -> Result<String, DataError> {
     let start = Instant::now();
-    let mut process = match Command::new("sh")
+    let mut process = Command::new("sh")
         .arg("-c")
         .arg(cmd)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
         .spawn()
-    {
-        Err(why) => panic!("couldn't spawn sqlite3: {}", why),
-        Ok(process) => process,
-    };
-
-    // stdin has type Option<ChildStdin>, but since we know this instance
-    // must have one, we can directly unwrap it.
-    match process.stdin.take().unwrap().write_all(input.as_bytes()) {
-        Err(why) => panic!("couldn't write to sqlite3 stdin: {}", why),
-        Ok(_) => {}
+        .map_err(|e| DataError::Sqlite(format!("couldn't spawn '{}': {}", cmd, e)))?;
+    let mut stdin = process.stdin.take().unwrap();
+    // Write from another thread so that sqlite3 can't block on a full stdout
+    // pipe while we are still writing.  Dropping stdin closes the pipe, which
+    // is what makes sqlite3 process the script.
+    let (written, output) = thread::scope(|scope| {
+        let writer = scope.spawn(move || stdin.write_all(input.as_bytes()));
+        let output = process.wait_with_output();
+        (writer.join().unwrap(), output)
+    });
+    let output =
+        output.map_err(|e| DataError::Sqlite(format!("couldn't read sqlite3 output: {}", e)))?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        return Err(DataError::Sqlite(if stderr.trim().is_empty() {
+            output.status.to_string()
+        } else {
+            format!("{} ({})", stderr.trim(), output.status)
+        }));
     }
-
-    // Because stdin does not live after the above calls, it is drop-ed,
-    // and the pipe is closed.
-    //
-    // This is very important, otherwise sqlite3 wouldn't start processing the
-    // input we just sent.
-
-    // The stdout field also has type Option<ChildStdout> so must be unwrapped.
-    let mut s = String::new();
-    match process.stdout.take().unwrap().read_to_string(&mut s) {
-        Err(why) => panic!("couldn't read sqlite3 stdout: {}", why),
-        Ok(_) => {}
+    written.map_err(|e| DataError::Sqlite(format!("couldn't write to sqlite3 stdin: {}", e)))?;
+    if !stderr.trim().is_empty() {
+        tracing::warn!("Warning: sqlite3 printed '{}'", stderr.trim());
     }
-    process.wait().unwrap();
-    println!(
+    let s = String::from_utf8(output.stdout)
+        .map_err(|e| DataError::Sqlite(format!("sqlite3 output is not UTF-8: {}", e)))?;
+    tracing::info!(
         "call_sqlite3 '{}' took {:.3}s",
         (if input.len() <= 80 {
             input.to_string()
@@ -306,24 +785,186 @@ pub fn call_sqlite3(cmd: &str, input: &str) -> String {
         .replace('\n', "\u{23CE}"),
         start.elapsed().as_secs_f64()
     );
-    return s;
+    Ok(s)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn builders_match_literals() {
+        assert_eq!(
+            Data202303::empty(1000),
+            Data202303 {
+                timestamp: 1000,
+                pv2012_kWh: None,
+                pv2022_kWh: None,
+                peak_conso_kWh: None,
+                off_conso_kWh: None,
+                peak_inj_kWh: None,
+                off_inj_kWh: None,
+                gas_m3: None,
+                water_m3: None,
+            }
+        );
+        let p1 = CompleteP1Measurement {
+            timestamp: chrono::DateTime::from_timestamp(1000, 0).unwrap(),
+            peak_hour_consumption: 1.0,
+            off_hour_consumption: 2.0,
+            peak_hour_injection: 3.0,
+            off_hour_injection: 4.0,
+            power_failures: Some(5),
+            gas_m3: Some(8.0),
+            water_m3: None,
+        };
+        assert_eq!(
+            Data202303::from_p1(1060, &p1)
+                .with_pv2012(Some(6.0))
+                .with_pv2022(Some(7.0)),
+            Data202303 {
+                timestamp: 1060,
+                pv2012_kWh: Some(6.0),
+                pv2022_kWh: Some(7.0),
+                peak_conso_kWh: Some(1.0),
+                off_conso_kWh: Some(2.0),
+                peak_inj_kWh: Some(3.0),
+                off_inj_kWh: Some(4.0),
+                gas_m3: Some(8.0),
+                water_m3: None,
+            }
+        );
+        assert_eq!(
+            Data202303::empty(1120)
+                .with_pv2012(Some(8.0))
+                .with_gas(Some(9.0))
+                .with_water(None),
+            Data202303 {
+                timestamp: 1120,
+                pv2012_kWh: Some(8.0),
+                pv2022_kWh: None,
+                peak_conso_kWh: None,
+                off_conso_kWh: None,
+                peak_inj_kWh: None,
+                off_inj_kWh: None,
+                gas_m3: Some(9.0),
+                water_m3: None,
+            }
+        );
+    }
+
+    #[test]
+    fn diff_subtracts_known_columns() {
+        let earlier = Data202303::empty(1000)
+            .with_pv2022(Some(10.0))
+            .with_gas(Some(5.0));
+        let later = Data202303::empty(1600)
+            .with_pv2022(Some(12.5))
+            .with_water(Some(3.0));
+        assert_eq!(
+            Data202303::diff(&later, &earlier),
+            Data202303::empty(600).with_pv2022(Some(2.5))
+        );
+    }
+
+    #[test]
+    fn average_power_kw_over_interval() {
+        let mut earlier = Data202303::empty(1000);
+        earlier.peak_conso_kWh = Some(100.0);
+        earlier.off_conso_kWh = Some(50.0);
+        let mut later = Data202303::empty(1900);
+        later.peak_conso_kWh = Some(100.25);
+        later.off_conso_kWh = Some(50.5);
+        assert_eq!(average_power_kw(&earlier, &later), Some(3.0));
+        assert_eq!(average_power_kw(&later, &earlier), None);
+        assert_eq!(average_power_kw(&earlier, &earlier), None);
+    }
+
+    #[test]
+    fn average_power_kw_needs_both_registers() {
+        let mut earlier = Data202303::empty(1000);
+        earlier.peak_conso_kWh = Some(100.0);
+        let mut later = Data202303::empty(1900);
+        later.peak_conso_kWh = Some(100.25);
+        later.off_conso_kWh = Some(50.5);
+        assert_eq!(average_power_kw(&earlier, &later), None);
+    }
+
+    #[test]
+    fn self_consumption_ratio_over_window() {
+        let first = full_data_202303(1000);
+        let last = Data202303 {
+            timestamp: 4600,
+            pv2012_kWh: first.pv2012_kWh.map(|v| v + 1.0),
+            pv2022_kWh: first.pv2022_kWh.map(|v| v + 3.0),
+            peak_inj_kWh: first.peak_inj_kWh.map(|v| v + 0.5),
+            off_inj_kWh: first.off_inj_kWh.map(|v| v + 0.5),
+            ..full_data_202303(4600)
+        };
+        assert_eq!(self_consumption_ratio(&first, &last), Some(0.75));
+        // Nothing produced
+        assert_eq!(self_consumption_ratio(&first, &first), None);
+        // More injected than produced (e.g. a register glitch)
+        let glitch = Data202303 {
+            peak_inj_kWh: first.peak_inj_kWh.map(|v| v + 10.0),
+            ..last
+        };
+        assert_eq!(self_consumption_ratio(&first, &glitch), Some(0.0));
+    }
+
+    #[test]
+    fn self_consumption_ratio_needs_every_register() {
+        let first = full_data_202303(1000);
+        let last = Data202303 {
+            pv2022_kWh: first.pv2022_kWh.map(|v| v + 3.0),
+            ..full_data_202303(4600)
+        };
+        assert_eq!(self_consumption_ratio(&first, &last), Some(1.0));
+        for missing in [
+            Data202303 {
+                pv2012_kWh: None,
+                ..clone_data202303(&last)
+            },
+            Data202303 {
+                off_inj_kWh: None,
+                ..clone_data202303(&last)
+            },
+        ] {
+            assert_eq!(self_consumption_ratio(&first, &missing), None);
+        }
+    }
+
     #[test]
     fn it_works() {
         let result = call_sqlite3("cat", "hello");
-        assert_eq!(result, "hello");
+        assert_eq!(result, Ok("hello".to_string()));
+        assert_eq!(SqliteCmd("cat").run("hello"), Ok("hello".to_string()));
+    }
+
+    #[test]
+    fn call_sqlite3_reports_failures() {
+        assert_eq!(
+            SqliteCmd("cat > /dev/null; echo 'Parse error: no such table: t' >&2; exit 1")
+                .run("SELECT * FROM t;"),
+            Err(DataError::Sqlite(
+                "Parse error: no such table: t (exit status: 1)".to_string()
+            ))
+        );
+        assert_eq!(
+            call_sqlite3("exit 3", ""),
+            Err(DataError::Sqlite("exit status: 3".to_string()))
+        );
+        // Larger than a pipe buffer both ways
+        let big = "x".repeat(1 << 20);
+        assert_eq!(call_sqlite3("cat", &big), Ok(big));
     }
 
     #[test]
     fn count_and_select_data_202208() {
-        let result = select_data_202208(
-            "cat > /dev/null; echo '2\n1356994800|487.0|0.0|82313.0|35983.0|9203.0|-393.0\n1359673200|553.0||82564.0|36184.0|9685.0|-385.0'"
-        ).unwrap();
+        let result = select_data_202208(&FakeSqlite::new(&[
+            "2\n1356994800|487.0|0.0|82313.0|35983.0|9203.0|-393.0\n1359673200|553.0||82564.0|36184.0|9685.0|-385.0",
+        ]))
+        .unwrap();
         assert_eq!(result.len(), 2);
         assert_eq!(
             result[0],
@@ -353,9 +994,11 @@ mod tests {
 
     #[test]
     fn count_and_select_data_202303() {
-        let result = select_data_202303(
-            "cat > /dev/null; echo '2\n1695485100|50621.3|3579.4|||630.0|1189.4|28973.5|867.5\n1695537420||3579.9||||||'"
-        ).unwrap();
+        let db = FakeSqlite::new(&[
+            "2\n1695485100|50621.3|3579.4|||630.0|1189.4|28973.5|867.5\n1695537420||3579.9||||||",
+        ]);
+        let result = select_data_202303(&db).unwrap();
+        assert!(db.scripts()[0].contains("FROM data_202303;"));
         assert_eq!(result.len(), 2);
         assert_eq!(
             result[0],
@@ -387,15 +1030,81 @@ mod tests {
         );
     }
 
+    #[test]
+    fn for_each_data_202303_calls_back_once_per_row() {
+        let mut timestamps = Vec::new();
+        let result = for_each_data_202303::<_, DataError>(
+            &FakeSqlite::new(&[
+                "3\n1695485100|50621.3|3579.4|||630.0|1189.4|28973.5|867.5\n1695485160||3579.5||||||\n1695537420||3579.9||||||",
+            ]),
+            |row| {
+                timestamps.push(row.timestamp);
+                Ok(())
+            },
+        );
+        assert_eq!(result, Ok(3));
+        assert_eq!(timestamps, vec![1695485100, 1695485160, 1695537420]);
+    }
+
+    #[test]
+    fn for_each_data_202303_stops_at_first_callback_error() {
+        let mut calls = 0;
+        let result = for_each_data_202303(
+            &FakeSqlite::new(&["3\n1695485100||||||||\n1695485160||||||||\n1695537420||||||||"]),
+            |_| {
+                calls += 1;
+                if calls == 2 {
+                    Err("stop".to_string())
+                } else {
+                    Ok(())
+                }
+            },
+        );
+        assert_eq!(result, Err("stop".to_string()));
+        assert_eq!(calls, 2);
+    }
+
+    #[test]
+    fn malformed_row_count_is_a_parse_error() {
+        let db = FakeSqlite::new(&["3x\n1695485100||||||||"]);
+        assert_eq!(
+            select_data_202303(&db),
+            Err(DataError::Parse {
+                line: "3x".to_string(),
+                reason: "Malformed row count for data_202303".to_string(),
+            })
+        );
+        assert_eq!(
+            select_data_202208(&FakeSqlite::new(&[""])),
+            Err(DataError::UnexpectedOutput("".to_string()))
+        );
+    }
+
+    #[test]
+    fn bad_float_column_is_a_parse_error() {
+        let db = FakeSqlite::new(&["1\n1695485100|50621.3|oops||||||"]);
+        match select_data_202303(&db) {
+            Err(DataError::Parse { line, reason }) => {
+                assert_eq!(line, "1695485100|50621.3|oops||||||");
+                assert_eq!(reason, "invalid float literal");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(
+            DataError::Parse {
+                line: "1|oops".to_string(),
+                reason: "invalid float literal".to_string()
+            }
+            .to_string(),
+            "invalid float literal in '1|oops'"
+        );
+    }
+
     #[test]
     fn can_insert_data_202303() {
+        let db = FakeSqlite::new(&["1234\n"]);
         let result = insert_data_202303(
-            "bash -c 'diff -w - <(cat <<EOF\n\
-.mode list\n\
-INSERT INTO data_202303 VALUES (1695485100, 50622.3, 3579.4, NULL, 630, 321, 1189.4, 28973.5, 867.5);\n\
-SELECT COUNT(*) FROM data_202303;\n\
-EOF\n
-) && echo \"1234\"'",
+            &db,
             &Data202303 {
                 timestamp: 1695485100,
                 pv2012_kWh: Some(50622.3),
@@ -408,22 +1117,48 @@ EOF\n
                 water_m3: Some(867.5),
             },
         );
-        assert_eq!(result.unwrap(), 1234)
+        assert_eq!(result.unwrap(), 1234);
+        assert_eq!(
+            db.scripts(),
+            vec![
+                ".mode list\n\
+INSERT INTO data_202303 (timestamp, pv2012_kWh, pv2022_kWh, peak_conso_kWh, off_conso_kWh, peak_inj_kWh, off_inj_kWh, gas_m3, water_m3) VALUES (1695485100, 50622.3, 3579.4, NULL, 630, 321, 1189.4, 28973.5, 867.5);\n\
+SELECT COUNT(*) FROM data_202303;"
+            ]
+        );
     }
 
     #[test]
     fn can_insert_many_data_202303() {
-        let result = insert_many_data_202303(
-            "bash -c 'diff -w - <(cat <<EOF\n\
-.mode list\n\
-SELECT COUNT(*) FROM data_202303;\n\
-BEGIN TRANSACTION;\n\
-INSERT INTO data_202303 VALUES (1695485100, 50622.3, 3579.4, NULL, 630, 321, 1189.4, 28973.5, 867.5);\n\
-COMMIT;\n\
-SELECT COUNT(*) FROM data_202303;\n\
-EOF\n
-) && echo \"13\n14\"'",
-            [&Data202303 {
+        let rows = [
+            full_data_202303(1695485100),
+            Data202303::empty(1695485160).with_pv2022(Some(3579.4)),
+        ];
+        let db = FakeSqlite::new(&["13\n14\n"]);
+        let result = insert_many_data_202303(&db, &rows);
+        assert_eq!(result, Ok(1));
+        assert_eq!(db.scripts(), vec![build_insert_many_sql(&rows).unwrap()]);
+    }
+
+    #[test]
+    fn insert_many_data_202303_rejects_unexpected_output() {
+        let db = FakeSqlite::new(&["Error: database is locked"]);
+        assert_eq!(
+            insert_many_data_202303(&db, &[Data202303::empty(1000).with_pv2022(Some(3579.4))]),
+            Err(DataError::UnexpectedOutput(
+                "Error: database is locked".to_string()
+            ))
+        );
+        // Nothing to insert, nothing run
+        let db = FakeSqlite::new(&[]);
+        assert_eq!(insert_many_data_202303(&db, &[]), Ok(0));
+        assert!(db.scripts().is_empty());
+    }
+
+    #[test]
+    fn build_insert_many_sql_two_rows() {
+        let rows = [
+            Data202303 {
                 timestamp: 1695485100,
                 pv2012_kWh: Some(50622.3),
                 pv2022_kWh: Some(3579.4),
@@ -433,8 +1168,393 @@ EOF\n
                 off_inj_kWh: Some(1189.4),
                 gas_m3: Some(28973.5),
                 water_m3: Some(867.5),
-            }],
+            },
+            Data202303::empty(1695485160).with_pv2022(Some(3579.4)),
+        ];
+        assert_eq!(
+            build_insert_many_sql(&rows).as_deref(),
+            Some(
+                ".mode list\n\
+SELECT COUNT(*) FROM data_202303;\n\
+BEGIN TRANSACTION;\n\
+INSERT INTO data_202303 (timestamp, pv2012_kWh, pv2022_kWh, peak_conso_kWh, off_conso_kWh, peak_inj_kWh, off_inj_kWh, gas_m3, water_m3) VALUES (1695485100, 50622.3, 3579.4, NULL, 630, 321, 1189.4, 28973.5, 867.5);\n\
+INSERT INTO data_202303 (timestamp, pv2012_kWh, pv2022_kWh, peak_conso_kWh, off_conso_kWh, peak_inj_kWh, off_inj_kWh, gas_m3, water_m3) VALUES (1695485160, NULL, 3579.4, NULL, NULL, NULL, NULL, NULL, NULL);\n\
+COMMIT;\n\
+SELECT COUNT(*) FROM data_202303;"
+            )
+        );
+    }
+
+    #[test]
+    fn build_insert_many_sql_empty() {
+        assert_eq!(build_insert_many_sql(&[]), None);
+    }
+
+    #[test]
+    fn build_insert_many_sql_runs_pragmas_before_transaction() {
+        let rows = [Data202303::empty(1000).with_pv2022(Some(3579.4))];
+        let sql = build_insert_many_sql_with_pragmas(
+            "PRAGMA journal_mode=WAL; PRAGMA synchronous=NORMAL;",
+            &rows,
+        )
+        .unwrap();
+        let pragmas = sql.find("PRAGMA journal_mode=WAL;").unwrap();
+        let begin = sql.find("BEGIN TRANSACTION;").unwrap();
+        assert!(pragmas < begin);
+        // Their output must not precede the first row count
+        let muted = sql.find(".output /dev/null").unwrap();
+        let unmuted = sql.find(".output stdout").unwrap();
+        let count = sql.find("SELECT COUNT(*)").unwrap();
+        assert!(muted < pragmas && pragmas < unmuted && unmuted < count);
+        // Blank pragmas leave the SQL as before
+        assert_eq!(
+            build_insert_many_sql_with_pragmas("  ", &rows),
+            build_insert_many_sql(&rows)
+        );
+    }
+
+    #[test]
+    fn fill_gaps_without_gaps_copies_rows() {
+        let rows: Vec<_> = [1000, 1060, 1120, 1150]
+            .map(|ts| Data202303::empty(ts).with_pv2022(Some(3579.4)))
+            .into();
+        assert_eq!(fill_gaps(&rows, 60), rows);
+        assert_eq!(fill_gaps(&[], 60), vec![]);
+    }
+
+    #[test]
+    fn fill_gaps_fills_single_large_gap() {
+        let rows: Vec<_> = [1000, 1060, 1300, 1360]
+            .map(|ts| Data202303::empty(ts).with_pv2022(Some(3579.4)))
+            .into();
+        let filled = fill_gaps(&rows, 60);
+        assert_eq!(
+            filled.iter().map(|d| d.timestamp).collect::<Vec<_>>(),
+            vec![1000, 1060, 1120, 1180, 1240, 1300, 1360]
+        );
+        for synthetic in &filled[2..5] {
+            assert_eq!(
+                synthetic,
+                &Data202303 {
+                    timestamp: synthetic.timestamp,
+                    pv2012_kWh: None,
+                    pv2022_kWh: None,
+                    peak_conso_kWh: None,
+                    off_conso_kWh: None,
+                    peak_inj_kWh: None,
+                    off_inj_kWh: None,
+                    gas_m3: None,
+                    water_m3: None,
+                }
+            );
+        }
+        assert_eq!(filled[5], rows[2]);
+    }
+
+    #[test]
+    fn fill_gaps_caps_filler_rows_without_overflowing() {
+        let rows: Vec<_> = [i64::MIN, 0, i64::MAX - 90, i64::MAX]
+            .map(|ts| Data202303::empty(ts).with_pv2022(Some(3579.4)))
+            .into();
+        let filled = fill_gaps(&rows, 60);
+        assert_eq!(filled.len(), rows.len() + 2 * MAX_FILLER_ROWS + 1);
+        assert_eq!(filled[1], Data202303::empty(i64::MIN + 60));
+        assert_eq!(
+            filled[MAX_FILLER_ROWS].timestamp,
+            i64::MIN + 60 * MAX_FILLER_ROWS as i64
+        );
+        assert_eq!(filled[MAX_FILLER_ROWS + 1], rows[1]);
+        // Stops at the first step past i64::MAX
+        assert_eq!(
+            filled[filled.len() - 3..]
+                .iter()
+                .map(|d| d.timestamp)
+                .collect::<Vec<_>>(),
+            vec![i64::MAX - 90, i64::MAX - 30, i64::MAX]
+        );
+    }
+
+    /// Every column set, to a different value.
+    fn full_data_202303(timestamp: i64) -> Data202303 {
+        Data202303 {
+            peak_conso_kWh: Some(3.0),
+            off_conso_kWh: Some(4.0),
+            peak_inj_kWh: Some(5.0),
+            off_inj_kWh: Some(6.0),
+            ..Data202303::empty(timestamp)
+                .with_pv2012(Some(1.0))
+                .with_pv2022(Some(2.0))
+                .with_gas(Some(7.0))
+                .with_water(Some(8.0))
+        }
+    }
+
+    #[test]
+    fn has_values_distinguishes_none_from_zero() {
+        assert!(!Data202303::empty(1000).has_values());
+        assert!(full_data_202303(1000).has_values());
+        let mut zero = Data202303::empty(1000);
+        zero.water_m3 = Some(0.0);
+        assert!(zero.has_values());
+    }
+
+    #[test]
+    fn field_accessor_knows_every_column() {
+        let full = full_data_202303(1000);
+        let names = [
+            "pv2012_kWh",
+            "pv2022_kWh",
+            "peak_conso_kWh",
+            "off_conso_kWh",
+            "peak_inj_kWh",
+            "off_inj_kWh",
+            "gas_m3",
+            "water_m3",
+        ];
+        assert_eq!(Data202303::VALUE_COLUMNS, names);
+        for name in names {
+            let get = Data202303::field_accessor(name).expect(name);
+            assert!(get(&full).is_some(), "{}", name);
+            assert_eq!(get(&Data202303::empty(1000)), None, "{}", name);
+        }
+        assert_eq!(
+            Data202303::field_accessor("gas_m3").unwrap()(&full),
+            full.gas_m3
+        );
+        assert!(Data202303::field_accessor("timestamp").is_none());
+        assert!(Data202303::field_accessor("GAS_M3").is_none());
+    }
+
+    #[test]
+    fn field_mut_matches_field_accessor() {
+        for name in Data202303::VALUE_COLUMNS {
+            let mut record = Data202303::empty(1000);
+            *record.field_mut(name).expect(name) = Some(1.5);
+            assert_eq!(
+                Data202303::field_accessor(name).unwrap()(&record),
+                Some(1.5),
+                "{}",
+                name
+            );
+            assert_eq!(
+                Data202303::VALUE_COLUMNS
+                    .iter()
+                    .filter(|other| Data202303::field_accessor(other).unwrap()(&record).is_some())
+                    .count(),
+                1
+            );
+        }
+        assert!(Data202303::empty(1000).field_mut("timestamp").is_none());
+    }
+
+    #[test]
+    fn merge_from_full_into_empty() {
+        let mut merged = Data202303::empty(1000);
+        merged.merge_from(&full_data_202303(2000));
+        assert_eq!(merged, full_data_202303(1000));
+    }
+
+    #[test]
+    fn merge_from_empty_into_full() {
+        let mut merged = full_data_202303(1000);
+        merged.merge_from(&Data202303::empty(2000));
+        assert_eq!(merged, full_data_202303(1000));
+    }
+
+    #[test]
+    fn merge_from_partial_overlap() {
+        let mut merged = Data202303::empty(1000);
+        merged.pv2012_kWh = Some(10.0);
+        merged.water_m3 = Some(80.0);
+        let mut other = Data202303::empty(2000);
+        other.pv2012_kWh = Some(1.0);
+        other.pv2022_kWh = Some(2.0);
+        other.gas_m3 = Some(7.0);
+        merged.merge_from(&other);
+        assert_eq!(
+            merged,
+            Data202303 {
+                timestamp: 1000,
+                pv2012_kWh: Some(10.0),
+                pv2022_kWh: Some(2.0),
+                peak_conso_kWh: None,
+                off_conso_kWh: None,
+                peak_inj_kWh: None,
+                off_inj_kWh: None,
+                gas_m3: Some(7.0),
+                water_m3: Some(80.0),
+            }
+        );
+    }
+
+    #[test]
+    fn can_select_latest_data_202303() {
+        assert_eq!(
+            select_latest_data_202303(&FakeSqlite::new(&["1695537420|50621.3|3579.9||||||867.5"])),
+            Ok(Some(Data202303 {
+                timestamp: 1695537420,
+                pv2012_kWh: Some(50621.3),
+                pv2022_kWh: Some(3579.9),
+                peak_conso_kWh: None,
+                off_conso_kWh: None,
+                peak_inj_kWh: None,
+                off_inj_kWh: None,
+                gas_m3: None,
+                water_m3: Some(867.5),
+            }))
+        );
+    }
+
+    /// Fails the way sqlite3 does on a database without the table.
+    const NO_SUCH_TABLE: &str =
+        "cat > /dev/null; echo 'Parse error near line 1: no such table: data_202303' >&2; exit 1";
+
+    #[test]
+    fn select_latest_data_202303_of_empty_table() {
+        let db = FakeSqlite::new(&[""]);
+        assert_eq!(select_latest_data_202303(&db), Ok(None));
+        assert!(db.scripts()[0].contains("ORDER BY timestamp DESC LIMIT 1;"));
+        assert!(select_latest_data_202303(&FakeSqlite::new(&["Error: no such table"])).is_err());
+        // What sqlite3 really does: the error goes to stderr, stdout stays empty
+        assert_eq!(
+            select_latest_data_202303(&SqliteCmd(NO_SUCH_TABLE)),
+            Err(DataError::Sqlite(
+                "Parse error near line 1: no such table: data_202303 (exit status: 1)".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn select_last_data_202303_oldest_first() {
+        let db = FakeSqlite::new(&["1695485160||3579.5||||||\n1695485100|50621.3|||||||\n"]);
+        assert_eq!(
+            select_last_data_202303(&db, 2),
+            Ok(vec![
+                Data202303::empty(1695485100).with_pv2012(Some(50621.3)),
+                Data202303::empty(1695485160).with_pv2022(Some(3579.5)),
+            ])
+        );
+        assert!(db.scripts()[0].contains("ORDER BY timestamp DESC LIMIT 2;"));
+        assert_eq!(
+            select_last_data_202303(&FakeSqlite::new(&[""]), 2),
+            Ok(vec![])
+        );
+        assert!(select_last_data_202303(&FakeSqlite::new(&["Error: no such table"]), 2).is_err());
+        assert!(matches!(
+            select_last_data_202303(&SqliteCmd(NO_SUCH_TABLE), 2),
+            Err(DataError::Sqlite(_))
+        ));
+    }
+
+    #[test]
+    fn select_data_202303_range_filters_on_timestamp() {
+        let db = FakeSqlite::new(&["1695485160||3579.5||||||\n"]);
+        assert_eq!(
+            select_data_202303_range(&db, 1695485160, 1695485160),
+            Ok(vec![
+                Data202303::empty(1695485160).with_pv2022(Some(3579.5))
+            ])
+        );
+        assert!(
+            db.scripts()[0].contains("WHERE timestamp >= 1695485160 AND timestamp <= 1695485160")
+        );
+        assert_eq!(
+            select_data_202303_range(&FakeSqlite::new(&[""]), 0, 1),
+            Ok(vec![])
+        );
+        assert!(
+            select_data_202303_range(&FakeSqlite::new(&["Error: no such table"]), 0, 1).is_err()
         );
-        assert_eq!(result.unwrap(), 1)
+    }
+
+    #[test]
+    fn select_daily_deltas_202303_parses_days() {
+        let db = FakeSqlite::new(&["2025-01-01|3.5|7.25\n2025-01-02||1.0\n"]);
+        assert_eq!(
+            select_daily_deltas_202303(&db, 1735686000, 1735858800, 3600),
+            Ok(vec![
+                DailyDelta {
+                    day: "2025-01-01".to_string(),
+                    peak: Some(3.5),
+                    off: Some(7.25),
+                },
+                DailyDelta {
+                    day: "2025-01-02".to_string(),
+                    peak: None,
+                    off: Some(1.0),
+                },
+            ])
+        );
+        let sql = &db.scripts()[0];
+        assert!(sql.contains("date(timestamp + 3600, 'unixepoch')"));
+        assert!(sql.contains("timestamp >= 1735686000 AND timestamp < 1735858800"));
+        assert!(
+            select_daily_deltas_202303(&FakeSqlite::new(&["Error: no such table"]), 0, 1, 0)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn find_gaps_in_clean_series() {
+        let rows: Vec<_> = [1000, 1060, 1125, 1180, 1240]
+            .map(|ts| Data202303::empty(ts).with_pv2022(Some(3579.4)))
+            .into();
+        assert_eq!(find_gaps(&rows, 60, 5), vec![]);
+        assert_eq!(find_gaps(&rows[..1], 60, 5), vec![]);
+        assert_eq!(find_gaps(&[], 60, 5), vec![]);
+    }
+
+    #[test]
+    fn find_gaps_reports_each_gap() {
+        let rows: Vec<_> = [1000, 1060, 1300, 1360, 1420, 5020, 5080]
+            .map(|ts| Data202303::empty(ts).with_pv2022(Some(3579.4)))
+            .into();
+        assert_eq!(find_gaps(&rows, 60, 5), vec![(1060, 1300), (1420, 5020)]);
+        // A larger tolerance hides the smaller gap
+        assert_eq!(find_gaps(&rows, 60, 200), vec![(1420, 5020)]);
+    }
+
+    #[test]
+    fn find_gaps_does_not_overflow() {
+        let rows: Vec<_> = [i64::MIN, i64::MAX, i64::MIN]
+            .map(|ts| Data202303::empty(ts).with_pv2022(Some(3579.4)))
+            .into();
+        assert_eq!(find_gaps(&rows, 60, 5), vec![(i64::MIN, i64::MAX)]);
+        assert_eq!(find_gaps(&rows, i64::MAX, i64::MAX), vec![]);
+    }
+
+    #[test]
+    fn can_insert_many_data_202303_chunked() {
+        let rows: Vec<_> = (1000..1005)
+            .map(|ts| Data202303::empty(ts).with_pv2022(Some(3579.4)))
+            .collect();
+        let db = FakeSqlite::new(&["0\n2", "2\n4", "4\n5"]);
+        let result = insert_many_data_202303_chunked(&db, &rows, 2);
+        assert_eq!(result, Ok(5));
+        let inserts: Vec<_> = db
+            .scripts()
+            .iter()
+            .map(|sql| sql.matches("INSERT").count())
+            .collect();
+        assert_eq!(inserts, vec![2, 2, 1]);
+    }
+
+    #[test]
+    fn insert_many_data_202303_chunked_stops_at_first_failing_chunk() {
+        let rows: Vec<_> = (1000..1005)
+            .map(|ts| Data202303::empty(ts).with_pv2022(Some(3579.4)))
+            .collect();
+        let db = FakeSqlite::new(&["0\n2", "oops", "4\n5"]);
+        let result = insert_many_data_202303_chunked(&db, &rows, 2);
+        assert_eq!(result.map_err(|(n, _)| n), Err(2));
+        assert_eq!(db.scripts().len(), 2);
+    }
+
+    #[test]
+    fn insert_many_data_202303_chunked_rejects_zero_chunk_size() {
+        let rows = vec![Data202303::empty(1000).with_pv2022(Some(3579.4))];
+        let db = FakeSqlite::new(&[]);
+        assert!(insert_many_data_202303_chunked(&db, &rows, 0).is_err());
+        assert!(db.scripts().is_empty());
     }
 }
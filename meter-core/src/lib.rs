@@ -2,3 +2,5 @@ pub mod data;
 pub mod p1_meter;
 pub mod pv2022;
 pub mod ringbuffer;
+pub mod shell;
+pub mod tariff;
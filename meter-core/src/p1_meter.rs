@@ -1,7 +1,9 @@
+use crate::data::Data202303;
 use chrono::{DateTime, FixedOffset, TimeZone, Utc};
 use std::borrow::Borrow;
 use std::error::Error;
-use std::num::ParseFloatError;
+use std::fmt::{self, Display};
+use std::num::ParseIntError;
 use std::str::FromStr;
 
 // 0-0:1.0.0(241025191816S)
@@ -13,6 +15,33 @@ use std::str::FromStr;
 // 1-0:2.8.1(006254.732*kWh)
 //
 // 1-0:2.8.2(002457.202*kWh)
+//
+// 0-0:96.7.21(00004) (number of power failures, optional)
+//
+// 0-1:24.2.1(241025191500S)(01234.567*m3) (M-Bus device on channel 1, optional)
+
+const POWER_FAILURES_PREFIX: &str = "0-0:96.7.21(";
+
+/// Why a P1 datagram could not be parsed.
+#[derive(Debug, PartialEq)]
+pub enum P1ParseError {
+    /// The `0-0:1.0.0` line does not hold a valid local date and time.
+    Timestamp { line: String, reason: String },
+    /// A known register holds a malformed value.
+    Register { line: String, reason: String },
+}
+
+impl Display for P1ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            P1ParseError::Timestamp { line, reason } | P1ParseError::Register { line, reason } => {
+                write!(f, "{} in '{}'", reason, line)
+            }
+        }
+    }
+}
+
+impl Error for P1ParseError {}
 
 fn strip_prefix_and_suffix<'a>(line: &'a str, prefix: &str, suffix: &str) -> Option<&'a str> {
     if line.starts_with(prefix) && line.ends_with(suffix) {
@@ -22,15 +51,82 @@ fn strip_prefix_and_suffix<'a>(line: &'a str, prefix: &str, suffix: &str) -> Opt
     }
 }
 
-fn parse_kwh(line: &str, prefix: &str) -> Result<Option<f64>, ParseFloatError> {
+/// Parse a register value the way the meter writes it: zero-padded digits
+/// with a decimal point (`002654.919`), padding optional.  Unlike
+/// `f64::from_str`, signs, exponents, `inf`/`NaN` and thousands separators
+/// are rejected.
+fn parse_kwh_value(kwh: &str) -> Result<f64, String> {
+    parse_register_value(kwh, "kWh")
+}
+
+/// Like `parse_kwh_value` for a register in `unit`.
+fn parse_register_value(value: &str, unit: &str) -> Result<f64, String> {
+    if value.contains(',') {
+        return Err(format!("Thousands separator in {} value '{}'", unit, value));
+    }
+    let all_digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+    let well_formed = match value.split_once('.') {
+        Some((int_part, frac_part)) => all_digits(int_part) && all_digits(frac_part),
+        None => all_digits(value),
+    };
+    if !well_formed {
+        return Err(format!("Invalid {} value '{}'", unit, value));
+    }
+    f64::from_str(value).map_err(|e| format!("Invalid {} value '{}': {}", unit, value, e))
+}
+
+fn parse_kwh(line: &str, prefix: &str) -> Result<Option<f64>, String> {
     match strip_prefix_and_suffix(line, prefix, "*kWh)") {
-        Some(kwh) => f64::from_str(kwh).map(Some),
+        Some(kwh) => parse_kwh_value(kwh).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Value of an M-Bus register, which comes after the time of the reading:
+/// `0-1:24.2.1(241025191500S)(01234.567*m3)`.
+fn parse_mbus_m3(line: &str, prefix: &str) -> Result<Option<f64>, String> {
+    match strip_prefix_and_suffix(line, prefix, "*m3)") {
+        Some(values) => match values.split_once(")(") {
+            Some((_, m3)) => parse_register_value(m3, "m3").map(Some),
+            None => Err(format!("Missing timestamp in M-Bus register '{}'", line)),
+        },
+        None => Ok(None),
+    }
+}
+
+fn parse_u32(line: &str, prefix: &str) -> Result<Option<u32>, ParseIntError> {
+    match strip_prefix_and_suffix(line, prefix, ")") {
+        Some(count) => u32::from_str(count).map(Some),
         None => Ok(None),
     }
 }
 
-fn parse_date_time(line: &str) -> Result<Option<DateTime<Utc>>, Box<dyn Error>> {
+/// Value of the register starting with `prefix` in `unit`.  The register in
+/// another unit is ignored with a warning rather than taken for another one.
+fn parse_register<T>(
+    line: &str,
+    prefix: &str,
+    unit: &'static str,
+    parse: fn(&str, &str) -> Result<Option<T>, String>,
+) -> Result<Option<T>, P1ParseError> {
+    match parse(line, prefix) {
+        Ok(None) if line.starts_with(prefix) => {
+            tracing::warn!("P1 warning: Expected a value in {} in '{}'", unit, line);
+            Ok(None)
+        }
+        parsed => parsed.map_err(|reason| P1ParseError::Register {
+            line: line.to_string(),
+            reason,
+        }),
+    }
+}
+
+fn parse_date_time(line: &str) -> Result<Option<DateTime<Utc>>, P1ParseError> {
     const DATA_LEN: usize = 13;
+    let error = |reason: String| P1ParseError::Timestamp {
+        line: line.to_string(),
+        reason,
+    };
     match strip_prefix_and_suffix(line, "0-0:1.0.0(", ")") {
         Some(yymmddhhmmssx) => {
             if yymmddhhmmssx.len() == DATA_LEN
@@ -40,12 +136,16 @@ fn parse_date_time(line: &str) -> Result<Option<DateTime<Utc>>, Box<dyn Error>>
                     .map(|summer_or_winter| summer_or_winter == 'S' || summer_or_winter == 'W')
                     .unwrap_or(false)
             {
-                let yy = 2000 + i32::from_str(&yymmddhhmmssx[0..2])?;
-                let mm = u32::from_str(&yymmddhhmmssx[2..4])?;
-                let dd = u32::from_str(&yymmddhhmmssx[4..6])?;
-                let hours = u32::from_str(&yymmddhhmmssx[6..8])?;
-                let mins = u32::from_str(&yymmddhhmmssx[8..10])?;
-                let secs = u32::from_str(&yymmddhhmmssx[10..12])?;
+                let field = |start: usize| {
+                    u32::from_str(&yymmddhhmmssx[start..start + 2])
+                        .map_err(|e| error(e.to_string()))
+                };
+                let yy = 2000 + field(0)? as i32;
+                let mm = field(2)?;
+                let dd = field(4)?;
+                let hours = field(6)?;
+                let mins = field(8)?;
+                let secs = field(10)?;
                 let offset = FixedOffset::east_opt(
                     (if yymmddhhmmssx.chars().nth(12).unwrap_or('?') == 'S' {
                         2 // Central European Summer Time
@@ -60,7 +160,7 @@ fn parse_date_time(line: &str) -> Result<Option<DateTime<Utc>>, Box<dyn Error>>
                 {
                     Ok(Some(datetime.to_utc()))
                 } else {
-                    Err("Unable to build datetime object from P1 0-0:1.0.0".into())
+                    Err(error("Unable to build datetime object".to_string()))
                 }
             } else {
                 Ok(None) // I should (but am not going to) define an error type here
@@ -114,6 +214,59 @@ mod tests {
         assert!(parse_kwh("prefix(bad-float*kWh)", "prefix(").is_err())
     }
 
+    #[test]
+    fn parse_kwh_padded_and_unpadded_agree() {
+        assert_eq!(
+            parse_kwh("1-0:1.8.1(002654.919*kWh)", "1-0:1.8.1("),
+            Ok(Some(2654.919))
+        );
+        assert_eq!(
+            parse_kwh("1-0:1.8.1(2654.919*kWh)", "1-0:1.8.1("),
+            Ok(Some(2654.919))
+        );
+        assert_eq!(
+            parse_kwh("1-0:1.8.1(000000*kWh)", "1-0:1.8.1("),
+            Ok(Some(0.0))
+        );
+    }
+
+    #[test]
+    fn parse_kwh_rejects_separators_and_stray_characters() {
+        assert_eq!(
+            parse_kwh("1-0:1.8.1(2,654.919*kWh)", "1-0:1.8.1("),
+            Err("Thousands separator in kWh value '2,654.919'".to_string())
+        );
+        for kwh in [
+            "+2654.919",
+            "-2654.919",
+            "2654.9e1",
+            " 2654.919",
+            ".919",
+            "2654.",
+            "inf",
+            "NaN",
+            "2654.9.19",
+        ] {
+            let parsed = parse_kwh(&format!("1-0:1.8.1({}*kWh)", kwh), "1-0:1.8.1(");
+            assert_eq!(
+                parsed,
+                Err(format!("Invalid kWh value '{}'", kwh)),
+                "{}",
+                kwh
+            );
+        }
+    }
+
+    #[test]
+    fn parse_u32_expect_count() {
+        assert_eq!(
+            parse_u32("0-0:96.7.21(00004)", POWER_FAILURES_PREFIX),
+            Ok(Some(4))
+        );
+        assert_eq!(parse_u32("0-0:96.7.21(00004)", "mismatch"), Ok(None));
+        assert!(parse_u32("0-0:96.7.21(-0004)", POWER_FAILURES_PREFIX).is_err());
+    }
+
     #[test]
     fn parse_date_time_mismatch_expect_none() {
         assert_eq!(parse_kwh("prefix(241025191816S)", "mismatch"), Ok(None));
@@ -158,6 +311,52 @@ mod tests {
         );
     }
 
+    #[test]
+    fn classify_p1_line_recognized_registers() {
+        assert_eq!(
+            classify_p1_line("0-0:1.0.0(241025191816S)"),
+            P1LineKind::Timestamp(Utc.with_ymd_and_hms(2024, 10, 25, 17, 18, 16).unwrap())
+        );
+        assert_eq!(
+            classify_p1_line("1-0:1.8.1(002654.919*kWh)"),
+            P1LineKind::PeakConso(2654.919)
+        );
+        assert_eq!(
+            classify_p1_line("1-0:1.8.2(002420.293*kWh)"),
+            P1LineKind::OffConso(2420.293)
+        );
+        assert_eq!(
+            classify_p1_line("1-0:2.8.1(006254.732*kWh)"),
+            P1LineKind::PeakInj(6254.732)
+        );
+        assert_eq!(
+            classify_p1_line("1-0:2.8.2(002457.202*kWh)"),
+            P1LineKind::OffInj(2457.202)
+        );
+        assert_eq!(
+            classify_p1_line("0-0:96.7.21(00004)"),
+            P1LineKind::PowerFailures(4)
+        );
+        // Tariffs swapped by the OBIS configuration
+        assert_eq!(
+            classify_p1_line_with_obis(
+                "1-0:1.8.1(002654.919*kWh)",
+                &P1ObisConfig::default().swapped_tariffs()
+            ),
+            P1LineKind::OffConso(2654.919)
+        );
+    }
+
+    #[test]
+    fn classify_p1_line_other_and_malformed() {
+        assert_eq!(classify_p1_line("1-0:32.7.0(230.1*V)"), P1LineKind::Other);
+        assert_eq!(classify_p1_line(""), P1LineKind::Other);
+        assert!(matches!(
+            classify_p1_line("1-0:1.8.1(bad*kWh)"),
+            P1LineKind::Malformed(_)
+        ));
+    }
+
     #[test]
     fn parse_lines_nonsense_returns_ok_none() {
         assert_eq!(
@@ -170,7 +369,145 @@ mod tests {
     fn parse_lines_happy_path() {
         assert_eq!(
             parse_lines("\n0-0:1.0.0(241025000000S)\n\n1-0:1.8.1(002654.919*kWh)\n\n1-0:1.8.2(002420.293*kWh)\n\n1-0:2.8.1(006254.732*kWh)\n\n1-0:2.8.2(002457.202*kWh)".lines()).expect("Ok(some meas) expected here"),
-            Some(CompleteP1Measurement { timestamp: Utc.with_ymd_and_hms(2024, 10, 24, 22, 0, 0).unwrap(), peak_hour_consumption: 2654.919, off_hour_consumption: 2420.293, peak_hour_injection: 6254.732, off_hour_injection: 2457.202 }),
+            Some(CompleteP1Measurement { timestamp: Utc.with_ymd_and_hms(2024, 10, 24, 22, 0, 0).unwrap(), peak_hour_consumption: 2654.919, off_hour_consumption: 2420.293, peak_hour_injection: 6254.732, off_hour_injection: 2457.202, power_failures: None, gas_m3: None, water_m3: None }),
+        )
+    }
+
+    fn mbus_obis() -> P1ObisConfig {
+        P1ObisConfig {
+            gas: Some(P1ObisConfig::mbus_prefix(1)),
+            water: Some(P1ObisConfig::mbus_prefix(2)),
+            ..P1ObisConfig::default()
+        }
+    }
+
+    #[test]
+    fn parse_lines_mbus_gas_and_water() {
+        let telegram = "/FLU5\n0-0:1.0.0(241025000000S)\n1-0:1.8.1(002654.919*kWh)\n1-0:1.8.2(002420.293*kWh)\n1-0:2.8.1(006254.732*kWh)\n1-0:2.8.2(002457.202*kWh)\n0-1:24.1.0(003)\n0-1:24.2.1(241024235500W)(28973.512*m3)\n0-2:24.1.0(007)\n0-2:24.2.1(241024235730W)(00867.504*m3)\n!ABCD";
+        let complete = parse_lines_with_obis(telegram.lines(), &mbus_obis())
+            .expect("no error")
+            .expect("complete measurement");
+        assert_eq!(complete.gas_m3, Some(28973.512));
+        assert_eq!(complete.water_m3, Some(867.504));
+        // Channels are only read when configured
+        let complete = parse_lines(telegram.lines())
+            .expect("no error")
+            .expect("complete measurement");
+        assert_eq!((complete.gas_m3, complete.water_m3), (None, None));
+        let obis = P1ObisConfig {
+            gas: None,
+            ..mbus_obis()
+        };
+        let complete = parse_lines_with_obis(telegram.lines(), &obis)
+            .expect("no error")
+            .expect("complete measurement");
+        assert_eq!((complete.gas_m3, complete.water_m3), (None, Some(867.504)));
+    }
+
+    #[test]
+    fn parse_lines_mbus_before_energy_registers_and_malformed() {
+        assert_eq!(
+            parse_lines_with_obis("0-0:1.0.0(241025000000S)\n0-2:24.2.1(241024235730W)(00867.504*m3)\n1-0:1.8.1(002654.919*kWh)\n1-0:1.8.2(002420.293*kWh)\n1-0:2.8.1(006254.732*kWh)\n1-0:2.8.2(002457.202*kWh)".lines(), &mbus_obis()).expect("Ok(some meas) expected here").map(|m| (m.gas_m3, m.water_m3)),
+            Some((None, Some(867.504))),
+        );
+        // A malformed M-Bus register does not cost the electricity registers
+        for telegram in [
+            "0-0:1.0.0(241025000000S)\n0-1:24.2.1(28973.512*m3)\n1-0:1.8.1(002654.919*kWh)\n1-0:1.8.2(002420.293*kWh)\n1-0:2.8.1(006254.732*kWh)\n1-0:2.8.2(002457.202*kWh)\n0-2:24.2.1(241024235730W)(00867.504*m3)",
+            "0-0:1.0.0(241025000000S)\n1-0:1.8.1(002654.919*kWh)\n1-0:1.8.2(002420.293*kWh)\n1-0:2.8.1(006254.732*kWh)\n1-0:2.8.2(002457.202*kWh)\n0-1:24.2.1(241024235500W)(-28973.512*m3)\n0-0:96.7.21(0x04)\n0-2:24.2.1(241024235730W)(00867.504*m3)",
+        ] {
+            assert_eq!(
+                parse_lines_with_obis(telegram.lines(), &mbus_obis())
+                    .expect("Ok(some meas) expected here")
+                    .map(|m| (
+                        m.peak_hour_consumption,
+                        m.power_failures,
+                        m.gas_m3,
+                        m.water_m3
+                    )),
+                Some((2654.919, None, None, Some(867.504))),
+                "{}",
+                telegram
+            );
+        }
+        assert_eq!(
+            classify_p1_line_with_obis("0-1:24.2.1(241024235500W)(28973.512*m3)", &mbus_obis()),
+            P1LineKind::Gas(28973.512)
+        );
+        assert_eq!(
+            classify_p1_line_with_obis("0-2:24.2.1(241024235730W)(00867.504*m3)", &mbus_obis()),
+            P1LineKind::Water(867.504)
+        );
+        assert!(matches!(
+            classify_p1_line_with_obis("0-2:24.2.1(241024235730W)(-867.504*m3)", &mbus_obis()),
+            P1LineKind::Malformed(_)
+        ));
+        assert_eq!(
+            classify_p1_line("0-1:24.2.1(241024235500W)(28973.512*m3)"),
+            P1LineKind::Other
+        );
+    }
+
+    #[test]
+    fn parse_lines_power_failures() {
+        // After the energy registers, as in most telegrams
+        assert_eq!(
+            parse_lines("/FLU5\n0-0:1.0.0(241025000000S)\n1-0:1.8.1(002654.919*kWh)\n1-0:1.8.2(002420.293*kWh)\n1-0:2.8.1(006254.732*kWh)\n1-0:2.8.2(002457.202*kWh)\n0-0:96.14.0(0001)\n0-0:96.7.21(00012)\n!ABCD\n0-0:96.7.21(00099)".lines()).expect("Ok(some meas) expected here"),
+            Some(CompleteP1Measurement { timestamp: Utc.with_ymd_and_hms(2024, 10, 24, 22, 0, 0).unwrap(), peak_hour_consumption: 2654.919, off_hour_consumption: 2420.293, peak_hour_injection: 6254.732, off_hour_injection: 2457.202, power_failures: Some(12), gas_m3: None, water_m3: None }),
+        );
+        // Before them
+        assert_eq!(
+            parse_lines("0-0:1.0.0(241025000000S)\n0-0:96.7.21(00003)\n1-0:1.8.1(002654.919*kWh)\n1-0:1.8.2(002420.293*kWh)\n1-0:2.8.1(006254.732*kWh)\n1-0:2.8.2(002457.202*kWh)".lines()).expect("Ok(some meas) expected here").map(|m| m.power_failures),
+            Some(Some(3)),
+        );
+        // Absent, even in the following datagram
+        assert_eq!(
+            parse_lines("0-0:1.0.0(241025000000S)\n1-0:1.8.1(002654.919*kWh)\n1-0:1.8.2(002420.293*kWh)\n1-0:2.8.1(006254.732*kWh)\n1-0:2.8.2(002457.202*kWh)\n!ABCD\n/FLU5\n0-0:96.7.21(00099)".lines()).expect("Ok(some meas) expected here").map(|m| m.power_failures),
+            Some(None),
+        );
+    }
+
+    #[test]
+    fn parse_lines_outcome_empty() {
+        let obis = P1ObisConfig::default();
+        assert_eq!(
+            parse_lines_outcome("".lines(), &obis).expect("Ok(Empty) expected here"),
+            P1Outcome::Empty
+        );
+        // Registers without a timestamp do not start a datagram
+        assert_eq!(
+            parse_lines_outcome("A\n1-0:1.8.1(002654.919*kWh)".lines(), &obis)
+                .expect("Ok(Empty) expected here"),
+            P1Outcome::Empty
+        );
+    }
+
+    #[test]
+    fn parse_lines_outcome_incomplete() {
+        match parse_lines_outcome(
+            "0-0:1.0.0(241025000000S)\n1-0:1.8.1(002654.919*kWh)\n1-0:2.8.1(006254.732*kWh)"
+                .lines(),
+            &P1ObisConfig::default(),
+        )
+        .expect("Ok(Incomplete) expected here")
+        {
+            P1Outcome::Incomplete(partial) => {
+                assert_eq!(
+                    partial.timestamp,
+                    Some(Utc.with_ymd_and_hms(2024, 10, 24, 22, 0, 0).unwrap())
+                );
+                assert_eq!(partial.peak_hour_consumption, Some(2654.919));
+                assert_eq!(partial.off_hour_consumption, None);
+                assert_eq!(partial.register_count(), 2);
+            }
+            other => panic!("Incomplete expected, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_lines_outcome_complete() {
+        assert_eq!(
+            parse_lines_outcome("0-0:1.0.0(241025000000S)\n1-0:1.8.1(002654.919*kWh)\n1-0:1.8.2(002420.293*kWh)\n1-0:2.8.1(006254.732*kWh)\n1-0:2.8.2(002457.202*kWh)".lines(), &P1ObisConfig::default()).expect("Ok(Complete) expected here"),
+            P1Outcome::Complete(CompleteP1Measurement { timestamp: Utc.with_ymd_and_hms(2024, 10, 24, 22, 0, 0).unwrap(), peak_hour_consumption: 2654.919, off_hour_consumption: 2420.293, peak_hour_injection: 6254.732, off_hour_injection: 2457.202, power_failures: None, gas_m3: None, water_m3: None }),
         )
     }
 
@@ -178,7 +515,7 @@ mod tests {
     fn parse_lines_skips_suffix_of_previous_datagram() {
         assert_eq!(
             parse_lines(".1(000054.732*kWh)\n\n1-0:2.8.2(000057.202*kWh)\n\n0-0:1.0.0(241025020000S)\n\n1-0:1.8.1(002654.919*kWh)\n\n1-0:1.8.2(002420.293*kWh)\n\n1-0:2.8.1(006254.732*kWh)\n\n1-0:2.8.2(002457.202*kWh)".lines()).expect("Ok(some meas) expected here"),
-            Some(CompleteP1Measurement { timestamp: Utc.with_ymd_and_hms(2024, 10, 25, 0,0,0).unwrap(), peak_hour_consumption: 2654.919, off_hour_consumption: 2420.293, peak_hour_injection: 6254.732, off_hour_injection: 2457.202 }),
+            Some(CompleteP1Measurement { timestamp: Utc.with_ymd_and_hms(2024, 10, 25, 0,0,0).unwrap(), peak_hour_consumption: 2654.919, off_hour_consumption: 2420.293, peak_hour_injection: 6254.732, off_hour_injection: 2457.202, power_failures: None, gas_m3: None, water_m3: None }),
         )
     }
 
@@ -186,18 +523,233 @@ mod tests {
     fn parse_lines_returns_first_full_datagram() {
         assert_eq!(
             parse_lines(".1(000054.732*kWh)\n\n1-0:2.8.2(000057.202*kWh)\n\n0-0:1.0.0(241025000000S)\n\n1-0:1.8.1(002654.919*kWh)\n\n1-0:1.8.2(002420.293*kWh)\n\n1-0:2.8.1(006254.732*kWh)\n\n1-0:2.8.2(002457.202*kWh)\n\n0-0:1.0.0(251126000000W)\n\n1-0:1.8.1(992654.919*kWh)\n\n1-0:1.8.2(992420.293*kWh)\n\n1-0:2.8.1(996254.732*kWh)\n\n1-0:2.8.2(992457.202*kWh)".lines()).expect("Ok(some meas) expected here"),
-            Some(CompleteP1Measurement { timestamp: Utc.with_ymd_and_hms(2024, 10, 24, 22,0,0).unwrap(), peak_hour_consumption: 2654.919, off_hour_consumption: 2420.293, peak_hour_injection: 6254.732, off_hour_injection: 2457.202 }),
+            Some(CompleteP1Measurement { timestamp: Utc.with_ymd_and_hms(2024, 10, 24, 22,0,0).unwrap(), peak_hour_consumption: 2654.919, off_hour_consumption: 2420.293, peak_hour_injection: 6254.732, off_hour_injection: 2457.202, power_failures: None, gas_m3: None, water_m3: None }),
+        )
+    }
+
+    #[test]
+    fn parse_bytes_handles_crlf_and_invalid_utf8() {
+        let mut telegram = b"/FLU5\\253769484_A\r\n\r\n0-0:96.1.4(50217)\r\n".to_vec();
+        telegram.extend_from_slice(b"0-0:96.13.0(\xff\xfe\x80garbage)\r\n");
+        telegram.extend_from_slice(b"0-0:1.0.0(241025000000S)\r\n1-0:1.8.1(002654.919*kWh)\r\n1-0:1.8.2(002420.293*kWh)\r\n1-0:2.8.1(006254.732*kWh)\r\n1-0:2.8.2(002457.202*kWh)\r\n!ABCD\r\n");
+        assert_eq!(
+            parse_bytes(&telegram).expect("Ok(some meas) expected here"),
+            Some(CompleteP1Measurement {
+                timestamp: Utc.with_ymd_and_hms(2024, 10, 24, 22, 0, 0).unwrap(),
+                peak_hour_consumption: 2654.919,
+                off_hour_consumption: 2420.293,
+                peak_hour_injection: 6254.732,
+                off_hour_injection: 2457.202,
+                power_failures: None,
+                gas_m3: None,
+                water_m3: None
+            }),
+        );
+        assert_eq!(
+            parse_bytes(b"\xff\r\nnonsense\r\n").expect("Ok(None) expected here"),
+            None
+        );
+    }
+
+    #[test]
+    fn parse_lines_reports_typed_errors() {
+        assert_eq!(
+            parse_lines(["0-0:1.0.0(240230191816S)"]),
+            Err(P1ParseError::Timestamp {
+                line: "0-0:1.0.0(240230191816S)".to_string(),
+                reason: "Unable to build datetime object".to_string(),
+            })
+        );
+        assert_eq!(
+            parse_lines(["0-0:1.0.0(241025191816S)", "1-0:1.8.1(2,654.919*kWh)"]),
+            Err(P1ParseError::Register {
+                line: "1-0:1.8.1(2,654.919*kWh)".to_string(),
+                reason: "Thousands separator in kWh value '2,654.919'".to_string(),
+            })
+        );
+        assert_eq!(
+            parse_lines(["0-0:1.0.0(241025191816S)", "1-0:1.8.1(2,654.919*kWh)"])
+                .unwrap_err()
+                .to_string(),
+            "Thousands separator in kWh value '2,654.919' in '1-0:1.8.1(2,654.919*kWh)'"
+        );
+        // A register in another unit is only a warning: it is not read
+        assert_eq!(
+            parse_lines([
+                "0-0:1.0.0(241025191816S)",
+                "1-0:1.8.1(2654919*Wh)",
+                "1-0:1.8.2(002420.293*kWh)",
+                "1-0:2.8.1(006254.732*kWh)",
+                "1-0:2.8.2(002457.202*kWh)",
+            ]),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn parse_lines_with_obis_swapped_tariffs() {
+        let obis = P1ObisConfig::default().swapped_tariffs();
+        assert_eq!(
+            parse_lines_with_obis("\n0-0:1.0.0(241025000000S)\n\n1-0:1.8.1(002654.919*kWh)\n\n1-0:1.8.2(002420.293*kWh)\n\n1-0:2.8.1(006254.732*kWh)\n\n1-0:2.8.2(002457.202*kWh)".lines(), &obis).expect("Ok(some meas) expected here"),
+            Some(CompleteP1Measurement { timestamp: Utc.with_ymd_and_hms(2024, 10, 24, 22, 0, 0).unwrap(), peak_hour_consumption: 2420.293, off_hour_consumption: 2654.919, peak_hour_injection: 2457.202, off_hour_injection: 6254.732, power_failures: None, gas_m3: None, water_m3: None }),
+        )
+    }
+
+    #[test]
+    fn parse_lines_with_obis_custom_prefixes() {
+        let obis = P1ObisConfig {
+            peak_hour_consumption: "1-0:1.8.2(".to_string(),
+            off_hour_consumption: "1-0:1.8.1(".to_string(),
+            peak_hour_injection: "1-0:2.8.1(".to_string(),
+            off_hour_injection: "1-0:2.8.2(".to_string(),
+            ..P1ObisConfig::default()
+        };
+        assert_eq!(
+            parse_lines_with_obis("0-0:1.0.0(241025000000S)\n1-0:1.8.1(002654.919*kWh)\n1-0:1.8.2(002420.293*kWh)\n1-0:2.8.1(006254.732*kWh)\n1-0:2.8.2(002457.202*kWh)".lines(), &obis).expect("Ok(some meas) expected here"),
+            Some(CompleteP1Measurement { timestamp: Utc.with_ymd_and_hms(2024, 10, 24, 22, 0, 0).unwrap(), peak_hour_consumption: 2420.293, off_hour_consumption: 2654.919, peak_hour_injection: 6254.732, off_hour_injection: 2457.202, power_failures: None, gas_m3: None, water_m3: None }),
+        )
+    }
+
+    #[test]
+    fn parse_lines_total_happy_path() {
+        assert_eq!(
+            parse_lines_total("\n0-0:1.0.0(241025000000S)\n\n1-0:1.8.0(005075.212*kWh)\n\n1-0:2.8.0(008711.934*kWh)".lines()).expect("Ok(some meas) expected here"),
+            Some(TotalP1Measurement { timestamp: Utc.with_ymd_and_hms(2024, 10, 24, 22, 0, 0).unwrap(), total_consumption: 5075.212, total_injection: 8711.934 }),
+        )
+    }
+
+    #[test]
+    fn parse_lines_total_in_any_order() {
+        assert_eq!(
+            parse_lines_total("0-0:1.0.0(241025000000S)\n1-0:2.8.0(008711.934*kWh)\n1-0:1.8.1(002654.919*kWh)\n1-0:1.8.0(005075.212*kWh)".lines()).expect("Ok(some meas) expected here"),
+            Some(TotalP1Measurement { timestamp: Utc.with_ymd_and_hms(2024, 10, 24, 22, 0, 0).unwrap(), total_consumption: 5075.212, total_injection: 8711.934 }),
+        )
+    }
+
+    #[test]
+    fn parse_lines_total_ignores_split_registers() {
+        assert_eq!(
+            parse_lines("\n0-0:1.0.0(241025000000S)\n\n1-0:1.8.0(005075.212*kWh)\n\n1-0:2.8.0(008711.934*kWh)".lines()).expect("Ok(None) expected here"),
+            None,
+        );
+        assert_eq!(
+            parse_lines_total("\n0-0:1.0.0(241025000000S)\n\n1-0:1.8.1(002654.919*kWh)\n\n1-0:1.8.2(002420.293*kWh)\n\n1-0:2.8.1(006254.732*kWh)\n\n1-0:2.8.2(002457.202*kWh)".lines()).expect("Ok(None) expected here"),
+            None,
+        )
+    }
+
+    #[test]
+    fn complete_p1_measurement_approx_eq() {
+        let meas = CompleteP1Measurement {
+            timestamp: Utc.with_ymd_and_hms(2024, 10, 24, 22, 0, 0).unwrap(),
+            peak_hour_consumption: 2654.5,
+            off_hour_consumption: 2420.25,
+            peak_hour_injection: 6254.75,
+            off_hour_injection: 2457.0,
+            power_failures: Some(3),
+            gas_m3: None,
+            water_m3: None,
+        };
+        let nudged = |delta: f64| CompleteP1Measurement {
+            off_hour_consumption: meas.off_hour_consumption + delta,
+            ..meas
+        };
+        assert!(meas.approx_eq(&nudged(0.0), 0.0));
+        assert!(meas.approx_eq(&nudged(0.125), 0.125));
+        assert!(meas.approx_eq(&nudged(-0.125), 0.125));
+        assert!(!meas.approx_eq(&nudged(0.25), 0.125));
+        assert!(!meas.approx_eq(&nudged(-0.25), 0.125));
+        assert!(!meas.approx_eq(
+            &CompleteP1Measurement {
+                timestamp: meas.timestamp + chrono::Duration::seconds(1),
+                ..nudged(0.0)
+            },
+            1.0
+        ));
+        assert!(!meas.approx_eq(
+            &CompleteP1Measurement {
+                power_failures: None,
+                gas_m3: None,
+                water_m3: None,
+                ..nudged(0.0)
+            },
+            1.0
+        ));
+    }
+
+    #[test]
+    fn check_totals_consistent_telegram() {
+        let telegram = "/FLU5\n0-0:1.0.0(241025000000S)\n1-0:1.8.0(005075.212*kWh)\n1-0:1.8.1(002654.919*kWh)\n1-0:1.8.2(002420.293*kWh)\n1-0:2.8.0(008711.934*kWh)\n1-0:2.8.1(006254.732*kWh)\n1-0:2.8.2(002457.202*kWh)\n!ABCD";
+        let complete = parse_lines(telegram.lines())
+            .expect("no error")
+            .expect("complete measurement");
+        assert!(check_totals(telegram.lines(), &complete).is_empty());
+        // Meters without the totals have nothing to check
+        let telegram = "0-0:1.0.0(241025000000S)\n1-0:1.8.1(002654.919*kWh)\n1-0:1.8.2(002420.293*kWh)\n1-0:2.8.1(006254.732*kWh)\n1-0:2.8.2(002457.202*kWh)";
+        assert!(check_totals(telegram.lines(), &complete).is_empty());
+    }
+
+    #[test]
+    fn check_totals_inconsistent_telegram() {
+        let telegram = "0-0:1.0.0(241025000000S)\n1-0:1.8.0(005075.212*kWh)\n1-0:1.8.1(002654.919*kWh)\n1-0:1.8.2(002420.293*kWh)\n1-0:2.8.0(008700.000*kWh)\n1-0:2.8.1(006254.732*kWh)\n1-0:2.8.2(002457.202*kWh)\n!ABCD\n1-0:1.8.0(000001.000*kWh)";
+        let complete = parse_lines(telegram.lines())
+            .expect("no error")
+            .expect("complete measurement");
+        assert_eq!(
+            check_totals(telegram.lines(), &complete),
+            vec!["total injection 8700.000kWh differs from peak + off = 8711.934kWh".to_string()]
+        );
+    }
+
+    #[test]
+    fn total_p1_measurement_to_data_202303_leaves_off_fields_empty() {
+        assert_eq!(
+            TotalP1Measurement {
+                timestamp: Utc.with_ymd_and_hms(2024, 10, 24, 22, 0, 0).unwrap(),
+                total_consumption: 5075.212,
+                total_injection: 8711.934
+            }
+            .to_data_202303(Some(12.5)),
+            Data202303 {
+                timestamp: 1729807200,
+                pv2012_kWh: None,
+                pv2022_kWh: Some(12.5),
+                peak_conso_kWh: Some(5075.212),
+                off_conso_kWh: None,
+                peak_inj_kWh: Some(8711.934),
+                off_inj_kWh: None,
+                gas_m3: None,
+                water_m3: None,
+            }
         )
     }
 }
 
-#[derive(PartialEq, Debug)]
-struct PartialP1Measurement {
-    timestamp: Option<DateTime<Utc>>,
-    peak_hour_consumption: Option<f64>,
-    off_hour_consumption: Option<f64>,
-    peak_hour_injection: Option<f64>,
-    off_hour_injection: Option<f64>,
+#[derive(Default, PartialEq, Debug)]
+pub struct PartialP1Measurement {
+    pub timestamp: Option<DateTime<Utc>>,
+    pub peak_hour_consumption: Option<f64>,
+    pub off_hour_consumption: Option<f64>,
+    pub peak_hour_injection: Option<f64>,
+    pub off_hour_injection: Option<f64>,
+    pub power_failures: Option<u32>,
+    pub gas_m3: Option<f64>,
+    pub water_m3: Option<f64>,
+}
+
+impl PartialP1Measurement {
+    /// How many of the 4 energy registers were read.
+    pub fn register_count(&self) -> usize {
+        [
+            self.peak_hour_consumption,
+            self.off_hour_consumption,
+            self.peak_hour_injection,
+            self.off_hour_injection,
+        ]
+        .iter()
+        .filter(|r| r.is_some())
+        .count()
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -207,6 +759,28 @@ pub struct CompleteP1Measurement {
     pub off_hour_consumption: f64,
     pub peak_hour_injection: f64,
     pub off_hour_injection: f64,
+    /// Not every meter reports it.
+    pub power_failures: Option<u32>,
+    /// Read from the M-Bus channels configured in `P1ObisConfig`, if any.
+    pub gas_m3: Option<f64>,
+    pub water_m3: Option<f64>,
+}
+
+impl CompleteP1Measurement {
+    /// Same timestamp and power failure count, registers at most `epsilon`
+    /// apart.
+    pub fn approx_eq(&self, other: &Self, epsilon: f64) -> bool {
+        self.timestamp == other.timestamp
+            && self.power_failures == other.power_failures
+            && [
+                (self.peak_hour_consumption, other.peak_hour_consumption),
+                (self.off_hour_consumption, other.off_hour_consumption),
+                (self.peak_hour_injection, other.peak_hour_injection),
+                (self.off_hour_injection, other.off_hour_injection),
+            ]
+            .iter()
+            .all(|(a, b)| (a - b).abs() <= epsilon)
+    }
 }
 
 fn complete_p1_measurement(
@@ -219,100 +793,257 @@ fn complete_p1_measurement(
             off_hour_consumption: Some(off_hour_consumption),
             peak_hour_injection: Some(peak_hour_injection),
             off_hour_injection: Some(off_hour_injection),
+            power_failures,
+            gas_m3,
+            water_m3,
         } => Ok(CompleteP1Measurement {
             timestamp,
             peak_hour_consumption,
             off_hour_consumption,
             peak_hour_injection,
             off_hour_injection,
+            power_failures,
+            gas_m3,
+            water_m3,
         }),
         _ => Err(partial),
     }
 }
 
+/// OBIS prefixes of the registers mapped onto the peak/off fields of
+/// `CompleteP1Measurement`, for meters that do not follow the usual tariff
+/// numbering.
+#[derive(Clone, Debug, PartialEq)]
+pub struct P1ObisConfig {
+    pub peak_hour_consumption: String,
+    pub off_hour_consumption: String,
+    pub peak_hour_injection: String,
+    pub off_hour_injection: String,
+    /// M-Bus register of the gas meter (see `mbus_prefix`), not read if `None`.
+    pub gas: Option<String>,
+    /// Same for the water meter.
+    pub water: Option<String>,
+}
+
+impl Default for P1ObisConfig {
+    fn default() -> Self {
+        P1ObisConfig {
+            peak_hour_consumption: "1-0:1.8.1(".to_string(),
+            off_hour_consumption: "1-0:1.8.2(".to_string(),
+            peak_hour_injection: "1-0:2.8.1(".to_string(),
+            off_hour_injection: "1-0:2.8.2(".to_string(),
+            gas: None,
+            water: None,
+        }
+    }
+}
+
+impl P1ObisConfig {
+    /// Same registers with the meaning of tariffs 1 and 2 swapped.
+    pub fn swapped_tariffs(self) -> Self {
+        P1ObisConfig {
+            peak_hour_consumption: self.off_hour_consumption,
+            off_hour_consumption: self.peak_hour_consumption,
+            peak_hour_injection: self.off_hour_injection,
+            off_hour_injection: self.peak_hour_injection,
+            gas: self.gas,
+            water: self.water,
+        }
+    }
+
+    /// Prefix of the meter reading of the M-Bus device on `channel` (1-4).
+    pub fn mbus_prefix(channel: u8) -> String {
+        format!("0-{}:24.2.1(", channel)
+    }
+}
+
+/// Fill the registers that do not decide whether a datagram is complete.  A
+/// malformed one is left unknown, with a warning, rather than losing the
+/// electricity registers of the whole datagram.
+fn step_optional_registers(
+    line: &str,
+    obis: &P1ObisConfig,
+    power_failures: &mut Option<u32>,
+    gas_m3: &mut Option<f64>,
+    water_m3: &mut Option<f64>,
+) {
+    if power_failures.is_none() {
+        match parse_u32(line, POWER_FAILURES_PREFIX) {
+            Ok(count) => *power_failures = count,
+            Err(e) => tracing::warn!("P1 warning: {} in '{}'", e, line),
+        }
+    }
+    for (field, prefix) in [(gas_m3, &obis.gas), (water_m3, &obis.water)] {
+        if field.is_none()
+            && let Some(prefix) = prefix
+        {
+            match parse_register(line, prefix, "m3", parse_mbus_m3) {
+                Ok(value) => *field = value,
+                Err(e) => tracing::warn!("P1 warning: {}", e),
+            }
+        }
+    }
+}
+
+/// Fill `field` from `line` if it is still unknown and `line` is its register.
+fn step_register(field: &mut Option<f64>, line: &str, prefix: &str) -> Result<bool, P1ParseError> {
+    if field.is_none()
+        && let Some(kwh) = parse_register(line, prefix, "kWh", parse_kwh)?
+    {
+        *field = Some(kwh);
+        return Ok(true);
+    }
+    Ok(false)
+}
+
 fn step_partial_p1_measurement(
-    partial: PartialP1Measurement,
+    mut partial: PartialP1Measurement,
     line: &str,
-) -> Result<PartialP1Measurement, Box<dyn Error>> {
-    match partial {
-        PartialP1Measurement {
-            timestamp: None, ..
-        } => match parse_date_time(line)? {
-            Some(timestamp) => Ok(PartialP1Measurement {
+    obis: &P1ObisConfig,
+) -> Result<PartialP1Measurement, P1ParseError> {
+    if partial.timestamp.is_none() {
+        // Registers only count once the start of a datagram has been seen
+        return Ok(match parse_date_time(line)? {
+            Some(timestamp) => PartialP1Measurement {
                 timestamp: Some(timestamp),
                 peak_hour_consumption: None,
                 off_hour_consumption: None,
                 peak_hour_injection: None,
                 off_hour_injection: None,
-            }),
-            _ => Ok(partial),
-        },
-        PartialP1Measurement {
-            timestamp: Some(timestamp),
-            peak_hour_consumption: None,
-            ..
-        } => match parse_kwh(line, "1-0:1.8.1(")? {
-            Some(kwh) => Ok(PartialP1Measurement {
-                timestamp: Some(timestamp),
-                peak_hour_consumption: Some(kwh),
-                off_hour_consumption: None,
-                peak_hour_injection: None,
-                off_hour_injection: None,
-            }),
-            _ => Ok(partial),
-        },
-        PartialP1Measurement {
-            timestamp: Some(timestamp),
-            peak_hour_consumption: Some(peak_hour_consumption),
-            off_hour_consumption: None,
-            ..
-        } => match parse_kwh(line, "1-0:1.8.2(")? {
-            Some(kwh) => Ok(PartialP1Measurement {
-                timestamp: Some(timestamp),
-                peak_hour_consumption: Some(peak_hour_consumption),
-                off_hour_consumption: Some(kwh),
-                peak_hour_injection: None,
-                off_hour_injection: None,
-            }),
-            _ => Ok(partial),
-        },
-        PartialP1Measurement {
-            timestamp: Some(timestamp),
-            peak_hour_consumption: Some(peak_hour_consumption),
-            off_hour_consumption: Some(off_hour_consumption),
-            peak_hour_injection: None,
-            ..
-        } => match parse_kwh(line, "1-0:2.8.1(")? {
-            Some(kwh) => Ok(PartialP1Measurement {
-                timestamp: Some(timestamp),
-                peak_hour_consumption: Some(peak_hour_consumption),
-                off_hour_consumption: Some(off_hour_consumption),
-                peak_hour_injection: Some(kwh),
-                off_hour_injection: None,
-            }),
-            _ => Ok(partial),
-        },
-        PartialP1Measurement {
-            timestamp: Some(timestamp),
-            peak_hour_consumption: Some(peak_hour_consumption),
-            off_hour_consumption: Some(off_hour_consumption),
-            peak_hour_injection: Some(peak_hour_injection),
-            off_hour_injection: None,
-        } => match parse_kwh(line, "1-0:2.8.2(")? {
-            Some(kwh) => Ok(PartialP1Measurement {
-                timestamp: Some(timestamp),
-                peak_hour_consumption: Some(peak_hour_consumption),
-                off_hour_consumption: Some(off_hour_consumption),
-                peak_hour_injection: Some(peak_hour_injection),
-                off_hour_injection: Some(kwh),
-            }),
-            _ => Ok(partial),
-        },
-        _ => Ok(partial),
+                power_failures: None,
+                gas_m3: None,
+                water_m3: None,
+            },
+            _ => partial,
+        });
+    }
+    let mut matched = false;
+    for (field, prefix) in [
+        (
+            &mut partial.peak_hour_consumption,
+            &obis.peak_hour_consumption,
+        ),
+        (
+            &mut partial.off_hour_consumption,
+            &obis.off_hour_consumption,
+        ),
+        (&mut partial.peak_hour_injection, &obis.peak_hour_injection),
+        (&mut partial.off_hour_injection, &obis.off_hour_injection),
+    ] {
+        if step_register(field, line, prefix)? {
+            matched = true;
+            break;
+        }
+    }
+    if !matched {
+        step_optional_registers(
+            line,
+            obis,
+            &mut partial.power_failures,
+            &mut partial.gas_m3,
+            &mut partial.water_m3,
+        );
+    }
+    Ok(partial)
+}
+
+/// What a single line of a P1 datagram holds, for diagnostics.
+#[derive(PartialEq, Debug)]
+pub enum P1LineKind {
+    Timestamp(DateTime<Utc>),
+    PeakConso(f64),
+    OffConso(f64),
+    PeakInj(f64),
+    OffInj(f64),
+    PowerFailures(u32),
+    Gas(f64),
+    Water(f64),
+    /// A known register whose value could not be parsed.
+    Malformed(String),
+    Other,
+}
+
+pub fn classify_p1_line(line: &str) -> P1LineKind {
+    classify_p1_line_with_obis(line, &P1ObisConfig::default())
+}
+
+/// Like `classify_p1_line` but recognizing the registers configured in `obis`.
+pub fn classify_p1_line_with_obis(line: &str, obis: &P1ObisConfig) -> P1LineKind {
+    match parse_date_time(line) {
+        Ok(Some(timestamp)) => return P1LineKind::Timestamp(timestamp),
+        Ok(None) => {}
+        Err(e) => return P1LineKind::Malformed(e.to_string()),
+    }
+    for (prefix, kind) in [
+        (
+            &obis.peak_hour_consumption,
+            P1LineKind::PeakConso as fn(f64) -> P1LineKind,
+        ),
+        (&obis.off_hour_consumption, P1LineKind::OffConso),
+        (&obis.peak_hour_injection, P1LineKind::PeakInj),
+        (&obis.off_hour_injection, P1LineKind::OffInj),
+    ] {
+        match parse_kwh(line, prefix) {
+            Ok(Some(kwh)) => return kind(kwh),
+            Ok(None) => {}
+            Err(e) => return P1LineKind::Malformed(e.to_string()),
+        }
+    }
+    for (prefix, kind) in [
+        (&obis.gas, P1LineKind::Gas as fn(f64) -> P1LineKind),
+        (&obis.water, P1LineKind::Water),
+    ] {
+        if let Some(prefix) = prefix {
+            match parse_mbus_m3(line, prefix) {
+                Ok(Some(m3)) => return kind(m3),
+                Ok(None) => {}
+                Err(e) => return P1LineKind::Malformed(e),
+            }
+        }
+    }
+    match parse_u32(line, POWER_FAILURES_PREFIX) {
+        Ok(Some(count)) => P1LineKind::PowerFailures(count),
+        Ok(None) => P1LineKind::Other,
+        Err(e) => P1LineKind::Malformed(e.to_string()),
     }
 }
 
-pub fn parse_lines<T>(lines: T) -> Result<Option<CompleteP1Measurement>, Box<dyn Error>>
+/// What was found in the P1 output.
+#[derive(PartialEq, Debug)]
+pub enum P1Outcome {
+    /// Not even the start of a datagram.
+    Empty,
+    /// A datagram started but ended before all registers were read.
+    Incomplete(PartialP1Measurement),
+    Complete(CompleteP1Measurement),
+}
+
+pub fn parse_lines<T>(lines: T) -> Result<Option<CompleteP1Measurement>, P1ParseError>
+where
+    T: IntoIterator,
+    T::Item: Borrow<str>,
+{
+    parse_lines_with_obis(lines, &P1ObisConfig::default())
+}
+
+/// Like `parse_lines` but reading the registers configured in `obis`.
+pub fn parse_lines_with_obis<T>(
+    lines: T,
+    obis: &P1ObisConfig,
+) -> Result<Option<CompleteP1Measurement>, P1ParseError>
+where
+    T: IntoIterator,
+    T::Item: Borrow<str>,
+{
+    match parse_lines_outcome(lines, obis)? {
+        P1Outcome::Complete(complete) => Ok(Some(complete)),
+        P1Outcome::Empty | P1Outcome::Incomplete(_) => Ok(None),
+    }
+}
+
+/// Like `parse_lines_with_obis` but telling an empty output from a truncated
+/// datagram.
+pub fn parse_lines_outcome<T>(lines: T, obis: &P1ObisConfig) -> Result<P1Outcome, P1ParseError>
 where
     T: IntoIterator,
     T::Item: Borrow<str>,
@@ -323,12 +1054,158 @@ where
         off_hour_consumption: None,
         peak_hour_injection: None,
         off_hour_injection: None,
+        power_failures: None,
+        gas_m3: None,
+        water_m3: None,
     };
-    for line in lines.into_iter() {
-        match complete_p1_measurement(step_partial_p1_measurement(partial, line.borrow())?) {
-            Ok(complete) => return Ok(Some(complete)),
+    let mut lines = lines.into_iter();
+    for line in lines.by_ref() {
+        match complete_p1_measurement(step_partial_p1_measurement(partial, line.borrow(), obis)?) {
+            Ok(mut complete) => {
+                // Optional registers may still follow, up to the end of the datagram
+                for line in lines.by_ref() {
+                    let line = line.borrow();
+                    if line.starts_with('!')
+                        || line.starts_with('/')
+                        || line.starts_with("0-0:1.0.0(")
+                    {
+                        break;
+                    }
+                    step_optional_registers(
+                        line,
+                        obis,
+                        &mut complete.power_failures,
+                        &mut complete.gas_m3,
+                        &mut complete.water_m3,
+                    );
+                }
+                return Ok(P1Outcome::Complete(complete));
+            }
             Err(new_partial) => partial = new_partial,
         }
     }
-    return Ok(None);
+    if partial.timestamp.is_some() {
+        Ok(P1Outcome::Incomplete(partial))
+    } else {
+        Ok(P1Outcome::Empty)
+    }
+}
+
+/// Like `parse_lines` but for a raw capture: lines may end in `\r\n` and
+/// bytes that are not valid UTF-8 are replaced rather than rejected.
+pub fn parse_bytes(data: &[u8]) -> Result<Option<CompleteP1Measurement>, P1ParseError> {
+    parse_lines(
+        data.split(|b| *b == b'\n').map(|line| {
+            String::from_utf8_lossy(line.strip_suffix(b"\r").unwrap_or(line)).into_owned()
+        }),
+    )
+}
+
+// Some meters do not split their registers by tariff and only report totals:
+//
+// 1-0:1.8.0(005075.212*kWh)
+//
+// 1-0:2.8.0(008711.934*kWh)
+
+#[derive(PartialEq, Debug)]
+pub struct TotalP1Measurement {
+    pub timestamp: DateTime<Utc>,
+    pub total_consumption: f64,
+    pub total_injection: f64,
+}
+
+impl TotalP1Measurement {
+    /// The totals go into the peak columns: the off-peak columns stay `None`
+    /// because the meter does not report the split.
+    pub fn to_data_202303(&self, pv_2022: Option<f64>) -> Data202303 {
+        Data202303 {
+            peak_conso_kWh: Some(self.total_consumption),
+            peak_inj_kWh: Some(self.total_injection),
+            ..Data202303::empty(self.timestamp.timestamp()).with_pv2022(pv_2022)
+        }
+    }
+}
+
+/// Like `parse_lines` but for meters reporting only the combined tariff
+/// registers (`1-0:1.8.0` and `1-0:2.8.0`), read into a `TotalP1Measurement`.
+pub fn parse_lines_total<T>(lines: T) -> Result<Option<TotalP1Measurement>, P1ParseError>
+where
+    T: IntoIterator,
+    T::Item: Borrow<str>,
+{
+    let obis = P1ObisConfig {
+        peak_hour_consumption: "1-0:1.8.0(".to_string(),
+        peak_hour_injection: "1-0:2.8.0(".to_string(),
+        ..P1ObisConfig::default()
+    };
+    let mut partial = PartialP1Measurement::default();
+    for line in lines.into_iter() {
+        partial = step_partial_p1_measurement(partial, line.borrow(), &obis)?;
+        if let PartialP1Measurement {
+            timestamp: Some(timestamp),
+            peak_hour_consumption: Some(total_consumption),
+            peak_hour_injection: Some(total_injection),
+            ..
+        } = partial
+        {
+            return Ok(Some(TotalP1Measurement {
+                timestamp,
+                total_consumption,
+                total_injection,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// Largest accepted difference between a total register and the sum of its
+/// tariff registers, the meter rounding each of them to the Wh.
+const TOTALS_EPSILON_KWH: f64 = 0.002;
+
+/// Compare the optional `1-0:1.8.0`/`1-0:2.8.0` totals of the datagram that
+/// produced `complete` with the sum of its tariff registers, describing each
+/// mismatch. Nothing is reported when the meter does not send the totals.
+pub fn check_totals<T>(lines: T, complete: &CompleteP1Measurement) -> Vec<String>
+where
+    T: IntoIterator,
+    T::Item: Borrow<str>,
+{
+    let mut in_datagram = false;
+    let mut totals = [None, None];
+    for line in lines.into_iter() {
+        let line = line.borrow();
+        if !in_datagram {
+            in_datagram = parse_date_time(line).ok().flatten() == Some(complete.timestamp);
+            continue;
+        }
+        if line.starts_with('!') || line.starts_with('/') || line.starts_with("0-0:1.0.0(") {
+            break;
+        }
+        for (total, prefix) in totals.iter_mut().zip(["1-0:1.8.0(", "1-0:2.8.0("]) {
+            if total.is_none() {
+                *total = parse_kwh(line, prefix).ok().flatten();
+            }
+        }
+    }
+    [
+        (
+            "consumption",
+            totals[0],
+            complete.peak_hour_consumption + complete.off_hour_consumption,
+        ),
+        (
+            "injection",
+            totals[1],
+            complete.peak_hour_injection + complete.off_hour_injection,
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(label, total, sum)| match total {
+        Some(total) if (total - sum).abs() > TOTALS_EPSILON_KWH => Some(format!(
+            "total {} {:.3}kWh differs from peak + off = {:.3}kWh",
+            label, total, sum
+        )),
+        _ => None,
+    })
+    .collect()
 }
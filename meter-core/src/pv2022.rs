@@ -1,68 +1,569 @@
+use crate::shell::{kill_group, sh_command, wait_until};
 use serde_json::Value;
 use std::{
+    borrow::Cow,
+    fmt,
     io::{BufReader, Read},
-    process::{Command, Stdio},
+    process::Stdio,
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
 };
 
+pub const DEFAULT_DIVISOR: f64 = 1000.0; // Wh -> kWh
+pub const PV_2022_SERIAL: &str = "0199-xxxxx9BD";
+pub const PV_2022_JSON_PATH: &str = "result.0199-xxxxx9BD.6400_00260100.1.0.val";
+
+#[derive(Debug, PartialEq)]
+enum JsonPathStep {
+    Key(String), // also used as array index when it parses as one
+    Index(usize),
+}
+
+impl fmt::Display for JsonPathStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonPathStep::Key(key) => write!(f, "'{}'", key),
+            JsonPathStep::Index(idx) => write!(f, "[{}]", idx),
+        }
+    }
+}
+
+/// Parse e.g. `result.0199-xxxxx9BD.6400_00260100.1[0].val`
+fn parse_json_path(path: &str) -> Result<Vec<JsonPathStep>, String> {
+    let mut steps = Vec::new();
+    for segment in path.split('.') {
+        let (key, mut indices) = segment.split_at(segment.find('[').unwrap_or(segment.len()));
+        if key.is_empty() && indices.is_empty() {
+            return Err(format!("Empty step in JSON path '{}'", path));
+        }
+        if !key.is_empty() {
+            steps.push(JsonPathStep::Key(key.to_string()));
+        }
+        while !indices.is_empty() {
+            let (index, rest) = indices
+                .strip_prefix('[')
+                .and_then(|s| s.split_once(']'))
+                .ok_or(format!(
+                    "Malformed index '{}' in JSON path '{}'",
+                    indices, path
+                ))?;
+            let index = index
+                .parse::<usize>()
+                .map_err(|e| format!("Bad index '{}' in JSON path '{}': {}", index, path, e))?;
+            steps.push(JsonPathStep::Index(index));
+            indices = rest;
+        }
+    }
+    Ok(steps)
+}
+
+fn walk_json_path<'a>(json: &'a Value, steps: &[JsonPathStep]) -> Result<&'a Value, String> {
+    let mut current = json;
+    for (pos, step) in steps.iter().enumerate() {
+        current = match (step, current) {
+            (JsonPathStep::Key(key), Value::Object(map)) => map.get(key),
+            (JsonPathStep::Key(key), Value::Array(vec)) => {
+                key.parse::<usize>().ok().and_then(|idx| vec.get(idx))
+            }
+            (JsonPathStep::Index(idx), Value::Array(vec)) => vec.get(*idx),
+            _ => None,
+        }
+        .ok_or(format!("JSON path step {} ({}) not found", pos + 1, step))?;
+    }
+    Ok(current)
+}
+
 /* {"result":{"0199-xxxxx9BD":{"6800_08822000":{"1":[{"validVals":[9401,9402,9403,9404,9405],"val":[{"tag":9404}]}]},"6800_10821E00":{"1":[{"val":"SN: xxxxxxx245"}]},"6800_08811F00":{"1":[{"validVals":[1129,1130],"val":[{"tag":1129}]}]},"6180_08214800":{"1":[{"val":[{"tag":307}]}]},"6180_08414900":{"1":[{"val":[{"tag":886}]}]},"6180_08522F00":{"1":[{"val":[{"tag":16777213}]}]},"6800_088A2900":{"1":[{"validVals":[302,9327,9375,9376,9437,19043],"val":[{"tag":302}]}]},"6100_40463600":{"1":[{"val":null}]},"6100_40463700":{"1":[{"val":null}]},"6100_40263F00":{"1":[{"val":null}]},"6400_00260100":{"1":[{"val":7459043}]},"6800_00832A00":{"1":[{"low":5000,"high":5000,"val":5000}]},"6800_008AA200":{"1":[{"low":0,"high":null,"val":0}]},"6400_00462500":{"1":[{"val":null}]},"6100_00418000":{"1":[{"val":null}]},"6800_08822B00":{"1":[{"validVals":[461],"val":[{"tag":461}]}]},"6100_0046C200":{"1":[{"val":null}]},"6400_0046C300":{"1":[{"val":7459043}]},"6802_08834500":{"1":[{"validVals":[303,1439],"val":[{"tag":1439}]}]},"6180_08412800":{"1":[{"val":[{"tag":16777213}]}]}}}}
 
 curl --silent --connect-timeout 1 --max-time 2 --insecure https://sunnyboy50/dyn/getDashValues.json */
 pub fn fetch_dashboard_value(
     pv_2022_cmd: &str,
+    divisor: f64,
+    timeout: Duration,
+    verbose: bool,
+) -> core::result::Result<Option<f64>, String> {
+    fetch_json_path_value(pv_2022_cmd, PV_2022_JSON_PATH, divisor, timeout, verbose)
+}
+
+/// Same as `fetch_dashboard_value` for another inverter, identified by its
+/// `serial` (the key under `result` in the dashboard JSON).
+pub fn fetch_device_value(
+    cmd: &str,
+    serial: &str,
+    divisor: f64,
+    timeout: Duration,
     verbose: bool,
-) -> core::result::Result<f64, String> {
-    let mut child = Command::new("sh")
-        .arg("-c")
-        .arg(pv_2022_cmd)
+) -> core::result::Result<Option<f64>, String> {
+    fetch_json_path_value(
+        cmd,
+        &format!("result.{}.6400_00260100.1.0.val", serial),
+        divisor,
+        timeout,
+        verbose,
+    )
+}
+
+/// How to pick the value of a register reporting several entries (e.g. one per
+/// phase).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValSelector {
+    /// The `val` of that entry.
+    Index(usize),
+    /// Sum of the `val`s that are not `null`.
+    Sum,
+    /// Largest of the `val`s that are not `null`.
+    Max,
+}
+
+impl Default for ValSelector {
+    fn default() -> Self {
+        ValSelector::Index(0)
+    }
+}
+
+/// Same as `fetch_device_value` for any `register` of the inverter, picking
+/// among its entries with `selector`.
+pub fn fetch_register_value(
+    cmd: &str,
+    serial: &str,
+    register: &str,
+    selector: ValSelector,
+    divisor: f64,
+    timeout: Duration,
+    verbose: bool,
+) -> core::result::Result<Option<f64>, String> {
+    check_divisor(divisor)?;
+    let json = fetch_json(cmd, timeout, verbose)?;
+    let json_path = parse_json_path(&format!("result.{}.{}.1", serial, register))?;
+    let entries = walk_json_path(&json, &json_path)?
+        .as_array()
+        .ok_or(format!("Register {} has no array of entries", register))?;
+    let val = |entry: &Value| -> Result<Option<f64>, String> {
+        match entry.get("val") {
+            None | Some(Value::Null) => Ok(None),
+            Some(val) => val
+                .as_f64()
+                .map(Some)
+                .ok_or(format!("Register {} has a non-numeric val", register)),
+        }
+    };
+    let value = match selector {
+        ValSelector::Index(idx) => {
+            let entry = entries.get(idx).ok_or(format!(
+                "Register {} has {} entries, entry {} requested",
+                register,
+                entries.len(),
+                idx
+            ))?;
+            val(entry)?
+        }
+        ValSelector::Sum | ValSelector::Max => {
+            let mut vals = Vec::with_capacity(entries.len());
+            for entry in entries {
+                vals.extend(val(entry)?);
+            }
+            if selector == ValSelector::Sum {
+                (!vals.is_empty()).then(|| vals.iter().sum())
+            } else {
+                vals.into_iter().reduce(f64::max)
+            }
+        }
+    };
+    Ok(value.map(|value| value / divisor))
+}
+
+fn check_divisor(divisor: f64) -> Result<(), String> {
+    if divisor == 0.0 || !divisor.is_finite() {
+        return Err(format!("Invalid divisor: {}", divisor));
+    }
+    Ok(())
+}
+
+/// Same as `fetch_dashboard_value` for the value found at `json_path` (keys
+/// and array indices separated by dots, or array indices between brackets).
+pub fn fetch_json_path_value(
+    cmd: &str,
+    json_path: &str,
+    divisor: f64,
+    timeout: Duration,
+    verbose: bool,
+) -> core::result::Result<Option<f64>, String> {
+    check_divisor(divisor)?;
+    let json_path = parse_json_path(json_path)?;
+    let json = fetch_json(cmd, timeout, verbose)?;
+    let value = walk_json_path(&json, &json_path)?;
+    if value.is_null() {
+        // Inverter asleep (e.g. at night): valid response without a value
+        return Ok(None);
+    }
+    let value = value.as_f64().ok_or("Invalid JSON response")?;
+
+    Ok(Some(value / divisor))
+}
+
+/// Run `cmd` and parse its output as JSON, giving up after `timeout` (`cmd` and
+/// everything it started are killed then).
+fn fetch_json(cmd: &str, timeout: Duration, verbose: bool) -> Result<Value, String> {
+    let deadline = Instant::now() + timeout;
+    let mut child = sh_command(cmd)
         .stdout(Stdio::piped())
         .spawn()
-        .map_err(|e| format!("Failed to spawn {}: {}", pv_2022_cmd, e))?;
+        .map_err(|e| format!("Failed to spawn {}: {}", cmd, e))?;
     let stdout = child
         .stdout
         .take()
-        .ok_or(format!("Failed to get output of {}", pv_2022_cmd))?;
+        .ok_or(format!("Failed to get output of {}", cmd))?;
 
-    let mut reader = BufReader::new(stdout);
-    let mut response_bytes = Vec::new();
-    reader
-        .read_to_end(&mut response_bytes)
-        .map_err(|e| format!("Failed to read stdout: {}", e))?;
-    child
-        .wait()
-        .map_err(|e| format!("Unable to wait for '{}': {}", pv_2022_cmd, e))?;
+    // Read on a helper thread so that a hung cmd can't block the caller
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut reader = BufReader::new(stdout);
+        let mut response_bytes = Vec::new();
+        let _ = tx.send(
+            reader
+                .read_to_end(&mut response_bytes)
+                .map(|_| response_bytes),
+        );
+    });
+    let response_bytes = match rx.recv_timeout(timeout) {
+        Ok(response_bytes) => response_bytes.map_err(|e| format!("Failed to read stdout: {}", e)),
+        Err(RecvTimeoutError::Timeout) => Err(format!(
+            "No response from '{}' after {:.3}s",
+            cmd,
+            timeout.as_secs_f64()
+        )),
+        Err(RecvTimeoutError::Disconnected) => Err(format!("Failed to read output of '{}'", cmd)),
+    };
+    // Even with a complete response, don't wait past the deadline
+    if wait_until(&mut child, deadline)
+        .map_err(|e| format!("Unable to wait for '{}': {}", cmd, e))?
+        .is_none()
+    {
+        kill_group(&mut child).map_err(|e| format!("Unable to kill '{}': {}", cmd, e))?;
+    }
+    let response_bytes = response_bytes?;
 
-    let response_text = std::str::from_utf8(&response_bytes)
-        .map_err(|e| format!("Failed to parse curl response as UTF-8: {}", e))?;
+    // Stray bytes are usually outside of the values we are after: replace
+    // them rather than discard the whole response
+    let response_text = String::from_utf8_lossy(&response_bytes);
+    if let Cow::Owned(_) = response_text {
+        tracing::warn!("Replaced invalid UTF-8 in the response of '{}'", cmd);
+    }
+    let response_text = response_text
+        .strip_prefix('\u{feff}')
+        .unwrap_or(&response_text);
 
     if verbose {
-        println!("response_text={}", response_text)
+        tracing::info!("response_text={}", response_text)
     };
-    let json: Value =
-        serde_json::from_str(response_text).map_err(|e| format!("Unable to parse JSON: {}", e))?;
-    let value = json["result"]["0199-xxxxx9BD"]["6400_00260100"]["1"][0]["val"]
-        .as_f64()
-        .ok_or("Invalid JSON response")?;
-
-    Ok(value / 1000.0)
+    serde_json::from_str(response_text).map_err(|e| format!("Unable to parse JSON: {}", e))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::time::Instant;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    const EXAMPLE_CMD: &str = "echo '{\"result\":{\"0199-xxxxx9BD\":{\"6800_08822000\":{\"1\":[{\"validVals\":[9401,9402,9403,9404,9405],\"val\":[{\"tag\":9404}]}]},\"6800_10821E00\":{\"1\":[{\"val\":\"SN: xxxxxxx245\"}]},\"6800_08811F00\":{\"1\":[{\"validVals\":[1129,1130],\"val\":[{\"tag\":1129}]}]},\"6180_08214800\":{\"1\":[{\"val\":[{\"tag\":307}]}]},\"6180_08414900\":{\"1\":[{\"val\":[{\"tag\":886}]}]},\"6180_08522F00\":{\"1\":[{\"val\":[{\"tag\":16777213}]}]},\"6800_088A2900\":{\"1\":[{\"validVals\":[302,9327,9375,9376,9437,19043],\"val\":[{\"tag\":302}]}]},\"6100_40463600\":{\"1\":[{\"val\":null}]},\"6100_40463700\":{\"1\":[{\"val\":null}]},\"6100_40263F00\":{\"1\":[{\"val\":null}]},\"6400_00260100\":{\"1\":[{\"val\":7459043}]},\"6800_00832A00\":{\"1\":[{\"low\":5000,\"high\":5000,\"val\":5000}]},\"6800_008AA200\":{\"1\":[{\"low\":0,\"high\":null,\"val\":0}]},\"6400_00462500\":{\"1\":[{\"val\":null}]},\"6100_00418000\":{\"1\":[{\"val\":null}]},\"6800_08822B00\":{\"1\":[{\"validVals\":[461],\"val\":[{\"tag\":461}]}]},\"6100_0046C200\":{\"1\":[{\"val\":null}]},\"6400_0046C300\":{\"1\":[{\"val\":7459043}]},\"6802_08834500\":{\"1\":[{\"validVals\":[303,1439],\"val\":[{\"tag\":1439}]}]},\"6180_08412800\":{\"1\":[{\"val\":[{\"tag\":16777213}]}]}}}}'";
 
     #[test]
     fn works_with_example() {
+        assert_eq!(
+            fetch_dashboard_value(EXAMPLE_CMD, DEFAULT_DIVISOR, TIMEOUT, true),
+            Ok(Some(7459.043))
+        );
+    }
+
+    const MULTI_VAL_CMD: &str = "echo '{\"result\":{\"0199-xxxxx9BD\":{\"6100_40465300\":{\"1\":[{\"val\":1200},{\"val\":800},{\"val\":null},{\"val\":1500}]}}}}'";
+
+    #[test]
+    fn works_with_bom_and_stray_bytes() {
+        assert_eq!(
+            fetch_dashboard_value(
+                &format!("printf '\\357\\273\\277'; {}", EXAMPLE_CMD),
+                DEFAULT_DIVISOR,
+                TIMEOUT,
+                true
+            ),
+            Ok(Some(7459.043))
+        );
+        assert_eq!(
+            fetch_register_value(
+                "printf '{\"result\":{\"0199-xxxxx9BD\":{\"6800_10821E00\":{\"1\":[{\"val\":\"SN: \\377\"}]},\"6100_40465300\":{\"1\":[{\"val\":1200}]}}}}'",
+                PV_2022_SERIAL,
+                "6100_40465300",
+                ValSelector::default(),
+                1.0,
+                TIMEOUT,
+                true
+            ),
+            Ok(Some(1200.0))
+        );
+        // Still an error when it is not JSON at all
+        assert!(
+            fetch_dashboard_value(
+                "printf '\\357\\273\\277nope'",
+                DEFAULT_DIVISOR,
+                TIMEOUT,
+                true
+            )
+            .is_err_and(|e| e.starts_with("Unable to parse JSON"))
+        );
+    }
+
+    #[test]
+    fn register_value_selects_index() {
+        assert_eq!(
+            fetch_register_value(
+                MULTI_VAL_CMD,
+                PV_2022_SERIAL,
+                "6100_40465300",
+                ValSelector::Index(1),
+                DEFAULT_DIVISOR,
+                TIMEOUT,
+                true
+            ),
+            Ok(Some(0.8))
+        );
+        assert_eq!(
+            fetch_register_value(
+                MULTI_VAL_CMD,
+                PV_2022_SERIAL,
+                "6100_40465300",
+                ValSelector::default(),
+                1.0,
+                TIMEOUT,
+                true
+            ),
+            Ok(Some(1200.0))
+        );
+        assert_eq!(
+            fetch_register_value(
+                EXAMPLE_CMD,
+                PV_2022_SERIAL,
+                "6400_00260100",
+                ValSelector::Index(0),
+                DEFAULT_DIVISOR,
+                TIMEOUT,
+                true
+            ),
+            fetch_dashboard_value(EXAMPLE_CMD, DEFAULT_DIVISOR, TIMEOUT, true)
+        );
+    }
+
+    #[test]
+    fn register_value_sum_and_max_skip_null() {
+        for (selector, expected) in [(ValSelector::Sum, 3500.0), (ValSelector::Max, 1500.0)] {
+            assert_eq!(
+                fetch_register_value(
+                    MULTI_VAL_CMD,
+                    PV_2022_SERIAL,
+                    "6100_40465300",
+                    selector,
+                    1.0,
+                    TIMEOUT,
+                    true
+                ),
+                Ok(Some(expected))
+            );
+        }
+    }
+
+    #[test]
+    fn register_value_short_array_names_register() {
+        let err = fetch_register_value(
+            MULTI_VAL_CMD,
+            PV_2022_SERIAL,
+            "6100_40465300",
+            ValSelector::Index(4),
+            1.0,
+            TIMEOUT,
+            true,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            "Register 6100_40465300 has 4 entries, entry 4 requested"
+        );
+    }
+
+    #[test]
+    fn divisor_of_one_returns_raw_value() {
+        assert_eq!(
+            fetch_dashboard_value(EXAMPLE_CMD, 1.0, TIMEOUT, true),
+            Ok(Some(7459043.0))
+        );
+    }
+
+    #[test]
+    fn invalid_divisor_is_rejected() {
+        assert!(fetch_dashboard_value(EXAMPLE_CMD, 0.0, TIMEOUT, true).is_err());
+        assert!(fetch_dashboard_value(EXAMPLE_CMD, f64::NAN, TIMEOUT, true).is_err());
+        assert!(fetch_dashboard_value(EXAMPLE_CMD, f64::INFINITY, TIMEOUT, true).is_err());
+    }
+
+    #[test]
+    fn null_value_is_not_an_error() {
         assert_eq!(
             fetch_dashboard_value(
-                "echo '{\"result\":{\"0199-xxxxx9BD\":{\"6800_08822000\":{\"1\":[{\"validVals\":[9401,9402,9403,9404,9405],\"val\":[{\"tag\":9404}]}]},\"6800_10821E00\":{\"1\":[{\"val\":\"SN: xxxxxxx245\"}]},\"6800_08811F00\":{\"1\":[{\"validVals\":[1129,1130],\"val\":[{\"tag\":1129}]}]},\"6180_08214800\":{\"1\":[{\"val\":[{\"tag\":307}]}]},\"6180_08414900\":{\"1\":[{\"val\":[{\"tag\":886}]}]},\"6180_08522F00\":{\"1\":[{\"val\":[{\"tag\":16777213}]}]},\"6800_088A2900\":{\"1\":[{\"validVals\":[302,9327,9375,9376,9437,19043],\"val\":[{\"tag\":302}]}]},\"6100_40463600\":{\"1\":[{\"val\":null}]},\"6100_40463700\":{\"1\":[{\"val\":null}]},\"6100_40263F00\":{\"1\":[{\"val\":null}]},\"6400_00260100\":{\"1\":[{\"val\":7459043}]},\"6800_00832A00\":{\"1\":[{\"low\":5000,\"high\":5000,\"val\":5000}]},\"6800_008AA200\":{\"1\":[{\"low\":0,\"high\":null,\"val\":0}]},\"6400_00462500\":{\"1\":[{\"val\":null}]},\"6100_00418000\":{\"1\":[{\"val\":null}]},\"6800_08822B00\":{\"1\":[{\"validVals\":[461],\"val\":[{\"tag\":461}]}]},\"6100_0046C200\":{\"1\":[{\"val\":null}]},\"6400_0046C300\":{\"1\":[{\"val\":7459043}]},\"6802_08834500\":{\"1\":[{\"validVals\":[303,1439],\"val\":[{\"tag\":1439}]}]},\"6180_08412800\":{\"1\":[{\"val\":[{\"tag\":16777213}]}]}}}}'",
+                "echo '{\"result\":{\"0199-xxxxx9BD\":{\"6400_00260100\":{\"1\":[{\"val\":null}]}}}}'",
+                DEFAULT_DIVISOR,
+                TIMEOUT,
                 true
             ),
-            Ok(7459.043)
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn non_numeric_value_is_an_error() {
+        assert!(
+            fetch_dashboard_value(
+                "echo '{\"result\":{\"0199-xxxxx9BD\":{\"6400_00260100\":{\"1\":[{\"val\":\"N/A\"}]}}}}'",
+                DEFAULT_DIVISOR,
+                TIMEOUT,
+                true
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn truncated_json_is_an_error() {
+        assert!(
+            fetch_dashboard_value(
+                "echo '{\"result\":{\"0199-xxxxx9BD\":{\"6400_00260100\":{\"1\":[{\"val\":74590'",
+                DEFAULT_DIVISOR,
+                TIMEOUT,
+                true
+            )
+            .is_err()
         );
     }
 
     #[test]
     fn handles_parse_error_without_panic() {
-        assert!(fetch_dashboard_value("echo '{\"result\":'", true).is_err());
+        assert!(
+            fetch_dashboard_value("echo '{\"result\":'", DEFAULT_DIVISOR, TIMEOUT, true).is_err()
+        );
+    }
+
+    #[test]
+    fn hung_command_times_out() {
+        let start = Instant::now();
+        assert!(
+            fetch_dashboard_value("sleep 10", DEFAULT_DIVISOR, Duration::from_secs(1), true)
+                .is_err()
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn hung_grandchild_is_killed() {
+        let start = Instant::now();
+        // sh would wait for its background job forever, keeping stdout open
+        assert!(
+            fetch_dashboard_value(
+                "sleep 30 & wait",
+                DEFAULT_DIVISOR,
+                Duration::from_millis(200),
+                true
+            )
+            .is_err()
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn response_without_exit_is_bounded() {
+        let start = Instant::now();
+        // Closes stdout but keeps running
+        assert!(
+            fetch_dashboard_value(
+                "echo '{}'; exec >&-; sleep 30",
+                DEFAULT_DIVISOR,
+                Duration::from_millis(200),
+                true
+            )
+            .is_err()
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn fetch_device_value_uses_serial() {
+        assert_eq!(
+            fetch_device_value(
+                "echo '{\"result\":{\"0199-xxxxx9BD\":{\"6400_00260100\":{\"1\":[{\"val\":1000}]}},\"0123-yyyyyyyy\":{\"6400_00260100\":{\"1\":[{\"val\":2000}]}}}}'",
+                "0123-yyyyyyyy",
+                DEFAULT_DIVISOR,
+                TIMEOUT,
+                true
+            ),
+            Ok(Some(2.0))
+        );
+    }
+
+    #[test]
+    fn parse_json_path_dotted_and_bracketed() {
+        assert_eq!(
+            parse_json_path("result.0199-xxxxx9BD.1[0][2].val"),
+            Ok(vec![
+                JsonPathStep::Key("result".to_string()),
+                JsonPathStep::Key("0199-xxxxx9BD".to_string()),
+                JsonPathStep::Key("1".to_string()),
+                JsonPathStep::Index(0),
+                JsonPathStep::Index(2),
+                JsonPathStep::Key("val".to_string()),
+            ])
+        );
+        assert!(parse_json_path("result..val").is_err());
+        assert!(parse_json_path("result[x]").is_err());
+        assert!(parse_json_path("result[0").is_err());
+    }
+
+    #[test]
+    fn fetch_json_path_value_matching_path() {
+        assert_eq!(
+            fetch_json_path_value(
+                EXAMPLE_CMD,
+                "result.0199-xxxxx9BD.6400_0046C300.1[0].val",
+                DEFAULT_DIVISOR,
+                TIMEOUT,
+                true
+            ),
+            Ok(Some(7459.043))
+        );
+    }
+
+    #[test]
+    fn fetch_json_path_value_wrong_key() {
+        assert_eq!(
+            fetch_json_path_value(
+                EXAMPLE_CMD,
+                "result.0199-xxxxx9BD.6400_99999999.1.0.val",
+                DEFAULT_DIVISOR,
+                TIMEOUT,
+                true
+            ),
+            Err("JSON path step 3 ('6400_99999999') not found".to_string())
+        );
+    }
+
+    #[test]
+    fn fetch_json_path_value_index_out_of_range() {
+        assert_eq!(
+            fetch_json_path_value(
+                EXAMPLE_CMD,
+                "result.0199-xxxxx9BD.6400_00260100.1[1].val",
+                DEFAULT_DIVISOR,
+                TIMEOUT,
+                true
+            ),
+            Err("JSON path step 5 ([1]) not found".to_string())
+        );
     }
 }
@@ -12,6 +12,16 @@ pub struct RingBufferView<'a, A> {
     ring_buffer: &'a RingBuffer<A>,
 }
 
+/// Bookkeeping of a `RingBuffer`, as shown by `display`.
+#[derive(Debug, PartialEq)]
+pub struct Internals {
+    pub start: usize,
+    pub end: usize,
+    pub capacity: usize,
+    /// Slots allocated so far, at most `capacity`.
+    pub buffer_len: usize,
+}
+
 pub fn new<A>(size: usize) -> RingBuffer<A> {
     assert!(size > 0);
     RingBuffer {
@@ -32,24 +42,68 @@ impl<'a, A> RingBufferView<'a, A> {
             return None;
         }
         let idx = (self.ring_buffer.start + idx) % self.ring_buffer.capacity;
-        if idx >= self.ring_buffer.buffer.capacity() {
-            return None;
-        }
-        return Some(&self.ring_buffer.buffer[idx]);
+        // Every logical index maps to an initialized slot
+        debug_assert!(idx < self.ring_buffer.buffer.len());
+        Some(&self.ring_buffer.buffer[idx])
     }
 
     pub fn iter_limited(&'a self, limit: usize) -> RingBufferViewIter<'a, A> {
         RingBufferViewIter {
-            buffer: &self.ring_buffer,
+            buffer: self.ring_buffer,
             index: 0,
             len: self.ring_buffer.len(),
             limit: Some(limit),
         }
     }
 
+    /// Every `stride`-th element starting with the first (`stride == 0` counts
+    /// as 1), without copying.
+    pub fn iter_stride(&self, stride: usize) -> impl Iterator<Item = &'a A> + use<'a, A> {
+        RingBufferViewIter {
+            buffer: self.ring_buffer,
+            index: 0,
+            len: self.ring_buffer.len(),
+            limit: None,
+        }
+        .step_by(stride.max(1))
+    }
+
+    /// Consecutive overlapping pairs in logical order, like `slice::windows(2)`:
+    /// nothing for fewer than 2 elements.
+    pub fn windows2(&self) -> impl Iterator<Item = (&'a A, &'a A)> + use<'a, A> {
+        let iter = RingBufferViewIter {
+            buffer: self.ring_buffer,
+            index: 0,
+            len: self.ring_buffer.len(),
+            limit: None,
+        };
+        let next = RingBufferViewIter {
+            buffer: self.ring_buffer,
+            index: 1,
+            len: self.ring_buffer.len(),
+            limit: None,
+        };
+        iter.zip(next)
+    }
+
+    /// References to the elements, in logical order.
+    pub fn snapshot(&self) -> Vec<&'a A> {
+        RingBufferViewIter {
+            buffer: self.ring_buffer,
+            index: 0,
+            len: self.ring_buffer.len(),
+            limit: None,
+        }
+        .collect()
+    }
+
     pub fn len(&self) -> usize {
         self.ring_buffer.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.ring_buffer.is_empty()
+    }
 }
 
 impl<'a, A> IntoIterator for &'a RingBufferView<'a, A> {
@@ -58,7 +112,7 @@ impl<'a, A> IntoIterator for &'a RingBufferView<'a, A> {
 
     fn into_iter(self) -> Self::IntoIter {
         RingBufferViewIter {
-            buffer: &self.ring_buffer,
+            buffer: self.ring_buffer,
             index: 0,
             len: self.ring_buffer.len(),
             limit: None,
@@ -77,7 +131,7 @@ impl<'a, A> Iterator for RingBufferViewIter<'a, A> {
     type Item = &'a A;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.index >= self.len || self.limit.map_or(false, |l| self.index >= l) {
+        if self.index >= self.len || self.limit.is_some_and(|l| self.index >= l) {
             return None;
         }
         let idx = (self.buffer.start + self.index) % self.buffer.capacity;
@@ -86,30 +140,61 @@ impl<'a, A> Iterator for RingBufferViewIter<'a, A> {
     }
 }
 
+impl<A> DoubleEndedIterator for RingBufferViewIter<'_, A> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let end = self.limit.map_or(self.len, |l| l.min(self.len));
+        if self.index >= end {
+            return None;
+        }
+        // Fold the limit into len now that the back end moves
+        self.len = end - 1;
+        self.limit = None;
+        let idx = (self.buffer.start + self.len) % self.buffer.capacity;
+        self.buffer.buffer.get(idx)
+    }
+}
+
 impl<A> RingBuffer<A> {
+    pub fn is_empty(&self) -> bool {
+        self.start == 0 && self.end == 0
+    }
+
     pub fn len(&self) -> usize {
         if self.start == 0 && self.end == 0 {
-            return 0;
+            0
         } else if self.start < self.end {
-            return self.end - self.start;
+            self.end - self.start
         } else {
-            return self.capacity + self.end - self.start;
+            self.capacity + self.end - self.start
         }
     }
 
     pub fn peek_first<B>(&self, cont: fn(&A) -> B) -> Option<B> {
         if self.start == 0 && self.end == 0 {
-            return None;
+            None
         } else {
-            return Some(cont(&self.buffer[self.start]));
+            Some(cont(&self.buffer[self.start]))
         }
     }
 
     pub fn peek_last<B>(&self, cont: fn(&A) -> B) -> Option<B> {
         if self.start == 0 && self.end == 0 {
-            return None;
+            None
+        } else {
+            Some(cont(&self.buffer[self.end - 1]))
+        }
+    }
+
+    /// Like `peek_last` but for the element `n` positions before the newest
+    /// one (`n == 0` is the newest).
+    pub fn peek_last_nth<B>(&self, n: usize, cont: fn(&A) -> B) -> Option<B> {
+        let len = self.len();
+        if n >= len {
+            None
         } else {
-            return Some(cont(&self.buffer[self.end - 1]));
+            Some(cont(
+                &self.buffer[(self.start + len - 1 - n) % self.capacity],
+            ))
         }
     }
 
@@ -120,7 +205,7 @@ impl<A> RingBuffer<A> {
                 swap(&mut self.buffer[0], &mut val);
                 self.start = 1;
                 self.end = 1;
-                return Some(val);
+                Some(val)
             } else {
                 if self.end >= self.buffer.len() {
                     self.buffer.push(val);
@@ -128,7 +213,7 @@ impl<A> RingBuffer<A> {
                     self.buffer[self.end] = val;
                 }
                 self.end += 1;
-                return None;
+                None
             }
         } else if self.start == self.end {
             let mut val = val;
@@ -139,7 +224,7 @@ impl<A> RingBuffer<A> {
             } else {
                 self.start = 0;
             }
-            return Some(val);
+            Some(val)
         } else {
             if self.buffer.len() < self.capacity {
                 self.buffer.push(val);
@@ -149,11 +234,35 @@ impl<A> RingBuffer<A> {
                 };
                 self.buffer[self.end] = val;
             }
-            self.end = self.end + 1;
+            self.end += 1;
             if self.end > self.capacity {
                 self.end = 0;
             }
-            return None;
+            None
+        }
+    }
+
+    /// The elements as one slice, oldest first, when they have not wrapped
+    /// around the end of the storage; `None` otherwise (iterate over a
+    /// `freeze`d view instead).
+    pub fn as_contiguous(&self) -> Option<&[A]> {
+        if self.start == 0 && self.end == 0 {
+            Some(&[])
+        } else if self.start < self.end {
+            Some(&self.buffer[self.start..self.end])
+        } else if self.end == 0 {
+            Some(&self.buffer[self.start..self.capacity])
+        } else {
+            None
+        }
+    }
+
+    pub fn internals(&self) -> Internals {
+        Internals {
+            start: self.start,
+            end: self.end,
+            capacity: self.capacity,
+            buffer_len: self.buffer.len(),
         }
     }
 
@@ -208,9 +317,9 @@ impl<A> RingBuffer<A> {
         }
         if len == self.capacity {
             // ring was already full before inserting, evict last element
-            return Some(val);
+            Some(val)
         } else {
-            return None;
+            None
         }
     }
 
@@ -232,6 +341,42 @@ impl<A> RingBuffer<A> {
         self.end = write_idx;
     }
 
+    /// Change the capacity, keeping the newest elements: shrinking below
+    /// `len()` drops the oldest ones.
+    pub fn resize(&mut self, new_capacity: usize) {
+        assert!(new_capacity > 0);
+        let len = self.len();
+        let mut old = replace(&mut self.buffer, Vec::with_capacity(new_capacity));
+        if len > 0 {
+            old.rotate_left(self.start);
+            old.truncate(len);
+            self.buffer
+                .extend(old.drain(len.saturating_sub(new_capacity)..));
+        }
+        self.start = 0;
+        self.end = self.buffer.len();
+        self.capacity = new_capacity;
+    }
+
+    /// Keep only the elements for which `f` returns true, preserving their
+    /// order.
+    pub fn retain<F>(&mut self, f: F)
+    where
+        F: FnMut(&A) -> bool,
+    {
+        let len = self.len();
+        if len > 0 {
+            self.buffer.rotate_left(self.start);
+            self.buffer.truncate(len);
+            self.buffer.retain(f);
+        } else {
+            // Slots left behind by `drop_first` are not elements
+            self.buffer.clear();
+        }
+        self.start = 0;
+        self.end = self.buffer.len();
+    }
+
     pub fn drop_first(&mut self, n: usize) {
         let len = self.len();
         if n >= len {
@@ -244,7 +389,7 @@ impl<A> RingBuffer<A> {
             if self.start < self.end {
                 self.start += 1;
             } else {
-                self.start = (self.start + 1) % self.buffer.capacity();
+                self.start = (self.start + 1) % self.capacity;
             }
             n -= 1;
         }
@@ -254,7 +399,7 @@ impl<A> RingBuffer<A> {
     where
         F: FnOnce(RingBufferViewIter<'_, A>) -> R,
     {
-        return self.with_view(|vw| f(vw.iter_limited(limit)));
+        self.with_view(|vw| f(vw.iter_limited(limit)))
     }
 
     pub fn with_view<R, F>(&mut self, f: F) -> R
@@ -262,7 +407,30 @@ impl<A> RingBuffer<A> {
         F: FnOnce(RingBufferView<'_, A>) -> R,
     {
         let frozen = freeze(self);
-        return f(frozen);
+        f(frozen)
+    }
+}
+
+/// Two ring buffers are equal when they hold the same logical sequence,
+/// whatever their capacity and internal rotation.
+impl<A: PartialEq> PartialEq for RingBuffer<A> {
+    fn eq(&self, other: &Self) -> bool {
+        freeze(self).snapshot() == freeze(other).snapshot()
+    }
+}
+
+/// Push each item in turn, discarding the records they overwrite.
+impl<A> Extend<A> for RingBuffer<A> {
+    fn extend<T: IntoIterator<Item = A>>(&mut self, iter: T) {
+        for val in iter {
+            self.push(val);
+        }
+    }
+}
+
+impl<A: Debug> Debug for RingBuffer<A> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(freeze(self).snapshot()).finish()
     }
 }
 
@@ -364,6 +532,30 @@ mod tests {
         assert_eq!(rb.peek_last(strlen), Some(7));
     }
 
+    #[test]
+    fn ringbuffer_peek_last_nth() {
+        let mut rb = new::<i32>(4);
+        assert_eq!(rb.peek_last_nth(0, idint), None);
+        rb.push(1);
+        rb.push(2);
+        assert_eq!(rb.peek_last_nth(0, idint), Some(2));
+        assert_eq!(rb.peek_last_nth(1, idint), Some(1));
+        assert_eq!(rb.peek_last_nth(2, idint), None);
+        for i in 3..8 {
+            rb.push(i); // 5 6 7> <4
+        }
+        assert_eq!(rb.peek_last_nth(0, idint), rb.peek_last(idint));
+        assert_eq!(rb.peek_last_nth(0, idint), Some(7));
+        assert_eq!(rb.peek_last_nth(1, idint), Some(6));
+        assert_eq!(rb.peek_last_nth(2, idint), Some(5));
+        assert_eq!(rb.peek_last_nth(3, idint), Some(4));
+        assert_eq!(rb.peek_last_nth(4, idint), None);
+        rb.drop_first(2); // 5 <6 7> 4
+        assert_eq!(rb.peek_last_nth(0, idint), Some(7));
+        assert_eq!(rb.peek_last_nth(1, idint), Some(6));
+        assert_eq!(rb.peek_last_nth(2, idint), None);
+    }
+
     #[test]
     fn ringbuffer_overwrites_when_pushing_enough() {
         let mut rb = new::<i32>(3);
@@ -716,6 +908,95 @@ mod tests {
         assert_eq!(collected, vec![0, 1, 2]);
     }
 
+    #[test]
+    fn ringbuffer_iter_stride_wrapped() {
+        let mut rb = new::<i32>(7);
+        for k in 1..=10 {
+            rb.push(k);
+        }
+        // 8 9 10> 4 5 6 7
+        let view = freeze(&rb);
+        let collected: Vec<_> = view.iter_stride(2).cloned().collect();
+        assert_eq!(collected, vec![4, 6, 8, 10]);
+        let collected: Vec<_> = view.iter_stride(3).cloned().collect();
+        assert_eq!(collected, vec![4, 7, 10]);
+        let collected: Vec<_> = view.iter_stride(0).cloned().collect();
+        assert_eq!(collected, vec![4, 5, 6, 7, 8, 9, 10]);
+        let collected: Vec<_> = view.iter_stride(8).cloned().collect();
+        assert_eq!(collected, vec![4]);
+        let empty = new::<i32>(3);
+        assert_eq!(freeze(&empty).iter_stride(2).count(), 0);
+    }
+
+    #[test]
+    fn ringbuffer_windows2_wrapped() {
+        let mut rb = new::<i32>(3);
+        for k in 1..=5 {
+            rb.push(k);
+        }
+        // 4 5> 3
+        let view = freeze(&rb);
+        let collected: Vec<_> = view.windows2().map(|(a, b)| (*a, *b)).collect();
+        assert_eq!(collected, vec![(3, 4), (4, 5)]);
+        rb.drop_first(2);
+        assert_eq!(freeze(&rb).windows2().count(), 0);
+        let empty = new::<i32>(3);
+        assert_eq!(freeze(&empty).windows2().count(), 0);
+    }
+
+    #[test]
+    fn ringbuffer_internals() {
+        let mut rb = new::<i32>(3);
+        for k in 1..=4 {
+            rb.push(k);
+        }
+        assert_eq!(
+            rb.internals(),
+            Internals {
+                start: 1,
+                end: 1,
+                capacity: 3,
+                buffer_len: 3
+            }
+        );
+    }
+
+    #[test]
+    fn ringbuffer_as_contiguous() {
+        let mut rb = new::<i32>(3);
+        assert_eq!(rb.as_contiguous(), Some(&[][..]));
+        rb.push(1);
+        rb.push(2);
+        assert_eq!(rb.as_contiguous(), Some(&[1, 2][..]));
+        rb.push(3);
+        // 1 2 3>
+        assert_eq!(rb.as_contiguous(), Some(&[1, 2, 3][..]));
+        rb.drop_first(1);
+        assert_eq!(rb.as_contiguous(), Some(&[2, 3][..]));
+    }
+
+    #[test]
+    fn ringbuffer_as_contiguous_wrapped() {
+        let mut rb = new::<i32>(3);
+        for k in 1..=4 {
+            rb.push(k);
+        }
+        // 4> 2 3
+        assert_eq!(rb.as_contiguous(), None);
+        rb.drop_first(2);
+        assert_eq!(rb.as_contiguous(), Some(&[4][..]));
+        for k in 5..=6 {
+            rb.push(k);
+        }
+        // Whenever there is a slice, it matches the logical sequence
+        for k in 7..=12 {
+            rb.push(k);
+            if let Some(slice) = rb.as_contiguous() {
+                assert_eq!(slice.iter().collect::<Vec<_>>(), freeze(&rb).snapshot());
+            }
+        }
+    }
+
     #[test]
     fn test_ring_buffer_wraparound() {
         let mut rb = new(5);
@@ -752,6 +1033,41 @@ mod tests {
         assert!(collected.is_empty());
     }
 
+    #[test]
+    fn test_ring_buffer_snapshot() {
+        let mut rb = new(3);
+        assert!(freeze(&rb).snapshot().is_empty());
+        for i in 0..5 {
+            rb.push(i);
+        }
+        assert_eq!(freeze(&rb).snapshot(), vec![&2, &3, &4]);
+    }
+
+    #[test]
+    fn test_ring_buffer_eq_ignores_rotation() {
+        let mut rb_a = new(4);
+        for i in 0..3 {
+            rb_a.push(i); // <0 1 2>
+        }
+        let mut rb_b = new(4);
+        for i in -3..3 {
+            rb_b.push(i); // 1 2> <-1 0
+        }
+        rb_b.drop_first(1);
+        assert_eq!(rb_a, rb_b);
+        let mut rb_c = new(7);
+        rb_c.push(9);
+        for i in 0..3 {
+            rb_c.push(i);
+        }
+        rb_c.drop_first(1);
+        assert_eq!(rb_a, rb_c);
+        rb_c.push(3);
+        assert_ne!(rb_a, rb_c);
+        rb_a.drop_first(3);
+        assert_eq!(rb_a, new(2));
+    }
+
     #[test]
     fn test_ring_buffer_insert_at() {
         let mut rb = new::<&'static str>(8);
@@ -869,4 +1185,153 @@ mod tests {
             vec!["a", "b", "c", "d", "e", "f", "g", "h"]
         );
     }
+
+    #[test]
+    fn ringbuffer_resize_grow_keeps_elements() {
+        let mut rb = new::<i32>(4);
+        for k in 1..=6 {
+            rb.push(k);
+        }
+        // 5 6> 3 4 (wrapped)
+        rb.resize(6);
+        assert_eq!(rb.get_capacity(), 6);
+        assert_eq!(freeze(&rb).snapshot(), vec![&3, &4, &5, &6]);
+        assert_eq!(rb.push(7), None);
+        assert_eq!(rb.push(8), None);
+        assert_eq!(rb.push(9), Some(3));
+        assert_eq!(freeze(&rb).snapshot(), vec![&4, &5, &6, &7, &8, &9]);
+        assert_eq!(rb.peek_first(idint), Some(4));
+        assert_eq!(rb.peek_last(idint), Some(9));
+    }
+
+    #[test]
+    fn ringbuffer_resize_shrink_drops_oldest() {
+        let mut rb = new::<i32>(5);
+        for k in 1..=7 {
+            rb.push(k);
+        }
+        // 6 7> 3 4 5 (wrapped)
+        rb.resize(2);
+        assert_eq!(rb.get_capacity(), 2);
+        assert_eq!(rb.len(), 2);
+        assert_eq!(freeze(&rb).snapshot(), vec![&6, &7]);
+        assert_eq!(rb.push(8), Some(6));
+        assert_eq!(freeze(&rb).snapshot(), vec![&7, &8]);
+        rb.drop_first(1);
+        assert_eq!(freeze(&rb).snapshot(), vec![&8]);
+    }
+
+    #[test]
+    fn ringbuffer_resize_empty_and_partial() {
+        let mut rb = new::<&str>(3);
+        rb.resize(5);
+        assert!(rb.is_empty());
+        assert_eq!(rb.get_capacity(), 5);
+        rb.push("a");
+        rb.push("bb");
+        rb.push("ccc");
+        rb.drop_first(1);
+        rb.resize(3);
+        assert_eq!(freeze(&rb).snapshot(), vec![&"bb", &"ccc"]);
+        assert_eq!(rb.peek_last(strlen), Some(3));
+    }
+
+    #[test]
+    fn ringbuffer_retain_wrapped() {
+        let mut rb = new::<i32>(5);
+        for k in 1..=7 {
+            rb.push(k);
+        }
+        // 6 7> 3 4 5
+        rb.retain(|x| x % 2 == 1);
+        assert_eq!(freeze(&rb).snapshot(), vec![&3, &5, &7]);
+        assert_eq!(rb.push(8), None);
+        assert_eq!(rb.push(9), None);
+        assert_eq!(rb.push(10), Some(3));
+        assert_eq!(freeze(&rb).snapshot(), vec![&5, &7, &8, &9, &10]);
+    }
+
+    #[test]
+    fn ringbuffer_retain_nothing_left() {
+        let mut rb = new::<i32>(3);
+        rb.retain(|_| false);
+        assert!(rb.is_empty());
+        rb.push(1);
+        rb.push(2);
+        rb.retain(|_| false);
+        assert!(rb.is_empty());
+        assert_eq!(rb.peek_last(idint), None);
+        rb.push(3);
+        assert_eq!(freeze(&rb).snapshot(), vec![&3]);
+    }
+
+    #[test]
+    fn ringbuffer_retain_after_drop_first_everything() {
+        let mut rb = new::<i32>(5);
+        for k in 1..=3 {
+            rb.push(k);
+        }
+        rb.drop_first(3);
+        rb.retain(|_| true);
+        assert!(rb.is_empty());
+        assert_eq!(rb.len(), 0);
+        rb.push(4);
+        assert_eq!(freeze(&rb).snapshot(), vec![&4]);
+    }
+
+    #[test]
+    fn ringbuffer_view_at_partially_filled() {
+        let mut rb = new::<i32>(8);
+        for k in 1..=5 {
+            rb.push(k);
+        }
+        rb.drop_first(2);
+        // Vec holds 5 slots out of 8, data lives in slots 2..5
+        let vw = freeze(&rb);
+        assert_eq!(vw.at(0), Some(&3));
+        assert_eq!(vw.at(2), Some(&5));
+        assert_eq!(vw.at(3), None);
+        assert_eq!(vw.at(7), None);
+
+        rb.halve_data();
+        let vw = freeze(&rb);
+        assert_eq!(vw.at(0), Some(&4));
+        assert_eq!(vw.at(1), None);
+    }
+
+    #[test]
+    fn ringbuffer_view_iter_rev() {
+        let mut rb = new::<i32>(4);
+        for k in 1..=6 {
+            rb.push(k);
+        }
+        // 5 6> 3 4
+        let vw = freeze(&rb);
+        assert_eq!(
+            vw.into_iter().rev().collect::<Vec<_>>(),
+            vec![&6, &5, &4, &3]
+        );
+        assert_eq!(
+            vw.iter_limited(3).rev().collect::<Vec<_>>(),
+            vec![&5, &4, &3]
+        );
+        let mut it = vw.into_iter();
+        assert_eq!(it.next(), Some(&3));
+        assert_eq!(it.next_back(), Some(&6));
+        assert_eq!(it.next(), Some(&4));
+        assert_eq!(it.next_back(), Some(&5));
+        assert_eq!(it.next(), None);
+        assert_eq!(it.next_back(), None);
+    }
+
+    #[test]
+    fn ringbuffer_extend_past_capacity_keeps_tail() {
+        let mut rb = new::<i32>(3);
+        rb.extend([1, 2]);
+        assert_eq!(freeze(&rb).snapshot(), vec![&1, &2]);
+        rb.extend(3..=7);
+        assert_eq!(freeze(&rb).snapshot(), vec![&5, &6, &7]);
+        rb.extend(std::iter::empty());
+        assert_eq!(rb.len(), 3);
+    }
 }
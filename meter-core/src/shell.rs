@@ -0,0 +1,80 @@
+use std::{
+    io,
+    os::unix::process::CommandExt,
+    process::{Child, Command, ExitStatus},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// `sh -c cmd` in a process group of its own, so that `kill_group` also stops
+/// whatever it started (e.g. `cat /dev/ttyUSB0`).
+pub fn sh_command(cmd: &str) -> Command {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(cmd).process_group(0);
+    command
+}
+
+/// Kill every process in the group of `child` (see `sh_command`) and reap it.
+pub fn kill_group(child: &mut Child) -> io::Result<ExitStatus> {
+    // The group id is the pid of its leader
+    if unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) } != 0 {
+        let e = io::Error::last_os_error();
+        // Already gone (and maybe reaped)
+        if e.raw_os_error() != Some(libc::ESRCH) {
+            return Err(e);
+        }
+    }
+    child.wait()
+}
+
+/// Wait for `child` until `deadline`, `None` when it is still running then.
+pub fn wait_until(child: &mut Child, deadline: Instant) -> io::Result<Option<ExitStatus>> {
+    loop {
+        match child.try_wait()? {
+            Some(status) => return Ok(Some(status)),
+            None if Instant::now() < deadline => thread::sleep(Duration::from_millis(20)),
+            None => return Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader},
+        process::Stdio,
+    };
+
+    #[test]
+    fn wait_until_returns_exit_status() {
+        let mut child = sh_command("exit 3").spawn().unwrap();
+        let status = wait_until(&mut child, Instant::now() + Duration::from_secs(5))
+            .unwrap()
+            .unwrap();
+        assert_eq!(status.code(), Some(3));
+    }
+
+    #[test]
+    fn kill_group_stops_grandchildren() {
+        // The grandchild keeps stdout open as long as it runs
+        let mut child = sh_command("echo started; sleep 30 & wait")
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        let mut line = String::new();
+        stdout.read_line(&mut line).unwrap();
+        assert_eq!(line, "started\n");
+        let start = Instant::now();
+        assert_eq!(
+            wait_until(&mut child, start + Duration::from_millis(100)).unwrap(),
+            None
+        );
+        assert!(!kill_group(&mut child).unwrap().success());
+        // End of file only once the grandchild is gone too
+        line.clear();
+        assert_eq!(stdout.read_line(&mut line).unwrap(), 0);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,199 @@
+use crate::data::Data202303;
+use chrono::{DateTime, Datelike, Timelike, Weekday};
+
+const DAY_SECS: u32 = 24 * 3600;
+
+/// When the off-hours tariff applies, as ranges of local time in seconds after
+/// midnight.  A range whose start is after its end wraps past midnight.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OffHoursSchedule {
+    /// Monday to Friday.
+    pub weekday: Vec<(u32, u32)>,
+    /// Saturday and Sunday.
+    pub weekend: Vec<(u32, u32)>,
+}
+
+impl Default for OffHoursSchedule {
+    /// 22:00 to 07:00 on weekdays and all weekend long.
+    fn default() -> Self {
+        OffHoursSchedule {
+            weekday: vec![(22 * 3600, 7 * 3600)],
+            weekend: vec![(0, DAY_SECS)],
+        }
+    }
+}
+
+/// True if `ts` falls in the off-hours of `schedule`, in the local time
+/// `tz_offset` seconds east of UTC.
+pub fn is_off_hours(ts: i64, tz_offset: i32, schedule: &OffHoursSchedule) -> bool {
+    let Some(local) = DateTime::from_timestamp(ts.saturating_add(tz_offset as i64), 0) else {
+        return false;
+    };
+    let ranges = match local.weekday() {
+        Weekday::Sat | Weekday::Sun => &schedule.weekend,
+        _ => &schedule.weekday,
+    };
+    let secs = local.num_seconds_from_midnight();
+    ranges.iter().any(|&(start, end)| {
+        if start <= end {
+            start <= secs && secs < end
+        } else {
+            secs >= start || secs < end
+        }
+    })
+}
+
+/// Describe how the registers changing between `prev` and `next` disagree with
+/// the tariff `schedule` expects, if both fall in the same tariff window.  This
+/// is only advisory: the meter's own tariff indicator has the final say.
+pub fn tariff_mismatch(
+    prev: &Data202303,
+    next: &Data202303,
+    tz_offset: i32,
+    schedule: &OffHoursSchedule,
+) -> Option<&'static str> {
+    let off_hours = is_off_hours(next.timestamp, tz_offset, schedule);
+    if off_hours != is_off_hours(prev.timestamp, tz_offset, schedule) {
+        return None;
+    }
+    let grew = |get: fn(&Data202303) -> Option<f64>| matches!((get(prev), get(next)), (Some(before), Some(after)) if after > before);
+    if off_hours && (grew(|r| r.peak_conso_kWh) || grew(|r| r.peak_inj_kWh)) {
+        Some("peak register changed during off-hours")
+    } else if !off_hours && (grew(|r| r.off_conso_kWh) || grew(|r| r.off_inj_kWh)) {
+        Some("off-hours register changed during peak hours")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{FixedOffset, TimeZone};
+
+    fn local_ts(y: i32, m: u32, d: u32, h: u32, min: u32) -> i64 {
+        FixedOffset::east_opt(3600)
+            .unwrap()
+            .with_ymd_and_hms(y, m, d, h, min, 0)
+            .unwrap()
+            .timestamp()
+    }
+
+    #[test]
+    fn weekday_boundaries() {
+        let schedule = OffHoursSchedule::default();
+        // 2025-01-08 is a Wednesday
+        assert!(is_off_hours(local_ts(2025, 1, 8, 6, 59), 3600, &schedule));
+        assert!(!is_off_hours(local_ts(2025, 1, 8, 7, 0), 3600, &schedule));
+        assert!(!is_off_hours(local_ts(2025, 1, 8, 21, 59), 3600, &schedule));
+        assert!(is_off_hours(local_ts(2025, 1, 8, 22, 0), 3600, &schedule));
+        assert!(is_off_hours(local_ts(2025, 1, 8, 0, 0), 3600, &schedule));
+        // Same instant, but 21:00 in UTC
+        assert!(!is_off_hours(local_ts(2025, 1, 8, 22, 0), 0, &schedule));
+    }
+
+    #[test]
+    fn weekend_handling() {
+        let schedule = OffHoursSchedule::default();
+        // 2025-01-11 is a Saturday, 2025-01-13 a Monday
+        assert!(is_off_hours(local_ts(2025, 1, 11, 12, 0), 3600, &schedule));
+        assert!(is_off_hours(local_ts(2025, 1, 12, 23, 59), 3600, &schedule));
+        assert!(!is_off_hours(local_ts(2025, 1, 13, 12, 0), 3600, &schedule));
+        let busy_weekend = OffHoursSchedule {
+            weekday: vec![],
+            weekend: vec![(0, 8 * 3600)],
+        };
+        assert!(is_off_hours(
+            local_ts(2025, 1, 11, 7, 0),
+            3600,
+            &busy_weekend
+        ));
+        assert!(!is_off_hours(
+            local_ts(2025, 1, 11, 8, 0),
+            3600,
+            &busy_weekend
+        ));
+        assert!(!is_off_hours(
+            local_ts(2025, 1, 8, 23, 0),
+            3600,
+            &busy_weekend
+        ));
+    }
+
+    #[test]
+    fn tariff_mismatch_only_when_registers_disagree() {
+        let schedule = OffHoursSchedule::default();
+        let night = local_ts(2025, 1, 8, 23, 0);
+        let noon = local_ts(2025, 1, 8, 12, 0);
+        assert_eq!(
+            tariff_mismatch(
+                &Data202303 {
+                    peak_conso_kWh: Some(10.0),
+                    off_conso_kWh: Some(20.0),
+                    ..Data202303::empty(night)
+                },
+                &Data202303 {
+                    peak_conso_kWh: Some(10.0),
+                    off_conso_kWh: Some(20.5),
+                    ..Data202303::empty(night + 60)
+                },
+                3600,
+                &schedule
+            ),
+            None
+        );
+        assert_eq!(
+            tariff_mismatch(
+                &Data202303 {
+                    peak_conso_kWh: Some(10.0),
+                    off_conso_kWh: Some(20.0),
+                    ..Data202303::empty(night)
+                },
+                &Data202303 {
+                    peak_conso_kWh: Some(10.5),
+                    off_conso_kWh: Some(20.0),
+                    ..Data202303::empty(night + 60)
+                },
+                3600,
+                &schedule
+            ),
+            Some("peak register changed during off-hours")
+        );
+        assert_eq!(
+            tariff_mismatch(
+                &Data202303 {
+                    peak_conso_kWh: Some(10.0),
+                    off_conso_kWh: Some(20.0),
+                    ..Data202303::empty(noon)
+                },
+                &Data202303 {
+                    peak_conso_kWh: Some(10.0),
+                    off_conso_kWh: Some(20.5),
+                    ..Data202303::empty(noon + 60)
+                },
+                3600,
+                &schedule
+            ),
+            Some("off-hours register changed during peak hours")
+        );
+        // Straddling the switch to off-hours: can't tell
+        let switch = local_ts(2025, 1, 8, 22, 0);
+        assert_eq!(
+            tariff_mismatch(
+                &Data202303 {
+                    peak_conso_kWh: Some(10.0),
+                    off_conso_kWh: Some(20.0),
+                    ..Data202303::empty(switch - 30)
+                },
+                &Data202303 {
+                    peak_conso_kWh: Some(10.5),
+                    off_conso_kWh: Some(20.5),
+                    ..Data202303::empty(switch + 30)
+                },
+                3600,
+                &schedule
+            ),
+            None
+        );
+    }
+}
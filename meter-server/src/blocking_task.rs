@@ -1,39 +1,172 @@
-use chrono::{DateTime, FixedOffset};
+use chrono::{DateTime, FixedOffset, TimeZone};
 use meter_core::{
-    data::{Data202303, clone_data202303, insert_many_data_202303},
-    p1_meter::{self, CompleteP1Measurement},
+    data::{
+        Data202303, DataError, Sqlite, build_insert_many_sql, build_insert_many_sql_with_pragmas,
+        clone_data202303, insert_many_data_202303_with_pragmas, select_last_data_202303,
+    },
+    p1_meter::{self, CompleteP1Measurement, P1ObisConfig, P1Outcome},
     pv2022,
-    ringbuffer::{self, RingBuffer, RingBufferView, freeze},
+    ringbuffer::{self, RingBuffer, freeze},
+    shell::{kill_group, sh_command, wait_until},
+    tariff::{OffHoursSchedule, tariff_mismatch},
 };
 use std::{
-    io::{BufRead, BufReader},
-    process::{Command, Stdio},
-    sync::{Arc, RwLock, RwLockWriteGuard},
-    time::{SystemTime, UNIX_EPOCH},
+    collections::HashMap,
+    fs::{self, File},
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    path::Path,
+    process::Stdio,
+    sync::{
+        Arc, RwLock, RwLockWriteGuard,
+        mpsc::{self, RecvTimeoutError},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 pub type SharedState = Arc<RwLock<AppState>>;
 
+/// Longest raw P1 telegram kept for debugging, in bytes.
+const MAX_TELEGRAM_LEN: usize = 4096;
+
+/// Pause before reading a P1 file source again.
+const FILE_REOPEN_DELAY: Duration = Duration::from_secs(1);
+
+/// Start of the files written by `AppState::save_snapshot`, with a format version.
+const SNAPSHOT_MAGIC: &[u8; 4] = b"AMR1";
+
+/// Default capacity of the in-memory buffer.
+pub const DEFAULT_CAPACITY: usize = 1440;
+
+/// Columns guarded against decreasing unless configured otherwise: the four
+/// electricity registers.
+pub const DEFAULT_DECREASING_GUARD: [&str; 4] = [
+    "peak_conso_kWh",
+    "off_conso_kWh",
+    "peak_inj_kWh",
+    "off_inj_kWh",
+];
+
 pub struct AppState {
     pub data: RingBuffer<Data202303>,
+    /// Raw text read from `p1_data_cmd` during the last poll, if any.
+    pub last_telegram: Option<String>,
+    /// Newest record already in SQLite at startup, to prefill the form.
+    pub latest_persisted: Option<Data202303>,
+    /// Columns (see `Data202303::field_accessor`) dropped from a new record
+    /// when they are lower than in the previous one.  Only that column is
+    /// dropped, not the whole record: the other values are still good and the
+    /// next reading of the column then starts again from a missing value, so
+    /// that polling is not stuck below a register reset forever.
+    pub decreasing_guard: Vec<String>,
+    /// Tariff schedule the registers are checked against, only to warn.
+    pub off_hours: Option<OffHoursSchedule>,
+    /// Re-render the form without a banner when a submission holds no value.
+    pub quiet_nothing_to_do: bool,
+    /// When (seconds since the epoch) the last flush succeeded, if ever.
+    pub last_flush_ts: Option<i64>,
+    /// How many rows that flush sent.
+    pub last_flush_rows: usize,
+    /// When set, a `pv2022_kWh` lower by more than this many kWh than the
+    /// last one of the same day is dropped.
+    pub pv2022_monotonic_tolerance: Option<f64>,
+    /// Round the polled timestamps to the nearest multiple of this many
+    /// seconds.
+    pub snap_to_grid_secs: Option<i64>,
+    /// Round the manual input timestamps to the same grid.
+    pub snap_manual_inputs: bool,
+    /// Number of unflushed records above which, while the flushes fail, the
+    /// poller slows down and `save_data` stops applying the retention policy.
+    pub high_water_mark: Option<usize>,
+    /// Whether the flushes fail with the buffer above `high_water_mark`.
+    pub degraded: bool,
+    /// Flushes failed in a row since the last one that succeeded.
+    pub flush_failures: usize,
+    /// Store a `pv2022_kWh` of exactly 0.0 as missing.
+    pub zero_pv2022_as_missing: bool,
+    /// Columns of the newest record shown below the form.
+    pub summary_fields: Vec<String>,
+    /// Whether rows were handed to the flush task and are not confirmed yet.
+    pub flush_pending: bool,
+    /// Records loaded by `seed_from_db`, by timestamp.  A buffered record
+    /// equal to one of them is already in SQLite and is not flushed again,
+    /// unlike one inserted or changed since (e.g. by a manual input).
+    pub persisted: HashMap<i64, Data202303>,
 }
 
 impl Default for AppState {
     fn default() -> Self {
         AppState {
-            data: ringbuffer::new::<Data202303>(1440),
+            data: ringbuffer::new::<Data202303>(DEFAULT_CAPACITY),
+            last_telegram: None,
+            latest_persisted: None,
+            decreasing_guard: DEFAULT_DECREASING_GUARD.map(String::from).to_vec(),
+            off_hours: None,
+            quiet_nothing_to_do: false,
+            last_flush_ts: None,
+            last_flush_rows: 0,
+            pv2022_monotonic_tolerance: None,
+            snap_to_grid_secs: None,
+            snap_manual_inputs: false,
+            high_water_mark: None,
+            degraded: false,
+            flush_failures: 0,
+            zero_pv2022_as_missing: false,
+            summary_fields: Vec::new(),
+            flush_pending: false,
+            persisted: HashMap::new(),
         }
     }
 }
 
+/// Seconds the local time zone is ahead of UTC at `timestamp`.
+fn local_offset(timestamp: i64) -> i32 {
+    chrono::Local
+        .timestamp_opt(timestamp, 0)
+        .single()
+        .map_or(0, |dt| dt.offset().local_minus_utc())
+}
+
+/// Days since the epoch in the local time zone.
+fn local_day(timestamp: i64) -> i64 {
+    (timestamp + local_offset(timestamp) as i64).div_euclid(24 * 3600)
+}
+
+/// How many records the buffer keeps, and for how long.
+#[derive(Default)]
+pub struct RetentionPolicy {
+    /// At most the capacity of the buffer, which alone limits the count when
+    /// unset.
+    pub max_records: Option<usize>,
+    /// Relative to the newest record.
+    pub max_age_secs: Option<i64>,
+}
+
 impl AppState {
+    /// Default state with room for `capacity` records.
+    pub fn with_capacity(capacity: usize) -> AppState {
+        AppState {
+            data: ringbuffer::new(capacity),
+            ..AppState::default()
+        }
+    }
+
+    /// Append a record with the polled values and return the one it
+    /// overwrote, if any.  Nothing is stored when no value is left.
+    ///
+    /// A missing PV value (e.g. the inverter is asleep at night) does not
+    /// prevent storing the P1 registers.  A PV value of 0.0 is stored as is,
+    /// unless `zero_pv2022_as_missing` is set.
     pub fn set_data(
         &mut self,
         p1: Option<CompleteP1Measurement>,
         pv_2022: Option<f64>,
+        pv_2012: Option<f64>,
         verbose: bool,
     ) -> Option<Data202303> {
-        if p1 == None && pv_2022 == None {
+        let pv_2022 = pv_2022.filter(|pv| !(self.zero_pv2022_as_missing && *pv == 0.0));
+        if p1.is_none() && pv_2022.is_none() && pv_2012.is_none() {
             return None;
         }
 
@@ -44,41 +177,88 @@ impl AppState {
                 .unwrap()
                 .as_secs() as i64,
         };
+        let timestamp = match self.snap_to_grid_secs {
+            Some(grid) => snap_to_grid(timestamp, grid),
+            None => timestamp,
+        };
         let time_since_last_update = match self.data.peek_last(|r| r.timestamp) {
-            Some(last_update) => timestamp - last_update,
+            Some(last_update) => checked_span(last_update, timestamp)?,
             None => 999,
         };
         if time_since_last_update < 60 {
             if verbose {
-                println!("time_since_last_update={}", time_since_last_update)
+                tracing::info!("time_since_last_update={}", time_since_last_update)
             };
             return None;
         }
 
-        self.data.push(match p1 {
-            Some(p1) => Data202303 {
-                timestamp,
-                pv2012_kWh: None,
-                pv2022_kWh: pv_2022,
-                peak_conso_kWh: Some(p1.peak_hour_consumption),
-                off_conso_kWh: Some(p1.off_hour_consumption),
-                peak_inj_kWh: Some(p1.peak_hour_injection),
-                off_inj_kWh: Some(p1.off_hour_injection),
-                gas_m3: None,
-                water_m3: None,
-            },
-            None => Data202303 {
-                timestamp,
-                pv2012_kWh: None,
-                pv2022_kWh: pv_2022,
-                peak_conso_kWh: None,
-                off_conso_kWh: None,
-                peak_inj_kWh: None,
-                off_inj_kWh: None,
-                gas_m3: None,
-                water_m3: None,
+        let pv_2022 = match (pv_2022, self.pv2022_monotonic_tolerance) {
+            (Some(value), Some(tolerance)) => match self.last_pv2022_same_day(timestamp) {
+                Some(before) if value < before - tolerance => {
+                    tracing::warn!(
+                        "Warning: pv2022_kWh went from {} to {} at {}, ignoring it",
+                        before,
+                        value,
+                        timestamp
+                    );
+                    None
+                }
+                _ => Some(value),
             },
-        })
+            _ => pv_2022,
+        };
+        let mut record = match p1 {
+            Some(p1) => Data202303::from_p1(timestamp, &p1),
+            None => Data202303::empty(timestamp),
+        }
+        .with_pv2012(pv_2012)
+        .with_pv2022(pv_2022);
+        // Keep the other values: after a meter reset, the next reading is
+        // compared with a missing value and becomes the new baseline
+        for field in self.decreased_fields(&record) {
+            tracing::warn!("Warning: {} decreased at {}, dropping it", field, timestamp);
+            if let Some(value) = record.field_mut(field) {
+                *value = None;
+            }
+        }
+        if !record.has_values() {
+            return None;
+        }
+        if let Some(schedule) = &self.off_hours
+            && let Some(last) = self.get_last_data()
+            && let Some(mismatch) =
+                tariff_mismatch(&last, &record, local_offset(timestamp), schedule)
+        {
+            tracing::warn!("Warning: {} at {}", mismatch, timestamp);
+        }
+        self.data.push(record)
+    }
+
+    /// Newest non-null `pv2022_kWh` recorded on the same local day as `timestamp`.
+    fn last_pv2022_same_day(&self, timestamp: i64) -> Option<f64> {
+        let day = local_day(timestamp);
+        freeze(&self.data)
+            .into_iter()
+            .rev()
+            .take_while(|r| local_day(r.timestamp) == day)
+            .find_map(|r| r.pv2022_kWh)
+    }
+
+    /// Guarded columns that are lower in `record` than in the last record.
+    fn decreased_fields(&self, record: &Data202303) -> Vec<&str> {
+        let view = freeze(&self.data);
+        let Some(last) = view.len().checked_sub(1).and_then(|idx| view.at(idx)) else {
+            return Vec::new();
+        };
+        self.decreasing_guard
+            .iter()
+            .filter(|name| {
+                Data202303::field_accessor(name).is_some_and(|get| {
+                    matches!((get(last), get(record)), (Some(before), Some(after)) if after < before)
+                })
+            })
+            .map(String::as_str)
+            .collect()
     }
 
     pub fn get_first_data(&self) -> Option<Data202303> {
@@ -89,317 +269,3060 @@ impl AppState {
         self.data.peek_last(clone_data202303)
     }
 
-    pub fn halve_data(&mut self) {
-        self.data.halve_data();
+    /// Newest record in the buffer, or else the newest one persisted at startup.
+    pub fn get_latest_data(&self) -> Option<Data202303> {
+        self.get_last_data()
+            .or_else(|| self.latest_persisted.as_ref().map(clone_data202303))
+    }
+
+    /// Average of the values `f` finds in the last `n` records, `None` if it
+    /// finds none.
+    pub fn avg_last_n<F>(&self, n: usize, f: F) -> Option<f64>
+    where
+        F: Fn(&Data202303) -> Option<f64>,
+    {
+        let (sum, count) = freeze(&self.data)
+            .into_iter()
+            .rev()
+            .take(n)
+            .filter_map(f)
+            .fold((0.0, 0), |(sum, count), x| (sum + x, count + 1));
+        if count > 0 {
+            Some(sum / count as f64)
+        } else {
+            None
+        }
+    }
+
+    /// Fraction of the records holding a value, for each of
+    /// `Data202303::VALUE_COLUMNS` (0.0 for an empty buffer).
+    pub fn field_fill_rates(&self) -> [(&'static str, f64); 8] {
+        let len = self.data.len();
+        Data202303::VALUE_COLUMNS.map(|name| {
+            let get = Data202303::field_accessor(name).unwrap();
+            let filled = freeze(&self.data)
+                .into_iter()
+                .filter(|r| get(r).is_some())
+                .count();
+            (
+                name,
+                if len == 0 {
+                    0.0
+                } else {
+                    filled as f64 / len as f64
+                },
+            )
+        })
+    }
+
+    /// Median of the gaps between consecutive records, `None` for fewer than
+    /// 2 records.
+    pub fn median_interval_secs(&self) -> Option<i64> {
+        let mut gaps: Vec<i64> = freeze(&self.data)
+            .windows2()
+            .map(|(earlier, later)| later.timestamp.saturating_sub(earlier.timestamp))
+            .collect();
+        if gaps.is_empty() {
+            return None;
+        }
+        gaps.sort_unstable();
+        let mid = gaps.len() / 2;
+        if gaps.len() % 2 == 1 {
+            Some(gaps[mid])
+        } else {
+            Some(gaps[mid - 1] + (gaps[mid] - gaps[mid - 1]) / 2)
+        }
+    }
+
+    /// Logical index of the first record not older than `ts`, assuming the
+    /// records are sorted.
+    fn first_index_not_before(&self, ts: i64) -> usize {
+        let view = freeze(&self.data);
+        let (mut left, mut right) = (0, view.len());
+        while left < right {
+            let mid = left + (right - left) / 2;
+            if view.at(mid).is_some_and(|r| r.timestamp < ts) {
+                left = mid + 1;
+            } else {
+                right = mid;
+            }
+        }
+        left
+    }
+
+    /// Where a record at `ts` goes to keep the records sorted (before those
+    /// at the same time), whether or not one is close enough to enrich.
+    pub fn insertion_index(&self, ts: i64) -> usize {
+        self.first_index_not_before(ts)
+    }
+
+    /// Logical index and copy of the record closest to `ts`, if it is at most
+    /// `max_dist` seconds away.  Assumes the records are sorted.
+    pub fn find_nearest(&self, ts: i64, max_dist: i64) -> Option<(usize, Data202303)> {
+        let max_dist = u64::try_from(max_dist).ok()?;
+        let idx = self.first_index_not_before(ts);
+        let view = freeze(&self.data);
+        // Only the neighbours on either side of `ts` can be closest.  On a tie,
+        // `min_by_key` keeps the earlier one, on account that the human took
+        // some time to fill in the form.
+        [idx.checked_sub(1), Some(idx)]
+            .into_iter()
+            .flatten()
+            .filter_map(|i| view.at(i).map(|r| (i, r)))
+            .filter(|(_, r)| r.timestamp.abs_diff(ts) <= max_dist)
+            .min_by_key(|(_, r)| r.timestamp.abs_diff(ts))
+            .map(|(i, r)| (i, clone_data202303(r)))
+    }
+
+    /// Replace the record with exactly timestamp `ts` by `new` (which should
+    /// carry the same timestamp to keep the records sorted) and return the old
+    /// one, `None` if there is no such record.  Assumes the records are sorted.
+    pub fn replace_by_timestamp(&mut self, ts: i64, new: Data202303) -> Option<Data202303> {
+        let idx = self.first_index_not_before(ts);
+        if freeze(&self.data).at(idx)?.timestamp != ts {
+            return None;
+        }
+        self.data.replace(idx, new)
+    }
+
+    /// Hash of the records in logical order, to tell whether they changed.
+    pub fn content_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for r in &freeze(&self.data) {
+            r.timestamp.hash(&mut hasher);
+            for value in [
+                r.pv2012_kWh,
+                r.pv2022_kWh,
+                r.peak_conso_kWh,
+                r.off_conso_kWh,
+                r.peak_inj_kWh,
+                r.off_inj_kWh,
+                r.gas_m3,
+                r.water_m3,
+            ] {
+                value.map(f64::to_bits).hash(&mut hasher);
+            }
+        }
+        hasher.finish()
+    }
+
+    /// Copy of the records sorted by timestamp, and whether they already were.
+    pub fn snapshot_sorted(&self) -> (Vec<Data202303>, bool) {
+        let mut records: Vec<Data202303> = freeze(&self.data)
+            .into_iter()
+            .map(clone_data202303)
+            .collect();
+        let was_sorted = records.is_sorted_by_key(|r| r.timestamp);
+        if !was_sorted {
+            records.sort_by_key(|r| r.timestamp);
+        }
+        (records, was_sorted)
+    }
+
+    /// Sort the buffer by timestamp if it is not already, keeping records with
+    /// equal timestamps in insertion order. Tell whether a reorder happened.
+    pub fn reorder_if_needed(&mut self) -> bool {
+        if freeze(&self.data)
+            .windows2()
+            .all(|(a, b)| a.timestamp <= b.timestamp)
+        {
+            return false;
+        }
+        let (records, _) = self.snapshot_sorted();
+        let mut data = ringbuffer::new(self.data.get_capacity());
+        data.extend(records);
+        self.data = data;
+        true
+    }
+
+    /// SQL script inserting every buffered record, persisted or not, leaving
+    /// the buffer as is.  `None` when the buffer is empty.
+    pub fn to_insert_sql(&self) -> Option<String> {
+        build_insert_many_sql(freeze(&self.data).iter_stride(1))
+    }
+
+    /// The records still to flush, in order (see `persisted`).
+    fn unflushed(&self) -> impl Iterator<Item = &Data202303> {
+        freeze(&self.data)
+            .iter_stride(1)
+            .filter(|r| self.persisted.get(&r.timestamp) != Some(*r))
+    }
+
+    /// Number of records still to flush.
+    pub fn unflushed_len(&self) -> usize {
+        self.unflushed().count()
+    }
+
+    /// Drop the first `count` records still to flush, keeping the persisted
+    /// ones among them.
+    fn drop_unflushed(&mut self, count: usize) {
+        if self.persisted.is_empty() {
+            self.data.drop_first(count);
+            return;
+        }
+        let persisted = &self.persisted;
+        let mut left = count;
+        self.data.retain(|r| {
+            if left == 0 || persisted.get(&r.timestamp) == Some(r) {
+                return true;
+            }
+            left -= 1;
+            false
+        });
+    }
+
+    /// Drop the records holding nothing but a timestamp.
+    pub fn compact_empty(&mut self) {
+        self.data.retain(Data202303::has_values);
+    }
+
+    /// Drop the leading records still to flush found in `flushed`, stopping
+    /// at the first one that is not (e.g. enriched by a manual input since it
+    /// was sent).
+    fn drop_flushed(&mut self, flushed: &[Data202303]) {
+        let count = self
+            .unflushed()
+            .take(flushed.len())
+            .take_while(|record| flushed.contains(record))
+            .count();
+        self.drop_unflushed(count);
+    }
+
+    fn record_flush(&mut self, attempted: usize, inserted: usize) {
+        if inserted < attempted {
+            tracing::warn!(
+                "Saved {} rows but row count only grew by {}",
+                attempted,
+                inserted
+            );
+        }
+        self.last_flush_ts = Some(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as i64,
+        );
+        self.last_flush_rows = attempted;
+        self.flush_failures = 0;
+        tracing::info!("Flushed {} rows ({} new)", attempted, inserted);
+    }
+
+    fn record_flush_failure(&mut self, e: &str) {
+        self.flush_failures += 1;
+        tracing::error!("Error saving data: {}", e);
+    }
+
+    /// Write the records to `path`: `SNAPSHOT_MAGIC`, their count (u64) and
+    /// for each one the timestamp (i64) followed by every column as a presence
+    /// byte and an f64, all little-endian.
+    pub fn save_snapshot(&self, path: &Path) -> io::Result<()> {
+        // Write aside and rename so that a crash never leaves a truncated file
+        let tmp_path = path.with_extension("tmp");
+        let mut out = BufWriter::new(File::create(&tmp_path)?);
+        out.write_all(SNAPSHOT_MAGIC)?;
+        out.write_all(&(self.data.len() as u64).to_le_bytes())?;
+        for r in &freeze(&self.data) {
+            out.write_all(&r.timestamp.to_le_bytes())?;
+            for value in [
+                r.pv2012_kWh,
+                r.pv2022_kWh,
+                r.peak_conso_kWh,
+                r.off_conso_kWh,
+                r.peak_inj_kWh,
+                r.off_inj_kWh,
+                r.gas_m3,
+                r.water_m3,
+            ] {
+                out.write_all(&[value.is_some() as u8])?;
+                out.write_all(&value.unwrap_or(0.0).to_le_bytes())?;
+            }
+        }
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(tmp_path, path)
+    }
+
+    /// State holding the records written by `save_snapshot` (only the newest
+    /// `capacity` of them if there are more).
+    pub fn load_snapshot(path: &Path, capacity: usize) -> io::Result<AppState> {
+        let mut input = BufReader::new(File::open(path)?);
+        let mut magic = [0; 4];
+        input.read_exact(&mut magic)?;
+        if &magic != SNAPSHOT_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a snapshot file",
+            ));
+        }
+        let mut u64_bytes = [0; 8];
+        input.read_exact(&mut u64_bytes)?;
+        let count = u64::from_le_bytes(u64_bytes);
+        let mut read_f64 = |input: &mut BufReader<File>| -> io::Result<Option<f64>> {
+            let mut present = [0; 1];
+            input.read_exact(&mut present)?;
+            input.read_exact(&mut u64_bytes)?;
+            Ok((present[0] != 0).then(|| f64::from_le_bytes(u64_bytes)))
+        };
+        let mut state = AppState::with_capacity(capacity);
+        for _ in 0..count {
+            let mut ts_bytes = [0; 8];
+            input.read_exact(&mut ts_bytes)?;
+            state.data.push(Data202303 {
+                timestamp: i64::from_le_bytes(ts_bytes),
+                pv2012_kWh: read_f64(&mut input)?,
+                pv2022_kWh: read_f64(&mut input)?,
+                peak_conso_kWh: read_f64(&mut input)?,
+                off_conso_kWh: read_f64(&mut input)?,
+                peak_inj_kWh: read_f64(&mut input)?,
+                off_inj_kWh: read_f64(&mut input)?,
+                gas_m3: read_f64(&mut input)?,
+                water_m3: read_f64(&mut input)?,
+            });
+        }
+        Ok(state)
+    }
+
+    /// Fill an empty buffer with the newest rows of the database, e.g. after a
+    /// restart, and return how many were loaded.  These rows are remembered in
+    /// `persisted` and are not flushed again unless they change.
+    ///
+    /// `save_snapshot` does not store `persisted`: once restored from a
+    /// snapshot, the seeded rows are sent again on the next flushes.
+    pub fn seed_from_db(&mut self, db: &dyn Sqlite) -> Result<usize, DataError> {
+        if !self.data.is_empty() {
+            return Ok(0);
+        }
+        let rows = select_last_data_202303(db, self.data.get_capacity())?;
+        let count = rows.len();
+        self.persisted = rows
+            .iter()
+            .map(|r| (r.timestamp, clone_data202303(r)))
+            .collect();
+        self.data.extend(rows);
+        Ok(count)
+    }
+
+    /// Drop the records older than `cutoff_ts`, relying on the records being
+    /// sorted by timestamp.
+    pub fn trim_older_than(&mut self, cutoff_ts: i64) {
+        self.drain_older_than(cutoff_ts);
+    }
+
+    /// Remove and return the leading records older than `cutoff_ts`, e.g. to
+    /// persist exactly those.
+    pub fn drain_older_than(&mut self, cutoff_ts: i64) -> Vec<Data202303> {
+        let drained: Vec<Data202303> = self.data.with_view(|vw| {
+            vw.into_iter()
+                .take_while(|r| r.timestamp < cutoff_ts)
+                .map(clone_data202303)
+                .collect()
+        });
+        self.data.drop_first(drained.len());
+        drained
+    }
+
+    /// Drop the records older than `policy.max_age_secs` before `now_ts`, then
+    /// the oldest ones until at most `policy.max_records` remain.
+    ///
+    /// Only the records already in the database age out: the age limit stops
+    /// at the first record still to flush.
+    pub fn apply_retention(&mut self, now_ts: i64, policy: &RetentionPolicy) {
+        if let Some(max_age_secs) = policy.max_age_secs {
+            let cutoff_ts = now_ts.saturating_sub(max_age_secs);
+            let first_unflushed = self.unflushed().next().map(|r| r.timestamp);
+            self.trim_older_than(first_unflushed.map_or(cutoff_ts, |ts| ts.min(cutoff_ts)));
+        }
+        let Some(max_records) = policy.max_records else {
+            return;
+        };
+        let excess = self
+            .data
+            .len()
+            .saturating_sub(max_records.min(self.data.get_capacity()));
+        self.data.drop_first(excess);
+    }
+}
+
+/// `timestamp` rounded to the nearest multiple of `grid` (halfway rounds up).
+pub fn snap_to_grid(timestamp: i64, grid: i64) -> i64 {
+    let below = timestamp - timestamp.rem_euclid(grid);
+    if timestamp - below >= grid - (timestamp - below) {
+        below.checked_add(grid).unwrap_or(below)
+    } else {
+        below
     }
 }
 
-pub fn poll_automated_measurements(
-    p1_data_cmd: &str,
-    pv_2022_cmd: &str,
-    verbose: bool,
-) -> (Option<CompleteP1Measurement>, Option<f64>) {
-    let mut child = Command::new("sh")
-        .arg("-c")
-        .arg(p1_data_cmd)
+/// `to - from`, or `None` (with a warning) if a corrupt timestamp makes it
+/// overflow.
+fn checked_span(from: i64, to: i64) -> Option<i64> {
+    let span = to.checked_sub(from);
+    if span.is_none() {
+        tracing::warn!("Warning: timestamps {} and {} are too far apart", from, to);
+    }
+    span
+}
+
+/// Where the P1 telegrams are read from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum P1Source {
+    /// Output of a shell command, spawned at each poll.
+    Cmd(String),
+    /// A file (or named pipe), read again whenever its end is reached.
+    File(String),
+    /// This process' standard input.
+    Stdin,
+}
+
+impl P1Source {
+    /// Parse `cmd:<shell>`, `file:<path>` or `stdin`.
+    pub fn parse(s: &str) -> Result<P1Source, String> {
+        if let Some(cmd) = s.strip_prefix("cmd:") {
+            Ok(P1Source::Cmd(cmd.to_string()))
+        } else if let Some(path) = s.strip_prefix("file:") {
+            Ok(P1Source::File(path.to_string()))
+        } else if s == "stdin" {
+            Ok(P1Source::Stdin)
+        } else {
+            Err(format!(
+                "'{}' is not one of cmd:<shell>, file:<path> or stdin",
+                s
+            ))
+        }
+    }
+}
+
+/// Lines of P1 data up to the end or the first read error, with bytes that
+/// are not valid UTF-8 replaced: serial noise is left for the parser to reject.
+fn p1_lines<R: BufRead>(reader: R) -> impl Iterator<Item = String> {
+    reader.split(b'\n').map_while(|line| match line {
+        Ok(line) => {
+            Some(String::from_utf8_lossy(line.strip_suffix(b"\r").unwrap_or(&line)).into_owned())
+        }
+        Err(e) => {
+            tracing::error!("P1 err: {}", e);
+            None
+        }
+    })
+}
+
+/// A telegram as its lines, or why the source could not be read.
+type Telegram = Result<Vec<String>, String>;
+
+/// Where the poller gets its P1 telegrams from.
+pub enum P1Reader {
+    /// Spawned at each poll.
+    Cmd(String),
+    /// Read by a thread of its own (see `forward_telegrams`), the newest
+    /// telegram being taken at each poll.
+    Telegrams {
+        source: P1Source,
+        telegrams: mpsc::Receiver<Telegram>,
+    },
+}
+
+impl P1Reader {
+    /// Start reading a file or stdin right away: reopening it at each poll
+    /// would leave a blocked thread behind after each timeout and lose what
+    /// was buffered past the end of a telegram.
+    pub fn new(source: &P1Source) -> P1Reader {
+        let path = match source {
+            P1Source::Cmd(cmd) => return P1Reader::Cmd(cmd.clone()),
+            P1Source::File(path) => Some(path.clone()),
+            P1Source::Stdin => None,
+        };
+        let (sender, telegrams) = mpsc::channel();
+        thread::spawn(move || forward_telegrams(path.as_deref(), &sender));
+        P1Reader::Telegrams {
+            source: source.clone(),
+            telegrams,
+        }
+    }
+}
+
+/// Send the telegrams read from the file at `path` (stdin if `None`) until
+/// the `P1Reader` is dropped or stdin is closed.  A telegram ends with its
+/// `!<CRC>` line or with the data.  A file is read again after
+/// `FILE_REOPEN_DELAY`, in case it is rewritten.
+fn forward_telegrams(path: Option<&str>, telegrams: &mpsc::Sender<Telegram>) {
+    loop {
+        let reader: Box<dyn BufRead> = match path {
+            Some(path) => match File::open(path) {
+                Ok(file) => Box::new(BufReader::new(file)),
+                Err(e) => {
+                    if telegrams
+                        .send(Err(format!("unable to open '{}': {}", path, e)))
+                        .is_err()
+                    {
+                        return;
+                    }
+                    thread::sleep(FILE_REOPEN_DELAY);
+                    continue;
+                }
+            },
+            None => Box::new(BufReader::new(io::stdin())),
+        };
+        let mut telegram = Vec::new();
+        let mut sent = false;
+        for line in p1_lines(reader) {
+            let end = line.starts_with('!');
+            telegram.push(line);
+            if end {
+                if telegrams.send(Ok(std::mem::take(&mut telegram))).is_err() {
+                    return;
+                }
+                sent = true;
+            }
+        }
+        // Even an empty file is worth reporting, unlike what trails the last
+        // telegram
+        if (!sent || !telegram.is_empty()) && telegrams.send(Ok(telegram)).is_err() {
+            return;
+        }
+        if path.is_none() {
+            tracing::info!("P1: end of stdin");
+            return;
+        }
+        thread::sleep(FILE_REOPEN_DELAY);
+    }
+}
+
+/// Parse `lines` as a P1 telegram, also returning (the beginning of) them to
+/// help debugging parse failures.
+fn parse_p1_lines<I>(lines: I, p1_obis: &P1ObisConfig) -> (Result<P1Outcome, String>, String)
+where
+    I: Iterator<Item = String>,
+{
+    let mut raw = String::new();
+    let lines = lines.inspect(|line| {
+        if raw.len() + line.len() < MAX_TELEGRAM_LEN {
+            raw.push_str(line);
+            raw.push('\n');
+        }
+    });
+    let parsed = p1_meter::parse_lines_outcome(lines, p1_obis).map_err(|e| e.to_string());
+    (parsed, raw)
+}
+
+/// Run `cmd` and parse its output, giving up after `p1_timeout` (`cmd` and
+/// everything it started are killed then).
+fn read_p1_cmd(
+    cmd: &str,
+    p1_timeout: Duration,
+    p1_obis: &P1ObisConfig,
+) -> Result<(Result<P1Outcome, String>, String), String> {
+    let deadline = Instant::now() + p1_timeout;
+    let mut child = sh_command(cmd)
         .stdout(Stdio::piped())
         .spawn()
-        .unwrap();
+        .map_err(|e| format!("unable to run '{}': {}", cmd, e))?;
     let stdout = child.stdout.take().unwrap();
-    let lines = BufReader::new(stdout).lines().map(|x| x.unwrap());
-    let p1 = match p1_meter::parse_lines(lines) {
-        Ok(Some(complete)) => {
+    // Parse on a helper thread so that a hung command can't block the poller
+    let (tx, rx) = mpsc::channel();
+    let p1_obis = p1_obis.clone();
+    thread::spawn(move || {
+        let _ = tx.send(parse_p1_lines(p1_lines(BufReader::new(stdout)), &p1_obis));
+    });
+    let received = rx.recv_timeout(p1_timeout).map_err(|e| match e {
+        RecvTimeoutError::Timeout => format!(
+            "no data after {:.3}s from '{}'",
+            p1_timeout.as_secs_f64(),
+            cmd
+        ),
+        RecvTimeoutError::Disconnected => format!("unable to read '{}'", cmd),
+    });
+    // Even after a complete telegram, don't wait past the deadline
+    if !matches!(wait_until(&mut child, deadline), Ok(Some(_)))
+        && let Err(e) = kill_group(&mut child)
+    {
+        tracing::error!("P1 err: unable to kill '{}': {}", cmd, e);
+    }
+    received
+}
+
+/// Newest telegram forwarded by the thread of a `P1Reader::Telegrams`,
+/// waiting at most `p1_timeout` for one when there is none yet.
+fn next_telegram(
+    source: &P1Source,
+    telegrams: &mpsc::Receiver<Telegram>,
+    p1_timeout: Duration,
+) -> Result<Vec<String>, String> {
+    match telegrams.try_iter().last() {
+        Some(telegram) => telegram,
+        None => telegrams.recv_timeout(p1_timeout).map_err(|e| match e {
+            RecvTimeoutError::Timeout => format!(
+                "no data after {:.3}s from {:?}",
+                p1_timeout.as_secs_f64(),
+                source
+            ),
+            RecvTimeoutError::Disconnected => format!("no more data from {:?}", source),
+        })?,
+    }
+}
+
+/// Read P1 telegrams like `read_p1` until a complete one is found, retrying at
+/// most `p1_retries` times when a telegram was cut short (e.g. a slow serial
+/// line caught halfway).
+fn read_p1_with_retries(
+    blocking_ref: &SharedState,
+    p1_reader: &P1Reader,
+    p1_timeout: Duration,
+    p1_retries: u32,
+    p1_obis: &P1ObisConfig,
+    verbose: bool,
+) -> Option<CompleteP1Measurement> {
+    for attempt in 0..=p1_retries {
+        match read_p1(blocking_ref, p1_reader, p1_timeout, p1_obis, verbose) {
+            Some(P1Outcome::Complete(complete)) => return Some(complete),
+            Some(P1Outcome::Incomplete(_)) if attempt < p1_retries => {
+                tracing::info!("P1: retrying ({}/{})", attempt + 1, p1_retries)
+            }
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// Read and parse one P1 telegram from `p1_reader`, giving up after
+/// `p1_timeout`.  `None` when nothing could be read.
+fn read_p1(
+    blocking_ref: &SharedState,
+    p1_reader: &P1Reader,
+    p1_timeout: Duration,
+    p1_obis: &P1ObisConfig,
+    verbose: bool,
+) -> Option<P1Outcome> {
+    let received = match p1_reader {
+        P1Reader::Cmd(cmd) => read_p1_cmd(cmd, p1_timeout, p1_obis),
+        P1Reader::Telegrams { source, telegrams } => next_telegram(source, telegrams, p1_timeout)
+            .map(|lines| parse_p1_lines(lines.into_iter(), p1_obis)),
+    };
+    let parsed = match received {
+        Ok((parsed, raw)) => {
+            if let Ok(P1Outcome::Complete(complete)) = &parsed {
+                for warning in p1_meter::check_totals(raw.lines(), complete) {
+                    tracing::warn!("P1 warning: {}", warning);
+                }
+            }
+            if !raw.is_empty() {
+                blocking_ref.write().unwrap().last_telegram = Some(raw);
+            }
+            parsed
+        }
+        Err(e) => {
+            tracing::error!("P1 err: {}", e);
+            return None;
+        }
+    };
+    match parsed {
+        Ok(P1Outcome::Complete(complete)) => {
             if verbose {
-                println!("complete = {:?}", complete)
+                tracing::info!("complete = {:?}", complete)
             };
-            Some(complete)
+            Some(P1Outcome::Complete(complete))
         }
-        Ok(None) => {
+        Ok(P1Outcome::Incomplete(partial)) => {
+            tracing::error!(
+                "P1 err: telegram truncated, got timestamp but only {} of 4 registers",
+                partial.register_count()
+            );
+            Some(P1Outcome::Incomplete(partial))
+        }
+        Ok(P1Outcome::Empty) => {
             if verbose {
-                println!("nothing parsed")
+                tracing::info!("P1: no data at all")
             };
+            Some(P1Outcome::Empty)
+        }
+        Err(e) => {
+            // One garbled register must not stop the polling loop
+            tracing::error!("P1 err: {}", e);
             None
         }
-        Err(_) => panic!("Error"),
-    };
-    child.wait().expect("unable to kill p1_data_cmd?");
-    let pv_2022 = match pv2022::fetch_dashboard_value(pv_2022_cmd, verbose) {
-        Ok(pv_2022) => {
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn poll_automated_measurements(
+    blocking_ref: &SharedState,
+    p1_reader: &P1Reader,
+    p1_timeout: Duration,
+    p1_retries: u32,
+    p1_obis: &P1ObisConfig,
+    pv_2022_cmd: &str,
+    pv_2022_json_path: &str,
+    pv_timeout: Duration,
+    pv_divisor: f64,
+    pv_2012_cmd: Option<(&str, &str)>,
+    verbose: bool,
+) -> (Option<CompleteP1Measurement>, Option<f64>, Option<f64>) {
+    let p1 = read_p1_with_retries(
+        blocking_ref,
+        p1_reader,
+        p1_timeout,
+        p1_retries,
+        p1_obis,
+        verbose,
+    );
+    let pv_2022 = log_pv_value(
+        "PV2022",
+        pv2022::fetch_json_path_value(
+            pv_2022_cmd,
+            pv_2022_json_path,
+            pv_divisor,
+            pv_timeout,
+            verbose,
+        ),
+        verbose,
+    );
+    let pv_2012 = pv_2012_cmd.and_then(|(pv_2012_cmd, pv_2012_serial)| {
+        log_pv_value(
+            "PV2012",
+            pv2022::fetch_device_value(
+                pv_2012_cmd,
+                pv_2012_serial,
+                pv_divisor,
+                pv_timeout,
+                verbose,
+            ),
+            verbose,
+        )
+    });
+    (p1, pv_2022, pv_2012)
+}
+
+/// Run `cmd` with `input` on stdin, failing unless it exits successfully
+/// within `timeout` (it is killed then).
+fn probe_cmd(cmd: &str, input: &str, timeout: Duration) -> Result<(), String> {
+    let mut child = sh_command(cmd)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("unable to run '{}': {}", cmd, e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // A command ignoring its input may already be gone
+        let _ = stdin.write_all(input.as_bytes());
+    }
+    match wait_until(&mut child, Instant::now() + timeout) {
+        Ok(Some(status)) if status.success() => Ok(()),
+        Ok(Some(status)) => Err(format!("'{}' failed: {}", cmd, status)),
+        Ok(None) => {
+            let _ = kill_group(&mut child);
+            Err(format!(
+                "'{}' still running after {:.3}s",
+                cmd,
+                timeout.as_secs_f64()
+            ))
+        }
+        Err(e) => Err(format!("unable to wait for '{}': {}", cmd, e)),
+    }
+}
+
+/// Best-effort startup check of the configured P1, PV and SQL commands, so
+/// that a typo shows up now rather than at the first poll or flush.  Return
+/// one warning per failing command, each probe giving up after its timeout.
+#[allow(clippy::too_many_arguments)]
+pub fn check_commands(
+    p1_source: &P1Source,
+    p1_timeout: Duration,
+    p1_obis: &P1ObisConfig,
+    pv_2022_cmd: &str,
+    pv_2022_json_path: &str,
+    pv_2012_cmd: Option<(&str, &str)>,
+    pv_timeout: Duration,
+    sql_cmd: &str,
+) -> Vec<String> {
+    let mut warnings = Vec::new();
+    // Reading a telegram is the only harmless thing a P1 command can do, while
+    // a telegram read from a file or stdin now would be lost to the poller
+    match p1_source {
+        P1Source::Cmd(cmd) => {
+            if !matches!(
+                read_p1(
+                    &SharedState::default(),
+                    &P1Reader::Cmd(cmd.clone()),
+                    p1_timeout,
+                    p1_obis,
+                    false
+                ),
+                Some(P1Outcome::Complete(_))
+            ) {
+                warnings.push(format!(
+                    "P1 source {:?} gave no complete telegram",
+                    p1_source
+                ));
+            }
+        }
+        P1Source::File(path) => {
+            if let Err(e) = File::open(path) {
+                warnings.push(format!("P1 source {:?}: {}", p1_source, e));
+            }
+        }
+        P1Source::Stdin => {}
+    }
+    if let Err(e) = pv2022::fetch_json_path_value(
+        pv_2022_cmd,
+        pv_2022_json_path,
+        pv2022::DEFAULT_DIVISOR,
+        pv_timeout,
+        false,
+    ) {
+        warnings.push(format!("PV2022 command '{}': {}", pv_2022_cmd, e));
+    }
+    if let Some((pv_2012_cmd, pv_2012_serial)) = pv_2012_cmd
+        && let Err(e) = pv2022::fetch_device_value(
+            pv_2012_cmd,
+            pv_2012_serial,
+            pv2022::DEFAULT_DIVISOR,
+            pv_timeout,
+            false,
+        )
+    {
+        warnings.push(format!("PV2012 command '{}': {}", pv_2012_cmd, e));
+    }
+    if let Err(e) = probe_cmd(sql_cmd, "SELECT 1;", pv_timeout) {
+        warnings.push(format!("SQL command: {}", e));
+    }
+    warnings
+}
+
+fn log_pv_value(name: &str, value: Result<Option<f64>, String>, verbose: bool) -> Option<f64> {
+    match value {
+        Ok(Some(value)) => {
+            if verbose {
+                tracing::info!("{}={}", name, value)
+            };
+            Some(value)
+        }
+        Ok(None) => {
             if verbose {
-                println!("PV2022={}", pv_2022)
+                tracing::info!("{} reported no value", name)
             };
-            Some(pv_2022)
+            None
         }
         Err(s) => {
-            println!("PV2022 err: {}", s);
+            tracing::error!("{} err: {}", name, s);
             None
         }
-    };
-    (p1, pv_2022)
+    }
 }
 
+/// Record the new measurements and flush the oldest ones to the database once
+/// the buffer spans more than `dump_interval` seconds.
+///
+/// When a flush succeeds, return the number of rows sent to the database and
+/// the net change in row count reported by it (smaller when rows already
+/// existed).  All the rows sent are dropped from the buffer.  Each flush
+/// runs `sqlite_pragmas` (e.g. `PRAGMA synchronous=NORMAL;`) first.  The
+/// records seeded from the database and unchanged since (see
+/// `AppState::persisted`) are neither sent nor dropped.
+///
+/// With a `flusher` (see `run_flusher`), the rows are sent to it instead and
+/// `None` is returned right away: they stay in the buffer until it confirms
+/// they were saved, and no other rows are sent in the meantime.
+///
+/// Except in a dry run, above the high-water mark or while the flushes fail,
+/// `retention` is applied afterwards.
+#[allow(clippy::too_many_arguments)]
 pub fn save_data(
     blocking_ref: &SharedState,
     p1: Option<CompleteP1Measurement>,
     pv_2022: Option<f64>,
-    sql_cmd: &str,
+    pv_2012: Option<f64>,
+    db: &dyn Sqlite,
     dump_interval: i64,
     verbose: bool,
     insert_batch_size: usize,
-) {
+    sqlite_pragmas: &str,
+    retention: &RetentionPolicy,
+    dry_run: bool,
+    sql_dump_file: Option<&Path>,
+    flusher: Option<&mpsc::Sender<Vec<Data202303>>>,
+) -> Option<(usize, usize)> {
     let state = &mut blocking_ref.write().unwrap();
-    if let Some(_) = state.set_data(p1, pv_2022, verbose) {
-        state.halve_data();
+    if state.set_data(p1, pv_2022, pv_2012, verbose).is_some() {
+        tracing::warn!("Warning: buffer full, the oldest record was overwritten");
+    }
+    let first_unflushed = state.unflushed().next().map(|r| r.timestamp);
+    let result = if let (Some(first), Some(last)) =
+        (first_unflushed, state.data.peek_last(|r| r.timestamp))
+        && checked_span(first, last).is_some_and(|span| span > dump_interval)
+    {
+        if dry_run {
+            // Show what would be executed but keep the buffer untouched
+            match build_insert_many_sql_with_pragmas(
+                sqlite_pragmas,
+                state.unflushed().take(insert_batch_size),
+            ) {
+                Some(sql) => tracing::info!("Dry run, not executing:\n{}", sql),
+                None => tracing::info!("Dry run, nothing to save"),
+            }
+            return None;
+        }
+        // Records without any value are not worth persisting
+        state.compact_empty();
+        let attempted = state.unflushed_len().min(insert_batch_size);
+        match flusher {
+            Some(_) if state.flush_pending => None,
+            Some(sender) => {
+                let rows = state
+                    .unflushed()
+                    .take(attempted)
+                    .map(clone_data202303)
+                    .collect();
+                match sender.send(rows) {
+                    Ok(()) => state.flush_pending = true,
+                    Err(_) => tracing::error!("Error saving data: flush task is gone"),
+                }
+                None
+            }
+            None => {
+                let saved = flush_rows(
+                    state.unflushed().take(attempted),
+                    attempted,
+                    db,
+                    sqlite_pragmas,
+                    sql_dump_file,
+                );
+                match saved {
+                    Ok(inserted) => {
+                        // The database accepted them all, even those it deduplicated
+                        state.drop_unflushed(attempted);
+                        state.record_flush(attempted, inserted);
+                        Some((attempted, inserted))
+                    }
+                    Err(e) => {
+                        state.record_flush_failure(&e);
+                        None
+                    }
+                }
+            }
+        }
+    } else {
+        None
+    };
+    if let Some(high_water_mark) = state.high_water_mark {
+        // A healthy buffer routinely holds up to `dump_interval` of records
+        let degraded = state.flush_failures > 0 && state.unflushed_len() > high_water_mark;
+        if degraded != state.degraded {
+            tracing::warn!(
+                "{} {} unflushed records (high-water mark {})",
+                if degraded {
+                    "Degraded mode, keeping"
+                } else {
+                    "Back to normal with"
+                },
+                state.unflushed_len(),
+                high_water_mark
+            );
+        }
+        state.degraded = degraded;
+    }
+    // Retention must not discard records that could not be saved
+    if !dry_run
+        && !state.degraded
+        && state.flush_failures == 0
+        && let Some(now_ts) = state.data.peek_last(|r| r.timestamp)
+    {
+        state.apply_retention(now_ts, retention);
+    }
+    result
+}
+
+/// Save the `attempted` `records` to `sql_dump_file` if any, to the database
+/// otherwise, and return the net change in row count.
+fn flush_rows<'a, I>(
+    records: I,
+    attempted: usize,
+    db: &dyn Sqlite,
+    sqlite_pragmas: &str,
+    sql_dump_file: Option<&Path>,
+) -> Result<usize, String>
+where
+    I: IntoIterator<Item = &'a Data202303>,
+{
+    match sql_dump_file {
+        // Keep the rows buffered if the file can't be written
+        Some(path) => append_insert_sql(path, records)
+            .map(|()| attempted)
+            .map_err(|e| format!("unable to append to {}: {}", path.display(), e)),
+        None => insert_many_data_202303_with_pragmas(db, sqlite_pragmas, records)
+            .map_err(|e| e.to_string()),
+    }
+}
+
+/// Save the batches sent by `save_data` until every sender is dropped, so
+/// that a slow database does not hold up the polling loop.  Each saved batch
+/// is then dropped from the buffer.
+pub fn run_flusher(
+    blocking_ref: &SharedState,
+    batches: &mpsc::Receiver<Vec<Data202303>>,
+    db: &dyn Sqlite,
+    sqlite_pragmas: &str,
+    sql_dump_file: Option<&Path>,
+) {
+    // A previous run may have died before confirming its batch
+    blocking_ref.write().unwrap().flush_pending = false;
+    for rows in batches {
+        let saved = flush_rows(&rows, rows.len(), db, sqlite_pragmas, sql_dump_file);
+        let mut state = blocking_ref.write().unwrap();
+        match saved {
+            Ok(inserted) => {
+                state.drop_flushed(&rows);
+                state.record_flush(rows.len(), inserted);
+            }
+            Err(e) => state.record_flush_failure(&e),
+        }
+        state.flush_pending = false;
+    }
+}
+
+/// Append the SQL script inserting `records` to `path`, to be replayed with
+/// sqlite3 elsewhere.
+fn append_insert_sql<'a, I>(path: &Path, records: I) -> io::Result<()>
+where
+    I: IntoIterator<Item = &'a Data202303>,
+{
+    let Some(sql) = build_insert_many_sql(records) else {
+        return Ok(());
+    };
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(file, "{}", sql)?;
+    file.sync_all()
+}
+
+/// Store the manual inputs in the record closest to `timestamp` (or a new one)
+/// and return that record.
+///
+/// A full buffer refuses a new record older than all the others, as it
+/// would be the next one evicted.
+pub fn save_manual_inputs(
+    state: &mut RwLockWriteGuard<'_, AppState>,
+    timestamp: DateTime<FixedOffset>,
+    #[allow(non_snake_case)] pv2012_kWh: Option<f64>,
+    gas_m3: Option<f64>,
+    water_m3: Option<f64>,
+) -> Result<Data202303, String> {
+    upsert_manual_inputs(state, timestamp, pv2012_kWh, gas_m3, water_m3).map(|(record, _)| record)
+}
+
+/// Like [`save_manual_inputs`] but also tell whether an existing record was
+/// updated (`true`) or a new one inserted (`false`).
+pub fn upsert_manual_inputs(
+    state: &mut RwLockWriteGuard<'_, AppState>,
+    timestamp: DateTime<FixedOffset>,
+    #[allow(non_snake_case)] pv2012_kWh: Option<f64>,
+    gas_m3: Option<f64>,
+    water_m3: Option<f64>,
+) -> Result<(Data202303, bool), String> {
+    // find_nearest relies on the records being sorted
+    if state.reorder_if_needed() {
+        tracing::warn!("Warning: records out of order, sorted them before saving manual inputs");
+    }
+    let timestamp = match state.snap_to_grid_secs {
+        Some(grid) if state.snap_manual_inputs => snap_to_grid(timestamp.timestamp(), grid),
+        _ => timestamp.timestamp(),
+    };
+    match state.find_nearest(timestamp, 60) {
+        Some((_, existing_data)) => {
+            let mut record = Data202303::empty(existing_data.timestamp)
+                .with_pv2012(pv2012_kWh)
+                .with_gas(gas_m3)
+                .with_water(water_m3);
+            record.merge_from(&existing_data);
+            state.replace_by_timestamp(record.timestamp, clone_data202303(&record));
+            Ok((record, true))
+        }
+        None => {
+            let idx = state.insertion_index(timestamp);
+            if idx == 0 && state.data.len() >= state.data.get_capacity() {
+                let e = format!(
+                    "Buffer full and {} is older than every record, not saving it",
+                    timestamp
+                );
+                tracing::warn!("Warning: {}", e);
+                return Err(e);
+            }
+            let record = Data202303::empty(timestamp)
+                .with_pv2012(pv2012_kWh)
+                .with_gas(gas_m3)
+                .with_water(water_m3);
+            state.data.insert_at(idx, clone_data202303(&record));
+            Ok((record, false))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use meter_core::data::FakeSqlite;
+    const FAKE_PV_2022: &str = "echo '{\"result\":{\"0199-xxxxx9BD\":{\"6800_08822000\":{\"1\":[{\"validVals\":[9401,9402,9403,9404,9405],\"val\":[{\"tag\":9404}]}]},\"6800_10821E00\":{\"1\":[{\"val\":\"SN: xxxxxxx245\"}]},\"6800_08811F00\":{\"1\":[{\"validVals\":[1129,1130],\"val\":[{\"tag\":1129}]}]},\"6180_08214800\":{\"1\":[{\"val\":[{\"tag\":307}]}]},\"6180_08414900\":{\"1\":[{\"val\":[{\"tag\":886}]}]},\"6180_08522F00\":{\"1\":[{\"val\":[{\"tag\":16777213}]}]},\"6800_088A2900\":{\"1\":[{\"validVals\":[302,9327,9375,9376,9437,19043],\"val\":[{\"tag\":302}]}]},\"6100_40463600\":{\"1\":[{\"val\":null}]},\"6100_40463700\":{\"1\":[{\"val\":null}]},\"6100_40263F00\":{\"1\":[{\"val\":null}]},\"6400_00260100\":{\"1\":[{\"val\":7439043}]},\"6800_00832A00\":{\"1\":[{\"low\":5000,\"high\":5000,\"val\":5000}]},\"6800_008AA200\":{\"1\":[{\"low\":0,\"high\":null,\"val\":0}]},\"6400_00462500\":{\"1\":[{\"val\":null}]},\"6100_00418000\":{\"1\":[{\"val\":null}]},\"6800_08822B00\":{\"1\":[{\"validVals\":[461],\"val\":[{\"tag\":461}]}]},\"6100_0046C200\":{\"1\":[{\"val\":null}]},\"6400_0046C300\":{\"1\":[{\"val\":7459043}]},\"6802_08834500\":{\"1\":[{\"validVals\":[303,1439],\"val\":[{\"tag\":1439}]}]},\"6180_08412800\":{\"1\":[{\"val\":[{\"tag\":16777213}]}]}}}}'";
+    const FAKE_P1: &str = "echo '0-0:1.0.0(241025000000S)'; echo '1-0:1.8.1(002654.919*kWh)'; echo '1-0:1.8.2(002420.293*kWh)'; echo '1-0:2.8.1(006254.732*kWh)'; echo '1-0:2.8.2(002457.202*kWh)';";
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn check_commands_warns_about_failing_probe() {
+        let check = |sql_cmd| {
+            check_commands(
+                &P1Source::Cmd(FAKE_P1.to_string()),
+                TIMEOUT,
+                &P1ObisConfig::default(),
+                FAKE_PV_2022,
+                pv2022::PV_2022_JSON_PATH,
+                None,
+                TIMEOUT,
+                sql_cmd,
+            )
+        };
+        assert_eq!(check("cat > /dev/null; echo 1"), Vec::<String>::new());
+        assert_eq!(
+            check("exit 3"),
+            vec!["SQL command: 'exit 3' failed: exit status: 3".to_string()]
+        );
+        let start = Instant::now();
+        let warnings = check_commands(
+            &P1Source::Cmd("echo nothing".to_string()),
+            TIMEOUT,
+            &P1ObisConfig::default(),
+            "exit 1",
+            pv2022::PV_2022_JSON_PATH,
+            None,
+            Duration::from_millis(200),
+            "sleep 10",
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(warnings.len(), 3, "{:?}", warnings);
+        assert!(warnings[2].ends_with("still running after 0.200s"));
+    }
+
+    #[test]
+    fn check_commands_warns_about_garbled_p1_output() {
+        for p1_cmd in [
+            FAKE_P1.replace("002654.919", "2,654.919"),
+            "printf '0-0:1.0.0(\\377\\376)\\n1-0:1.8.1(-*kWh)\\n'".to_string(),
+        ] {
+            let p1_source = P1Source::Cmd(p1_cmd);
+            let warnings = check_commands(
+                &p1_source,
+                TIMEOUT,
+                &P1ObisConfig::default(),
+                FAKE_PV_2022,
+                pv2022::PV_2022_JSON_PATH,
+                None,
+                TIMEOUT,
+                "cat > /dev/null; echo 1",
+            );
+            assert_eq!(
+                warnings,
+                vec![format!(
+                    "P1 source {:?} gave no complete telegram",
+                    p1_source
+                )]
+            );
+        }
+    }
+
+    #[test]
+    fn no_measurement() {
+        assert_eq!(
+            poll_automated_measurements(
+                &SharedState::default(),
+                &P1Reader::Cmd("echo A".to_string()),
+                TIMEOUT,
+                0,
+                &P1ObisConfig::default(),
+                "echo B",
+                pv2022::PV_2022_JSON_PATH,
+                TIMEOUT,
+                pv2022::DEFAULT_DIVISOR,
+                None,
+                true
+            ),
+            (None, None, None)
+        )
+    }
+
+    #[test]
+    fn only_pv_2022_measurement() {
+        assert_eq!(
+            poll_automated_measurements(
+                &SharedState::default(),
+                &P1Reader::Cmd("echo A".to_string()),
+                TIMEOUT,
+                0,
+                &P1ObisConfig::default(),
+                FAKE_PV_2022,
+                pv2022::PV_2022_JSON_PATH,
+                TIMEOUT,
+                pv2022::DEFAULT_DIVISOR,
+                None,
+                true
+            ),
+            (None, Some(7439.043), None)
+        )
+    }
+
+    #[test]
+    fn only_p1_measurement() {
+        assert_eq!(
+            poll_automated_measurements(
+                &SharedState::default(),
+                &P1Reader::Cmd(FAKE_P1.to_string()),
+                TIMEOUT,
+                0,
+                &P1ObisConfig::default(),
+                "echo B",
+                pv2022::PV_2022_JSON_PATH,
+                TIMEOUT,
+                pv2022::DEFAULT_DIVISOR,
+                None,
+                true
+            ),
+            (
+                Some(CompleteP1Measurement {
+                    timestamp: Utc.with_ymd_and_hms(2024, 10, 24, 22, 0, 0).unwrap(),
+                    peak_hour_consumption: 2654.919,
+                    off_hour_consumption: 2420.293,
+                    peak_hour_injection: 6254.732,
+                    off_hour_injection: 2457.202,
+                    power_failures: None,
+                    gas_m3: None,
+                    water_m3: None,
+                }),
+                None,
+                None
+            )
+        )
+    }
+
+    #[test]
+    fn both_measurements() {
+        assert_eq!(
+            poll_automated_measurements(
+                &SharedState::default(),
+                &P1Reader::Cmd(FAKE_P1.to_string()),
+                TIMEOUT,
+                0,
+                &P1ObisConfig::default(),
+                FAKE_PV_2022,
+                pv2022::PV_2022_JSON_PATH,
+                TIMEOUT,
+                pv2022::DEFAULT_DIVISOR,
+                None,
+                true
+            ),
+            (
+                Some(CompleteP1Measurement {
+                    timestamp: Utc.with_ymd_and_hms(2024, 10, 24, 22, 0, 0).unwrap(),
+                    peak_hour_consumption: 2654.919,
+                    off_hour_consumption: 2420.293,
+                    peak_hour_injection: 6254.732,
+                    off_hour_injection: 2457.202,
+                    power_failures: None,
+                    gas_m3: None,
+                    water_m3: None,
+                }),
+                Some(7439.043),
+                None
+            )
+        )
+    }
+
+    #[test]
+    fn pv_2012_measurement_when_configured() {
+        assert_eq!(
+            poll_automated_measurements(
+                &SharedState::default(),
+                &P1Reader::Cmd("echo A".to_string()),
+                TIMEOUT,
+                0,
+                &P1ObisConfig::default(),
+                FAKE_PV_2022,
+                pv2022::PV_2022_JSON_PATH,
+                TIMEOUT,
+                pv2022::DEFAULT_DIVISOR,
+                Some((FAKE_PV_2022, "0199-xxxxx9BD")),
+                true
+            ),
+            (None, Some(7439.043), Some(7439.043))
+        );
+        assert_eq!(
+            poll_automated_measurements(
+                &SharedState::default(),
+                &P1Reader::Cmd("echo A".to_string()),
+                TIMEOUT,
+                0,
+                &P1ObisConfig::default(),
+                "echo B",
+                pv2022::PV_2022_JSON_PATH,
+                TIMEOUT,
+                pv2022::DEFAULT_DIVISOR,
+                Some((FAKE_PV_2022, "0199-xxxxx9BD")),
+                true
+            ),
+            (None, None, Some(7439.043))
+        );
+    }
+
+    #[test]
+    fn set_data_stores_pv_2012() {
+        let mut state = AppState::default();
+        state.set_data(None, Some(1.5), Some(2.5), true);
+        state.set_data(None, None, None, true);
+        assert_eq!(state.data.len(), 1);
+        let last = state.get_last_data().unwrap();
+        assert_eq!(last.pv2022_kWh, Some(1.5));
+        assert_eq!(last.pv2012_kWh, Some(2.5));
+    }
+
+    #[test]
+    fn p1_source_parse() {
+        assert_eq!(
+            P1Source::parse("cmd:cat /tmp/p1_data.txt"),
+            Ok(P1Source::Cmd("cat /tmp/p1_data.txt".to_string()))
+        );
+        assert_eq!(
+            P1Source::parse("file:/dev/ttyUSB0"),
+            Ok(P1Source::File("/dev/ttyUSB0".to_string()))
+        );
+        assert_eq!(P1Source::parse("stdin"), Ok(P1Source::Stdin));
+        assert!(P1Source::parse("cat /tmp/p1_data.txt").is_err());
+    }
+
+    fn read_p1_from(source: &P1Source) -> Option<CompleteP1Measurement> {
+        read_p1_with_retries(
+            &SharedState::default(),
+            &P1Reader::new(source),
+            TIMEOUT,
+            0,
+            &P1ObisConfig::default(),
+            true,
+        )
+    }
+
+    #[test]
+    fn incomplete_p1_measurement_is_retried() {
+        let marker = std::env::temp_dir().join(format!(
+            "axum-meter-readings-p1-retry-{}",
+            std::process::id()
+        ));
+        // Half a telegram the first time, the whole one afterwards
+        let cmd = format!(
+            "if [ -e {marker} ]; then {FAKE_P1} else touch {marker}; echo '0-0:1.0.0(241025000000S)'; echo '1-0:1.8.1(002654.919*kWh)'; fi",
+            marker = marker.display()
+        );
+        let read = |retries| {
+            read_p1_with_retries(
+                &SharedState::default(),
+                &P1Reader::Cmd(cmd.clone()),
+                TIMEOUT,
+                retries,
+                &P1ObisConfig::default(),
+                true,
+            )
+        };
+        assert_eq!(read(0), None);
+        std::fs::remove_file(&marker).unwrap();
+        assert_eq!(read(1).map(|p1| p1.off_hour_injection), Some(2457.202));
+        std::fs::remove_file(&marker).unwrap();
+        // An empty output is not worth retrying
+        assert_eq!(
+            read_p1_with_retries(
+                &SharedState::default(),
+                &P1Reader::Cmd(format!("touch {}", marker.display())),
+                TIMEOUT,
+                3,
+                &P1ObisConfig::default(),
+                true,
+            ),
+            None
+        );
+        std::fs::remove_file(&marker).unwrap();
+    }
+
+    #[test]
+    fn garbled_p1_register_does_not_stop_polling() {
+        let poll = |p1_cmd: &str| {
+            poll_automated_measurements(
+                &SharedState::default(),
+                &P1Reader::Cmd(p1_cmd.to_string()),
+                TIMEOUT,
+                0,
+                &P1ObisConfig::default(),
+                FAKE_PV_2022,
+                pv2022::PV_2022_JSON_PATH,
+                TIMEOUT,
+                pv2022::DEFAULT_DIVISOR,
+                None,
+                true,
+            )
+        };
+        let (p1, pv_2022, _) = poll(&FAKE_P1.replace("002654.919", "2,654.919"));
+        assert_eq!(p1, None);
+        assert!(pv_2022.is_some());
+        let (p1, _, _) = poll(FAKE_P1);
+        assert_eq!(p1.map(|p1| p1.peak_hour_consumption), Some(2654.919));
+    }
+
+    #[test]
+    fn p1_cmd_is_killed_at_the_deadline() {
+        let read = |cmd: &str| {
+            let start = Instant::now();
+            let p1 = read_p1_with_retries(
+                &SharedState::default(),
+                &P1Reader::Cmd(cmd.to_string()),
+                Duration::from_millis(500),
+                0,
+                &P1ObisConfig::default(),
+                true,
+            );
+            assert!(start.elapsed() < Duration::from_secs(3), "{}", cmd);
+            p1
+        };
+        // Writes its telegram but never exits
+        let p1 = read(&format!("{} exec >&-; sleep 30", FAKE_P1));
+        assert_eq!(p1.map(|p1| p1.peak_hour_consumption), Some(2654.919));
+        // A grandchild keeps stdout open
+        assert_eq!(read(&format!("{} sleep 30 & wait", FAKE_P1)), None);
+    }
+
+    #[test]
+    fn p1_cmd_with_non_utf8_noise() {
+        let p1 = read_p1_from(&P1Source::Cmd(format!(
+            "printf '/ISK5\\377\\376\\r\\n'; {}",
+            FAKE_P1
+        )));
+        assert_eq!(p1.map(|p1| p1.off_hour_injection), Some(2457.202));
+    }
+
+    #[test]
+    fn p1_measurement_from_cmd_source() {
+        let p1 = read_p1_from(&P1Source::parse(&format!("cmd:{}", FAKE_P1)).unwrap());
+        assert_eq!(p1.map(|p1| p1.peak_hour_consumption), Some(2654.919));
+    }
+
+    #[test]
+    fn p1_measurement_from_file_source() {
+        let path =
+            std::env::temp_dir().join(format!("axum-meter-readings-p1-{}.txt", std::process::id()));
+        std::fs::write(
+            &path,
+            "0-0:1.0.0(241025000000S)\r\n1-0:1.8.1(002654.919*kWh)\r\n1-0:1.8.2(002420.293*kWh)\r\n1-0:2.8.1(006254.732*kWh)\r\n1-0:2.8.2(002457.202*kWh)\r\n",
+        )
+        .unwrap();
+        let source = P1Source::parse(&format!("file:{}", path.display())).unwrap();
+        let p1 = read_p1_from(&source);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(p1.map(|p1| p1.off_hour_injection), Some(2457.202));
+
+        // A missing file is reported, not a panic
+        assert_eq!(read_p1_from(&source), None);
+    }
+
+    #[test]
+    fn p1_fifo_source_is_read_by_one_long_lived_thread() {
+        let path = std::env::temp_dir().join(format!(
+            "axum-meter-readings-p1-{}.fifo",
+            std::process::id()
+        ));
+        assert!(
+            std::process::Command::new("mkfifo")
+                .arg(&path)
+                .status()
+                .unwrap()
+                .success()
+        );
+        let telegram = |peak: &str| {
+            format!(
+                "/ISK5\r\n0-0:1.0.0(241025000000S)\r\n1-0:1.8.1({}*kWh)\r\n1-0:1.8.2(002420.293*kWh)\r\n1-0:2.8.1(006254.732*kWh)\r\n1-0:2.8.2(002457.202*kWh)\r\n!1234\r\n",
+                peak
+            )
+        };
+        let reader = P1Reader::new(&P1Source::File(path.display().to_string()));
+        let read = || {
+            read_p1_with_retries(
+                &SharedState::default(),
+                &reader,
+                Duration::from_millis(200),
+                0,
+                &P1ObisConfig::default(),
+                true,
+            )
+            .map(|p1| p1.peak_hour_consumption)
+        };
+        // Nobody writes yet: these polls time out without starting readers
+        assert_eq!(read(), None);
+        assert_eq!(read(), None);
+        let mut fifo = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        // Both telegrams arrive in one read, the newest one wins
+        fifo.write_all((telegram("002654.919") + &telegram("002655.000")).as_bytes())
+            .unwrap();
+        fifo.flush().unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(read(), Some(2655.0));
+        fifo.write_all(telegram("002655.100").as_bytes()).unwrap();
+        fifo.flush().unwrap();
+        assert_eq!(read(), Some(2655.1));
+        drop(fifo);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn hung_p1_measurement_times_out() {
+        let start = Instant::now();
+        assert_eq!(
+            poll_automated_measurements(
+                &SharedState::default(),
+                &P1Reader::Cmd("sleep 10".to_string()),
+                Duration::from_secs(1),
+                0,
+                &P1ObisConfig::default(),
+                "echo B",
+                pv2022::PV_2022_JSON_PATH,
+                TIMEOUT,
+                pv2022::DEFAULT_DIVISOR,
+                None,
+                true
+            ),
+            (None, None, None)
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn save_data_flushes_when_more_than_1h_of_data() {
+        let state: SharedState = Arc::new(RwLock::new(AppState::default()));
+        let mut timestamp = Utc.with_ymd_and_hms(2024, 10, 25, 2, 0, 0).unwrap();
+        let db = FakeSqlite::new(&["10\n14"]);
+
+        // Insert the first record
+        save_data(
+            &state,
+            Some(CompleteP1Measurement {
+                timestamp,
+                peak_hour_consumption: 1.0,
+                off_hour_consumption: 2.0,
+                peak_hour_injection: 3.0,
+                off_hour_injection: 4.0,
+                power_failures: None,
+                gas_m3: None,
+                water_m3: None,
+            }),
+            Some(1234.0),
+            None,
+            &db,
+            3600,
+            true,
+            100,
+            "",
+            &RetentionPolicy::default(),
+            false,
+            None,
+            None,
+        );
+
+        assert_eq!(state.read().unwrap().data.len(), 1);
+
+        // more entries, each 2 minutes later than the previous
+        for i in 0..4 {
+            timestamp += chrono::Duration::minutes(2);
+            save_data(
+                &state,
+                Some(CompleteP1Measurement {
+                    timestamp,
+                    peak_hour_consumption: 1.0,
+                    off_hour_consumption: 2.0,
+                    peak_hour_injection: 3.0,
+                    off_hour_injection: 4.0,
+                    power_failures: None,
+                    gas_m3: None,
+                    water_m3: None,
+                }),
+                Some(5678.0 + (i as f64)),
+                None,
+                &db,
+                3600,
+                true,
+                100,
+                "",
+                &RetentionPolicy::default(),
+                false,
+                None,
+                None,
+            );
+        }
+
+        assert_eq!(state.read().unwrap().data.len(), 5);
+        assert!(db.scripts().is_empty());
+
+        // "last" entry, 2h later to make sure that ringbuffer is "flushed"
+        timestamp += chrono::Duration::hours(2);
+        save_data(
+            &state,
+            Some(CompleteP1Measurement {
+                timestamp,
+                peak_hour_consumption: 11.0,
+                off_hour_consumption: 12.0,
+                peak_hour_injection: 13.0,
+                off_hour_injection: 14.0,
+                power_failures: None,
+                gas_m3: None,
+                water_m3: None,
+            }),
+            Some(6789.0),
+            None,
+            &db,
+            3600,
+            true,
+            4,
+            "",
+            &RetentionPolicy::default(),
+            false,
+            None,
+            None,
+        );
+
+        // After flushing, the buffer should have dropped the 4 entries sent
+        let state_ref = state.read().unwrap();
+        assert_eq!(state_ref.data.len(), 2);
+
+        let first_opt = state_ref.get_first_data();
+        let last_opt = state_ref.get_last_data();
+
+        assert!(first_opt.is_some());
+        assert!(last_opt.is_some());
+        let first_opt = first_opt.unwrap();
+        let last_opt = last_opt.unwrap();
+        assert_eq!(first_opt.pv2022_kWh, Some(5681.0));
+        assert_eq!(last_opt.timestamp, timestamp.timestamp());
+        assert_eq!(last_opt.pv2022_kWh, Some(6789.0));
+    }
+
+    fn p1_at(timestamp: chrono::DateTime<Utc>) -> CompleteP1Measurement {
+        CompleteP1Measurement {
+            timestamp,
+            peak_hour_consumption: 1.0,
+            off_hour_consumption: 2.0,
+            peak_hour_injection: 3.0,
+            off_hour_injection: 4.0,
+            power_failures: None,
+            gas_m3: None,
+            water_m3: None,
+        }
+    }
+
+    #[test]
+    fn set_data_drops_decreasing_guarded_fields() {
+        let now = Utc.with_ymd_and_hms(2024, 10, 25, 2, 0, 0).unwrap();
+        let reset_injection = |minutes: i64, value: f64| CompleteP1Measurement {
+            timestamp: now + chrono::Duration::minutes(minutes),
+            peak_hour_injection: value,
+            ..p1_at(now)
+        };
+
+        // Injection excluded: the decreased value is kept
+        let mut state = AppState {
+            decreasing_guard: vec!["peak_conso_kWh".to_string(), "off_conso_kWh".to_string()],
+            ..AppState::default()
+        };
+        state.set_data(Some(p1_at(now)), None, None, true);
+        state.set_data(Some(reset_injection(1, 0.5)), None, None, true);
+        assert_eq!(state.get_last_data().unwrap().peak_inj_kWh, Some(0.5));
+
+        // Injection included, as by default: the decreased value is dropped
+        let mut state = AppState::default();
+        state.set_data(Some(p1_at(now)), Some(3.0), None, true);
+        state.set_data(Some(reset_injection(1, 0.5)), Some(3.5), None, true);
+        // The rest of the record is kept
+        let last = state.get_last_data().unwrap();
+        assert_eq!(last.peak_inj_kWh, None);
+        assert_eq!(last.peak_conso_kWh, Some(p1_at(now).peak_hour_consumption));
+        assert_eq!(last.pv2022_kWh, Some(3.5));
+        // Polling resumes from the new baseline after a meter reset
+        state.set_data(Some(reset_injection(2, 0.6)), None, None, true);
+        state.set_data(Some(reset_injection(3, 0.7)), None, None, true);
+        assert_eq!(
+            timestamps(&state),
+            (0..4).map(|m| now.timestamp() + 60 * m).collect::<Vec<_>>()
+        );
+        assert_eq!(state.get_last_data().unwrap().peak_inj_kWh, Some(0.7));
+
+        // A missing value is not a decrease
+        state
+            .data
+            .push(Data202303::empty(now.timestamp() + 240).with_pv2022(Some(1.0)));
+        assert!(
+            state
+                .decreased_fields(&Data202303::empty(now.timestamp() + 300).with_pv2022(Some(1.0)))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn set_data_clamps_pv2022_regressions_when_asked() {
+        let now = Utc.with_ymd_and_hms(2024, 6, 25, 12, 0, 0).unwrap();
+        let pv_at = |state: &mut AppState, minutes: i64, pv: f64| {
+            let p1 = p1_at(now + chrono::Duration::minutes(minutes));
+            state.set_data(Some(p1), Some(pv), None, true);
+            state.get_last_data().unwrap().pv2022_kWh
+        };
+
+        // Off by default
+        let mut state = AppState::default();
+        assert_eq!(pv_at(&mut state, 0, 10.0), Some(10.0));
+        assert_eq!(pv_at(&mut state, 1, 2.0), Some(2.0));
+
+        let mut state = AppState {
+            pv2022_monotonic_tolerance: Some(0.01),
+            ..AppState::default()
+        };
+        assert_eq!(pv_at(&mut state, 0, 10.0), Some(10.0));
+        // Normal increase
+        assert_eq!(pv_at(&mut state, 1, 10.5), Some(10.5));
+        // Jitter within the tolerance
+        assert_eq!(pv_at(&mut state, 2, 10.495), Some(10.495));
+        // Glitch: the record is kept without the PV value...
+        assert_eq!(pv_at(&mut state, 3, 3.0), None);
+        assert_eq!(timestamps(&state).len(), 4);
+        // ...and the next value is compared to the last non-null one
+        assert_eq!(pv_at(&mut state, 4, 10.4), None);
+        assert_eq!(pv_at(&mut state, 5, 10.6), Some(10.6));
+
+        // A reset on a later day is accepted
+        let reset = 2 * 24 * 60;
+        assert_eq!(pv_at(&mut state, reset, 0.1), Some(0.1));
+        assert_eq!(pv_at(&mut state, reset + 1, 0.2), Some(0.2));
+    }
+
+    #[test]
+    fn snap_to_grid_rounds_to_nearest() {
+        assert_eq!(snap_to_grid(1_000_005, 15), 1_000_005);
+        assert_eq!(snap_to_grid(1_000_011, 15), 1_000_005);
+        assert_eq!(snap_to_grid(1_000_012, 15), 1_000_005);
+        assert_eq!(snap_to_grid(1_000_013, 15), 1_000_020);
+        assert_eq!(snap_to_grid(1_000_010, 10), 1_000_010);
+        assert_eq!(snap_to_grid(1_000_015, 10), 1_000_020);
+        assert_eq!(snap_to_grid(-7, 15), 0);
+        assert_eq!(snap_to_grid(-8, 15), -15);
+        assert_eq!(snap_to_grid(i64::MAX, 15), i64::MAX - i64::MAX % 15);
+    }
+
+    #[test]
+    fn set_data_keeps_p1_without_pv_and_zero_pv() {
+        let now = Utc.with_ymd_and_hms(2024, 10, 25, 2, 0, 0).unwrap();
+        let mut state = AppState::default();
+        state.set_data(Some(p1_at(now)), None, None, true);
+        let record = state.get_last_data().unwrap();
+        assert_eq!(record.pv2022_kWh, None);
+        assert_eq!(record.peak_conso_kWh, Some(1.0));
+
+        state.set_data(
+            Some(p1_at(now + chrono::Duration::minutes(1))),
+            Some(0.0),
+            None,
+            true,
+        );
+        assert_eq!(state.get_last_data().unwrap().pv2022_kWh, Some(0.0));
+        assert_eq!(timestamps(&state).len(), 2);
+
+        state.zero_pv2022_as_missing = true;
+        state.set_data(
+            Some(p1_at(now + chrono::Duration::minutes(2))),
+            Some(0.0),
+            None,
+            true,
+        );
+        let record = state.get_last_data().unwrap();
+        assert_eq!(record.pv2022_kWh, None);
+        assert_eq!(record.peak_conso_kWh, Some(1.0));
+        // Nothing left to store
+        assert!(state.set_data(None, Some(0.0), None, true).is_none());
+        assert_eq!(timestamps(&state).len(), 3);
+        state.set_data(
+            Some(p1_at(now + chrono::Duration::minutes(3))),
+            Some(0.5),
+            None,
+            true,
+        );
+        assert_eq!(state.get_last_data().unwrap().pv2022_kWh, Some(0.5));
+    }
+
+    #[test]
+    fn set_data_snaps_polled_timestamps_when_asked() {
+        let now = Utc.with_ymd_and_hms(2024, 10, 25, 2, 0, 0).unwrap();
+        let mut state = AppState {
+            snap_to_grid_secs: Some(15),
+            ..AppState::default()
+        };
+        for (offset, expected) in [(7, 0), (68, 75), (135, 135)] {
+            let p1 = p1_at(now + chrono::Duration::seconds(offset));
+            state.set_data(Some(p1), None, None, true);
+            assert_eq!(
+                state.get_last_data().unwrap().timestamp,
+                now.timestamp() + expected
+            );
+        }
+    }
+
+    #[test]
+    fn manual_inputs_snap_only_when_asked() {
+        let at = |secs: i64| {
+            Utc.timestamp_opt(1_000_000_000 + secs, 0)
+                .unwrap()
+                .fixed_offset()
+        };
+        let state = RwLock::new(AppState {
+            snap_to_grid_secs: Some(15),
+            ..AppState::default()
+        });
+        let (record, _) =
+            upsert_manual_inputs(&mut state.write().unwrap(), at(8), None, Some(1.0), None)
+                .unwrap();
+        assert_eq!(record.timestamp, 1_000_000_008);
+
+        state.write().unwrap().snap_manual_inputs = true;
+        let (record, _) =
+            upsert_manual_inputs(&mut state.write().unwrap(), at(508), None, Some(1.0), None)
+                .unwrap();
+        assert_eq!(record.timestamp, 1_000_000_515);
+    }
+
+    #[test]
+    fn set_data_survives_extreme_timestamps() {
+        let now = Utc.with_ymd_and_hms(2024, 10, 25, 2, 0, 0).unwrap();
+
+        // Overflow: not recorded
+        let mut state = AppState::default();
+        state
+            .data
+            .push(Data202303::empty(i64::MIN).with_pv2022(Some(1.0)));
+        assert_eq!(state.set_data(Some(p1_at(now)), None, None, true), None);
+        assert_eq!(state.data.len(), 1);
+
+        // Last record in the far future: the 60s gate holds
+        let mut state = AppState::default();
+        state
+            .data
+            .push(Data202303::empty(i64::MAX).with_pv2022(Some(1.0)));
+        assert_eq!(state.set_data(Some(p1_at(now)), None, None, true), None);
+        assert_eq!(state.data.len(), 1);
+    }
+
+    #[test]
+    fn save_data_does_not_flush_on_overflow() {
+        let state: SharedState = Arc::new(RwLock::new(AppState::default()));
+        {
+            let mut w = state.write().unwrap();
+            w.data
+                .push(Data202303::empty(i64::MIN).with_pv2022(Some(1.0)));
+            w.data
+                .push(Data202303::empty(i64::MAX).with_pv2022(Some(1.0)));
+        }
+        let db = FakeSqlite::new(&[]);
+        save_data(
+            &state,
+            None,
+            None,
+            None,
+            &db,
+            3600,
+            true,
+            100,
+            "",
+            &RetentionPolicy {
+                max_age_secs: Some(60),
+                ..RetentionPolicy::default()
+            },
+            false,
+            None,
+            None,
+        );
+        // Not flushed, so not aged out by the retention policy either
+        assert!(db.scripts().is_empty());
+        assert_eq!(timestamps(&state.read().unwrap()), vec![i64::MIN, i64::MAX]);
+
+        // A sane span next to an extreme max age does not panic either
+        let state: SharedState = Arc::new(RwLock::new(AppState::default()));
+        {
+            let mut w = state.write().unwrap();
+            w.data
+                .push(Data202303::empty(-10_000).with_pv2022(Some(1.0)));
+            w.data.push(Data202303::empty(0).with_pv2022(Some(1.0)));
+        }
+        save_data(
+            &state,
+            None,
+            None,
+            None,
+            &FakeSqlite::new(&["0\n1"]),
+            3600,
+            true,
+            1,
+            "",
+            &RetentionPolicy {
+                max_age_secs: Some(i64::MAX),
+                ..RetentionPolicy::default()
+            },
+            false,
+            None,
+            None,
+        );
+        assert_eq!(timestamps(&state.read().unwrap()), vec![0]);
+    }
+
+    #[test]
+    fn save_data_in_dry_run_keeps_all_data() {
+        let state: SharedState = Arc::new(RwLock::new(AppState::default()));
+        let mut timestamp = Utc.with_ymd_and_hms(2024, 10, 25, 2, 0, 0).unwrap();
+        let db = FakeSqlite::new(&[]);
+
+        for i in 0..6 {
+            save_data(
+                &state,
+                Some(CompleteP1Measurement {
+                    timestamp,
+                    peak_hour_consumption: 1.0,
+                    off_hour_consumption: 2.0,
+                    peak_hour_injection: 3.0,
+                    off_hour_injection: 4.0,
+                    power_failures: None,
+                    gas_m3: None,
+                    water_m3: None,
+                }),
+                Some(5678.0 + (i as f64)),
+                None,
+                &db,
+                3600,
+                true,
+                100,
+                "",
+                &RetentionPolicy {
+                    max_age_secs: Some(60),
+                    ..RetentionPolicy::default()
+                },
+                true,
+                None,
+                None,
+            );
+            timestamp += chrono::Duration::hours(1);
+        }
+
+        // Every call after the 2nd would have flushed without dry run
+        assert!(db.scripts().is_empty());
+        let state_ref = state.read().unwrap();
+        assert_eq!(state_ref.data.len(), 6);
+        assert_eq!(
+            state_ref.get_first_data().unwrap().timestamp,
+            Utc.with_ymd_and_hms(2024, 10, 25, 2, 0, 0)
+                .unwrap()
+                .timestamp()
+        );
+    }
+
+    #[test]
+    fn save_data_keeps_cadence_while_flush_is_pending() {
+        let state: SharedState = Arc::new(RwLock::new(AppState::default()));
+        {
+            let mut w = state.write().unwrap();
+            for ts in [0, 1200, 2400, 3600] {
+                w.data.push(Data202303::empty(ts).with_pv2022(Some(1.0)));
+            }
+        }
+        let (sender, batches) = mpsc::channel();
+        let flush_ref = Arc::clone(&state);
+        let flusher = thread::spawn(move || {
+            run_flusher(
+                &flush_ref,
+                &batches,
+                &FakeSqlite::new(&["4\n7"]).with_delay(Duration::from_secs(1)),
+                "",
+                None,
+            )
+        });
+        for _ in 0..3 {
+            let start = Instant::now();
+            let result = save_data(
+                &state,
+                None,
+                Some(2.0),
+                None,
+                &FakeSqlite::new(&[]),
+                3000,
+                false,
+                3,
+                "",
+                &RetentionPolicy::default(),
+                false,
+                None,
+                Some(&sender),
+            );
+            assert_eq!(result, None);
+            assert!(start.elapsed() < Duration::from_millis(500));
+            let state_ref = state.read().unwrap();
+            assert!(state_ref.flush_pending);
+            // Nothing is dropped before the flush task confirms
+            assert_eq!(state_ref.data.peek_first(|r| r.timestamp), Some(0));
+        }
+        drop(sender);
+        flusher.join().unwrap();
+        let state_ref = state.read().unwrap();
+        assert!(!state_ref.flush_pending);
+        assert_eq!(state_ref.last_flush_rows, 3);
+        assert_eq!(state_ref.data.peek_first(|r| r.timestamp), Some(3600));
+    }
+
+    #[test]
+    fn save_data_drops_all_sent_rows_even_without_net_change() {
+        let state: SharedState = Arc::new(RwLock::new(AppState::default()));
+        {
+            let mut w = state.write().unwrap();
+            for ts in [0, 1200, 2400, 3600] {
+                w.data.push(Data202303::empty(ts).with_pv2022(Some(1.0)));
+            }
+        }
+        // Every row already existed (e.g. re-sent after a crash)
+        let result = save_data(
+            &state,
+            None,
+            None,
+            None,
+            &FakeSqlite::new(&["4\n4"]),
+            3000,
+            true,
+            3,
+            "",
+            &RetentionPolicy::default(),
+            false,
+            None,
+            None,
+        );
+        assert_eq!(result, Some((3, 0)));
+        assert_eq!(timestamps(&state.read().unwrap()), vec![3600]);
+    }
+
+    #[test]
+    fn save_data_drains_in_batches() {
+        let state: SharedState = Arc::new(RwLock::new(AppState::default()));
+        {
+            let mut w = state.write().unwrap();
+            for ts in [0, 600, 1200, 1800, 2400, 3000, 3600, 4200] {
+                w.data.push(Data202303::empty(ts).with_pv2022(Some(1.0)));
+            }
+        }
+        let db = FakeSqlite::new(&["0\n3", "3\n6"]);
+        let flush = || {
+            save_data(
+                &state,
+                None,
+                None,
+                None,
+                &db,
+                3000,
+                true,
+                3,
+                "",
+                &RetentionPolicy::default(),
+                false,
+                None,
+                None,
+            )
+        };
+        assert_eq!(flush(), Some((3, 3)));
+        assert_eq!(
+            timestamps(&state.read().unwrap()),
+            vec![1800, 2400, 3000, 3600, 4200]
+        );
+        assert_eq!(flush(), None);
+        // Only once the buffer spans more than dump_interval again
+        state
+            .write()
+            .unwrap()
+            .data
+            .push(Data202303::empty(4900).with_pv2022(Some(1.0)));
+        assert_eq!(flush(), Some((3, 3)));
+        assert_eq!(timestamps(&state.read().unwrap()), vec![3600, 4200, 4900]);
+    }
+
+    #[test]
+    fn save_data_records_last_flush() {
+        let state: SharedState = Arc::new(RwLock::new(AppState::default()));
+        {
+            let mut w = state.write().unwrap();
+            for ts in [0, 1200, 2400, 3600] {
+                w.data.push(Data202303::empty(ts).with_pv2022(Some(1.0)));
+            }
+        }
+        assert_eq!(state.read().unwrap().last_flush_ts, None);
+        let before = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let result = save_data(
+            &state,
+            None,
+            None,
+            None,
+            &FakeSqlite::new(&["1\n4"]),
+            3000,
+            true,
+            3,
+            "",
+            &RetentionPolicy::default(),
+            false,
+            None,
+            None,
+        );
+        assert_eq!(result, Some((3, 3)));
+        let state = state.read().unwrap();
+        assert!(state.last_flush_ts.is_some_and(|ts| ts >= before));
+        assert_eq!(state.last_flush_rows, 3);
+    }
+
+    #[test]
+    fn save_data_keeps_rows_when_insert_fails() {
+        let state: SharedState = Arc::new(RwLock::new(AppState::default()));
+        {
+            let mut w = state.write().unwrap();
+            for ts in [0, 1200, 2400, 3600] {
+                w.data.push(Data202303::empty(ts).with_pv2022(Some(1.0)));
+            }
+        }
+        let result = save_data(
+            &state,
+            None,
+            None,
+            None,
+            &FakeSqlite::new(&["Error: database is locked"]),
+            3000,
+            true,
+            3,
+            "",
+            &RetentionPolicy::default(),
+            false,
+            None,
+            None,
+        );
+        assert_eq!(result, None);
+        assert_eq!(state.read().unwrap().data.len(), 4);
+    }
+
+    #[test]
+    fn save_data_stops_thinning_above_high_water_mark() {
+        let state: SharedState = Arc::new(RwLock::new(AppState {
+            high_water_mark: Some(2),
+            ..AppState::default()
+        }));
+        let retention = RetentionPolicy {
+            max_records: Some(4),
+            max_age_secs: None,
+        };
+        let now = Utc.with_ymd_and_hms(2024, 10, 25, 2, 0, 0).unwrap();
+        // Never answers with the row counts
+        let db = FakeSqlite::new(&[]);
+        let save = |minutes| {
+            save_data(
+                &state,
+                Some(p1_at(now + chrono::Duration::minutes(minutes))),
+                None,
+                None,
+                &db,
+                0,
+                true,
+                100,
+                "",
+                &retention,
+                false,
+                None,
+                None,
+            )
+        };
+        for minutes in 0..3 {
+            assert_eq!(save(minutes), None);
+            assert_eq!(state.read().unwrap().degraded, minutes >= 2);
+        }
+        // The flushes keep failing, yet nothing is dropped beyond max_records
+        for minutes in 3..6 {
+            assert_eq!(save(minutes), None);
+        }
+        assert_eq!(state.read().unwrap().data.len(), 6);
+        assert!(state.read().unwrap().degraded);
+
+        // Without a mark, the failed flushes still hold retention back
+        state.write().unwrap().high_water_mark = None;
+        state.write().unwrap().degraded = false;
+        save(6);
+        assert_eq!(state.read().unwrap().data.len(), 7);
+    }
+
+    #[test]
+    fn save_data_skips_retention_after_failed_flush() {
+        let state: SharedState = Arc::new(RwLock::new(AppState::default()));
+        {
+            let mut w = state.write().unwrap();
+            for ts in [0, 1200, 2400, 3600] {
+                w.data.push(Data202303::empty(ts).with_pv2022(Some(1.0)));
+            }
+        }
+        let retention = RetentionPolicy {
+            max_age_secs: Some(600),
+            ..RetentionPolicy::default()
+        };
+        let db = FakeSqlite::new(&["Error: database is locked", "0\n3"]);
+        let save = || {
+            save_data(
+                &state, None, None, None, &db, 3000, true, 3, "", &retention, false, None, None,
+            )
+        };
+        assert_eq!(save(), None);
+        assert_eq!(
+            timestamps(&state.read().unwrap()),
+            vec![0, 1200, 2400, 3600]
+        );
+        // Once the database is back, the saved rows go and retention resumes
+        state
+            .write()
+            .unwrap()
+            .data
+            .push(Data202303::empty(4200).with_pv2022(Some(1.0)));
+        assert_eq!(save(), Some((3, 3)));
+        assert_eq!(timestamps(&state.read().unwrap()), vec![3600, 4200]);
+    }
+
+    #[test]
+    fn save_data_keeps_backlog_older_than_max_age() {
+        let state: SharedState = Arc::new(RwLock::new(AppState::default()));
+        {
+            let mut w = state.write().unwrap();
+            for ts in [0, 1200, 2400, 3600, 4800] {
+                w.data.push(Data202303::empty(ts).with_pv2022(Some(1.0)));
+            }
+        }
+        let retention = RetentionPolicy {
+            max_age_secs: Some(600),
+            ..RetentionPolicy::default()
+        };
+        let db = FakeSqlite::new(&["0\n1", "1\n2"]);
+        let save = || {
+            save_data(
+                &state, None, None, None, &db, 3000, true, 1, "", &retention, false, None, None,
+            )
+        };
+        // Only one row per flush, the others wait for the next ones
+        assert_eq!(save(), Some((1, 1)));
+        assert_eq!(
+            timestamps(&state.read().unwrap()),
+            vec![1200, 2400, 3600, 4800]
+        );
+        assert_eq!(save(), Some((1, 1)));
+        assert_eq!(timestamps(&state.read().unwrap()), vec![2400, 3600, 4800]);
+    }
+
+    #[test]
+    fn save_data_is_degraded_only_while_flushes_fail() {
+        let state: SharedState = Arc::new(RwLock::new(AppState {
+            high_water_mark: Some(2),
+            ..AppState::default()
+        }));
+        let db = FakeSqlite::new(&["Error: database is locked", "0\n7"]);
+        let save = || {
+            save_data(
+                &state,
+                None,
+                None,
+                None,
+                &db,
+                3000,
+                true,
+                100,
+                "",
+                &RetentionPolicy::default(),
+                false,
+                None,
+                None,
+            )
+        };
+        // Waiting for dump_interval to elapse is not a failure
+        for ts in [0, 600, 1200, 1800] {
+            state
+                .write()
+                .unwrap()
+                .data
+                .push(Data202303::empty(ts).with_pv2022(Some(1.0)));
+        }
+        assert_eq!(save(), None);
+        assert!(!state.read().unwrap().degraded);
+
+        for ts in [2400, 3000, 3600] {
+            state
+                .write()
+                .unwrap()
+                .data
+                .push(Data202303::empty(ts).with_pv2022(Some(1.0)));
+        }
+        assert_eq!(save(), None);
+        assert_eq!(state.read().unwrap().flush_failures, 1);
+        assert!(state.read().unwrap().degraded);
+
+        assert_eq!(save(), Some((7, 7)));
+        assert_eq!(state.read().unwrap().flush_failures, 0);
+        assert!(!state.read().unwrap().degraded);
+    }
+
+    #[test]
+    fn with_capacity_sizes_the_buffer() {
+        let mut state = AppState::with_capacity(2000);
+        for ts in 0..1500 {
+            state
+                .data
+                .push(Data202303::empty(ts).with_pv2022(Some(1.0)));
+        }
+        assert_eq!(state.data.len(), 1500);
+        assert_eq!(state.data.get_capacity(), 2000);
+    }
+
+    #[test]
+    fn seed_from_db_fills_empty_buffer() {
+        let mut state = AppState::default();
+        state.data.resize(2);
+        let rows = "1695485160||3579.5||||||\n1695485100|50621.3|||||||\n";
+        assert_eq!(state.seed_from_db(&FakeSqlite::new(&[rows])), Ok(2));
+        assert_eq!(timestamps(&state), vec![1695485100, 1695485160]);
+        assert_eq!(state.get_last_data().unwrap().pv2022_kWh, Some(3579.5));
+
+        // Records already buffered are not mixed with older ones
+        assert_eq!(state.seed_from_db(&FakeSqlite::new(&[rows])), Ok(0));
+        assert_eq!(timestamps(&state), vec![1695485100, 1695485160]);
+
+        let mut state = AppState::default();
+        assert!(
+            state
+                .seed_from_db(&FakeSqlite::new(&["Error: no such table"]))
+                .is_err()
+        );
+        assert!(state.data.is_empty());
+    }
+
+    #[test]
+    fn save_data_neither_resends_nor_drops_seeded_rows() {
+        let seeded = "600||1.0||||||\n0||1.0||||||\n";
+        let flush = |flusher: Option<&mpsc::Sender<Vec<Data202303>>>| {
+            let state: SharedState = Arc::new(RwLock::new(AppState::default()));
+            {
+                let mut w = state.write().unwrap();
+                assert_eq!(w.seed_from_db(&FakeSqlite::new(&[seeded])), Ok(2));
+                assert_eq!(w.persisted.len(), 2);
+                for ts in [1200, 2400, 4300] {
+                    w.data.push(Data202303::empty(ts).with_pv2022(Some(1.0)));
+                }
+                assert_eq!(w.unflushed_len(), 3);
+            }
+            let db = FakeSqlite::new(&["2\n5"]);
+            let result = save_data(
+                &state,
+                None,
+                None,
+                None,
+                &db,
+                3000,
+                true,
+                10,
+                "",
+                &RetentionPolicy::default(),
+                false,
+                None,
+                flusher,
+            );
+            (state, db, result)
+        };
+
+        let (state, db, result) = flush(None);
+        assert_eq!(result, Some((3, 3)));
+        let scripts = db.scripts();
+        assert_eq!(scripts.len(), 1);
+        assert_eq!(scripts[0].matches("INSERT").count(), 3);
+        assert!(!scripts[0].contains("VALUES (0,"));
+        assert!(!scripts[0].contains("VALUES (600,"));
+        assert_eq!(timestamps(&state.read().unwrap()), vec![0, 600]);
+        assert_eq!(state.read().unwrap().unflushed_len(), 0);
+
+        // Same through the flush task
+        let (tx, rx) = mpsc::channel();
+        let (state, _, result) = flush(Some(&tx));
+        assert_eq!(result, None);
+        drop(tx);
+        let db = FakeSqlite::new(&["2\n5"]);
+        run_flusher(&state, &rx, &db, "", None);
+        assert_eq!(db.scripts()[0].matches("INSERT").count(), 3);
+        assert!(!db.scripts()[0].contains("VALUES (600,"));
+        assert_eq!(timestamps(&state.read().unwrap()), vec![0, 600]);
+    }
+
+    #[test]
+    fn save_data_flushes_manual_inputs_among_seeded_rows() {
+        let at = |secs: i64| DateTime::from_timestamp(secs, 0).unwrap().fixed_offset();
+        let state: SharedState = Arc::new(RwLock::new(AppState::default()));
+        {
+            let mut w = state.write().unwrap();
+            let seeded = "1200||1.0||||||\n600||1.0||||||\n0||1.0||||||\n";
+            assert_eq!(w.seed_from_db(&FakeSqlite::new(&[seeded])), Ok(3));
+            // Backfilled between two seeded rows, and merged into one of them
+            assert!(save_manual_inputs(&mut w, at(300), None, Some(1.0), None).is_ok());
+            assert!(save_manual_inputs(&mut w, at(600), None, None, Some(2.0)).is_ok());
+            w.data.push(Data202303::empty(4300).with_pv2022(Some(1.0)));
+            assert_eq!(w.unflushed_len(), 3);
+        }
+        let db = FakeSqlite::new(&["3\n5"]);
+        let result = save_data(
+            &state,
+            None,
+            None,
+            None,
+            &db,
+            3000,
+            true,
+            10,
+            "",
+            &RetentionPolicy::default(),
+            false,
+            None,
+            None,
+        );
+        assert_eq!(result, Some((3, 2)));
+        let script = &db.scripts()[0];
+        assert_eq!(script.matches("INSERT").count(), 3);
+        assert!(script.contains("VALUES (300, NULL, NULL, NULL, NULL, NULL, NULL, 1, NULL);"));
+        assert!(script.contains("VALUES (600, NULL, 1, NULL, NULL, NULL, NULL, NULL, 2);"));
+        assert!(script.contains("VALUES (4300,"));
+        assert_eq!(timestamps(&state.read().unwrap()), vec![0, 1200]);
+    }
+
+    #[test]
+    fn save_manual_inputs_refuses_ancient_record_when_full() {
+        let at = |secs: i64| DateTime::from_timestamp(secs, 0).unwrap().fixed_offset();
+        let state = RwLock::new(AppState::default());
+        state.write().unwrap().data.resize(3);
+        for ts in [1000, 1200, 1400] {
+            state
+                .write()
+                .unwrap()
+                .data
+                .push(Data202303::empty(ts).with_pv2022(Some(1.0)));
+        }
+        assert!(
+            save_manual_inputs(&mut state.write().unwrap(), at(500), None, Some(1.0), None)
+                .is_err()
+        );
+        assert_eq!(timestamps(&state.read().unwrap()), vec![1000, 1200, 1400]);
+        // Close enough to enrich the oldest record
+        assert_eq!(
+            save_manual_inputs(&mut state.write().unwrap(), at(990), None, Some(1.0), None)
+                .map(|r| (r.timestamp, r.gas_m3)),
+            Ok((1000, Some(1.0)))
+        );
+        // Newer records still evict the oldest one
+        save_manual_inputs(&mut state.write().unwrap(), at(1300), None, Some(2.0), None).unwrap();
+        let state = state.read().unwrap();
+        assert_eq!(timestamps(&state), vec![1200, 1300, 1400]);
+    }
+
+    #[test]
+    fn insertion_index_front_middle_and_end() {
+        let mut state = AppState::default();
+        assert_eq!(state.insertion_index(1000), 0);
+        for ts in [1000, 1060, 1120] {
+            state
+                .data
+                .push(Data202303::empty(ts).with_pv2022(Some(1.0)));
+        }
+        assert_eq!(state.insertion_index(900), 0);
+        assert_eq!(state.insertion_index(1000), 0);
+        assert_eq!(state.insertion_index(1001), 1);
+        assert_eq!(state.insertion_index(1119), 2);
+        assert_eq!(state.insertion_index(1120), 2);
+        assert_eq!(state.insertion_index(1121), 3);
+
+        // Same answers once the buffer wraps around
+        state.data.resize(3);
+        state
+            .data
+            .push(Data202303::empty(1180).with_pv2022(Some(1.0)));
+        assert_eq!(state.insertion_index(1000), 0);
+        assert_eq!(state.insertion_index(1150), 2);
+        assert_eq!(state.insertion_index(1200), 3);
+    }
+
+    #[test]
+    fn field_fill_rates_over_mixed_buffer() {
+        let mut state = AppState::default();
+        assert!(state.field_fill_rates().iter().all(|(_, r)| *r == 0.0));
+
+        state.data.push(
+            Data202303::empty(1000)
+                .with_pv2022(Some(1.0))
+                .with_gas(Some(1.0)),
+        );
+        state
+            .data
+            .push(Data202303::empty(1060).with_pv2022(Some(1.0)));
+        state.data.push(Data202303::empty(1120).with_gas(Some(2.0)));
+        state
+            .data
+            .push(Data202303::empty(1180).with_water(Some(3.0)));
+        let rates = state.field_fill_rates();
+        assert_eq!(rates[0], ("pv2012_kWh", 0.0));
+        assert_eq!(rates[1], ("pv2022_kWh", 0.5));
+        assert_eq!(rates[2], ("peak_conso_kWh", 0.0));
+        assert_eq!(rates[6], ("gas_m3", 0.5));
+        assert_eq!(rates[7], ("water_m3", 0.25));
+    }
+
+    #[test]
+    fn save_data_appends_to_sql_dump_file() {
+        let state: SharedState = Arc::new(RwLock::new(AppState::default()));
+        {
+            let mut w = state.write().unwrap();
+            for ts in [0, 1200, 2400, 3600] {
+                w.data.push(Data202303::empty(ts).with_pv2022(Some(1.0)));
+            }
+        }
+        let path = std::env::temp_dir().join(format!(
+            "axum-meter-readings-dump-{}.sql",
+            std::process::id()
+        ));
+        fs::write(&path, "-- earlier dump\n").unwrap();
+        let db = FakeSqlite::new(&[]);
+        let result = save_data(
+            &state,
+            None,
+            None,
+            None,
+            &db,
+            3000,
+            true,
+            3,
+            "",
+            &RetentionPolicy::default(),
+            false,
+            Some(&path),
+            None,
+        );
+        assert_eq!(result, Some((3, 3)));
+        assert_eq!(timestamps(&state.read().unwrap()), vec![3600]);
+        let expected = build_insert_many_sql(
+            [
+                Data202303::empty(0).with_pv2022(Some(1.0)),
+                Data202303::empty(1200).with_pv2022(Some(1.0)),
+                Data202303::empty(2400).with_pv2022(Some(1.0)),
+            ]
+            .iter(),
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("-- earlier dump\n{}\n", expected)
+        );
+        fs::remove_file(&path).unwrap();
+
+        // An unwritable file keeps the rows buffered
+        state
+            .write()
+            .unwrap()
+            .data
+            .push(Data202303::empty(7200).with_pv2022(Some(1.0)));
+        let result = save_data(
+            &state,
+            None,
+            None,
+            None,
+            &db,
+            3000,
+            true,
+            3,
+            "",
+            &RetentionPolicy::default(),
+            false,
+            Some(Path::new("/nonexistent-dir/dump.sql")),
+            None,
+        );
+        assert_eq!(result, None);
+        assert_eq!(timestamps(&state.read().unwrap()), vec![3600, 7200]);
+        assert!(db.scripts().is_empty());
     }
-    if let (Some(first), Some(last)) = (state.get_first_data(), state.get_last_data()) {
-        if last.timestamp - first.timestamp > dump_interval {
-            match insert_many_data_202303(
-                sql_cmd,
-                freeze(&state.data).iter_limited(insert_batch_size),
-            ) {
-                Ok(n) if n > 0 => state.data.drop_first(n),
-                Ok(_) => println!("No error but no data saved either"),
-                Err(e) => println!("Error saving data: {}", e),
-            }
+
+    #[test]
+    fn to_insert_sql_has_one_insert_per_record() {
+        let mut state = AppState::default();
+        assert_eq!(state.to_insert_sql(), None);
+        for ts in [1000, 1060, 1120] {
+            state
+                .data
+                .push(Data202303::empty(ts).with_pv2022(Some(1.0)));
         }
+        // Including the records already in the database
+        state
+            .persisted
+            .insert(1000, Data202303::empty(1000).with_pv2022(Some(1.0)));
+        let sql = state.to_insert_sql().unwrap();
+        assert_eq!(sql.matches("INSERT INTO data_202303").count(), 3);
+        assert!(sql.contains("VALUES (1000, NULL, 1, "));
+        assert!(sql.contains("VALUES (1060, NULL, 1, "));
+        assert_eq!(state.data.len(), 3);
     }
-}
 
-pub fn save_manual_inputs(
-    state: &mut RwLockWriteGuard<'_, AppState>,
-    timestamp: DateTime<FixedOffset>,
-    #[allow(non_snake_case)] pv2012_kWh: Option<f64>,
-    gas_m3: Option<f64>,
-    water_m3: Option<f64>,
-) {
-    let len = state.data.len();
-    let timestamp = timestamp.timestamp();
-    match state.data.with_view(
-        |vw: RingBufferView<'_, Data202303>| -> Result<(usize, Data202303), usize> {
-            if len == 0 {
-                return Err(0);
-            }
-            let mut left = 0;
-            let mut right = vw.len();  // *NOT* len-1 because mid-point is biased towards left through integer division
-            let mut best: Option<(usize, Data202303)> = None;
-            let mut mid: usize;
-            let mut old_mid = right;
-            while {
-                mid = (left + right) / 2;
-                if let Some(elt) = vw.at(mid) {
-                    let elt_diff = (elt.timestamp - timestamp).abs();
-                    if elt_diff <= 60 {
-                        if best.as_ref().map_or(
-                            true,
-                            |(
-                                _,
-                                Data202303 {
-                                    timestamp: best_ts, ..
-                                },
-                            )| {
-                                let best_diff = (best_ts - timestamp).abs();
-                                best_diff > elt_diff
-                                    // If same distance, prefer earlier (on account that the human took some time to fill it in manually)
-                                    || ((best_diff == elt_diff) && (best_ts > &elt.timestamp))},
-                        ) {
-                            best = Some((mid, clone_data202303(elt)));
-                        }
-                    }
-                    if elt.timestamp < timestamp {
-                        left = mid;
-                    } else {
-                        right = mid;
-                    }
-                } else {
-                    panic!("Not reached1: we should only look inside the correct range. left={} mid={} right={} len={}", left, mid, right, len);
-                }
-                left < right && mid != old_mid
-            } {
-                old_mid = mid;
-            };
-            if let Some(best) = best {
-                return Ok(best);
-            }
-            if let Some(Data202303 { timestamp: ts, .. }) = vw.at(left) {
-                if *ts < timestamp {
-                    Err(left + 1)
-                } else {
-                    Err(left)
-                }
-            } else {
-                panic!("Not reached2: we should only look inside the correct range. left={} mid={} right={} len={}", left, mid, right, len);
-            }
-        },
-    ) {
-        Ok((idx, existing_data)) => {
-            state.data.replace(
-                idx,
-                Data202303 {
-                    timestamp: existing_data.timestamp,
-                    pv2012_kWh,
-                    pv2022_kWh: existing_data.pv2022_kWh,
-                    peak_conso_kWh: existing_data.peak_conso_kWh,
-                    off_conso_kWh: existing_data.off_conso_kWh,
-                    peak_inj_kWh: existing_data.peak_inj_kWh,
-                    off_inj_kWh: existing_data.off_inj_kWh,
-                    gas_m3,
-                    water_m3,
-                },
+    #[test]
+    fn snapshot_round_trip_of_wrapped_buffer() {
+        let mut state = AppState {
+            data: ringbuffer::new(4),
+            ..AppState::default()
+        };
+        for ts in [1000, 1060, 1120, 1180, 1240, 1300] {
+            state.data.push(
+                Data202303::empty(ts)
+                    .with_pv2022(Some(ts as f64 / 7.0))
+                    .with_gas((ts % 120 == 0).then_some(-0.0)),
             );
         }
-        Err(idx) => {
-            state.data.insert_at(
-                idx,
-                Data202303 {
-                    timestamp,
-                    pv2012_kWh,
-                    pv2022_kWh: None,
-                    peak_conso_kWh: None,
-                    off_conso_kWh: None,
-                    peak_inj_kWh: None,
-                    off_inj_kWh: None,
-                    gas_m3,
-                    water_m3,
-                },
-            );
+        let path = std::env::temp_dir().join(format!(
+            "axum-meter-readings-snapshot-{}.bin",
+            std::process::id()
+        ));
+        state.save_snapshot(&path).unwrap();
+        let loaded = AppState::load_snapshot(&path, 4).unwrap();
+        assert_eq!(loaded.data, state.data);
+        assert_eq!(loaded.data.get_capacity(), 4);
+        // Smaller capacity keeps the newest records
+        let loaded = AppState::load_snapshot(&path, 2).unwrap();
+        assert_eq!(timestamps(&loaded), vec![1240, 1300]);
+        // Truncated or foreign files are rejected
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(AppState::load_snapshot(&path, 4).is_err());
+        fs::write(&path, b"nonsense").unwrap();
+        assert!(AppState::load_snapshot(&path, 4).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replace_by_timestamp_hit() {
+        let mut state = AppState::default();
+        for ts in [1000, 1060, 1120, 1180] {
+            state
+                .data
+                .push(Data202303::empty(ts).with_pv2022(Some(1.0)));
         }
+        let mut new = Data202303::empty(1120).with_pv2022(Some(1.0));
+        new.gas_m3 = Some(12.5);
+        let old = state.replace_by_timestamp(1120, new);
+        assert_eq!(old, Some(Data202303::empty(1120).with_pv2022(Some(1.0))));
+        assert_eq!(timestamps(&state), vec![1000, 1060, 1120, 1180]);
+        assert_eq!(
+            state.data.with_view(|vw| vw.at(2).unwrap().gas_m3),
+            Some(12.5)
+        );
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use chrono::{Duration, TimeZone, Utc};
-    const FAKE_PV_2022: &str = "echo '{\"result\":{\"0199-xxxxx9BD\":{\"6800_08822000\":{\"1\":[{\"validVals\":[9401,9402,9403,9404,9405],\"val\":[{\"tag\":9404}]}]},\"6800_10821E00\":{\"1\":[{\"val\":\"SN: xxxxxxx245\"}]},\"6800_08811F00\":{\"1\":[{\"validVals\":[1129,1130],\"val\":[{\"tag\":1129}]}]},\"6180_08214800\":{\"1\":[{\"val\":[{\"tag\":307}]}]},\"6180_08414900\":{\"1\":[{\"val\":[{\"tag\":886}]}]},\"6180_08522F00\":{\"1\":[{\"val\":[{\"tag\":16777213}]}]},\"6800_088A2900\":{\"1\":[{\"validVals\":[302,9327,9375,9376,9437,19043],\"val\":[{\"tag\":302}]}]},\"6100_40463600\":{\"1\":[{\"val\":null}]},\"6100_40463700\":{\"1\":[{\"val\":null}]},\"6100_40263F00\":{\"1\":[{\"val\":null}]},\"6400_00260100\":{\"1\":[{\"val\":7439043}]},\"6800_00832A00\":{\"1\":[{\"low\":5000,\"high\":5000,\"val\":5000}]},\"6800_008AA200\":{\"1\":[{\"low\":0,\"high\":null,\"val\":0}]},\"6400_00462500\":{\"1\":[{\"val\":null}]},\"6100_00418000\":{\"1\":[{\"val\":null}]},\"6800_08822B00\":{\"1\":[{\"validVals\":[461],\"val\":[{\"tag\":461}]}]},\"6100_0046C200\":{\"1\":[{\"val\":null}]},\"6400_0046C300\":{\"1\":[{\"val\":7459043}]},\"6802_08834500\":{\"1\":[{\"validVals\":[303,1439],\"val\":[{\"tag\":1439}]}]},\"6180_08412800\":{\"1\":[{\"val\":[{\"tag\":16777213}]}]}}}}'";
-    const FAKE_P1: &str = "echo '0-0:1.0.0(241025000000S)'; echo '1-0:1.8.1(002654.919*kWh)'; echo '1-0:1.8.2(002420.293*kWh)'; echo '1-0:2.8.1(006254.732*kWh)'; echo '1-0:2.8.2(002457.202*kWh)';";
     #[test]
-    fn no_measurement() {
+    fn replace_by_timestamp_miss() {
+        let mut state = AppState::default();
         assert_eq!(
-            poll_automated_measurements("echo A", "echo B", true),
-            (None, None)
-        )
+            state.replace_by_timestamp(1000, Data202303::empty(1000).with_pv2022(Some(1.0))),
+            None
+        );
+        for ts in [1000, 1060, 1120] {
+            state
+                .data
+                .push(Data202303::empty(ts).with_pv2022(Some(1.0)));
+        }
+        // Between records, before the first and after the last
+        for ts in [1059, 999, 1121] {
+            assert_eq!(
+                state.replace_by_timestamp(ts, Data202303::empty(ts).with_pv2022(Some(1.0))),
+                None
+            );
+        }
+        assert_eq!(timestamps(&state), vec![1000, 1060, 1120]);
     }
 
     #[test]
-    fn only_pv_2022_measurement() {
+    fn find_nearest_prefers_earlier_on_tie() {
+        let mut state = AppState::default();
+        assert_eq!(state.find_nearest(1000, 60), None);
+        for ts in [1000, 1060, 1120, 1180] {
+            state
+                .data
+                .push(Data202303::empty(ts).with_pv2022(Some(1.0)));
+        }
+        // Equidistant candidates
         assert_eq!(
-            poll_automated_measurements("echo A", FAKE_PV_2022, true),
-            (None, Some(7439.043))
-        )
+            state.find_nearest(1090, 60).map(|(i, r)| (i, r.timestamp)),
+            Some((1, 1060))
+        );
+        assert_eq!(
+            state.find_nearest(1150, 30).map(|(i, r)| (i, r.timestamp)),
+            Some((2, 1120))
+        );
+        // Closer to the later one
+        assert_eq!(
+            state.find_nearest(1091, 60).map(|(i, r)| (i, r.timestamp)),
+            Some((2, 1120))
+        );
+        // Exact match, before the first and after the last
+        assert_eq!(state.find_nearest(1120, 0).map(|(i, _)| i), Some(2));
+        assert_eq!(state.find_nearest(950, 60).map(|(i, _)| i), Some(0));
+        assert_eq!(state.find_nearest(1230, 60).map(|(i, _)| i), Some(3));
+        // Too far
+        assert_eq!(state.find_nearest(1090, 29), None);
+        assert_eq!(state.find_nearest(2000, 60), None);
+        assert_eq!(state.find_nearest(1000, -1), None);
     }
 
     #[test]
-    fn only_p1_measurement() {
+    fn content_hash_follows_content() {
+        let mut a = AppState::default();
+        let mut b = AppState::default();
+        assert_eq!(a.content_hash(), b.content_hash());
+        for ts in [1000, 1060] {
+            a.data.push(Data202303::empty(ts).with_pv2022(Some(1.0)));
+            b.data.push(Data202303::empty(ts).with_pv2022(Some(1.0)));
+        }
+        assert_eq!(a.content_hash(), b.content_hash());
+
+        let before = a.content_hash();
+        a.data.push(Data202303::empty(1120).with_pv2022(Some(1.0)));
+        assert_ne!(a.content_hash(), before);
+
+        // Same timestamps, one value moved to another column
+        b.data.push(Data202303::empty(1120).with_pv2022(Some(1.0)));
+        assert_eq!(a.content_hash(), b.content_hash());
+        b.data.replace(
+            2,
+            Data202303 {
+                pv2022_kWh: None,
+                pv2012_kWh: Some(1.0),
+                ..Data202303::empty(1120).with_pv2022(Some(1.0))
+            },
+        );
+        assert_ne!(a.content_hash(), b.content_hash());
+    }
+
+    #[test]
+    fn snapshot_sorted_of_sorted_buffer() {
+        let mut state = AppState::default();
+        for ts in [1000, 1060, 1060, 1120] {
+            state
+                .data
+                .push(Data202303::empty(ts).with_pv2022(Some(1.0)));
+        }
+        let (records, was_sorted) = state.snapshot_sorted();
+        assert!(was_sorted);
         assert_eq!(
-            poll_automated_measurements(FAKE_P1, "echo B", true),
-            (
-                Some(CompleteP1Measurement {
-                    timestamp: Utc.with_ymd_and_hms(2024, 10, 24, 22, 0, 0).unwrap(),
-                    peak_hour_consumption: 2654.919,
-                    off_hour_consumption: 2420.293,
-                    peak_hour_injection: 6254.732,
-                    off_hour_injection: 2457.202
-                }),
-                None
-            )
-        )
+            records.iter().map(|r| r.timestamp).collect::<Vec<_>>(),
+            vec![1000, 1060, 1060, 1120]
+        );
     }
 
     #[test]
-    fn both_measurements() {
+    fn snapshot_sorted_detects_disorder() {
+        let mut state = AppState::default();
+        for ts in [1000, 1120, 1060, 1180] {
+            state
+                .data
+                .push(Data202303::empty(ts).with_pv2022(Some(1.0)));
+        }
+        let (records, was_sorted) = state.snapshot_sorted();
+        assert!(!was_sorted);
         assert_eq!(
-            poll_automated_measurements(FAKE_P1, FAKE_PV_2022, true),
-            (
-                Some(CompleteP1Measurement {
-                    timestamp: Utc.with_ymd_and_hms(2024, 10, 24, 22, 0, 0).unwrap(),
-                    peak_hour_consumption: 2654.919,
-                    off_hour_consumption: 2420.293,
-                    peak_hour_injection: 6254.732,
-                    off_hour_injection: 2457.202
-                }),
-                Some(7439.043)
-            )
-        )
+            records.iter().map(|r| r.timestamp).collect::<Vec<_>>(),
+            vec![1000, 1060, 1120, 1180]
+        );
+        // The buffer itself is left alone
+        assert_eq!(timestamps(&state), vec![1000, 1120, 1060, 1180]);
     }
 
     #[test]
-    fn save_data_flushes_when_more_than_1h_of_data() {
-        let state: SharedState = Arc::new(RwLock::new(AppState::default()));
-        let mut timestamp = Utc.with_ymd_and_hms(2024, 10, 25, 2, 0, 0).unwrap();
+    fn reorder_if_needed_leaves_sorted_buffer_alone() {
+        let mut state = AppState::default();
+        for ts in [1000, 1060, 1060, 1120] {
+            state
+                .data
+                .push(Data202303::empty(ts).with_pv2022(Some(1.0)));
+        }
+        assert!(!state.reorder_if_needed());
+        assert_eq!(timestamps(&state), vec![1000, 1060, 1060, 1120]);
+    }
 
-        // Insert the first record
-        save_data(
-            &state,
-            Some(CompleteP1Measurement {
-                timestamp,
-                peak_hour_consumption: 1.0,
-                off_hour_consumption: 2.0,
-                peak_hour_injection: 3.0,
-                off_hour_injection: 4.0,
-            }),
-            Some(1234.0),
-            "echo dontcallmenow; exit 123",
-            3600,
-            true,
-            100,
+    #[test]
+    fn reorder_if_needed_sorts_stably() {
+        let mut state = AppState::default();
+        for (ts, gas) in [(1000, 1.0), (1120, 2.0), (1060, 3.0), (1000, 4.0)] {
+            let mut record = Data202303::empty(ts).with_pv2022(Some(1.0));
+            record.gas_m3 = Some(gas);
+            state.data.push(record);
+        }
+        assert!(state.reorder_if_needed());
+        assert_eq!(
+            freeze(&state.data)
+                .into_iter()
+                .map(|r| (r.timestamp, r.gas_m3))
+                .collect::<Vec<_>>(),
+            vec![
+                (1000, Some(1.0)),
+                (1000, Some(4.0)),
+                (1060, Some(3.0)),
+                (1120, Some(2.0)),
+            ]
         );
+        assert!(!state.reorder_if_needed());
+    }
 
-        assert_eq!(state.read().unwrap().data.len(), 1);
+    fn timestamps(state: &AppState) -> Vec<i64> {
+        freeze(&state.data)
+            .into_iter()
+            .map(|r| r.timestamp)
+            .collect()
+    }
 
-        // more entries, each 2 minutes later than the previous
-        for i in 0..4 {
-            timestamp += Duration::minutes(2);
-            save_data(
-                &state,
-                Some(CompleteP1Measurement {
-                    timestamp,
-                    peak_hour_consumption: 1.0,
-                    off_hour_consumption: 2.0,
-                    peak_hour_injection: 3.0,
-                    off_hour_injection: 4.0,
-                }),
-                Some(5678.0 + (i as f64)),
-                &format!("echo dontcallmenow; exit 1{}4", i),
-                3600,
-                true,
-                100,
-            );
+    #[test]
+    fn drain_older_than_returns_leading_records() {
+        let mut state = AppState::default();
+        for ts in [1000, 1060, 1120, 1180] {
+            state
+                .data
+                .push(Data202303::empty(ts).with_pv2022(Some(1.0)));
         }
+        assert_eq!(state.drain_older_than(1000), vec![]);
+        assert_eq!(timestamps(&state), vec![1000, 1060, 1120, 1180]);
+        assert_eq!(
+            state.drain_older_than(1100),
+            vec![
+                Data202303::empty(1000).with_pv2022(Some(1.0)),
+                Data202303::empty(1060).with_pv2022(Some(1.0))
+            ]
+        );
+        assert_eq!(timestamps(&state), vec![1120, 1180]);
+        assert_eq!(state.drain_older_than(1100), vec![]);
+        assert_eq!(timestamps(&state), vec![1120, 1180]);
+    }
 
-        assert_eq!(state.read().unwrap().data.len(), 5);
+    #[test]
+    fn trim_older_than_drops_leading_records() {
+        let mut state = AppState::default();
+        for ts in [1000, 1060, 1120, 1180, 1240] {
+            state.data.push(Data202303 {
+                timestamp: ts,
+                pv2012_kWh: None,
+                pv2022_kWh: Some(ts as f64),
+                peak_conso_kWh: None,
+                off_conso_kWh: None,
+                peak_inj_kWh: None,
+                off_inj_kWh: None,
+                gas_m3: None,
+                water_m3: None,
+            });
+        }
+        state.trim_older_than(999);
+        assert_eq!(timestamps(&state), vec![1000, 1060, 1120, 1180, 1240]);
+        state.trim_older_than(1120);
+        assert_eq!(timestamps(&state), vec![1120, 1180, 1240]);
+        state.trim_older_than(1200);
+        assert_eq!(timestamps(&state), vec![1240]);
+        state.trim_older_than(5000);
+        assert_eq!(timestamps(&state), Vec::<i64>::new());
+    }
 
-        // "last" entry, 2h later to make sure that ringbuffer is "flushed"
-        timestamp += Duration::hours(2);
-        save_data(
-            &state,
-            Some(CompleteP1Measurement {
-                timestamp,
-                peak_hour_consumption: 11.0,
-                off_hour_consumption: 12.0,
-                peak_hour_injection: 13.0,
-                off_hour_injection: 14.0,
-            }),
-            Some(6789.0),
-            "echo 10; echo 14",
-            3600,
-            true,
-            100,
+    /// Buffer holding `timestamps`, all of them already in the database.
+    fn persisted_state(timestamps: &[i64]) -> AppState {
+        let mut state = AppState::default();
+        for ts in timestamps {
+            let record = Data202303::empty(*ts).with_pv2022(Some(1.0));
+            state.persisted.insert(*ts, clone_data202303(&record));
+            state.data.push(record);
+        }
+        state
+    }
+
+    #[test]
+    fn apply_retention_by_age_only() {
+        let mut state = persisted_state(&[1000, 1060, 1120, 1180, 1240]);
+        let policy = RetentionPolicy {
+            max_records: Some(10),
+            max_age_secs: Some(120),
+        };
+        state.apply_retention(1240, &policy);
+        assert_eq!(timestamps(&state), vec![1120, 1180, 1240]);
+        state.apply_retention(i64::MIN, &policy);
+        assert_eq!(timestamps(&state), vec![1120, 1180, 1240]);
+    }
+
+    #[test]
+    fn apply_retention_by_age_keeps_unflushed_records() {
+        let mut state = persisted_state(&[1000, 1060]);
+        for ts in [1120, 1180, 1240] {
+            state
+                .data
+                .push(Data202303::empty(ts).with_pv2022(Some(1.0)));
+        }
+        let policy = RetentionPolicy {
+            max_records: None,
+            max_age_secs: Some(60),
+        };
+        state.apply_retention(1240, &policy);
+        assert_eq!(timestamps(&state), vec![1120, 1180, 1240]);
+        // Not even once they are all older than the limit
+        state.apply_retention(5000, &policy);
+        assert_eq!(timestamps(&state), vec![1120, 1180, 1240]);
+    }
+
+    #[test]
+    fn apply_retention_by_count_only() {
+        let mut state = AppState::default();
+        for ts in [1000, 1060, 1120, 1180, 1240] {
+            state
+                .data
+                .push(Data202303::empty(ts).with_pv2022(Some(1.0)));
+        }
+        let policy = RetentionPolicy {
+            max_records: Some(2),
+            max_age_secs: None,
+        };
+        state.apply_retention(1_000_000, &policy);
+        assert_eq!(timestamps(&state), vec![1180, 1240]);
+    }
+
+    #[test]
+    fn apply_retention_by_age_and_count() {
+        let mut state = persisted_state(&[1000, 1060, 1120, 1180, 1240]);
+        // Age leaves 3, count then keeps 2
+        state.apply_retention(
+            1240,
+            &RetentionPolicy {
+                max_records: Some(2),
+                max_age_secs: Some(150),
+            },
+        );
+        assert_eq!(timestamps(&state), vec![1180, 1240]);
+        // Count leaves 2, age then keeps 1
+        state.apply_retention(
+            1240,
+            &RetentionPolicy {
+                max_records: Some(4),
+                max_age_secs: Some(30),
+            },
         );
+        assert_eq!(timestamps(&state), vec![1240]);
+    }
 
-        // After flushing, the buffer should have dropped 14-10==4 entries
-        let state_ref = state.read().unwrap();
-        assert_eq!(state_ref.data.len(), 2);
+    #[test]
+    fn save_data_keeps_records_beyond_default_capacity_after_resize() {
+        let state: SharedState = Arc::new(RwLock::new(AppState::default()));
+        {
+            let mut w = state.write().unwrap();
+            // As done by POST /admin/capacity/2000
+            w.data.resize(2000);
+            for ts in 0..1500 {
+                w.data.push(Data202303::empty(ts).with_pv2022(Some(1.0)));
+            }
+        }
+        let db = FakeSqlite::new(&[]);
+        let poll = |retention: &RetentionPolicy| {
+            save_data(
+                &state, None, None, None, &db, 3600, true, 100, "", retention, false, None, None,
+            )
+        };
+        assert_eq!(poll(&RetentionPolicy::default()), None);
+        assert_eq!(state.read().unwrap().data.len(), 1500);
+        // An explicit limit still applies
+        poll(&RetentionPolicy {
+            max_records: Some(1440),
+            max_age_secs: None,
+        });
+        assert_eq!(state.read().unwrap().data.len(), 1440);
+        assert!(db.scripts().is_empty());
+    }
 
-        let first_opt = state_ref.get_first_data();
-        let last_opt = state_ref.get_last_data();
+    #[test]
+    fn compact_empty_keeps_zero_values() {
+        let mut state = AppState::default();
+        let mut zero = Data202303::empty(1060).with_pv2022(Some(1.0));
+        zero.pv2022_kWh = Some(0.0);
+        let mut empty = Data202303::empty(1000).with_pv2022(Some(1.0));
+        empty.pv2022_kWh = None;
+        state.data.push(clone_data202303(&empty));
+        state.data.push(zero);
+        empty.timestamp = 1120;
+        state.data.push(empty);
+        state
+            .data
+            .push(Data202303::empty(1180).with_pv2022(Some(1.0)));
+        state.compact_empty();
+        assert_eq!(timestamps(&state), vec![1060, 1180]);
+        assert_eq!(state.get_first_data().unwrap().pv2022_kWh, Some(0.0));
+    }
 
-        assert!(first_opt.is_some());
-        assert!(last_opt.is_some());
-        let first_opt = first_opt.unwrap();
-        let last_opt = last_opt.unwrap();
-        assert_eq!(first_opt.pv2022_kWh, Some(5681.0));
-        assert_eq!(last_opt.timestamp, timestamp.timestamp());
-        assert_eq!(last_opt.pv2022_kWh, Some(6789.0));
+    #[test]
+    fn avg_last_n_skips_none() {
+        let mut state = AppState::default();
+        assert_eq!(state.avg_last_n(3, |r| r.pv2022_kWh), None);
+        for (ts, pv) in [
+            (1000, Some(100.0)),
+            (1060, Some(2.0)),
+            (1120, None),
+            (1180, Some(4.0)),
+            (1240, None),
+        ] {
+            let mut record = Data202303::empty(ts).with_pv2022(Some(1.0));
+            record.pv2022_kWh = pv;
+            state.data.push(record);
+        }
+        assert_eq!(state.avg_last_n(1, |r| r.pv2022_kWh), None);
+        assert_eq!(state.avg_last_n(3, |r| r.pv2022_kWh), Some(4.0));
+        assert_eq!(state.avg_last_n(4, |r| r.pv2022_kWh), Some(3.0));
+        assert_eq!(state.avg_last_n(99, |r| r.pv2022_kWh), Some(106.0 / 3.0));
+        assert_eq!(state.avg_last_n(0, |r| r.pv2022_kWh), None);
+        assert_eq!(state.avg_last_n(5, |r| r.gas_m3), None);
+    }
+
+    #[test]
+    fn median_interval_secs_evenly_spaced() {
+        let mut state = AppState::default();
+        assert_eq!(state.median_interval_secs(), None);
+        state
+            .data
+            .push(Data202303::empty(1000).with_pv2022(Some(1.0)));
+        assert_eq!(state.median_interval_secs(), None);
+        for ts in [1060, 1120, 1180] {
+            state
+                .data
+                .push(Data202303::empty(ts).with_pv2022(Some(1.0)));
+        }
+        assert_eq!(state.median_interval_secs(), Some(60));
+    }
+
+    #[test]
+    fn median_interval_secs_unevenly_spaced() {
+        let mut state = AppState::default();
+        for ts in [1000, 1015, 1075, 1135, 4735] {
+            state
+                .data
+                .push(Data202303::empty(ts).with_pv2022(Some(1.0)));
+        }
+        // Gaps 15, 60, 60, 3600: the long outage does not skew the median
+        assert_eq!(state.median_interval_secs(), Some(60));
+        state
+            .data
+            .push(Data202303::empty(4750).with_pv2022(Some(1.0)));
+        // Gaps 15, 15, 60, 60, 3600
+        assert_eq!(state.median_interval_secs(), Some(60));
+        state
+            .data
+            .push(Data202303::empty(4765).with_pv2022(Some(1.0)));
+        // Gaps 15, 15, 15, 60, 60, 3600
+        assert_eq!(state.median_interval_secs(), Some(37));
     }
 
     #[test]
@@ -467,7 +3390,8 @@ mod tests {
             Some(2.0),
             Some(3.0),
             Some(4.0),
-        );
+        )
+        .unwrap();
         w.data.with_view(|vw| {
             assert_eq!(
                 vw.into_iter().map(clone_data202303).collect::<Vec<_>>(),
@@ -533,6 +3457,8 @@ mod tests {
     }
 
     #[test]
+    // 3.14 is a meter reading, not an approximation of pi
+    #[allow(clippy::approx_constant)]
     fn save_manual_inputs_parameterized() {
         struct Case {
             name: &'static str,
@@ -648,6 +3574,35 @@ mod tests {
                     },
                 ],
             },
+            Case {
+                name: "update_keeps_previous_manual_inputs",
+                existing: vec![Data202303 {
+                    timestamp: 1500,
+                    pv2012_kWh: Some(10.0),
+                    pv2022_kWh: Some(42.0),
+                    peak_conso_kWh: None,
+                    off_conso_kWh: None,
+                    peak_inj_kWh: None,
+                    off_inj_kWh: None,
+                    gas_m3: Some(20.0),
+                    water_m3: None,
+                }],
+                input_ts: 1510,
+                input_pv2012: None,
+                input_gas: Some(21.0),
+                input_water: Some(30.0),
+                expected: vec![Data202303 {
+                    timestamp: 1500,
+                    pv2012_kWh: Some(10.0), // preserved
+                    pv2022_kWh: Some(42.0),
+                    peak_conso_kWh: None,
+                    off_conso_kWh: None,
+                    peak_inj_kWh: None,
+                    off_inj_kWh: None,
+                    gas_m3: Some(21.0), // overwritten
+                    water_m3: Some(30.0),
+                }],
+            },
             Case {
                 name: "update_exact_match (single pre-existing element)",
                 existing: vec![Data202303 {
@@ -950,7 +3905,8 @@ mod tests {
                 case.input_pv2012,
                 case.input_gas,
                 case.input_water,
-            );
+            )
+            .unwrap();
 
             w.data.with_view(|vw| {
                 let got: Vec<_> = vw.into_iter().map(clone_data202303).collect();
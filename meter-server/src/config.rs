@@ -0,0 +1,672 @@
+use std::{env, str::FromStr, time::Duration};
+
+use meter_core::{p1_meter::P1ObisConfig, pv2022};
+
+use crate::{
+    DEFAULT_BASE_PATH, DEFAULT_MAX_BODY_BYTES, LogFormat,
+    blocking_task::{DEFAULT_CAPACITY, DEFAULT_DECREASING_GUARD, P1Source, RetentionPolicy},
+    parse_field_list, parse_log_format,
+};
+
+/// Settings read from the `AXUM_METER_READINGS_*` environment variables.
+#[derive(Debug, PartialEq)]
+pub struct Config {
+    pub bind_addr: String,
+    pub p1_cmd: String,
+    /// Takes precedence over `p1_cmd` when set.
+    pub p1_source: P1Source,
+    pub p1_timeout_secs: u64,
+    /// Extra attempts when a telegram is incomplete.
+    pub p1_retries: u32,
+    pub p1_obis: P1ObisConfig,
+    pub pv_cmd: String,
+    pub pv_2022_json_path: String,
+    pub pv_timeout_secs: u64,
+    pub pv_divisor: f64,
+    pub pv_2012_cmd: Option<String>,
+    pub pv_2012_serial: String,
+    pub sql_cmd: String,
+    /// Seconds of data to accumulate before flushing to the database.
+    pub dump_interval: i64,
+    pub verbose: bool,
+    pub poll_secs: u64,
+    /// Most records kept in memory.
+    pub capacity: usize,
+    pub snapshot_path: Option<String>,
+    pub seed_from_db: bool,
+    pub decreasing_guard: Vec<String>,
+    pub quiet_nothing_to_do: bool,
+    pub high_water_mark: Option<usize>,
+    pub summary_fields: Vec<String>,
+    pub zero_pv2022_as_missing: bool,
+    pub pv2022_monotonic_tolerance: Option<f64>,
+    pub snap_to_grid_secs: Option<i64>,
+    pub snap_manual_inputs: bool,
+    pub check_off_hours: bool,
+    pub insert_batch_size: usize,
+    pub max_age_secs: Option<i64>,
+    /// Most records kept after a flush, up to `capacity`.
+    pub retention_max_records: Option<usize>,
+    pub dry_run: bool,
+    pub sqlite_pragmas: String,
+    pub sql_dump_file: Option<String>,
+    pub log_format: LogFormat,
+    pub async_flush: bool,
+    pub static_dir: Option<String>,
+    pub auth: Option<String>,
+    pub max_body_bytes: usize,
+    pub base_path: String,
+    /// Requests per minute and client, unlimited when unset.
+    pub rate_limit_per_minute: Option<u32>,
+}
+
+impl Config {
+    /// Parse and validate the configuration, then log the effective values.
+    pub fn from_env() -> Result<Config, String> {
+        let config = Config::from_vars(|name| env::var(name).ok())?;
+        config.log();
+        Ok(config)
+    }
+
+    /// Like `from_env` with `var` looking up the variables, without logging
+    /// the effective values.
+    pub fn from_vars<F>(var: F) -> Result<Config, String>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let string = |name: &str, default: &str| var(name).unwrap_or_else(|| default.to_string());
+        let p1_cmd = string("AXUM_METER_READINGS_P1_DATA_CMD", "cat /tmp/p1_data.txt");
+        let p1_source = match var("AXUM_METER_READINGS_P1_SOURCE") {
+            Some(s) => {
+                P1Source::parse(&s).map_err(|e| format!("AXUM_METER_READINGS_P1_SOURCE {}", e))?
+            }
+            None => P1Source::Cmd(p1_cmd.clone()),
+        };
+        let mbus_channel = |name: &str| -> Result<Option<String>, String> {
+            match parse_opt_var::<_, u8>(&var, name)? {
+                Some(channel) if (1..=4).contains(&channel) => {
+                    Ok(Some(P1ObisConfig::mbus_prefix(channel)))
+                }
+                Some(channel) => Err(format!("{}={} is not a channel (1-4)", name, channel)),
+                None => Ok(None),
+            }
+        };
+        let tariffs = if parse_bool_var(&var, "AXUM_METER_READINGS_P1_SWAP_TARIFFS", false)? {
+            P1ObisConfig::default().swapped_tariffs()
+        } else {
+            P1ObisConfig::default()
+        };
+        let p1_obis = P1ObisConfig {
+            gas: mbus_channel("AXUM_METER_READINGS_P1_GAS_CHANNEL")?,
+            water: mbus_channel("AXUM_METER_READINGS_P1_WATER_CHANNEL")?,
+            ..tariffs
+        };
+        let field_list = |name: &str| var(name).map_or_else(Vec::new, |s| parse_field_list(&s));
+        // AXUM_METER_READINGS_FLUSH_BATCH is accepted as an alias
+        let insert_batch_size = match var("AXUM_METER_READINGS_INSERT_BATCH_SIZE") {
+            Some(_) => parse_var(&var, "AXUM_METER_READINGS_INSERT_BATCH_SIZE", 100)?,
+            None => parse_var(&var, "AXUM_METER_READINGS_FLUSH_BATCH", 100)?,
+        };
+        let log_format = match var("AXUM_METER_READINGS_LOG_FORMAT") {
+            Some(s) => parse_log_format(&s).ok_or_else(|| {
+                format!(
+                    "AXUM_METER_READINGS_LOG_FORMAT='{}' is neither text nor json",
+                    s
+                )
+            })?,
+            None => LogFormat::Text,
+        };
+        let config = Config {
+            bind_addr: string("AXUM_METER_READINGS_BIND_ADDR", "127.0.0.1:3000"),
+            p1_cmd,
+            p1_source,
+            p1_timeout_secs: parse_var(&var, "AXUM_METER_READINGS_P1_TIMEOUT_SECS", 10)?,
+            p1_retries: parse_var(&var, "AXUM_METER_READINGS_P1_RETRIES", 2)?,
+            p1_obis,
+            pv_cmd: string("AXUM_METER_READINGS_PV_2022_CMD", "cat /tmp/pv_2022.json"),
+            pv_2022_json_path: string(
+                "AXUM_METER_READINGS_PV_2022_JSON_PATH",
+                pv2022::PV_2022_JSON_PATH,
+            ),
+            pv_timeout_secs: parse_var(&var, "AXUM_METER_READINGS_PV_TIMEOUT_SECS", 10)?,
+            pv_divisor: parse_var(
+                &var,
+                "AXUM_METER_READINGS_PV_DIVISOR",
+                pv2022::DEFAULT_DIVISOR,
+            )?,
+            pv_2012_cmd: var("AXUM_METER_READINGS_PV_2012_CMD"),
+            pv_2012_serial: string("AXUM_METER_READINGS_PV_2012_SERIAL", pv2022::PV_2022_SERIAL),
+            sql_cmd: string("AXUM_METER_READINGS_SQL_CMD", "cat /tmp/sql_cmd.log"),
+            dump_interval: parse_var(&var, "AXUM_METER_READINGS_DUMP_INTERVAL", 3600)?,
+            verbose: parse_bool_var(&var, "AXUM_METER_READINGS_VERBOSE", true)?,
+            poll_secs: parse_var(&var, "AXUM_METER_READINGS_POLLING_PERIOD", 15)?,
+            capacity: parse_var(&var, "AXUM_METER_READINGS_MAX_RECORDS", DEFAULT_CAPACITY)?,
+            snapshot_path: var("AXUM_METER_READINGS_SNAPSHOT_PATH"),
+            seed_from_db: parse_bool_var(&var, "AXUM_METER_READINGS_SEED_FROM_DB", true)?,
+            // Empty to guard no column at all
+            decreasing_guard: var("AXUM_METER_READINGS_DECREASING_GUARD").map_or_else(
+                || DEFAULT_DECREASING_GUARD.map(String::from).to_vec(),
+                |s| parse_field_list(&s),
+            ),
+            quiet_nothing_to_do: parse_bool_var(
+                &var,
+                "AXUM_METER_READINGS_QUIET_NOTHING_TO_DO",
+                false,
+            )?,
+            high_water_mark: parse_opt_var(&var, "AXUM_METER_READINGS_HIGH_WATER_MARK")?,
+            summary_fields: field_list("AXUM_METER_READINGS_SUMMARY_FIELDS"),
+            zero_pv2022_as_missing: parse_bool_var(
+                &var,
+                "AXUM_METER_READINGS_ZERO_PV_AS_MISSING",
+                false,
+            )?,
+            pv2022_monotonic_tolerance: parse_opt_var(
+                &var,
+                "AXUM_METER_READINGS_PV2022_MONOTONIC_TOLERANCE",
+            )?,
+            snap_to_grid_secs: parse_opt_var(&var, "AXUM_METER_READINGS_SNAP_TO_GRID_SECS")?,
+            snap_manual_inputs: parse_bool_var(
+                &var,
+                "AXUM_METER_READINGS_SNAP_MANUAL_INPUTS",
+                false,
+            )?,
+            check_off_hours: parse_bool_var(&var, "AXUM_METER_READINGS_CHECK_OFF_HOURS", false)?,
+            insert_batch_size,
+            max_age_secs: parse_opt_var(&var, "AXUM_METER_READINGS_MAX_AGE_SECS")?,
+            retention_max_records: parse_opt_var(
+                &var,
+                "AXUM_METER_READINGS_RETENTION_MAX_RECORDS",
+            )?,
+            dry_run: parse_bool_var(&var, "AXUM_METER_READINGS_DRY_RUN", false)?,
+            sqlite_pragmas: string("AXUM_METER_READINGS_SQLITE_PRAGMAS", ""),
+            sql_dump_file: var("AXUM_METER_READINGS_SQL_DUMP_FILE"),
+            log_format,
+            async_flush: parse_bool_var(&var, "AXUM_METER_READINGS_ASYNC_FLUSH", false)?,
+            static_dir: var("AXUM_METER_READINGS_STATIC_DIR"),
+            auth: var("AXUM_METER_READINGS_AUTH"),
+            max_body_bytes: parse_var(
+                &var,
+                "AXUM_METER_READINGS_MAX_BODY_BYTES",
+                DEFAULT_MAX_BODY_BYTES,
+            )?,
+            base_path: string("AXUM_METER_READINGS_BASE_PATH", DEFAULT_BASE_PATH),
+            // 0 is the same as unset
+            rate_limit_per_minute: parse_opt_var(&var, "AXUM_METER_READINGS_RATE_LIMIT")?
+                .filter(|n| *n > 0),
+        };
+        if config.bind_addr.trim().is_empty() {
+            return Err("AXUM_METER_READINGS_BIND_ADDR is empty".to_string());
+        }
+        for (name, value) in [
+            (
+                "AXUM_METER_READINGS_DUMP_INTERVAL",
+                Some(config.dump_interval),
+            ),
+            (
+                "AXUM_METER_READINGS_POLLING_PERIOD",
+                Some(config.poll_secs as i64),
+            ),
+            (
+                "AXUM_METER_READINGS_MAX_RECORDS",
+                Some(config.capacity as i64),
+            ),
+            (
+                "AXUM_METER_READINGS_INSERT_BATCH_SIZE",
+                Some(config.insert_batch_size as i64),
+            ),
+            (
+                "AXUM_METER_READINGS_SNAP_TO_GRID_SECS",
+                config.snap_to_grid_secs,
+            ),
+            ("AXUM_METER_READINGS_MAX_AGE_SECS", config.max_age_secs),
+            (
+                "AXUM_METER_READINGS_RETENTION_MAX_RECORDS",
+                config.retention_max_records.map(|n| n as i64),
+            ),
+        ] {
+            if let Some(value) = value
+                && value <= 0
+            {
+                return Err(format!("{}={} must be strictly positive", name, value));
+            }
+        }
+        if let Some(tolerance) = config.pv2022_monotonic_tolerance
+            && tolerance < 0.0
+        {
+            return Err(format!(
+                "AXUM_METER_READINGS_PV2022_MONOTONIC_TOLERANCE={} is negative",
+                tolerance
+            ));
+        }
+        Ok(config)
+    }
+
+    pub fn polling_period(&self) -> Duration {
+        Duration::from_secs(self.poll_secs)
+    }
+
+    pub fn p1_timeout(&self) -> Duration {
+        Duration::from_secs(self.p1_timeout_secs)
+    }
+
+    pub fn pv_timeout(&self) -> Duration {
+        Duration::from_secs(self.pv_timeout_secs)
+    }
+
+    /// The buffer itself holds at most `capacity` records.
+    pub fn retention(&self) -> RetentionPolicy {
+        RetentionPolicy {
+            max_records: self.retention_max_records,
+            max_age_secs: self.max_age_secs,
+        }
+    }
+
+    fn log(&self) {
+        println!("AXUM_METER_READINGS_BIND_ADDR='{}'", self.bind_addr);
+        println!("AXUM_METER_READINGS_P1_DATA_CMD='{}'", self.p1_cmd);
+        println!("AXUM_METER_READINGS_PV_2022_CMD='{}'", self.pv_cmd);
+        println!("AXUM_METER_READINGS_SQL_CMD='{}'", self.sql_cmd);
+        println!("AXUM_METER_READINGS_DUMP_INTERVAL='{}'", self.dump_interval);
+        println!("AXUM_METER_READINGS_VERBOSE={}", self.verbose);
+        println!("AXUM_METER_READINGS_POLLING_PERIOD={}", self.poll_secs);
+        println!("AXUM_METER_READINGS_MAX_RECORDS={}", self.capacity);
+        println!("AXUM_METER_READINGS_P1_SOURCE={:?}", self.p1_source);
+        println!(
+            "AXUM_METER_READINGS_P1_TIMEOUT_SECS={}",
+            self.p1_timeout_secs
+        );
+        println!("AXUM_METER_READINGS_P1_RETRIES={}", self.p1_retries);
+        println!("P1 registers: {:?}", self.p1_obis);
+        println!(
+            "AXUM_METER_READINGS_PV_2022_JSON_PATH='{}'",
+            self.pv_2022_json_path
+        );
+        println!(
+            "AXUM_METER_READINGS_PV_TIMEOUT_SECS={}",
+            self.pv_timeout_secs
+        );
+        println!("AXUM_METER_READINGS_PV_DIVISOR={}", self.pv_divisor);
+        println!("AXUM_METER_READINGS_PV_2012_CMD={:?}", self.pv_2012_cmd);
+        println!(
+            "AXUM_METER_READINGS_PV_2012_SERIAL='{}'",
+            self.pv_2012_serial
+        );
+        println!("AXUM_METER_READINGS_SNAPSHOT_PATH={:?}", self.snapshot_path);
+        println!("AXUM_METER_READINGS_SEED_FROM_DB={}", self.seed_from_db);
+        println!(
+            "AXUM_METER_READINGS_DECREASING_GUARD={:?}",
+            self.decreasing_guard
+        );
+        println!(
+            "AXUM_METER_READINGS_QUIET_NOTHING_TO_DO={}",
+            self.quiet_nothing_to_do
+        );
+        println!(
+            "AXUM_METER_READINGS_HIGH_WATER_MARK={:?}",
+            self.high_water_mark
+        );
+        println!(
+            "AXUM_METER_READINGS_SUMMARY_FIELDS={:?}",
+            self.summary_fields
+        );
+        println!(
+            "AXUM_METER_READINGS_ZERO_PV_AS_MISSING={}",
+            self.zero_pv2022_as_missing
+        );
+        println!(
+            "AXUM_METER_READINGS_PV2022_MONOTONIC_TOLERANCE={:?}",
+            self.pv2022_monotonic_tolerance
+        );
+        println!(
+            "AXUM_METER_READINGS_SNAP_TO_GRID_SECS={:?} (manual inputs: {})",
+            self.snap_to_grid_secs, self.snap_manual_inputs
+        );
+        println!(
+            "AXUM_METER_READINGS_CHECK_OFF_HOURS={}",
+            self.check_off_hours
+        );
+        println!(
+            "AXUM_METER_READINGS_INSERT_BATCH_SIZE={}",
+            self.insert_batch_size
+        );
+        println!("AXUM_METER_READINGS_MAX_AGE_SECS={:?}", self.max_age_secs);
+        println!(
+            "AXUM_METER_READINGS_RETENTION_MAX_RECORDS={:?}",
+            self.retention_max_records
+        );
+        println!("AXUM_METER_READINGS_DRY_RUN={}", self.dry_run);
+        println!(
+            "AXUM_METER_READINGS_SQLITE_PRAGMAS='{}'",
+            self.sqlite_pragmas
+        );
+        println!("AXUM_METER_READINGS_SQL_DUMP_FILE={:?}", self.sql_dump_file);
+        println!("AXUM_METER_READINGS_LOG_FORMAT={:?}", self.log_format);
+        println!("AXUM_METER_READINGS_ASYNC_FLUSH={}", self.async_flush);
+        println!("AXUM_METER_READINGS_STATIC_DIR={:?}", self.static_dir);
+        println!(
+            "AXUM_METER_READINGS_AUTH={}",
+            if self.auth.is_some() {
+                "<set>"
+            } else {
+                "<unset>"
+            }
+        );
+        println!("AXUM_METER_READINGS_MAX_BODY_BYTES={}", self.max_body_bytes);
+        println!("AXUM_METER_READINGS_BASE_PATH='{}'", self.base_path);
+        println!(
+            "AXUM_METER_READINGS_RATE_LIMIT={:?}",
+            self.rate_limit_per_minute
+        );
+    }
+}
+
+/// Value of `name` parsed as a `T`, `default` when unset.
+fn parse_var<F, T>(var: &F, name: &str, default: T) -> Result<T, String>
+where
+    F: Fn(&str) -> Option<String>,
+    T: FromStr,
+{
+    Ok(parse_opt_var(var, name)?.unwrap_or(default))
+}
+
+/// Value of `name` parsed as a `T`, `None` when unset.
+fn parse_opt_var<F, T>(var: &F, name: &str) -> Result<Option<T>, String>
+where
+    F: Fn(&str) -> Option<String>,
+    T: FromStr,
+{
+    var(name)
+        .map(|s| {
+            s.trim()
+                .parse::<T>()
+                .map_err(|_| format!("{}='{}' is not a valid number", name, s))
+        })
+        .transpose()
+}
+
+/// Value of `name` parsed with `parse_bool`, `default` when unset.
+fn parse_bool_var<F>(var: &F, name: &str, default: bool) -> Result<bool, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    match var(name) {
+        Some(s) => parse_bool(&s).ok_or_else(|| format!("{}='{}' is not a boolean", name, s)),
+        None => Ok(default),
+    }
+}
+
+fn parse_bool(s: &str) -> Option<bool> {
+    match s.trim().to_lowercase().as_str() {
+        "false" | "no" | "0" | "off" => Some(false),
+        "true" | "yes" | "1" | "on" => Some(true),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn config_from(vars: &[(&str, &str)]) -> Result<Config, String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        Config::from_vars(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn from_vars_defaults() {
+        assert_eq!(
+            config_from(&[]),
+            Ok(Config {
+                bind_addr: "127.0.0.1:3000".to_string(),
+                p1_cmd: "cat /tmp/p1_data.txt".to_string(),
+                p1_source: P1Source::Cmd("cat /tmp/p1_data.txt".to_string()),
+                p1_timeout_secs: 10,
+                p1_retries: 2,
+                p1_obis: P1ObisConfig::default(),
+                pv_cmd: "cat /tmp/pv_2022.json".to_string(),
+                pv_2022_json_path: pv2022::PV_2022_JSON_PATH.to_string(),
+                pv_timeout_secs: 10,
+                pv_divisor: pv2022::DEFAULT_DIVISOR,
+                pv_2012_cmd: None,
+                pv_2012_serial: pv2022::PV_2022_SERIAL.to_string(),
+                sql_cmd: "cat /tmp/sql_cmd.log".to_string(),
+                dump_interval: 3600,
+                verbose: true,
+                poll_secs: 15,
+                capacity: 1440,
+                snapshot_path: None,
+                seed_from_db: true,
+                decreasing_guard: vec![
+                    "peak_conso_kWh".to_string(),
+                    "off_conso_kWh".to_string(),
+                    "peak_inj_kWh".to_string(),
+                    "off_inj_kWh".to_string(),
+                ],
+                quiet_nothing_to_do: false,
+                high_water_mark: None,
+                summary_fields: Vec::new(),
+                zero_pv2022_as_missing: false,
+                pv2022_monotonic_tolerance: None,
+                snap_to_grid_secs: None,
+                snap_manual_inputs: false,
+                check_off_hours: false,
+                insert_batch_size: 100,
+                max_age_secs: None,
+                retention_max_records: None,
+                dry_run: false,
+                sqlite_pragmas: String::new(),
+                sql_dump_file: None,
+                log_format: LogFormat::Text,
+                async_flush: false,
+                static_dir: None,
+                auth: None,
+                max_body_bytes: 64 * 1024,
+                base_path: "/axum-meter-readings".to_string(),
+                rate_limit_per_minute: None,
+            })
+        );
+    }
+
+    #[test]
+    fn from_vars_overrides() {
+        let config = config_from(&[
+            ("AXUM_METER_READINGS_BIND_ADDR", "0.0.0.0:8080"),
+            ("AXUM_METER_READINGS_SQL_CMD", "sqlite3 /var/lib/meters.db"),
+            ("AXUM_METER_READINGS_DUMP_INTERVAL", " 600 "),
+            ("AXUM_METER_READINGS_VERBOSE", "off"),
+            ("AXUM_METER_READINGS_POLLING_PERIOD", "30"),
+        ])
+        .unwrap();
+        assert_eq!(config.bind_addr, "0.0.0.0:8080");
+        assert_eq!(config.sql_cmd, "sqlite3 /var/lib/meters.db");
+        assert_eq!(config.dump_interval, 600);
+        assert!(!config.verbose);
+        assert_eq!(config.polling_period(), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn from_vars_overrides_polling_settings() {
+        let config = config_from(&[
+            ("AXUM_METER_READINGS_P1_SOURCE", "file:/dev/ttyUSB0"),
+            ("AXUM_METER_READINGS_P1_TIMEOUT_SECS", "3"),
+            ("AXUM_METER_READINGS_P1_RETRIES", "0"),
+            ("AXUM_METER_READINGS_P1_SWAP_TARIFFS", "yes"),
+            ("AXUM_METER_READINGS_P1_GAS_CHANNEL", "2"),
+            ("AXUM_METER_READINGS_PV_TIMEOUT_SECS", "5"),
+            ("AXUM_METER_READINGS_PV_DIVISOR", "1000"),
+            ("AXUM_METER_READINGS_PV_2012_CMD", "cat /tmp/pv_2012.json"),
+            ("AXUM_METER_READINGS_HIGH_WATER_MARK", "500"),
+            ("AXUM_METER_READINGS_FLUSH_BATCH", "20"),
+            ("AXUM_METER_READINGS_MAX_AGE_SECS", "86400"),
+            ("AXUM_METER_READINGS_RETENTION_MAX_RECORDS", "720"),
+            ("AXUM_METER_READINGS_DRY_RUN", "on"),
+            ("AXUM_METER_READINGS_LOG_FORMAT", "JSON"),
+            ("AXUM_METER_READINGS_ASYNC_FLUSH", "1"),
+            ("AXUM_METER_READINGS_SUMMARY_FIELDS", "gas_m3, nonsense"),
+        ])
+        .unwrap();
+        assert_eq!(config.p1_source, P1Source::File("/dev/ttyUSB0".to_string()));
+        assert_eq!(config.p1_timeout(), Duration::from_secs(3));
+        assert_eq!(config.p1_retries, 0);
+        assert_eq!(
+            config.p1_obis,
+            P1ObisConfig {
+                gas: Some("0-2:24.2.1(".to_string()),
+                ..P1ObisConfig::default().swapped_tariffs()
+            }
+        );
+        assert_eq!(config.pv_timeout(), Duration::from_secs(5));
+        assert_eq!(config.pv_divisor, 1000.0);
+        assert_eq!(config.pv_2012_cmd.as_deref(), Some("cat /tmp/pv_2012.json"));
+        assert_eq!(config.high_water_mark, Some(500));
+        assert_eq!(config.insert_batch_size, 20);
+        assert_eq!(config.retention().max_age_secs, Some(86400));
+        assert_eq!(config.retention().max_records, Some(720));
+        assert!(config.dry_run);
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert!(config.async_flush);
+        assert_eq!(config.summary_fields, vec!["gas_m3".to_string()]);
+
+        // The preferred name wins over the alias
+        let config = config_from(&[
+            ("AXUM_METER_READINGS_INSERT_BATCH_SIZE", "50"),
+            ("AXUM_METER_READINGS_FLUSH_BATCH", "20"),
+        ])
+        .unwrap();
+        assert_eq!(config.insert_batch_size, 50);
+    }
+
+    #[test]
+    fn from_vars_overrides_decreasing_guard() {
+        let config = config_from(&[(
+            "AXUM_METER_READINGS_DECREASING_GUARD",
+            "peak_conso_kWh,off_conso_kWh",
+        )])
+        .unwrap();
+        assert_eq!(
+            config.decreasing_guard,
+            vec!["peak_conso_kWh".to_string(), "off_conso_kWh".to_string()]
+        );
+        let config = config_from(&[("AXUM_METER_READINGS_DECREASING_GUARD", "")]).unwrap();
+        assert!(config.decreasing_guard.is_empty());
+    }
+
+    #[test]
+    fn from_vars_overrides_server_settings() {
+        let config = config_from(&[
+            ("AXUM_METER_READINGS_STATIC_DIR", "/srv/www"),
+            ("AXUM_METER_READINGS_AUTH", "admin:secret"),
+            ("AXUM_METER_READINGS_MAX_BODY_BYTES", "1024"),
+            ("AXUM_METER_READINGS_BASE_PATH", "/meters"),
+            ("AXUM_METER_READINGS_RATE_LIMIT", "60"),
+        ])
+        .unwrap();
+        assert_eq!(config.static_dir.as_deref(), Some("/srv/www"));
+        assert_eq!(config.auth.as_deref(), Some("admin:secret"));
+        assert_eq!(config.max_body_bytes, 1024);
+        assert_eq!(config.base_path, "/meters");
+        assert_eq!(config.rate_limit_per_minute, Some(60));
+        let config = config_from(&[("AXUM_METER_READINGS_RATE_LIMIT", "0")]).unwrap();
+        assert_eq!(config.rate_limit_per_minute, None);
+    }
+
+    #[test]
+    fn from_vars_rejects_invalid_values() {
+        for (name, value, error) in [
+            (
+                "AXUM_METER_READINGS_DUMP_INTERVAL",
+                "1h",
+                "AXUM_METER_READINGS_DUMP_INTERVAL='1h' is not a valid number",
+            ),
+            (
+                "AXUM_METER_READINGS_DUMP_INTERVAL",
+                "-5",
+                "AXUM_METER_READINGS_DUMP_INTERVAL=-5 must be strictly positive",
+            ),
+            (
+                "AXUM_METER_READINGS_POLLING_PERIOD",
+                "0",
+                "AXUM_METER_READINGS_POLLING_PERIOD=0 must be strictly positive",
+            ),
+            (
+                "AXUM_METER_READINGS_MAX_RECORDS",
+                "-1",
+                "AXUM_METER_READINGS_MAX_RECORDS='-1' is not a valid number",
+            ),
+            (
+                "AXUM_METER_READINGS_VERBOSE",
+                "maybe",
+                "AXUM_METER_READINGS_VERBOSE='maybe' is not a boolean",
+            ),
+            (
+                "AXUM_METER_READINGS_BIND_ADDR",
+                " ",
+                "AXUM_METER_READINGS_BIND_ADDR is empty",
+            ),
+            (
+                "AXUM_METER_READINGS_P1_SOURCE",
+                "/dev/ttyUSB0",
+                "AXUM_METER_READINGS_P1_SOURCE '/dev/ttyUSB0' is not one of cmd:<shell>, file:<path> or stdin",
+            ),
+            (
+                "AXUM_METER_READINGS_P1_WATER_CHANNEL",
+                "5",
+                "AXUM_METER_READINGS_P1_WATER_CHANNEL=5 is not a channel (1-4)",
+            ),
+            (
+                "AXUM_METER_READINGS_P1_RETRIES",
+                "many",
+                "AXUM_METER_READINGS_P1_RETRIES='many' is not a valid number",
+            ),
+            (
+                "AXUM_METER_READINGS_INSERT_BATCH_SIZE",
+                "0",
+                "AXUM_METER_READINGS_INSERT_BATCH_SIZE=0 must be strictly positive",
+            ),
+            (
+                "AXUM_METER_READINGS_SNAP_TO_GRID_SECS",
+                "0",
+                "AXUM_METER_READINGS_SNAP_TO_GRID_SECS=0 must be strictly positive",
+            ),
+            (
+                "AXUM_METER_READINGS_RETENTION_MAX_RECORDS",
+                "0",
+                "AXUM_METER_READINGS_RETENTION_MAX_RECORDS=0 must be strictly positive",
+            ),
+            (
+                "AXUM_METER_READINGS_PV2022_MONOTONIC_TOLERANCE",
+                "-0.5",
+                "AXUM_METER_READINGS_PV2022_MONOTONIC_TOLERANCE=-0.5 is negative",
+            ),
+            (
+                "AXUM_METER_READINGS_DRY_RUN",
+                "perhaps",
+                "AXUM_METER_READINGS_DRY_RUN='perhaps' is not a boolean",
+            ),
+            (
+                "AXUM_METER_READINGS_LOG_FORMAT",
+                "xml",
+                "AXUM_METER_READINGS_LOG_FORMAT='xml' is neither text nor json",
+            ),
+        ] {
+            assert_eq!(config_from(&[(name, value)]), Err(error.to_string()));
+        }
+    }
+
+    #[test]
+    fn parse_bool_recognized_spellings() {
+        for s in ["false", "FALSE", "No", "no", "0", "off", "OFF", " false "] {
+            assert_eq!(parse_bool(s), Some(false), "{}", s);
+        }
+        for s in ["true", "TRUE", "Yes", "yes", "1", "on", "On"] {
+            assert_eq!(parse_bool(s), Some(true), "{}", s);
+        }
+        for s in ["", "2", "maybe", "enabled"] {
+            assert_eq!(parse_bool(s), None, "{}", s);
+        }
+    }
+}
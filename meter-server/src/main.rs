@@ -1,25 +1,73 @@
 use axum::{
-    Router,
-    extract::{Form, State},
+    Json, Router,
+    body::Body,
+    extract::{ConnectInfo, DefaultBodyLimit, Form, OriginalUri, Path, Query, Request, State},
     handler::Handler,
-    http::StatusCode,
-    response::{Html, IntoResponse, Redirect},
-    routing::get_service,
+    http::{
+        HeaderMap, Method, StatusCode,
+        header::{AUTHORIZATION, CONTENT_TYPE, ETAG, IF_NONE_MATCH, WWW_AUTHENTICATE},
+    },
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, get_service, post_service},
+};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use chrono::{self, DateTime, FixedOffset};
+use meter_core::{
+    data::{
+        Data202303, SqliteCmd, clone_data202303, for_each_data_202303, select_daily_deltas_202303,
+        select_data_202303_range, select_latest_data_202303,
+    },
+    ringbuffer::freeze,
+    tariff::OffHoursSchedule,
 };
-use chrono::{self, DateTime};
 use serde::Deserialize;
 use std::{
-    env,
-    sync::Arc,
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, mpsc},
     thread,
     time::{Duration, Instant},
 };
 use tokio::task;
+use tokio_stream::{StreamExt, wrappers::ReceiverStream};
+use tower_http::{compression::CompressionLayer, services::ServeDir};
+use tracing_subscriber::{Layer, layer::SubscriberExt};
 
 mod blocking_task;
-use blocking_task::{SharedState, poll_automated_measurements, save_data, save_manual_inputs};
+mod config;
+use blocking_task::{
+    AppState, P1Reader, SharedState, check_commands, poll_automated_measurements, run_flusher,
+    save_data, save_manual_inputs, upsert_manual_inputs,
+};
+use config::Config;
 
-const FORM_PATH: &str = "/axum-meter-readings/form";
+/// Prefix of every route unless `AXUM_METER_READINGS_BASE_PATH` says otherwise.
+const DEFAULT_BASE_PATH: &str = "/axum-meter-readings";
+const FORM_PATH: &str = "/form";
+/// Largest request body accepted unless `AXUM_METER_READINGS_MAX_BODY_BYTES` says otherwise.
+const DEFAULT_MAX_BODY_BYTES: usize = 64 * 1024;
+const SUMMARY_AVG_WINDOW: usize = 5;
+/// How much longer the poller sleeps while above the high-water mark.
+const DEGRADED_SLOWDOWN: u32 = 4;
+const EXPORT_PATH: &str = "/api/export";
+/// Rows read ahead of the client by `EXPORT_PATH`.
+const EXPORT_CHUNKS: usize = 16;
+const DAILY_PATH: &str = "/api/daily";
+/// Days covered by `DAILY_PATH` when the query does not say.
+const DEFAULT_DAILY_DAYS: i64 = 30;
+const SERIES_PATH: &str = "/api/series";
+const SINCE_START_PATH: &str = "/api/since-start";
+const STATUS_PATH: &str = "/api/status";
+const DIAGNOSTICS_PATH: &str = "/api/diagnostics";
+const WATER_PATH: &str = "/api/water";
+const GAS_PATH: &str = "/api/gas";
+const IMPORT_PATH: &str = "/api/import";
+const LAST_TELEGRAM_PATH: &str = "/api/debug/last-telegram";
+const RECORD_PATH: &str = "/api/record/{timestamp}";
+const CAPACITY_PATH: &str = "/admin/capacity/{n}";
+const RINGBUFFER_PATH: &str = "/admin/debug/ringbuffer";
+const INSERT_SQL_PATH: &str = "/admin/insert.sql";
 
 #[allow(non_snake_case)]
 #[derive(Deserialize)]
@@ -35,8 +83,13 @@ fn render_form_field(
     name: &str,
     unit: &str,
     value: &Result<Option<f64>, (String, &'static str)>,
+    placeholder: Option<f64>,
 ) -> String {
     let empty_string = String::new();
+    let placeholder = match placeholder {
+        Some(f) => &format!(r#" placeholder="{f}""#),
+        None => &empty_string,
+    };
     let input_value = match value {
         Ok(Some(f)) => &(f.to_string()),
         Ok(None) => &empty_string,
@@ -51,19 +104,23 @@ fn render_form_field(
     format!(
         r#"<div>
             <label for="{name}">{label} {unit}</label>
-            <input type="number" id="{name}" name="{name}" value="{input_value}" step="0.001" min="0">
+            <input type="number" id="{name}" name="{name}" value="{input_value}"{placeholder} step="0.001" min="0">
             {error_msg}
         </div>"#
     )
 }
 
+#[allow(clippy::too_many_arguments)]
 fn render_form(
+    form_path: &str,
     timestamp_error: &str,
     pv2012: &Result<Option<f64>, (String, &'static str)>,
     gas: &Result<Option<f64>, (String, &'static str)>,
     water: &Result<Option<f64>, (String, &'static str)>,
-    state_data_len: usize,
+    summary: &str,
     general_error_msg: &str,
+    info_msg: &str,
+    previous: Option<&Data202303>,
 ) -> String {
     let empty_string = String::new();
     let general_error = if general_error_msg.is_empty() {
@@ -71,6 +128,11 @@ fn render_form(
     } else {
         &format!(r#"<div class="general-error">{}</div>"#, general_error_msg)
     };
+    let info = if info_msg.is_empty() {
+        &empty_string
+    } else {
+        &format!(r#"<div class="info">{}</div>"#, info_msg)
+    };
 
     let timestamp_err = if timestamp_error.is_empty() {
         &empty_string
@@ -138,10 +200,15 @@ fn render_form(
             color: #b00020;
             font-weight: bold;
         }}
+        .info {{
+            margin-bottom: 1em;
+            color: #555;
+        }}
     </style>
 </head>
 <body>
     {general_error}
+    {info}
     <form action="{form_path}" method="POST">
         <div>
             <label for="timestamp">Timestamp</label>
@@ -157,33 +224,85 @@ fn render_form(
     </form>
 
     <div class="summary">
-        {state_data_len} input measurements
+        {summary}
     </div>
 </body>
 </html>"#,
         general_error = general_error,
-        form_path = FORM_PATH,
+        info = info,
+        form_path = form_path,
         timestamp = chrono::Local::now().format("%Y-%m-%dT%H:%M:00%:z"),
         timestamp_err = timestamp_err,
-        pv2012_field = render_form_field("PV2012", "pv2012_kWh", "(kWh)", pv2012),
-        gas_field = render_form_field("Gas", "gas", "(m³)", gas),
-        water_field = render_form_field("Water", "water", "(m³)", water),
-        state_data_len = state_data_len,
+        pv2012_field = render_form_field(
+            "PV2012",
+            "pv2012_kWh",
+            "(kWh)",
+            pv2012,
+            previous.and_then(|p| p.pv2012_kWh)
+        ),
+        gas_field = render_form_field("Gas", "gas", "(m³)", gas, previous.and_then(|p| p.gas_m3)),
+        water_field = render_form_field(
+            "Water",
+            "water",
+            "(m³)",
+            water,
+            previous.and_then(|p| p.water_m3)
+        ),
+        summary = summary,
     )
 }
 
-async fn get_form(State(state): State<SharedState>) -> Html<String> {
+/// Number of buffered records and, when known, the recent PV2022 average and
+/// the `summary_fields` of the newest record.
+fn render_summary(state: &AppState) -> String {
+    let mut summary = format!("{} input measurements", state.data.len());
+    if let Some(interval) = state.median_interval_secs() {
+        summary.push_str(&format!(" (one every {}s)", interval));
+    }
+    if let Some(avg) = state.avg_last_n(SUMMARY_AVG_WINDOW, |r| r.pv2022_kWh) {
+        summary.push_str(&format!(
+            ", PV2022 {:.3} kWh (average of the last {})",
+            avg, SUMMARY_AVG_WINDOW
+        ));
+    }
+    if let Some(latest) = state.get_latest_data() {
+        let values: Vec<String> = state
+            .summary_fields
+            .iter()
+            .filter_map(|name| {
+                let value = Data202303::field_accessor(name)?(&latest)?;
+                Some(format!("{} {:.3}", name, value))
+            })
+            .collect();
+        if !values.is_empty() {
+            summary.push_str(&format!("; latest: {}", values.join(", ")));
+        }
+    }
+    summary
+}
+
+async fn get_form(State(state): State<SharedState>, OriginalUri(uri): OriginalUri) -> Html<String> {
     let state = state.read().unwrap();
     Html(render_form(
+        uri.path(),
         "",
         &Ok(None),
         &Ok(None),
         &Ok(None),
-        state.data.len(),
+        &render_summary(&state),
+        "",
         "",
+        state.get_latest_data().as_ref(),
     ))
 }
 
+/// Summary and newest record shown below the form, read without keeping the
+/// lock while the page is built.
+fn form_context(state: &SharedState) -> (String, Option<Data202303>) {
+    let state = state.read().unwrap();
+    (render_summary(&state), state.get_latest_data())
+}
+
 fn parse_opt_positive_float(s: &str) -> Result<Option<f64>, &'static str> {
     let s = s.trim();
     if s.is_empty() {
@@ -205,11 +324,15 @@ fn parse_opt_positive_float(s: &str) -> Result<Option<f64>, &'static str> {
 
 async fn post_form(
     State(state): State<SharedState>,
+    OriginalUri(uri): OriginalUri,
     Form(form_data): Form<FormData>,
 ) -> Result<(StatusCode, impl IntoResponse), Html<String>> {
-    println!(
+    tracing::info!(
         "Form submitted with: timestamp={}, pv2012_kWh={}, gas={}, water={}",
-        form_data.timestamp, form_data.pv2012_kWh, form_data.gas, form_data.water
+        form_data.timestamp,
+        form_data.pv2012_kWh,
+        form_data.gas,
+        form_data.water
     );
     match (
         DateTime::parse_from_rfc3339(&form_data.timestamp),
@@ -218,125 +341,2871 @@ async fn post_form(
         parse_opt_positive_float(&form_data.water),
     ) {
         (Ok(_), Ok(None), Ok(None), Ok(None)) => {
-            let state = state.read().unwrap();
-            return Err(Html(render_form(
+            let quiet = state.read().unwrap().quiet_nothing_to_do;
+            let (summary, latest) = form_context(&state);
+            let info = if quiet {
+                String::new()
+            } else {
+                format!("Nothing to do for timestamp={}", form_data.timestamp)
+            };
+            Err(Html(render_form(
+                uri.path(),
                 "",
                 &Ok(None),
                 &Ok(None),
                 &Ok(None),
-                state.data.len(),
-                &format!(
-                    "Nothing to do for timestamp={}, pv2012_kWh={}, gas={}, water={}",
-                    form_data.timestamp, form_data.pv2012_kWh, form_data.gas, form_data.water
-                ),
-            )));
+                &summary,
+                "",
+                &info,
+                latest.as_ref(),
+            )))
         }
         (Ok(timestamp), Ok(pv2012), Ok(gas), Ok(water)) => {
-            let mut state = state.write().unwrap();
-            save_manual_inputs(&mut state, timestamp, pv2012, gas, water);
-            return Ok((StatusCode::SEE_OTHER, Redirect::to(FORM_PATH)));
+            let saved =
+                save_manual_inputs(&mut state.write().unwrap(), timestamp, pv2012, gas, water);
+            match saved {
+                Ok(_) => Ok((StatusCode::SEE_OTHER, Redirect::to(uri.path()))),
+                Err(e) => {
+                    let (summary, latest) = form_context(&state);
+                    Err(Html(render_form(
+                        uri.path(),
+                        "",
+                        &Ok(None),
+                        &Ok(None),
+                        &Ok(None),
+                        &summary,
+                        &e,
+                        "",
+                        latest.as_ref(),
+                    )))
+                }
+            }
         }
         (e_timestamp, e_pv2012, e_gas, e_water) => {
-            let state = state.read().unwrap();
+            let (summary, latest) = form_context(&state);
             let timestamp_error = if let Err(e) = e_timestamp {
                 format!("{}", e)
             } else {
                 String::new()
             };
             let form = render_form(
+                uri.path(),
                 &timestamp_error,
                 &(e_pv2012.map_err(|e| (form_data.pv2012_kWh, e))),
                 &(e_gas.map_err(|e| (form_data.gas, e))),
                 &(e_water.map_err(|e| (form_data.water, e))),
-                state.data.len(),
+                &summary,
+                "",
                 "",
+                latest.as_ref(),
             );
-            return Err(Html(form));
+            Err(Html(form))
         }
     }
 }
 
-#[tokio::main]
-async fn main() {
-    let shared_state = SharedState::default();
-
-    let p1_data_cmd = env::var("AXUM_METER_READINGS_P1_DATA_CMD")
-        .unwrap_or_else(|_| "cat /tmp/p1_data.txt".to_string());
-    let pv_2022_cmd = env::var("AXUM_METER_READINGS_PV_2022_CMD")
-        .unwrap_or_else(|_| "cat /tmp/pv_2022.json".to_string());
-    let sql_cmd = env::var("AXUM_METER_READINGS_SQL_CMD")
-        .unwrap_or_else(|_| "cat /tmp/sql_cmd.log".to_string());
-    let dump_interval = env::var("AXUM_METER_READINGS_DUMP_INTERVAL")
-        .map_or(None, |s| s.parse::<i64>().ok())
-        .unwrap_or(3600);
-    let verbose = env::var("AXUM_METER_READINGS_VERBOSE").map_or(true, |s| {
-        s.to_uppercase() != "FALSE" && s.to_uppercase() != "NO" && s != "0"
-    });
-    let blocking_ref = Arc::clone(&shared_state);
-    let polling_period = Duration::from_secs(
-        env::var("AXUM_METER_READINGS_POLLING_PERIOD")
-            .ok()
-            .map(|s| s.parse::<u64>().ok())
-            .flatten()
-            .unwrap_or(15),
+fn format_request_log(
+    method: &Method,
+    path: &str,
+    status: StatusCode,
+    elapsed: Duration,
+) -> String {
+    format!(
+        "{} {} -> {} in {:.3}ms",
+        method,
+        path,
+        status.as_u16(),
+        elapsed.as_secs_f64() * 1000.0
+    )
+}
+
+/// Log method, path, status and latency of every request (never the body).
+async fn log_request(request: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let response = next.run(request).await;
+    tracing::info!(
+        "{}",
+        format_request_log(&method, &path, response.status(), start.elapsed())
     );
-    let insert_batch_size = env::var("AXUM_METER_READINGS_INSERT_BATCH_SIZE")
-        .ok()
-        .map(|s| s.parse::<usize>().ok())
-        .flatten()
-        .unwrap_or(100);
-    let _res = task::spawn_blocking(move || {
-        println!("AXUM_METER_READINGS_P1_DATA_CMD='{}'", p1_data_cmd);
-        println!("AXUM_METER_READINGS_PV_2022_CMD='{}'", pv_2022_cmd);
-        println!("AXUM_METER_READINGS_SQL_CMD='{}'", sql_cmd);
-        println!("AXUM_METER_READINGS_DUMP_INTERVAL='{}'", dump_interval);
-        println!("AXUM_METER_READINGS_VERBOSE={}", verbose);
-        println!(
-            "AXUM_METER_READINGS_POLLING_PERIOD={:.3}",
-            polling_period.as_secs_f64()
-        );
-        println!(
-            "AXUM_METER_READINGS_INSERT_BATCH_SIZE={}",
-            insert_batch_size
-        );
-        loop {
-            let start = Instant::now();
-            let (p1, pv_2022) = poll_automated_measurements(&p1_data_cmd, &pv_2022_cmd, verbose);
-            save_data(
-                &blocking_ref,
-                p1,
-                pv_2022,
-                &sql_cmd,
-                dump_interval,
-                verbose,
-                insert_batch_size,
-            );
-            let elapsed = start.elapsed();
-            if elapsed < polling_period {
-                thread::sleep(polling_period - elapsed);
-            } else {
-                println!(
-                    "Warning: poll_automated_measurements took longer than {}s: {}s",
-                    polling_period.as_secs(),
-                    elapsed.as_secs()
-                );
-            }
+    response
+}
+
+/// Compare without short-circuiting on the first differing byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Check the `Authorization: Basic ...` header against `expected` (`user:pass`).
+fn is_authorized(headers: &HeaderMap, expected: &str) -> bool {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|encoded| BASE64.decode(encoded.trim()).ok())
+        .is_some_and(|decoded| constant_time_eq(&decoded, expected.as_bytes()))
+}
+
+/// Require HTTP Basic auth when credentials are configured, no-op otherwise.
+async fn require_basic_auth(
+    State(auth): State<Option<Arc<str>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match auth {
+        Some(expected) if !is_authorized(request.headers(), &expected) => (
+            StatusCode::UNAUTHORIZED,
+            [(WWW_AUTHENTICATE, r#"Basic realm="axum-meter-readings""#)],
+        )
+            .into_response(),
+        _ => next.run(request).await,
+    }
+}
+
+/// Token bucket per client IP allowing `per_minute` requests per minute, with
+/// bursts of up to `per_minute` requests.
+struct RateLimiter {
+    per_minute: u32,
+    buckets: Mutex<HashMap<Option<IpAddr>, (f64, Instant)>>,
+}
+
+impl RateLimiter {
+    fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take one token from `client`'s bucket, `false` if it is empty.
+    fn try_acquire(&self, client: Option<IpAddr>, now: Instant) -> bool {
+        let capacity = f64::from(self.per_minute);
+        let mut buckets = self.buckets.lock().unwrap();
+        let (tokens, last) = buckets.entry(client).or_insert((capacity, now));
+        let refill = now.saturating_duration_since(*last).as_secs_f64() * capacity / 60.0;
+        *tokens = (*tokens + refill).min(capacity);
+        *last = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
         }
+    }
+}
+
+/// Answer `429` to clients sending too many mutating requests, GET and HEAD
+/// requests are never throttled.
+async fn rate_limit(
+    State(limiter): State<Option<Arc<RateLimiter>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(limiter) = limiter else {
+        return next.run(request).await;
+    };
+    if matches!(*request.method(), Method::GET | Method::HEAD) {
+        return next.run(request).await;
+    }
+    let client = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|info| info.0.ip());
+    if limiter.try_acquire(client, Instant::now()) {
+        next.run(request).await
+    } else {
+        StatusCode::TOO_MANY_REQUESTS.into_response()
+    }
+}
+
+/// Send the persisted rows to `chunks` as the pieces of a JSON array, one row
+/// at a time, or an error if they cannot all be read.  Nothing is sent before
+/// the query succeeded, and the export stops once `chunks` is closed.
+fn export_json(sql_cmd: &str, chunks: &tokio::sync::mpsc::Sender<Result<String, String>>) {
+    let mut separator = "[";
+    let exported = for_each_data_202303(&SqliteCmd(sql_cmd), |row| {
+        let json = serde_json::to_string(&row).map_err(|e| e.to_string())?;
+        chunks
+            .blocking_send(Ok(format!("{}{}", separator, json)))
+            .map_err(|_| "client is gone".to_string())?;
+        separator = ",";
+        Ok(())
     });
+    let last = match exported {
+        Ok(0) => Ok("[]".to_string()),
+        Ok(_) => Ok("]".to_string()),
+        Err(e) => Err(e),
+    };
+    // Fails only when the client is gone
+    let _ = chunks.blocking_send(last);
+}
 
-    // Build our application by composing routes
-    let app = Router::new()
+async fn get_export(State(sql_cmd): State<Arc<str>>) -> Response {
+    let (sender, mut chunks) = tokio::sync::mpsc::channel(EXPORT_CHUNKS);
+    task::spawn_blocking(move || export_json(&sql_cmd, &sender));
+    match chunks.recv().await {
+        Some(Ok(first)) => {
+            let rest = ReceiverStream::new(chunks);
+            (
+                [(CONTENT_TYPE, "application/json")],
+                Body::from_stream(tokio_stream::once(Ok(first)).chain(rest)),
+            )
+                .into_response()
+        }
+        Some(Err(e)) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": e })),
+        )
+            .into_response(),
+        None => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": "export task is gone" })),
+        )
+            .into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct DailyQuery {
+    from: Option<String>,
+    to: Option<String>,
+    tz_offset: Option<String>,
+}
+
+/// Timestamps `[from_ts, to_ts)` spanning the days from `from` to `to`
+/// (both included, by default the last `DEFAULT_DAILY_DAYS` up to today) and
+/// the offset east of UTC in seconds where those days start.
+fn daily_range(query: &DailyQuery, now: DateTime<FixedOffset>) -> Result<(i64, i64, i64), String> {
+    let tz_offset = match query.tz_offset.as_deref().map(str::trim) {
+        None | Some("") => now.offset().local_minus_utc() as i64,
+        Some(s) => match s.parse::<i64>() {
+            Ok(secs) if secs.abs() <= 14 * 3600 => secs,
+            _ => return Err(format!("invalid tz_offset '{}'", s)),
+        },
+    };
+    let parse_day = |name: &str, value: &Option<String>| match value.as_deref().map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(s) => chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d")
+            .map(Some)
+            .map_err(|_| format!("invalid {} '{}', expected YYYY-MM-DD", name, s)),
+    };
+    let today = DateTime::from_timestamp(now.timestamp() + tz_offset, 0)
+        .ok_or("invalid current time")?
+        .date_naive();
+    let to = parse_day("to", &query.to)?.unwrap_or(today);
+    let from = parse_day("from", &query.from)?
+        .unwrap_or(to - chrono::Duration::days(DEFAULT_DAILY_DAYS - 1));
+    if from > to {
+        return Err("from is after to".to_string());
+    }
+    let midnight = |day: chrono::NaiveDate| {
+        day.and_time(chrono::NaiveTime::MIN).and_utc().timestamp() - tz_offset
+    };
+    Ok((
+        midnight(from),
+        midnight(to + chrono::Duration::days(1)),
+        tz_offset,
+    ))
+}
+
+async fn get_daily(State(sql_cmd): State<Arc<str>>, Query(query): Query<DailyQuery>) -> Response {
+    let (from_ts, to_ts, tz_offset) = match daily_range(&query, chrono::Local::now().fixed_offset())
+    {
+        Ok(range) => range,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({ "error": e })),
+            )
+                .into_response();
+        }
+    };
+    match task::spawn_blocking(move || {
+        select_daily_deltas_202303(&SqliteCmd(&sql_cmd), from_ts, to_ts, tz_offset)
+    })
+    .await
+    {
+        Ok(Ok(days)) => Json(days).into_response(),
+        Ok(Err(e)) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+/// True if the `If-None-Match` header lists `etag` (or is `*`).
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|candidate| {
+            let candidate = candidate.trim();
+            candidate == "*" || candidate == etag
+        })
+}
+
+#[derive(Deserialize)]
+struct SeriesQuery {
+    field: Option<String>,
+}
+
+/// The records still in memory as a JSON array, only those where `?field=`
+/// (if any) has a value, with a weak ETag so that pollers can skip unchanged
+/// data.
+async fn get_series(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Query(query): Query<SeriesQuery>,
+) -> Response {
+    let field = match query.field.as_deref().map(Data202303::field_accessor) {
+        None => None,
+        Some(Some(get)) => Some(get),
+        Some(None) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({ "error": "unknown field" })),
+            )
+                .into_response();
+        }
+    };
+    let state = state.read().unwrap();
+    let etag = format!("W/\"{:016x}\"", state.content_hash());
+    if etag_matches(&headers, &etag) {
+        return (StatusCode::NOT_MODIFIED, [(ETAG, etag)]).into_response();
+    }
+    let mut records = freeze(&state.data).snapshot();
+    if let Some(get) = field {
+        records.retain(|record| get(record).is_some());
+    }
+    ([(ETAG, etag)], Json(records)).into_response()
+}
+
+/// Usage between the first and last records still in memory.
+async fn get_since_start(State(state): State<SharedState>) -> Response {
+    let state = state.read().unwrap();
+    if state.data.len() < 2 {
+        return StatusCode::NO_CONTENT.into_response();
+    }
+    let (Some(first), Some(last)) = (state.get_first_data(), state.get_last_data()) else {
+        return StatusCode::NO_CONTENT.into_response();
+    };
+    let diff = Data202303::diff(&last, &first);
+    let mut delta = serde_json::to_value(&diff).unwrap_or_default();
+    if let Some(columns) = delta.as_object_mut() {
+        columns.remove("timestamp");
+    }
+    Json(serde_json::json!({
+        "from": first.timestamp,
+        "to": last.timestamp,
+        "span_secs": diff.timestamp,
+        "delta": delta,
+    }))
+    .into_response()
+}
+
+/// Whether polling and persisting are keeping up.
+async fn get_app_status(State(state): State<SharedState>) -> Json<serde_json::Value> {
+    let state = state.read().unwrap();
+    Json(serde_json::json!({
+        "records": state.data.len(),
+        "capacity": state.data.get_capacity(),
+        "last_record_ts": state.data.peek_last(|r| r.timestamp),
+        "last_flush_ts": state.last_flush_ts,
+        "last_flush_rows": state.last_flush_rows,
+        "degraded": state.degraded,
+    }))
+}
+
+/// Fraction of the buffered records holding each column.
+async fn get_diagnostics(State(state): State<SharedState>) -> Json<serde_json::Value> {
+    let state = state.read().unwrap();
+    let fill_rates: serde_json::Map<String, serde_json::Value> = state
+        .field_fill_rates()
+        .into_iter()
+        .map(|(name, rate)| (name.to_string(), rate.into()))
+        .collect();
+    Json(serde_json::json!({
+        "records": state.data.len(),
+        "fill_rates": fill_rates,
+    }))
+}
+
+#[derive(Deserialize)]
+struct ManualValue {
+    timestamp: String,
+    value: serde_json::Value,
+}
+
+fn parse_manual_value(body: &ManualValue) -> Result<(DateTime<FixedOffset>, f64), String> {
+    let timestamp = DateTime::parse_from_rfc3339(&body.timestamp).map_err(|e| e.to_string())?;
+    let raw = match &body.value {
+        serde_json::Value::Number(n) => n.to_string(),
+        serde_json::Value::String(s) => s.clone(),
+        _ => return Err("Value must be a number".to_string()),
+    };
+    match parse_opt_positive_float(&raw)? {
+        Some(value) => Ok((timestamp, value)),
+        None => Err("Value is required".to_string()),
+    }
+}
+
+/// Save a single manual reading, `to_gas_water` says which column it goes in.
+fn save_manual_value(
+    state: &SharedState,
+    body: &ManualValue,
+    to_gas_water: fn(f64) -> (Option<f64>, Option<f64>),
+) -> Response {
+    match parse_manual_value(body) {
+        Ok((timestamp, value)) => {
+            let (gas, water) = to_gas_water(value);
+            let mut state = state.write().unwrap();
+            match save_manual_inputs(&mut state, timestamp, None, gas, water) {
+                Ok(record) => Json(record).into_response(),
+                Err(e) => (
+                    StatusCode::CONFLICT,
+                    Json(serde_json::json!({ "error": e })),
+                )
+                    .into_response(),
+            }
+        }
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "error": e })),
+        )
+            .into_response(),
+    }
+}
+
+async fn post_water(State(state): State<SharedState>, Json(body): Json<ManualValue>) -> Response {
+    save_manual_value(&state, &body, |value| (None, Some(value)))
+}
+
+async fn post_gas(State(state): State<SharedState>, Json(body): Json<ManualValue>) -> Response {
+    save_manual_value(&state, &body, |value| (Some(value), None))
+}
+
+/// Parse one `timestamp,pv2012_kWh,gas,water` row, the timestamp being either
+/// RFC3339 or seconds since the epoch.
+#[allow(clippy::type_complexity)]
+fn parse_import_row(
+    row: &str,
+) -> Result<(DateTime<FixedOffset>, Option<f64>, Option<f64>, Option<f64>), String> {
+    let fields: Vec<&str> = row.split(',').map(str::trim).collect();
+    let [timestamp, pv2012, gas, water] = fields[..] else {
+        return Err(format!("Expected 4 columns, got {}", fields.len()));
+    };
+    let timestamp = match timestamp.parse::<i64>() {
+        Ok(epoch) => DateTime::from_timestamp(epoch, 0)
+            .ok_or_else(|| format!("Timestamp out of range: {}", epoch))?
+            .into(),
+        Err(_) => DateTime::parse_from_rfc3339(timestamp).map_err(|e| e.to_string())?,
+    };
+    let parse = |label: &str, value: &str| {
+        parse_opt_positive_float(value).map_err(|e| format!("{}: {}", label, e))
+    };
+    match (
+        parse("pv2012_kWh", pv2012)?,
+        parse("gas", gas)?,
+        parse("water", water)?,
+    ) {
+        (None, None, None) => Err("No value to save".to_string()),
+        (pv2012, gas, water) => Ok((timestamp, pv2012, gas, water)),
+    }
+}
+
+/// Save every row of a `timestamp,pv2012_kWh,gas,water` CSV upload, collecting
+/// per-row errors instead of stopping at the first one.
+async fn post_import(State(state): State<SharedState>, body: String) -> Response {
+    let mut inserted = 0;
+    let mut updated = 0;
+    let mut errors = Vec::new();
+    let mut state = state.write().unwrap();
+    for (idx, row) in body.lines().enumerate() {
+        let row = row.trim();
+        if row.is_empty() || (idx == 0 && row.starts_with("timestamp")) {
+            continue;
+        }
+        match parse_import_row(row) {
+            Ok((timestamp, pv2012, gas, water)) => {
+                match upsert_manual_inputs(&mut state, timestamp, pv2012, gas, water) {
+                    Ok((_, true)) => updated += 1,
+                    Ok((_, false)) => inserted += 1,
+                    Err(e) => errors.push(serde_json::json!({ "line": idx + 1, "error": e })),
+                }
+            }
+            Err(e) => errors.push(serde_json::json!({ "line": idx + 1, "error": e })),
+        }
+    }
+    Json(serde_json::json!({
+        "inserted": inserted,
+        "updated": updated,
+        "failed": errors.len(),
+        "errors": errors,
+    }))
+    .into_response()
+}
+
+async fn get_last_telegram(State(state): State<SharedState>) -> Response {
+    match &state.read().unwrap().last_telegram {
+        Some(telegram) => (
+            [(CONTENT_TYPE, "text/plain; charset=utf-8")],
+            telegram.clone(),
+        )
+            .into_response(),
+        None => StatusCode::NO_CONTENT.into_response(),
+    }
+}
+
+/// What `RingBuffer::display` prints, for diagnosing buffer bugs.
+async fn get_ringbuffer(State(state): State<SharedState>) -> Json<serde_json::Value> {
+    let state = state.read().unwrap();
+    let internals = state.data.internals();
+    Json(serde_json::json!({
+        "start": internals.start,
+        "end": internals.end,
+        "capacity": internals.capacity,
+        "buffer_len": internals.buffer_len,
+        "records": freeze(&state.data).snapshot(),
+    }))
+}
+
+/// SQL script inserting the buffered records, e.g. to pipe into another
+/// sqlite3 database.  The buffer is left as is.
+async fn get_insert_sql(State(state): State<SharedState>) -> Response {
+    match state.read().unwrap().to_insert_sql() {
+        Some(sql) => ([(CONTENT_TYPE, "text/plain; charset=utf-8")], sql).into_response(),
+        None => StatusCode::NO_CONTENT.into_response(),
+    }
+}
+
+/// Columns to change in a buffered record, the others are kept.
+#[allow(non_snake_case)]
+#[derive(Deserialize)]
+struct RecordPatch {
+    timestamp: Option<i64>,
+    pv2012_kWh: Option<f64>,
+    pv2022_kWh: Option<f64>,
+    peak_conso_kWh: Option<f64>,
+    off_conso_kWh: Option<f64>,
+    peak_inj_kWh: Option<f64>,
+    off_inj_kWh: Option<f64>,
+    gas_m3: Option<f64>,
+    water_m3: Option<f64>,
+}
+
+/// The record with exactly that timestamp, from the buffer or else from the
+/// database.
+async fn get_record(
+    State((state, sql_cmd)): State<(SharedState, Arc<str>)>,
+    Path(timestamp): Path<i64>,
+) -> Response {
+    let buffered = state
+        .read()
+        .unwrap()
+        .find_nearest(timestamp, 0)
+        .map(|(_, record)| record);
+    if let Some(record) = buffered {
+        return Json(record).into_response();
+    }
+    match task::spawn_blocking(move || {
+        select_data_202303_range(&SqliteCmd(&sql_cmd), timestamp, timestamp)
+    })
+    .await
+    {
+        Ok(Ok(rows)) => match rows.into_iter().next() {
+            Some(record) => Json(record).into_response(),
+            None => (
+                StatusCode::NOT_FOUND,
+                Json(serde_json::json!({ "error": "no record with that timestamp" })),
+            )
+                .into_response(),
+        },
+        Ok(Err(e)) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+/// Edit the buffered record with exactly that timestamp and return it.
+async fn put_record(
+    State(state): State<SharedState>,
+    Path(timestamp): Path<i64>,
+    Json(patch): Json<RecordPatch>,
+) -> Response {
+    if patch.timestamp.is_some_and(|ts| ts != timestamp) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "error": "timestamp does not match the path" })),
+        )
+            .into_response();
+    }
+    let mut state = state.write().unwrap();
+    let Some((_, existing)) = state.find_nearest(timestamp, 0) else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({ "error": "no record with that timestamp" })),
+        )
+            .into_response();
+    };
+    let mut record = Data202303 {
+        timestamp,
+        pv2012_kWh: patch.pv2012_kWh,
+        pv2022_kWh: patch.pv2022_kWh,
+        peak_conso_kWh: patch.peak_conso_kWh,
+        off_conso_kWh: patch.off_conso_kWh,
+        peak_inj_kWh: patch.peak_inj_kWh,
+        off_inj_kWh: patch.off_inj_kWh,
+        gas_m3: patch.gas_m3,
+        water_m3: patch.water_m3,
+    };
+    record.merge_from(&existing);
+    state.replace_by_timestamp(timestamp, clone_data202303(&record));
+    Json(record).into_response()
+}
+
+/// Change the capacity of the in-memory buffer.  Shrinking below the current
+/// length drops the oldest records.
+async fn post_capacity(State(state): State<SharedState>, Path(n): Path<usize>) -> Response {
+    if n == 0 {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "error": "capacity must be strictly positive" })),
+        )
+            .into_response();
+    }
+    let mut state = state.write().unwrap();
+    state.data.resize(n);
+    Json(serde_json::json!({
+        "capacity": state.data.get_capacity(),
+        "len": state.data.len(),
+    }))
+    .into_response()
+}
+
+async fn get_favicon() -> StatusCode {
+    StatusCode::NO_CONTENT
+}
+
+/// `base_path` with a leading slash and without trailing ones, empty for the
+/// root.
+fn normalize_base_path(base_path: &str) -> String {
+    let base_path = base_path.trim().trim_matches('/');
+    if base_path.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", base_path)
+    }
+}
+
+fn build_router(
+    shared_state: &SharedState,
+    base_path: &str,
+    static_dir: Option<&str>,
+    auth: Option<&str>,
+    sql_cmd: &str,
+    max_body_bytes: usize,
+    rate_limit_per_minute: Option<u32>,
+) -> Router {
+    let auth: Option<Arc<str>> = auth.map(Arc::from);
+    let limiter = rate_limit_per_minute.map(|n| Arc::new(RateLimiter::new(n)));
+    let routes = Router::new()
         .route(
             FORM_PATH,
-            get_service(get_form.with_state(Arc::clone(&shared_state)))
-                .post_service(post_form.with_state(Arc::clone(&shared_state))),
+            get_service(get_form.with_state(Arc::clone(shared_state))).post_service(
+                post_form
+                    .layer(middleware::from_fn_with_state(
+                        auth.clone(),
+                        require_basic_auth,
+                    ))
+                    .with_state(Arc::clone(shared_state)),
+            ),
+        )
+        .route(
+            WATER_PATH,
+            post_service(
+                post_water
+                    .layer(middleware::from_fn_with_state(
+                        auth.clone(),
+                        require_basic_auth,
+                    ))
+                    .with_state(Arc::clone(shared_state)),
+            ),
+        )
+        .route(
+            GAS_PATH,
+            post_service(
+                post_gas
+                    .layer(middleware::from_fn_with_state(
+                        auth.clone(),
+                        require_basic_auth,
+                    ))
+                    .with_state(Arc::clone(shared_state)),
+            ),
         )
-        .with_state(Arc::clone(&shared_state));
+        .route(
+            IMPORT_PATH,
+            post_service(
+                post_import
+                    .layer(middleware::from_fn_with_state(
+                        auth.clone(),
+                        require_basic_auth,
+                    ))
+                    .with_state(Arc::clone(shared_state)),
+            ),
+        )
+        .route(
+            LAST_TELEGRAM_PATH,
+            get_service(
+                get_last_telegram
+                    .layer(middleware::from_fn_with_state(
+                        auth.clone(),
+                        require_basic_auth,
+                    ))
+                    .with_state(Arc::clone(shared_state)),
+            ),
+        )
+        .route(
+            RECORD_PATH,
+            get_service(
+                get_record.with_state((Arc::clone(shared_state), Arc::<str>::from(sql_cmd))),
+            )
+            .put_service(
+                put_record
+                    .layer(middleware::from_fn_with_state(
+                        auth.clone(),
+                        require_basic_auth,
+                    ))
+                    .with_state(Arc::clone(shared_state)),
+            ),
+        )
+        .route(
+            RINGBUFFER_PATH,
+            get_service(
+                get_ringbuffer
+                    .layer(middleware::from_fn_with_state(
+                        auth.clone(),
+                        require_basic_auth,
+                    ))
+                    .with_state(Arc::clone(shared_state)),
+            ),
+        )
+        .route(
+            INSERT_SQL_PATH,
+            get_service(
+                get_insert_sql
+                    .layer(middleware::from_fn_with_state(
+                        auth.clone(),
+                        require_basic_auth,
+                    ))
+                    .with_state(Arc::clone(shared_state)),
+            ),
+        )
+        .route(
+            CAPACITY_PATH,
+            post_service(
+                post_capacity
+                    .layer(middleware::from_fn_with_state(auth, require_basic_auth))
+                    .with_state(Arc::clone(shared_state)),
+            ),
+        )
+        .route(
+            SINCE_START_PATH,
+            get_service(get_since_start.with_state(Arc::clone(shared_state))),
+        )
+        .route(
+            STATUS_PATH,
+            get_service(get_app_status.with_state(Arc::clone(shared_state))),
+        )
+        .route(
+            DIAGNOSTICS_PATH,
+            get_service(get_diagnostics.with_state(Arc::clone(shared_state))),
+        )
+        .route(
+            SERIES_PATH,
+            get_service(get_series.with_state(Arc::clone(shared_state))),
+        )
+        .route(
+            EXPORT_PATH,
+            get_service(get_export.with_state(Arc::<str>::from(sql_cmd))),
+        )
+        .route(
+            DAILY_PATH,
+            get_service(get_daily.with_state(Arc::<str>::from(sql_cmd))),
+        )
+        .layer(middleware::from_fn_with_state(limiter, rate_limit));
+    let app = match normalize_base_path(base_path).as_str() {
+        "" => routes,
+        base_path => Router::new().nest(base_path, routes),
+    }
+    .route("/favicon.ico", get(get_favicon));
+    match static_dir {
+        Some(dir) => app.nest_service("/static", ServeDir::new(dir)),
+        None => app,
+    }
+    .layer(DefaultBodyLimit::max(max_body_bytes))
+    .layer(CompressionLayer::new())
+    .layer(middleware::from_fn(log_request))
+    .with_state(Arc::clone(shared_state))
+}
 
-    // Run our app with hyper
-    let bind_addr =
-        env::var("AXUM_METER_READINGS_BIND_ADDR").unwrap_or_else(|_| "127.0.0.1:3000".to_string());
-    let listener = tokio::net::TcpListener::bind(bind_addr).await.unwrap();
-    println!("listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, app).await.unwrap();
+/// How the messages and the outcome of each poll cycle are logged.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LogFormat {
+    /// The usual free-form messages only.
+    Text,
+    /// One JSON object per message and per cycle (see `log_cycle`), for log
+    /// collectors.
+    Json,
+}
+
+fn parse_log_format(s: &str) -> Option<LogFormat> {
+    match s.trim().to_lowercase().as_str() {
+        "text" => Some(LogFormat::Text),
+        "json" => Some(LogFormat::Json),
+        _ => None,
+    }
+}
+
+/// `tracing_subscriber`'s JSON formatter writing one object per event, its
+/// fields at the top level.
+fn json_subscriber<W>(make_writer: W) -> impl tracing::Subscriber + Send + Sync
+where
+    W: for<'w> tracing_subscriber::fmt::MakeWriter<'w> + Send + Sync + 'static,
+{
+    tracing_subscriber::fmt()
+        .json()
+        .flatten_event(true)
+        .with_writer(make_writer)
+        .finish()
+}
+
+/// Target of the `log_cycle` events, only meant for the JSON output.
+const CYCLE_TARGET: &str = "meter_server::cycle";
+
+/// Formatter writing each message alone on its line, as `println!` would,
+/// without the per-cycle summaries.
+fn text_subscriber<W>(make_writer: W) -> impl tracing::Subscriber + Send + Sync
+where
+    W: for<'w> tracing_subscriber::fmt::MakeWriter<'w> + Send + Sync + 'static,
+{
+    tracing_subscriber::registry().with(
+        tracing_subscriber::fmt::layer()
+            .without_time()
+            .with_level(false)
+            .with_target(false)
+            .with_ansi(false)
+            .with_writer(make_writer)
+            .with_filter(tracing_subscriber::filter::filter_fn(|metadata| {
+                metadata.target() != CYCLE_TARGET
+            })),
+    )
+}
+
+/// Install the subscriber for `format`, through which every message after
+/// the configuration is logged.
+fn init_logging(format: LogFormat) {
+    let installed = match format {
+        LogFormat::Text => {
+            tracing::subscriber::set_global_default(text_subscriber(std::io::stdout))
+        }
+        LogFormat::Json => {
+            tracing::subscriber::set_global_default(json_subscriber(std::io::stdout))
+        }
+    };
+    if let Err(e) = installed {
+        println!("Unable to set up {:?} logging: {}", format, e);
+    }
+}
+
+/// Structured summary of one poll cycle.
+fn log_cycle(counter: u64, p1_present: bool, pv_value: Option<f64>, rows_flushed: usize) {
+    tracing::info!(
+        target: CYCLE_TARGET,
+        counter,
+        p1_present,
+        pv_value,
+        rows_flushed,
+        "poll"
+    );
+}
+
+/// Column names from a comma separated list, ignoring (with a warning) those
+/// that `Data202303::field_accessor` does not know.
+fn parse_field_list(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .filter(|name| {
+            let known = Data202303::field_accessor(name).is_some();
+            if !known {
+                println!("Warning: unknown field '{}' ignored", name);
+            }
+            known
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+/// Run `body` on the blocking thread pool, restarting it after `backoff`
+/// whenever it panics, until it returns normally.
+async fn supervise_blocking<F>(name: &'static str, backoff: Duration, body: F)
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    let body = Arc::new(body);
+    loop {
+        let body = Arc::clone(&body);
+        match task::spawn_blocking(move || body()).await {
+            Ok(()) => return,
+            Err(e) => {
+                tracing::error!(
+                    "{} stopped ({}), restarting in {:.3}s",
+                    name,
+                    e,
+                    backoff.as_secs_f64()
+                );
+                tokio::time::sleep(backoff).await;
+            }
+        }
+    }
+}
+
+/// Resolves on Ctrl-C or SIGTERM.
+async fn shutdown_signal() {
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Unable to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await
+            }
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate => {},
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let config = Config::from_env().unwrap_or_else(|e| {
+        println!("Invalid configuration: {}", e);
+        std::process::exit(1)
+    });
+    init_logging(config.log_format);
+    let shared_state: SharedState = Arc::new(std::sync::RwLock::new(
+        match config.snapshot_path.as_deref().map(std::path::Path::new) {
+            Some(path) if path.exists() => match AppState::load_snapshot(path, config.capacity) {
+                Ok(state) => {
+                    tracing::info!("Loaded {} records from {:?}", state.data.len(), path);
+                    state
+                }
+                Err(e) => {
+                    tracing::error!("Unable to load snapshot {:?}: {}", path, e);
+                    AppState::with_capacity(config.capacity)
+                }
+            },
+            _ => AppState::with_capacity(config.capacity),
+        },
+    ));
+
+    let p1_source = config.p1_source.clone();
+    let p1_timeout = config.p1_timeout();
+    let p1_retries = config.p1_retries;
+    let p1_obis = config.p1_obis.clone();
+    let pv_2022_cmd = config.pv_cmd.clone();
+    let pv_2022_json_path = config.pv_2022_json_path.clone();
+    let pv_timeout = config.pv_timeout();
+    let pv_divisor = config.pv_divisor;
+    let pv_2012_cmd = config.pv_2012_cmd.clone();
+    let pv_2012_serial = config.pv_2012_serial.clone();
+    let sql_cmd = config.sql_cmd.clone();
+    for warning in check_commands(
+        &p1_source,
+        p1_timeout,
+        &p1_obis,
+        &pv_2022_cmd,
+        &pv_2022_json_path,
+        pv_2012_cmd
+            .as_deref()
+            .map(|cmd| (cmd, pv_2012_serial.as_str())),
+        pv_timeout,
+        &sql_cmd,
+    ) {
+        tracing::warn!("WARNING: {}", warning);
+    }
+    match select_latest_data_202303(&SqliteCmd(&sql_cmd)) {
+        Ok(latest) => {
+            tracing::info!("Latest persisted record: {:?}", latest);
+            shared_state.write().unwrap().latest_persisted = latest;
+        }
+        Err(e) => tracing::error!("Unable to read latest persisted record: {}", e),
+    }
+    if config.seed_from_db {
+        match shared_state
+            .write()
+            .unwrap()
+            .seed_from_db(&SqliteCmd(&sql_cmd))
+        {
+            Ok(count) => tracing::info!("Seeded the buffer with {} persisted records", count),
+            Err(e) => tracing::error!("Unable to seed the buffer: {}", e),
+        }
+    }
+    {
+        let mut state = shared_state.write().unwrap();
+        state.decreasing_guard = config.decreasing_guard.clone();
+        state.quiet_nothing_to_do = config.quiet_nothing_to_do;
+        state.high_water_mark = config.high_water_mark;
+        state.summary_fields = config.summary_fields.clone();
+        state.zero_pv2022_as_missing = config.zero_pv2022_as_missing;
+        state.pv2022_monotonic_tolerance = config.pv2022_monotonic_tolerance;
+        state.snap_to_grid_secs = config.snap_to_grid_secs;
+        state.snap_manual_inputs = config.snap_manual_inputs;
+        if config.check_off_hours {
+            state.off_hours = Some(OffHoursSchedule::default());
+        }
+    }
+    let dump_interval = config.dump_interval;
+    let verbose = config.verbose;
+    let blocking_ref = Arc::clone(&shared_state);
+    let polling_period = config.polling_period();
+    let insert_batch_size = config.insert_batch_size;
+    let retention = config.retention();
+    let dry_run = config.dry_run;
+    let sqlite_pragmas = config.sqlite_pragmas.clone();
+    let sql_dump_file = config.sql_dump_file.clone();
+    let flusher = (config.async_flush && !dry_run).then(|| {
+        let (sender, batches) = mpsc::channel();
+        // Only one flush task at a time, but it may be restarted
+        let batches = Mutex::new(batches);
+        let flush_ref = Arc::clone(&shared_state);
+        let sql_cmd = sql_cmd.clone();
+        let sqlite_pragmas = sqlite_pragmas.clone();
+        let sql_dump_file = sql_dump_file.clone();
+        tokio::spawn(supervise_blocking(
+            "Flush task",
+            Duration::from_secs(10),
+            move || {
+                flush_ref.clear_poison();
+                run_flusher(
+                    &flush_ref,
+                    &batches.lock().unwrap_or_else(|e| e.into_inner()),
+                    &SqliteCmd(&sql_cmd),
+                    &sqlite_pragmas,
+                    sql_dump_file.as_deref().map(std::path::Path::new),
+                )
+            },
+        ));
+        sender
+    });
+    tokio::spawn(supervise_blocking(
+        "Polling loop",
+        Duration::from_secs(10),
+        move || {
+            // A previous run may have panicked while holding the lock
+            blocking_ref.clear_poison();
+            let p1_reader = P1Reader::new(&p1_source);
+            let mut counter = 0;
+            loop {
+                let start = Instant::now();
+                counter += 1;
+                let (p1, pv_2022, pv_2012) = poll_automated_measurements(
+                    &blocking_ref,
+                    &p1_reader,
+                    p1_timeout,
+                    p1_retries,
+                    &p1_obis,
+                    &pv_2022_cmd,
+                    &pv_2022_json_path,
+                    pv_timeout,
+                    pv_divisor,
+                    pv_2012_cmd
+                        .as_deref()
+                        .map(|cmd| (cmd, pv_2012_serial.as_str())),
+                    verbose,
+                );
+                let p1_present = p1.is_some();
+                let flushed = save_data(
+                    &blocking_ref,
+                    p1,
+                    pv_2022,
+                    pv_2012,
+                    &SqliteCmd(&sql_cmd),
+                    dump_interval,
+                    verbose,
+                    insert_batch_size,
+                    &sqlite_pragmas,
+                    &retention,
+                    dry_run,
+                    sql_dump_file.as_deref().map(std::path::Path::new),
+                    flusher.as_ref(),
+                );
+                log_cycle(
+                    counter,
+                    p1_present,
+                    pv_2022,
+                    flushed.map_or(0, |(sent, _)| sent),
+                );
+                let elapsed = start.elapsed();
+                // Make the buffer last longer while the database is down
+                let polling_period = if blocking_ref.read().unwrap().degraded {
+                    polling_period * DEGRADED_SLOWDOWN
+                } else {
+                    polling_period
+                };
+                if elapsed < polling_period {
+                    thread::sleep(polling_period - elapsed);
+                } else {
+                    tracing::warn!(
+                        "Warning: poll_automated_measurements took longer than {}s: {}s",
+                        polling_period.as_secs(),
+                        elapsed.as_secs()
+                    );
+                }
+            }
+        },
+    ));
+
+    // Build our application by composing routes
+    let app = build_router(
+        &shared_state,
+        &config.base_path,
+        config.static_dir.as_deref(),
+        config.auth.as_deref(),
+        &config.sql_cmd,
+        config.max_body_bytes,
+        config.rate_limit_per_minute,
+    );
+
+    // Run our app with hyper
+    let listener = tokio::net::TcpListener::bind(&config.bind_addr)
+        .await
+        .unwrap();
+    tracing::info!("listening on {}", listener.local_addr().unwrap());
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await
+    .unwrap();
+    if let Some(path) = config.snapshot_path {
+        let state = shared_state.read().unwrap();
+        match state.save_snapshot(std::path::Path::new(&path)) {
+            Ok(()) => tracing::info!("Saved {} records to {}", state.data.len(), path),
+            Err(e) => tracing::error!("Unable to save snapshot {}: {}", path, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::Request};
+    use blocking_task::RetentionPolicy;
+    use meter_core::{data::FakeSqlite, p1_meter::P1ObisConfig, pv2022};
+    use std::env;
+    use std::fs;
+    use tower::ServiceExt;
+
+    // Routes as served under the default base path
+    const FORM_PATH: &str = "/axum-meter-readings/form";
+    const EXPORT_PATH: &str = "/axum-meter-readings/api/export";
+    const DAILY_PATH: &str = "/axum-meter-readings/api/daily";
+    const SERIES_PATH: &str = "/axum-meter-readings/api/series";
+    const SINCE_START_PATH: &str = "/axum-meter-readings/api/since-start";
+    const STATUS_PATH: &str = "/axum-meter-readings/api/status";
+    const DIAGNOSTICS_PATH: &str = "/axum-meter-readings/api/diagnostics";
+    const WATER_PATH: &str = "/axum-meter-readings/api/water";
+    const GAS_PATH: &str = "/axum-meter-readings/api/gas";
+    const IMPORT_PATH: &str = "/axum-meter-readings/api/import";
+    const LAST_TELEGRAM_PATH: &str = "/axum-meter-readings/api/debug/last-telegram";
+    const RECORD_PATH: &str = "/axum-meter-readings/api/record/{timestamp}";
+    const CAPACITY_PATH: &str = "/axum-meter-readings/admin/capacity/{n}";
+    const RINGBUFFER_PATH: &str = "/axum-meter-readings/admin/debug/ringbuffer";
+    const INSERT_SQL_PATH: &str = "/axum-meter-readings/admin/insert.sql";
+
+    const FAKE_SQL: &str = "cat > /dev/null; echo '2\n1695485100|50621.3|3579.4|||630.0|1189.4|28973.5|867.5\n1695485160||3579.5||||||'";
+
+    async fn get_status(app: Router, uri: &str) -> StatusCode {
+        app.oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[test]
+    fn request_log_has_method_path_status_and_latency() {
+        assert_eq!(
+            format_request_log(
+                &Method::POST,
+                FORM_PATH,
+                StatusCode::SEE_OTHER,
+                Duration::from_micros(1500)
+            ),
+            "POST /axum-meter-readings/form -> 303 in 1.500ms"
+        );
+    }
+
+    #[tokio::test]
+    async fn logged_router_still_answers_requests() {
+        let app = build_router(
+            &SharedState::default(),
+            DEFAULT_BASE_PATH,
+            None,
+            None,
+            FAKE_SQL,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        );
+        assert_eq!(get_status(app.clone(), FORM_PATH).await, StatusCode::OK);
+        assert_eq!(get_status(app, "/nowhere").await, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn routes_follow_custom_base_path() {
+        let state = SharedState::default();
+        let app = build_router(
+            &state,
+            "/meters/",
+            None,
+            None,
+            FAKE_SQL,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        );
+        assert_eq!(
+            get_status(app.clone(), "/meters/api/series").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            get_status(app.clone(), SERIES_PATH).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            get_status(app.clone(), "/favicon.ico").await,
+            StatusCode::NO_CONTENT
+        );
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/meters/form")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(String::from_utf8_lossy(&body).contains(r#"action="/meters/form""#));
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/meters/form")
+                    .header("Content-Type", "application/x-www-form-urlencoded")
+                    .body(Body::from(FORM_BODY))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(response.headers()["Location"], "/meters/form");
+
+        // An empty base path serves the routes at the root
+        let app = build_router(
+            &state,
+            "/",
+            None,
+            None,
+            FAKE_SQL,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        );
+        assert_eq!(get_status(app, "/api/series").await, StatusCode::OK);
+    }
+
+    #[test]
+    fn normalize_base_path_variants() {
+        assert_eq!(
+            normalize_base_path("/axum-meter-readings"),
+            "/axum-meter-readings"
+        );
+        assert_eq!(normalize_base_path("meters/"), "/meters");
+        assert_eq!(normalize_base_path("/a/b//"), "/a/b");
+        assert_eq!(normalize_base_path("/"), "");
+        assert_eq!(normalize_base_path(""), "");
+    }
+
+    #[tokio::test]
+    async fn responses_are_gzipped_when_accepted() {
+        let app = build_router(
+            &SharedState::default(),
+            DEFAULT_BASE_PATH,
+            None,
+            None,
+            FAKE_SQL,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        );
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri(FORM_PATH)
+                    .header("Accept-Encoding", "gzip")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["Content-Encoding"], "gzip");
+
+        let app = build_router(
+            &SharedState::default(),
+            DEFAULT_BASE_PATH,
+            None,
+            None,
+            FAKE_SQL,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        );
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri(FORM_PATH)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(response.headers().get("Content-Encoding").is_none());
+    }
+
+    const FORM_BODY: &str =
+        "timestamp=2025-01-02T03%3A04%3A00%2B01%3A00&pv2012_kWh=&gas=1234.5&water=";
+
+    async fn post_form_with_auth(
+        state: &SharedState,
+        auth: Option<&str>,
+        authorization: Option<&str>,
+    ) -> Response {
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(FORM_PATH)
+            .header("Content-Type", "application/x-www-form-urlencoded");
+        if let Some(authorization) = authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        build_router(
+            state,
+            DEFAULT_BASE_PATH,
+            None,
+            auth,
+            FAKE_SQL,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        )
+        .oneshot(request.body(Body::from(FORM_BODY)).unwrap())
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn empty_form_submission_shows_info_unless_quiet() {
+        const EMPTY_FORM_BODY: &str =
+            "timestamp=2025-01-02T03%3A04%3A00%2B01%3A00&pv2012_kWh=&gas=&water=";
+        let state = SharedState::default();
+        for (quiet, expect_banner) in [(false, true), (true, false)] {
+            state.write().unwrap().quiet_nothing_to_do = quiet;
+            let response = build_router(
+                &state,
+                DEFAULT_BASE_PATH,
+                None,
+                None,
+                FAKE_SQL,
+                DEFAULT_MAX_BODY_BYTES,
+                None,
+            )
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri(FORM_PATH)
+                    .header("Content-Type", "application/x-www-form-urlencoded")
+                    .body(Body::from(EMPTY_FORM_BODY))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let html = String::from_utf8(body.to_vec()).unwrap();
+            assert_eq!(
+                html.contains(r#"<div class="info">Nothing to do for timestamp=2025-01-02T03:04:00+01:00</div>"#),
+                expect_banner,
+                "quiet={}",
+                quiet
+            );
+            assert!(!html.contains(r#"<div class="general-error">"#));
+        }
+        assert_eq!(state.read().unwrap().data.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn basic_auth_is_noop_when_unset() {
+        let state = SharedState::default();
+        let response = post_form_with_auth(&state, None, None).await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(state.read().unwrap().data.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn basic_auth_rejects_missing_credentials() {
+        let state = SharedState::default();
+        let response = post_form_with_auth(&state, Some("me:secret"), None).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers()[WWW_AUTHENTICATE],
+            r#"Basic realm="axum-meter-readings""#
+        );
+        assert_eq!(state.read().unwrap().data.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn basic_auth_rejects_wrong_credentials() {
+        let state = SharedState::default();
+        for authorization in [
+            format!("Basic {}", BASE64.encode("me:wrong")),
+            format!("Basic {}", BASE64.encode("me:secretx")),
+            "Basic not-base64!".to_string(),
+            format!("Bearer {}", BASE64.encode("me:secret")),
+        ] {
+            let response =
+                post_form_with_auth(&state, Some("me:secret"), Some(&authorization)).await;
+            assert_eq!(
+                response.status(),
+                StatusCode::UNAUTHORIZED,
+                "{}",
+                authorization
+            );
+        }
+        assert_eq!(state.read().unwrap().data.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn basic_auth_accepts_correct_credentials() {
+        let state = SharedState::default();
+        let authorization = format!("Basic {}", BASE64.encode("me:secret"));
+        let response = post_form_with_auth(&state, Some("me:secret"), Some(&authorization)).await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(state.read().unwrap().data.len(), 1);
+
+        // GET stays open so the form can be displayed
+        let app = build_router(
+            &state,
+            DEFAULT_BASE_PATH,
+            None,
+            Some("me:secret"),
+            FAKE_SQL,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        );
+        assert_eq!(get_status(app, FORM_PATH).await, StatusCode::OK);
+    }
+
+    async fn get_json(app: Router, uri: &str) -> (StatusCode, serde_json::Value) {
+        let response = app
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn status_reports_last_flush() {
+        let state = SharedState::default();
+        {
+            let mut w = state.write().unwrap();
+            w.data.push(Data202303::empty(1000));
+            w.last_flush_ts = Some(990);
+            w.last_flush_rows = 12;
+            w.degraded = true;
+        }
+        let app = build_router(
+            &state,
+            DEFAULT_BASE_PATH,
+            None,
+            None,
+            FAKE_SQL,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        );
+        let (status, json) = get_json(app, STATUS_PATH).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "records": 1,
+                "capacity": 1440,
+                "last_record_ts": 1000,
+                "last_flush_ts": 990,
+                "last_flush_rows": 12,
+                "degraded": true,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn diagnostics_report_fill_rates() {
+        let state = SharedState::default();
+        {
+            let mut w = state.write().unwrap();
+            w.data.push(Data202303::empty(1000).with_gas(Some(1.0)));
+            w.data.push(Data202303::empty(1060).with_pv2022(Some(2.0)));
+        }
+        let app = build_router(
+            &state,
+            DEFAULT_BASE_PATH,
+            None,
+            None,
+            FAKE_SQL,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        );
+        let (status, json) = get_json(app, DIAGNOSTICS_PATH).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "records": 2,
+                "fill_rates": {
+                    "pv2012_kWh": 0.0,
+                    "pv2022_kWh": 0.5,
+                    "peak_conso_kWh": 0.0,
+                    "off_conso_kWh": 0.0,
+                    "peak_inj_kWh": 0.0,
+                    "off_inj_kWh": 0.0,
+                    "gas_m3": 0.5,
+                    "water_m3": 0.0,
+                },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn export_returns_all_rows_as_json_array() {
+        let app = build_router(
+            &SharedState::default(),
+            DEFAULT_BASE_PATH,
+            None,
+            None,
+            FAKE_SQL,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        );
+        let (status, json) = get_json(app, EXPORT_PATH).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            json,
+            serde_json::json!([
+                {
+                    "timestamp": 1695485100,
+                    "pv2012_kWh": 50621.3,
+                    "pv2022_kWh": 3579.4,
+                    "peak_conso_kWh": null,
+                    "off_conso_kWh": null,
+                    "peak_inj_kWh": 630.0,
+                    "off_inj_kWh": 1189.4,
+                    "gas_m3": 28973.5,
+                    "water_m3": 867.5
+                },
+                {
+                    "timestamp": 1695485160,
+                    "pv2012_kWh": null,
+                    "pv2022_kWh": 3579.5,
+                    "peak_conso_kWh": null,
+                    "off_conso_kWh": null,
+                    "peak_inj_kWh": null,
+                    "off_inj_kWh": null,
+                    "gas_m3": null,
+                    "water_m3": null
+                }
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn since_start_reports_deltas() {
+        let state = SharedState::default();
+        let app = build_router(
+            &state,
+            DEFAULT_BASE_PATH,
+            None,
+            None,
+            FAKE_SQL,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        );
+        assert_eq!(
+            get_status(app.clone(), SINCE_START_PATH).await,
+            StatusCode::NO_CONTENT
+        );
+        state.write().unwrap().data.push(
+            Data202303::empty(1000)
+                .with_pv2022(Some(3579.5))
+                .with_gas(Some(28973.5)),
+        );
+        assert_eq!(
+            get_status(app.clone(), SINCE_START_PATH).await,
+            StatusCode::NO_CONTENT
+        );
+        state.write().unwrap().data.push(Data202303 {
+            peak_conso_kWh: Some(101.0),
+            ..Data202303::empty(4600)
+                .with_pv2022(Some(3580.0))
+                .with_gas(Some(28974.0))
+        });
+        let (status, json) = get_json(app, SINCE_START_PATH).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "from": 1000,
+                "to": 4600,
+                "span_secs": 3600,
+                "delta": {
+                    "pv2012_kWh": null,
+                    "pv2022_kWh": 0.5,
+                    "peak_conso_kWh": null,
+                    "off_conso_kWh": null,
+                    "peak_inj_kWh": null,
+                    "off_inj_kWh": null,
+                    "gas_m3": 0.5,
+                    "water_m3": null,
+                }
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn series_answers_304_while_unchanged() {
+        let state = SharedState::default();
+        state
+            .write()
+            .unwrap()
+            .data
+            .push(Data202303::empty(1695485100).with_pv2022(Some(3579.4)));
+        let app = build_router(
+            &state,
+            DEFAULT_BASE_PATH,
+            None,
+            None,
+            FAKE_SQL,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        );
+        let get_series = |etag: Option<String>| {
+            let mut request = Request::builder().uri(SERIES_PATH);
+            if let Some(etag) = etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let response = get_series(None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[ETAG].to_str().unwrap().to_string();
+        assert!(etag.starts_with("W/\""));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json[0]["pv2022_kWh"], 3579.4);
+
+        let response = get_series(Some(format!("\"other\", {}", etag)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[ETAG], etag.as_str());
+
+        state.write().unwrap().data.drop_first(1);
+        let response = get_series(Some(etag.clone())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_ne!(response.headers()[ETAG], etag.as_str());
+    }
+
+    #[tokio::test]
+    async fn series_filters_on_field() {
+        let state = SharedState::default();
+        {
+            let mut w = state.write().unwrap();
+            w.data
+                .push(Data202303::empty(1695485100).with_pv2022(Some(3579.4)));
+            w.data.push(
+                Data202303::empty(1695485160)
+                    .with_pv2022(Some(3579.5))
+                    .with_gas(Some(28973.5)),
+            );
+            w.data
+                .push(Data202303::empty(1695485220).with_pv2022(Some(3579.6)));
+        }
+        let app = build_router(
+            &state,
+            DEFAULT_BASE_PATH,
+            None,
+            None,
+            FAKE_SQL,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        );
+        let (status, json) = get_json(app.clone(), &format!("{}?field=gas_m3", SERIES_PATH)).await;
+        assert_eq!(status, StatusCode::OK);
+        let timestamps: Vec<_> = json
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|row| row["timestamp"].as_i64().unwrap())
+            .collect();
+        assert_eq!(timestamps, vec![1695485160]);
+
+        let (status, json) =
+            get_json(app.clone(), &format!("{}?field=pv2022_kWh", SERIES_PATH)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json.as_array().unwrap().len(), 3);
+
+        let (status, json) = get_json(app, &format!("{}?field=timestamp", SERIES_PATH)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(json, serde_json::json!({ "error": "unknown field" }));
+    }
+
+    #[tokio::test]
+    async fn daily_totals_from_aggregated_rows() {
+        let app = build_router(
+            &SharedState::default(),
+            DEFAULT_BASE_PATH,
+            None,
+            None,
+            "grep -q 'timestamp >= 1735686000 AND timestamp < 1735858800' && echo '2025-01-01|3.5|7.25' && echo '2025-01-02||1.0'",
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        );
+        let (status, json) = get_json(
+            app.clone(),
+            &format!(
+                "{}?from=2025-01-01&to=2025-01-02&tz_offset=3600",
+                DAILY_PATH
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            json,
+            serde_json::json!([
+                { "day": "2025-01-01", "peak": 3.5, "off": 7.25 },
+                { "day": "2025-01-02", "peak": null, "off": 1.0 },
+            ])
+        );
+
+        for query in [
+            "?from=yesterday",
+            "?to=2025-13-01",
+            "?tz_offset=abc",
+            "?from=2025-01-02&to=2025-01-01",
+        ] {
+            let (status, json) = get_json(app.clone(), &format!("{}{}", DAILY_PATH, query)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", query);
+            assert!(json["error"].is_string(), "{}", query);
+        }
+    }
+
+    #[test]
+    fn daily_range_defaults_to_last_30_days() {
+        let now = DateTime::parse_from_rfc3339("2025-01-31T00:30:00+01:00").unwrap();
+        let query = DailyQuery {
+            from: None,
+            to: None,
+            tz_offset: None,
+        };
+        // 2025-01-02T00:00:00+01:00 up to 2025-02-01T00:00:00+01:00
+        assert_eq!(daily_range(&query, now), Ok((1735772400, 1738364400, 3600)));
+        let query = DailyQuery {
+            from: Some(" 2025-01-30 ".to_string()),
+            to: None,
+            tz_offset: Some("0".to_string()),
+        };
+        // Still 2025-01-30 in UTC
+        assert_eq!(daily_range(&query, now), Ok((1738195200, 1738281600, 0)));
+    }
+
+    #[tokio::test]
+    async fn export_of_empty_table_is_empty_array() {
+        let app = build_router(
+            &SharedState::default(),
+            DEFAULT_BASE_PATH,
+            None,
+            None,
+            "cat > /dev/null; echo 0",
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        );
+        let (status, json) = get_json(app, EXPORT_PATH).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json, serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn export_streams_one_row_at_a_time() {
+        let app = build_router(
+            &SharedState::default(),
+            DEFAULT_BASE_PATH,
+            None,
+            None,
+            FAKE_SQL,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        );
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri(EXPORT_PATH)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut body = response.into_body().into_data_stream();
+        let mut chunks = Vec::new();
+        while let Some(chunk) = body.next().await {
+            chunks.push(String::from_utf8(chunk.unwrap().to_vec()).unwrap());
+        }
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks[0].starts_with("[{\"timestamp\":1695485100,"));
+        assert!(chunks[1].starts_with(",{\"timestamp\":1695485160,"));
+        assert_eq!(chunks[2], "]");
+        let json: serde_json::Value = serde_json::from_str(&chunks.concat()).unwrap();
+        assert_eq!(json.as_array().unwrap().len(), 2);
+
+        // Too late for a 500: the body is cut short instead
+        let app = build_router(
+            &SharedState::default(),
+            DEFAULT_BASE_PATH,
+            None,
+            None,
+            "cat > /dev/null; echo '2\n1695485100||3579.4||||||\ngarbage'",
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        );
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri(EXPORT_PATH)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut body = response.into_body().into_data_stream();
+        assert!(body.next().await.unwrap().is_ok());
+        assert!(body.next().await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn export_reports_sqlite_errors_as_500() {
+        let app = build_router(
+            &SharedState::default(),
+            DEFAULT_BASE_PATH,
+            None,
+            None,
+            "cat > /dev/null; echo 'Error: no such table: data_202303'",
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        );
+        let (status, json) = get_json(app, EXPORT_PATH).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "error": "Malformed row count for data_202303 in 'Error: no such table: data_202303'"
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn sqlite_failures_are_500() {
+        let app = build_router(
+            &SharedState::default(),
+            DEFAULT_BASE_PATH,
+            None,
+            None,
+            "cat > /dev/null; echo 'Parse error near line 1: no such table: data_202303' >&2; exit 1",
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        );
+        for uri in [
+            EXPORT_PATH.to_string(),
+            format!("{}?from=2025-01-01&to=2025-01-02", DAILY_PATH),
+            RECORD_PATH.replace("{timestamp}", "1000"),
+        ] {
+            let (status, json) = get_json(app.clone(), &uri).await;
+            assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR, "{}", uri);
+            assert_eq!(
+                json,
+                serde_json::json!({
+                    "error": "SQLite failed: Parse error near line 1: no such table: data_202303 (exit status: 1)"
+                }),
+                "{}",
+                uri
+            );
+        }
+    }
+
+    async fn post_capacity_json(state: &SharedState, n: &str) -> (StatusCode, serde_json::Value) {
+        let response = build_router(
+            state,
+            DEFAULT_BASE_PATH,
+            None,
+            None,
+            FAKE_SQL,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        )
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri(CAPACITY_PATH.replace("{n}", n))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn capacity_can_grow_and_shrink() {
+        let state = SharedState::default();
+        {
+            let mut w = state.write().unwrap();
+            for ts in 0..5 {
+                w.data
+                    .push(Data202303::empty(1000 + 60 * ts).with_pv2022(Some(ts as f64)));
+            }
+        }
+
+        let (status, json) = post_capacity_json(&state, "2000").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json, serde_json::json!({ "capacity": 2000, "len": 5 }));
+
+        let (status, json) = post_capacity_json(&state, "3").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json, serde_json::json!({ "capacity": 3, "len": 3 }));
+        // Oldest records were dropped
+        assert_eq!(
+            state.read().unwrap().get_first_data().map(|d| d.timestamp),
+            Some(1120)
+        );
+    }
+
+    #[tokio::test]
+    async fn capacity_rejects_zero_and_garbage() {
+        let state = SharedState::default();
+        let (status, json) = post_capacity_json(&state, "0").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            json,
+            serde_json::json!({ "error": "capacity must be strictly positive" })
+        );
+        let (status, _) = post_capacity_json(&state, "many").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(state.read().unwrap().data.get_capacity(), 1440);
+    }
+
+    async fn post_json(
+        state: &SharedState,
+        uri: &str,
+        json: serde_json::Value,
+    ) -> (StatusCode, serde_json::Value) {
+        send_json(state, Method::POST, uri, json).await
+    }
+
+    async fn send_json(
+        state: &SharedState,
+        method: Method,
+        uri: &str,
+        json: serde_json::Value,
+    ) -> (StatusCode, serde_json::Value) {
+        let response = build_router(
+            state,
+            DEFAULT_BASE_PATH,
+            None,
+            None,
+            FAKE_SQL,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        )
+        .oneshot(
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("Content-Type", "application/json")
+                .body(Body::from(json.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn record_endpoint_edits_existing_record() {
+        let state = SharedState::default();
+        for (ts, pv) in [(1000, 10.0), (1060, 11.0)] {
+            state.write().unwrap().data.push(Data202303 {
+                peak_conso_kWh: Some(100.0),
+                ..Data202303::empty(ts).with_pv2022(Some(pv))
+            });
+        }
+        let uri = RECORD_PATH.replace("{timestamp}", "1060");
+        let (status, json) = send_json(
+            &state,
+            Method::PUT,
+            &uri,
+            serde_json::json!({ "timestamp": 1060, "peak_conso_kWh": 101.5, "gas_m3": 28973.5 }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "timestamp": 1060,
+                "pv2012_kWh": null,
+                "pv2022_kWh": 11.0,
+                "peak_conso_kWh": 101.5,
+                "off_conso_kWh": null,
+                "peak_inj_kWh": null,
+                "off_inj_kWh": null,
+                "gas_m3": 28973.5,
+                "water_m3": null,
+            })
+        );
+        let latest = state.read().unwrap().get_last_data().unwrap();
+        assert_eq!(latest.peak_conso_kWh, Some(101.5));
+        assert_eq!(latest.gas_m3, Some(28973.5));
+        assert_eq!(state.read().unwrap().data.len(), 2);
+
+        let (status, _) = send_json(
+            &state,
+            Method::PUT,
+            &uri,
+            serde_json::json!({ "timestamp": 1000, "gas_m3": 1.0 }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn get_record_from_buffer_then_database() {
+        let state = SharedState::default();
+        state
+            .write()
+            .unwrap()
+            .data
+            .push(Data202303::empty(1000).with_gas(Some(28973.5)));
+        let app = |sql_cmd| {
+            build_router(
+                &state,
+                DEFAULT_BASE_PATH,
+                None,
+                None,
+                sql_cmd,
+                DEFAULT_MAX_BODY_BYTES,
+                None,
+            )
+        };
+        let uri = |ts: &str| RECORD_PATH.replace("{timestamp}", ts);
+
+        // Buffered records do not need the database
+        let (status, json) = get_json(app("exit 1"), &uri("1000")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["timestamp"], 1000);
+        assert_eq!(json["gas_m3"], 28973.5);
+
+        let (status, json) = get_json(
+            app("cat > /dev/null; echo '1695485160||3579.5||||||'"),
+            &uri("1695485160"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["timestamp"], 1695485160);
+        assert_eq!(json["pv2022_kWh"], 3579.5);
+
+        let (status, _) = get_json(app("cat > /dev/null"), &uri("1001")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            get_status(app("exit 1"), &uri("yesterday")).await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn record_endpoint_missing_timestamp_is_404() {
+        let state = SharedState::default();
+        state
+            .write()
+            .unwrap()
+            .data
+            .push(Data202303::empty(1000).with_pv2022(Some(10.0)));
+        let (status, json) = send_json(
+            &state,
+            Method::PUT,
+            &RECORD_PATH.replace("{timestamp}", "1001"),
+            serde_json::json!({ "gas_m3": 1.0 }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(json["error"].is_string());
+        assert_eq!(state.read().unwrap().get_last_data().unwrap().gas_m3, None);
+    }
+
+    #[tokio::test]
+    async fn water_endpoint_saves_water_only() {
+        let state = SharedState::default();
+        let (status, json) = post_json(
+            &state,
+            WATER_PATH,
+            serde_json::json!({ "timestamp": "2025-01-02T03:04:00+01:00", "value": 867.5 }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["timestamp"], 1735783440);
+        assert_eq!(json["water_m3"], 867.5);
+        assert_eq!(json["gas_m3"], serde_json::Value::Null);
+        assert_eq!(json["pv2012_kWh"], serde_json::Value::Null);
+        assert_eq!(state.read().unwrap().data.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn gas_endpoint_saves_gas_only() {
+        let state = SharedState::default();
+        let (status, json) = post_json(
+            &state,
+            GAS_PATH,
+            serde_json::json!({ "timestamp": "2025-01-02T03:04:00+01:00", "value": "28973.5" }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["gas_m3"], 28973.5);
+        assert_eq!(json["water_m3"], serde_json::Value::Null);
+        assert_eq!(state.read().unwrap().data.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn oversized_bodies_are_rejected_with_413() {
+        let state = SharedState::default();
+        let padding = "x".repeat(200);
+        let body = format!(
+            r#"{{"timestamp":"2024-10-25T02:00:00+02:00","value":1.5,"padding":"{}"}}"#,
+            padding
+        );
+        for (limit, expected) in [(100, StatusCode::PAYLOAD_TOO_LARGE), (1000, StatusCode::OK)] {
+            let response =
+                build_router(&state, DEFAULT_BASE_PATH, None, None, FAKE_SQL, limit, None)
+                    .oneshot(
+                        Request::builder()
+                            .method(Method::POST)
+                            .uri(WATER_PATH)
+                            .header("Content-Type", "application/json")
+                            .body(Body::from(body.clone()))
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+            assert_eq!(response.status(), expected, "limit={}", limit);
+        }
+        // Only the request within the limit was recorded
+        assert_eq!(state.read().unwrap().data.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn water_and_gas_endpoints_reject_bad_values() {
+        let state = SharedState::default();
+        for (uri, json, error) in [
+            (
+                WATER_PATH,
+                serde_json::json!({ "timestamp": "2025-01-02T03:04:00+01:00", "value": -1.0 }),
+                "Value must be strictly positive",
+            ),
+            (
+                GAS_PATH,
+                serde_json::json!({ "timestamp": "2025-01-02T03:04:00+01:00", "value": -2 }),
+                "Value must be strictly positive",
+            ),
+            (
+                GAS_PATH,
+                serde_json::json!({ "timestamp": "2025-01-02T03:04:00+01:00", "value": "" }),
+                "Value is required",
+            ),
+            (
+                WATER_PATH,
+                serde_json::json!({ "timestamp": "2025-01-02T03:04:00+01:00", "value": true }),
+                "Value must be a number",
+            ),
+        ] {
+            let (status, body) = post_json(&state, uri, json).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(body, serde_json::json!({ "error": error }));
+        }
+        let (status, _) = post_json(
+            &state,
+            WATER_PATH,
+            serde_json::json!({ "timestamp": "yesterday", "value": 1.0 }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(state.read().unwrap().data.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn import_csv_reports_each_row() {
+        let state = SharedState::default();
+        state
+            .write()
+            .unwrap()
+            .data
+            .push(Data202303::empty(1735783440));
+        let csv = "timestamp,pv2012_kWh,gas,water\n\
+                   2025-01-02T03:04:00+01:00,,28973.5,\n\
+                   1735790000,12.5,,867.5\n\
+                   1735800000,-1,,\n";
+        let response = build_router(
+            &state,
+            DEFAULT_BASE_PATH,
+            None,
+            None,
+            FAKE_SQL,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        )
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri(IMPORT_PATH)
+                .header("Content-Type", "text/csv")
+                .body(Body::from(csv))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "inserted": 1,
+                "updated": 1,
+                "failed": 1,
+                "errors": [
+                    { "line": 4, "error": "pv2012_kWh: Value must be strictly positive" },
+                ],
+            })
+        );
+        let state = state.read().unwrap();
+        assert_eq!(state.data.len(), 2);
+        assert_eq!(state.get_first_data().unwrap().gas_m3, Some(28973.5));
+        assert_eq!(state.get_last_data().unwrap().water_m3, Some(867.5));
+    }
+
+    #[tokio::test]
+    async fn mutating_requests_beyond_rate_limit_get_429() {
+        let state = SharedState::default();
+        let app = build_router(
+            &state,
+            DEFAULT_BASE_PATH,
+            None,
+            None,
+            FAKE_SQL,
+            DEFAULT_MAX_BODY_BYTES,
+            Some(2),
+        );
+        let mut statuses = Vec::new();
+        for _ in 0..3 {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method(Method::POST)
+                        .uri(WATER_PATH)
+                        .header("Content-Type", "application/json")
+                        .body(Body::from(
+                            r#"{"timestamp":"2025-01-02T03:04:00+01:00","value":867.5}"#,
+                        ))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            statuses.push(response.status());
+        }
+        assert_eq!(
+            statuses,
+            [
+                StatusCode::OK,
+                StatusCode::OK,
+                StatusCode::TOO_MANY_REQUESTS
+            ]
+        );
+        // Reads are never throttled
+        assert_eq!(get_status(app, SERIES_PATH).await, StatusCode::OK);
+    }
+
+    #[test]
+    fn rate_limiter_refills_per_client() {
+        let limiter = RateLimiter::new(60);
+        let start = Instant::now();
+        let a = Some(IpAddr::from([192, 168, 1, 2]));
+        let b = Some(IpAddr::from([192, 168, 1, 3]));
+        for _ in 0..60 {
+            assert!(limiter.try_acquire(a, start));
+        }
+        assert!(!limiter.try_acquire(a, start));
+        assert!(limiter.try_acquire(b, start));
+        assert!(limiter.try_acquire(a, start + Duration::from_secs(1)));
+        assert!(!limiter.try_acquire(a, start + Duration::from_secs(1)));
+    }
+
+    #[tokio::test]
+    async fn last_telegram_is_empty_before_first_poll() {
+        let app = build_router(
+            &SharedState::default(),
+            DEFAULT_BASE_PATH,
+            None,
+            None,
+            FAKE_SQL,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        );
+        assert_eq!(
+            get_status(app, LAST_TELEGRAM_PATH).await,
+            StatusCode::NO_CONTENT
+        );
+    }
+
+    #[tokio::test]
+    async fn last_telegram_returns_captured_text() {
+        let state = SharedState::default();
+        let blocking_ref = Arc::clone(&state);
+        let (p1, _, _) = task::spawn_blocking(move || {
+            poll_automated_measurements(
+                &blocking_ref,
+                &P1Reader::Cmd("echo '/FLU5'; echo '0-0:1.0.0(241025000000S)'; echo '1-0:1.8.1(002654.919*kWh)'; echo '1-0:1.8.2(002420.293*kWh)'; echo '1-0:2.8.1(006254.732*kWh)'; echo '1-0:2.8.2(002457.202*kWh)'".to_string()),
+                Duration::from_secs(5),
+                0,
+                &P1ObisConfig::default(),
+                "echo '{}'",
+                pv2022::PV_2022_JSON_PATH,
+                Duration::from_secs(5),
+                pv2022::DEFAULT_DIVISOR,
+                None,
+                true,
+            )
+        })
+        .await
+        .unwrap();
+        assert!(p1.is_some());
+
+        let response = build_router(
+            &state,
+            DEFAULT_BASE_PATH,
+            None,
+            None,
+            FAKE_SQL,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        )
+        .oneshot(
+            Request::builder()
+                .uri(LAST_TELEGRAM_PATH)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[CONTENT_TYPE],
+            "text/plain; charset=utf-8"
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(
+            &body[..],
+            b"/FLU5\n0-0:1.0.0(241025000000S)\n1-0:1.8.1(002654.919*kWh)\n1-0:1.8.2(002420.293*kWh)\n1-0:2.8.1(006254.732*kWh)\n1-0:2.8.2(002457.202*kWh)\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn last_telegram_requires_auth_when_configured() {
+        let state = SharedState::default();
+        state.write().unwrap().last_telegram =
+            Some("0-0:96.1.1(4B384547303034303436333935353037)\n".to_string());
+        let app = build_router(
+            &state,
+            DEFAULT_BASE_PATH,
+            None,
+            Some("me:secret"),
+            FAKE_SQL,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        );
+        assert_eq!(
+            get_status(app, LAST_TELEGRAM_PATH).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn ringbuffer_internals_follow_wraparound() {
+        let state = SharedState::default();
+        state.write().unwrap().data.resize(3);
+        let app = || {
+            build_router(
+                &state,
+                DEFAULT_BASE_PATH,
+                None,
+                None,
+                FAKE_SQL,
+                DEFAULT_MAX_BODY_BYTES,
+                None,
+            )
+        };
+        let fetch = |app: Router| async move {
+            let response = app
+                .oneshot(
+                    Request::builder()
+                        .uri(RINGBUFFER_PATH)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+        let timestamps = |json: &serde_json::Value| -> Vec<i64> {
+            json["records"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|r| r["timestamp"].as_i64().unwrap())
+                .collect()
+        };
+
+        for ts in [1000, 1060] {
+            state.write().unwrap().data.push(Data202303::empty(ts));
+        }
+        let json = fetch(app()).await;
+        assert_eq!(
+            (
+                &json["start"],
+                &json["end"],
+                &json["capacity"],
+                &json["buffer_len"]
+            ),
+            (&0.into(), &2.into(), &3.into(), &2.into())
+        );
+        assert_eq!(timestamps(&json), vec![1000, 1060]);
+
+        for ts in [1120, 1180] {
+            state.write().unwrap().data.push(Data202303::empty(ts));
+        }
+        let json = fetch(app()).await;
+        assert_eq!(
+            (
+                &json["start"],
+                &json["end"],
+                &json["capacity"],
+                &json["buffer_len"]
+            ),
+            (&1.into(), &1.into(), &3.into(), &3.into())
+        );
+        assert_eq!(timestamps(&json), vec![1060, 1120, 1180]);
+    }
+
+    #[tokio::test]
+    async fn insert_sql_covers_the_buffer() {
+        let state = SharedState::default();
+        let app = || {
+            build_router(
+                &state,
+                DEFAULT_BASE_PATH,
+                None,
+                Some("me:secret"),
+                FAKE_SQL,
+                DEFAULT_MAX_BODY_BYTES,
+                None,
+            )
+        };
+        let fetch = |app: Router| async move {
+            app.oneshot(
+                Request::builder()
+                    .uri(INSERT_SQL_PATH)
+                    .header(
+                        AUTHORIZATION,
+                        format!("Basic {}", BASE64.encode("me:secret")),
+                    )
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+        };
+        assert_eq!(
+            get_status(app(), INSERT_SQL_PATH).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(fetch(app()).await.status(), StatusCode::NO_CONTENT);
+
+        for ts in [1000, 1060] {
+            state
+                .write()
+                .unwrap()
+                .data
+                .push(Data202303::empty(ts).with_gas(Some(28973.5)));
+        }
+        let response = fetch(app()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let sql = String::from_utf8(body.to_vec()).unwrap();
+        assert_eq!(sql.matches("INSERT INTO data_202303").count(), 2);
+        assert_eq!(state.read().unwrap().data.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn ringbuffer_internals_require_auth_when_configured() {
+        let app = build_router(
+            &SharedState::default(),
+            DEFAULT_BASE_PATH,
+            None,
+            Some("me:secret"),
+            FAKE_SQL,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        );
+        assert_eq!(
+            get_status(app, RINGBUFFER_PATH).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn form_placeholders_show_latest_persisted_values() {
+        let state = SharedState::default();
+        state.write().unwrap().latest_persisted = Some(
+            Data202303::empty(1695537420)
+                .with_pv2012(Some(50621.3))
+                .with_pv2022(Some(3579.9))
+                .with_water(Some(867.5)),
+        );
+        let response = build_router(
+            &state,
+            DEFAULT_BASE_PATH,
+            None,
+            None,
+            FAKE_SQL,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        )
+        .oneshot(
+            Request::builder()
+                .uri(FORM_PATH)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let html = String::from_utf8(body.to_vec()).unwrap();
+        assert!(html.contains(r#"name="pv2012_kWh" value="" placeholder="50621.3""#));
+        assert!(html.contains(r#"name="gas" value="" step"#));
+        assert!(html.contains(r#"name="water" value="" placeholder="867.5""#));
+    }
+
+    #[test]
+    fn summary_includes_pv2022_average_when_known() {
+        let mut state = AppState::default();
+        assert_eq!(render_summary(&state), "0 input measurements");
+        for (ts, pv) in [(1000, 10.0), (1060, 11.0), (1120, 12.5)] {
+            state.data.push(Data202303::empty(ts).with_pv2022(Some(pv)));
+        }
+        assert_eq!(
+            render_summary(&state),
+            "3 input measurements (one every 60s), PV2022 11.167 kWh (average of the last 5)"
+        );
+    }
+
+    #[test]
+    fn summary_shows_configured_fields_of_latest_record() {
+        let mut state = AppState {
+            summary_fields: parse_field_list("gas_m3, nonsense, peak_conso_kWh, water_m3"),
+            ..AppState::default()
+        };
+        state.data.push(Data202303 {
+            peak_conso_kWh: Some(2654.919),
+            off_conso_kWh: Some(2420.293),
+            ..Data202303::empty(1000)
+                .with_pv2012(Some(50621.3))
+                .with_gas(Some(28973.5))
+        });
+        assert_eq!(
+            render_summary(&state),
+            "1 input measurements; latest: gas_m3 28973.500, peak_conso_kWh 2654.919"
+        );
+        state.summary_fields.clear();
+        assert_eq!(render_summary(&state), "1 input measurements");
+    }
+
+    #[test]
+    fn parse_field_list_skips_unknown_names() {
+        assert_eq!(
+            parse_field_list(" peak_conso_kWh, nonsense,,off_conso_kWh "),
+            vec!["peak_conso_kWh".to_string(), "off_conso_kWh".to_string()]
+        );
+        assert!(parse_field_list("").is_empty());
+    }
+
+    #[test]
+    fn parse_log_format_accepts_text_and_json() {
+        assert_eq!(parse_log_format(" JSON "), Some(LogFormat::Json));
+        assert_eq!(parse_log_format("text"), Some(LogFormat::Text));
+        assert_eq!(parse_log_format("xml"), None);
+    }
+
+    /// Collects what a subscriber writes.
+    #[derive(Clone, Default)]
+    struct CapturedLog(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLog {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn poll_cycle_logs_json_object() {
+        let log = CapturedLog::default();
+        let writer = log.clone();
+        let state = SharedState::default();
+        tracing::subscriber::with_default(json_subscriber(move || writer.clone()), || {
+            let (p1, pv_2022, pv_2012) = poll_automated_measurements(
+                &state,
+                &P1Reader::Cmd("echo '0-0:1.0.0(241025000000S)'; echo '1-0:1.8.1(002654.919*kWh)'; echo '1-0:1.8.2(002420.293*kWh)'; echo '1-0:2.8.1(006254.732*kWh)'; echo '1-0:2.8.2(002457.202*kWh)'".to_string()),
+                Duration::from_secs(5),
+                0,
+                &P1ObisConfig::default(),
+                "echo '{}'",
+                pv2022::PV_2022_JSON_PATH,
+                Duration::from_secs(5),
+                pv2022::DEFAULT_DIVISOR,
+                None,
+                false,
+            );
+            let p1_present = p1.is_some();
+            let flushed = save_data(
+                &state,
+                p1,
+                pv_2022,
+                pv_2012,
+                &FakeSqlite::new(&[]),
+                3600,
+                false,
+                100,
+                "",
+                &RetentionPolicy::default(),
+                false,
+                None,
+                None,
+            );
+            log_cycle(1, p1_present, pv_2022, flushed.map_or(0, |(sent, _)| sent));
+        });
+        let output = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        // The messages of the cycle are JSON objects too
+        let events: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert!(events.len() > 1);
+        let event = events.last().unwrap();
+        assert_eq!(event["message"], "poll");
+        assert_eq!(event["counter"], 1);
+        assert_eq!(event["p1_present"], true);
+        assert_eq!(event["rows_flushed"], 0);
+        assert!(event.get("pv_value").is_none());
+    }
+
+    #[test]
+    fn text_log_prints_messages_without_cycle_summary() {
+        let log = CapturedLog::default();
+        let writer = log.clone();
+        tracing::subscriber::with_default(text_subscriber(move || writer.clone()), || {
+            tracing::warn!("Warning: buffer full, the oldest record was overwritten");
+            log_cycle(1, false, None, 0);
+            tracing::info!("Flushed {} rows ({} new)", 3, 2);
+        });
+        assert_eq!(
+            String::from_utf8(log.0.lock().unwrap().clone()).unwrap(),
+            "Warning: buffer full, the oldest record was overwritten\nFlushed 3 rows (2 new)\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn supervisor_restarts_panicking_body() {
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            supervise_blocking("Test loop", Duration::from_millis(10), move || {
+                if counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 {
+                    panic!("first run fails");
+                }
+            }),
+        )
+        .await
+        .expect("supervisor should return once the body succeeds");
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn favicon_returns_no_content() {
+        let app = build_router(
+            &SharedState::default(),
+            DEFAULT_BASE_PATH,
+            None,
+            None,
+            FAKE_SQL,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        );
+        assert_eq!(
+            get_status(app, "/favicon.ico").await,
+            StatusCode::NO_CONTENT
+        );
+    }
+
+    #[tokio::test]
+    async fn static_files_are_served_from_configured_dir() {
+        let dir =
+            env::temp_dir().join(format!("axum-meter-readings-static-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("chart.js"), "console.log('chart');").unwrap();
+        let state = SharedState::default();
+
+        let app = build_router(
+            &state,
+            DEFAULT_BASE_PATH,
+            dir.to_str(),
+            None,
+            FAKE_SQL,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        );
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/static/chart.js")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"console.log('chart');");
+
+        let app = build_router(
+            &state,
+            DEFAULT_BASE_PATH,
+            dir.to_str(),
+            None,
+            FAKE_SQL,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        );
+        assert_eq!(
+            get_status(app, "/static/missing.js").await,
+            StatusCode::NOT_FOUND
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn static_route_is_absent_without_dir() {
+        let app = build_router(
+            &SharedState::default(),
+            DEFAULT_BASE_PATH,
+            None,
+            None,
+            FAKE_SQL,
+            DEFAULT_MAX_BODY_BYTES,
+            None,
+        );
+        assert_eq!(
+            get_status(app, "/static/chart.js").await,
+            StatusCode::NOT_FOUND
+        );
+    }
 }